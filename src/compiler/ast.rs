@@ -0,0 +1,466 @@
+use std::ops::Range;
+
+/*
+ *
+ * AST
+ *
+ * Nodes produced by the parser. Every node
+ * carries the byte range of source it was
+ * parsed from.
+ *
+ * Equality between nodes ignores spans, so
+ * trees parsed from differently formatted
+ * source compare equal.
+ *
+ */
+
+#[derive(Debug, Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Range<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Literal(LiteralValue),
+
+    Variable(String),
+
+    This,
+
+    // `[a, b, ...]`, a new list of the elements.
+    List(Vec<Expr>),
+
+    // `{key: value, ...}`. Keys are arbitrary
+    // expressions; whether they are valid map keys
+    // is only known at runtime.
+    Map(Vec<(Expr, Expr)>),
+
+    // Assignment is an expression evaluating to
+    // the assigned value, so `a = b = 5` works.
+    Assign {
+        name: String,
+        value: Box<Expr>,
+    },
+
+    // `!a` or `-a`, binding tighter than any binary
+    // operator but looser than calls, so `-a.b`
+    // negates `a.b`.
+    Unary {
+        op: UnaryOp,
+        operand: Box<Expr>,
+    },
+
+    Binary {
+        op: BinaryOp,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+
+    // A method call on `receiver`, or on the
+    // enclosing scope when there is none.
+    //
+    // `a.length` is a getter while `a.length()` is
+    // a method taking no arguments; Wren treats
+    // them as different signatures.
+    Call {
+        receiver: Option<Box<Expr>>,
+        name: String,
+        args: Vec<Expr>,
+        is_getter: bool,
+    },
+
+    // `receiver[args]`, which calls the subscript
+    // operator with the signature `[_]`, `[_,_]`...
+    Subscript {
+        receiver: Box<Expr>,
+        args: Vec<Expr>,
+    },
+
+    // `receiver[args] = value`, which calls the subscript
+    // setter `[_]=(_)`, `[_,_]=(_)`... The value is the
+    // last of `args`.
+    SubscriptSet {
+        receiver: Box<Expr>,
+        args: Vec<Expr>,
+    },
+
+    // `receiver[args] op= value`, which calls the subscript
+    // operator and stores `element op value` back with the
+    // setter, evaluating `receiver` and `args` only once.
+    SubscriptUpdate {
+        receiver: Box<Expr>,
+        args: Vec<Expr>,
+        op: BinaryOp,
+        value: Box<Expr>,
+    },
+
+    // `receiver.name op= value`, which calls the getter
+    // `name` and stores `current op value` back with the
+    // setter `name=(_)`, evaluating `receiver` only once.
+    PropertyUpdate {
+        receiver: Box<Expr>,
+        name: String,
+        op: BinaryOp,
+        value: Box<Expr>,
+    },
+
+    // `super.name(args)` calls the superclass's method
+    // on `this`, and `super.name` and `super.name = value`
+    // its getter and setter. Without a name, `super(args)`
+    // chains to the superclass constructor of the same
+    // name, and `kind` is `Constructor`.
+    Super {
+        name: Option<String>,
+        args: Vec<Expr>,
+        kind: MemberKind,
+    },
+
+    // `{ |params| body }`, a function passed as the
+    // last argument of a call, as in `Fiber.new { }`.
+    Fn {
+        params: Vec<String>,
+        body: Vec<Stmt>,
+        /// Whether `body` is one expression on the line of the `{`,
+        /// whose value the block returns.
+        expression_body: bool,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum LiteralValue {
+    // The value, and the literal as it was written, e.g.
+    // `1_000` or `0xff`, so a formatter can keep it. Folded
+    // and generated numbers have no source text.
+    Num(f64, Option<String>),
+    Str(String),
+    Bool(bool),
+    Null,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    /// `!`
+    Not,
+    /// `-`
+    Negate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Multiply,
+    Divide,
+    Modulo,
+    Add,
+    Subtract,
+    InclusiveRange,
+    ExclusiveRange,
+    LeftShift,
+    RightShift,
+    BitwiseAnd,
+    BitwiseXor,
+    BitwiseOr,
+    LessThan,
+    LessThanEqual,
+    GreaterThan,
+    GreaterThanEqual,
+    Is,
+    Equal,
+    NotEqual,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Range<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StmtKind {
+    Expression(Expr),
+
+    Var {
+        name: String,
+        initializer: Option<Expr>,
+    },
+
+    Block(Vec<Stmt>),
+
+    For {
+        variable: String,
+        sequence: Expr,
+        body: Box<Stmt>,
+    },
+
+    // `if (condition) then_branch else else_branch`, where
+    // the `else` is optional.
+    If {
+        condition: Expr,
+        then_branch: Box<Stmt>,
+        else_branch: Option<Box<Stmt>>,
+    },
+
+    // Loops while `condition` is truthy. `for` loops
+    // are desugared into these before codegen.
+    While {
+        condition: Expr,
+        body: Box<Stmt>,
+    },
+
+    // `return value`; a bare `return` returns null.
+    Return(Option<Expr>),
+
+    // Leave, or jump back to the start of, the innermost loop.
+    Break,
+    Continue,
+
+    Class(ClassDecl),
+
+    // `import "module" for Name, Other as Alias`;
+    // each name is paired with its alias, if any.
+    Import {
+        module: String,
+        names: Vec<(String, Option<String>)>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClassDecl {
+    pub name: String,
+    /// The comment lines right before the declaration, if any.
+    pub doc: Option<String>,
+    pub superclass: Option<String>,
+    pub members: Vec<ClassMember>,
+    /// The `static __name = value` lines, in source order.
+    pub static_fields: Vec<StaticField>,
+}
+
+/// A static field given a value when its class is declared,
+/// before any instance exists.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StaticField {
+    pub name: String,
+    pub value: Expr,
+    pub span: Range<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemberKind {
+    Constructor,
+    Method,
+    Getter,
+    Setter,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClassMember {
+    pub kind: MemberKind,
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Vec<Stmt>,
+    /// Whether `body` is one expression on the line of the `{`,
+    /// as in `name { value }`, whose value the method returns.
+    pub expression_body: bool,
+    pub is_static: bool,
+    pub is_foreign: bool,
+    pub attributes: Vec<Attribute>,
+    /// The comment lines right before the member, if any.
+    pub doc: Option<String>,
+    pub span: Range<usize>,
+}
+
+impl PartialEq for ClassMember {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+            && self.name == other.name
+            && self.params == other.params
+            && self.body == other.body
+            && self.expression_body == other.expression_body
+            && self.is_static == other.is_static
+            && self.is_foreign == other.is_foreign
+            && self.attributes == other.attributes
+            && self.doc == other.doc
+    }
+}
+
+/// `#name`, `#name = value` or `#name(key, key = value)` before a
+/// class member. A bare name as a value is kept as a string.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attribute {
+    pub name: String,
+    pub value: Option<LiteralValue>,
+    /// The entries of a group, in order.
+    pub group: Vec<(String, Option<LiteralValue>)>,
+    /// Written `#!name`, to be kept for the program at runtime.
+    pub runtime: bool,
+}
+
+impl ClassMember {
+    /// The signature Wren dispatches on, e.g. `foo(_,_)`, `foo` or `foo=(_)`.
+    ///
+    /// `foo` and `foo()` are different signatures.
+    pub fn signature(&self) -> String {
+        let params = vec!["_"; self.params.len()].join(",");
+        match self.kind {
+            MemberKind::Getter => self.name.clone(),
+            MemberKind::Setter => format!("{}=({params})", self.name),
+            MemberKind::Method | MemberKind::Constructor => format!("{}({params})", self.name),
+        }
+    }
+
+    /// Whether this member is called on the class rather than an instance.
+    /// Constructors are invoked on the class, like static methods.
+    pub fn on_class(&self) -> bool {
+        self.is_static || self.kind == MemberKind::Constructor
+    }
+}
+
+// Like spans, how a number was written does not matter.
+impl PartialEq for LiteralValue {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (LiteralValue::Num(a, _), LiteralValue::Num(b, _)) => a == b,
+            (LiteralValue::Str(a), LiteralValue::Str(b)) => a == b,
+            (LiteralValue::Bool(a), LiteralValue::Bool(b)) => a == b,
+            (LiteralValue::Null, LiteralValue::Null) => true,
+            _ => false,
+        }
+    }
+}
+
+impl PartialEq for Expr {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+
+impl PartialEq for Stmt {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+
+/**
+ *
+ * Describes the first structural difference
+ * between two expressions, ignoring spans.
+ *
+ * The description starts with a path from the
+ * root, e.g. `expr.right.args[0]`, and returns
+ * `None` when the trees are equal.
+ *
+ */
+pub fn diff(a: &Expr, b: &Expr) -> Option<String> {
+    diff_at("expr".to_string(), a, b)
+}
+
+fn diff_at(path: String, a: &Expr, b: &Expr) -> Option<String> {
+    match (&a.kind, &b.kind) {
+        (
+            ExprKind::Binary {
+                op: op_a,
+                left: left_a,
+                right: right_a,
+            },
+            ExprKind::Binary {
+                op: op_b,
+                left: left_b,
+                right: right_b,
+            },
+        ) => {
+            if op_a != op_b {
+                return Some(format!("{path}.op: {op_a:?} != {op_b:?}"));
+            }
+            diff_at(format!("{path}.left"), left_a, left_b)
+                .or_else(|| diff_at(format!("{path}.right"), right_a, right_b))
+        }
+        (
+            ExprKind::Call {
+                receiver: receiver_a,
+                name: name_a,
+                args: args_a,
+                is_getter: getter_a,
+            },
+            ExprKind::Call {
+                receiver: receiver_b,
+                name: name_b,
+                args: args_b,
+                is_getter: getter_b,
+            },
+        ) => {
+            if name_a != name_b {
+                return Some(format!("{path}.name: {name_a:?} != {name_b:?}"));
+            }
+            if getter_a != getter_b {
+                return Some(format!("{path}.is_getter: {getter_a} != {getter_b}"));
+            }
+            match (receiver_a, receiver_b) {
+                (Some(receiver_a), Some(receiver_b)) => {
+                    if let Some(found) = diff_at(format!("{path}.receiver"), receiver_a, receiver_b)
+                    {
+                        return Some(found);
+                    }
+                }
+                (None, None) => {}
+                _ => return Some(format!("{path}.receiver: {receiver_a:?} != {receiver_b:?}")),
+            }
+            diff_list(&format!("{path}.args"), args_a, args_b)
+        }
+        _ if a == b => None,
+        (kind_a, kind_b) => Some(format!("{path}: {kind_a:?} != {kind_b:?}")),
+    }
+}
+
+fn diff_list(path: &str, a: &[Expr], b: &[Expr]) -> Option<String> {
+    if a.len() != b.len() {
+        return Some(format!(
+            "{path}: {} elements != {} elements",
+            a.len(),
+            b.len()
+        ));
+    }
+    a.iter()
+        .zip(b)
+        .enumerate()
+        .find_map(|(i, (a, b))| diff_at(format!("{path}[{i}]"), a, b))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::parser::Parser;
+
+    fn expr(src: &str) -> Expr {
+        Parser::new(src).unwrap().expression().unwrap()
+    }
+
+    #[test]
+    fn equality_ignores_spans() {
+        assert_eq!(expr("a+b"), expr("a   +   b"));
+        assert_eq!(diff(&expr("(a)"), &expr("a")), None);
+    }
+
+    #[test]
+    fn diff_reports_leaf_path() {
+        let a = expr("1 + 2 * 3");
+        let b = expr("1 + 2 * 4");
+
+        assert_eq!(
+            diff(&a, &b),
+            Some("expr.right.right: Literal(Num(3.0, Some(\"3\"))) != Literal(Num(4.0, Some(\"4\")))".to_string())
+        );
+    }
+
+    #[test]
+    fn diff_reports_call_arguments() {
+        let a = expr("x in list");
+        let b = expr("y in list");
+
+        assert_eq!(
+            diff(&a, &b),
+            Some("expr.args[0]: Variable(\"x\") != Variable(\"y\")".to_string())
+        );
+    }
+}
@@ -0,0 +1,2509 @@
+use std::collections::HashSet;
+use std::ops::Range;
+use std::rc::Rc;
+
+use super::ast::{
+    BinaryOp, ClassDecl, ClassMember, Expr, ExprKind, LiteralValue, MemberKind, Stmt, StmtKind,
+    UnaryOp,
+};
+use super::desugar::desugar_for;
+use super::diagnostic::{Diagnostic, DiagnosticSink};
+use super::error::{CompileError, ResolveError};
+use super::fold::{eliminate_dead_code, fold_program};
+use super::lexer::lex_collect_errors;
+use super::parser::Parser;
+use crate::vm::chunk::{Capture, Chunk, ChunkBuilder, Function, JumpHandle, Op, MAIN_MODULE};
+use crate::vm::value::Value;
+use crate::vm::CORE_VARIABLES;
+
+/*
+ *
+ * Codegen
+ *
+ * Lowers the AST of a module into a chunk.
+ *
+ * Variables declared at the top level are module
+ * variables, stored by name in the VM's globals.
+ * Method and block bodies may use ones declared
+ * further down, since they run later.
+ * Variables declared inside blocks are locals living
+ * in stack slots; slot 0 holds the running script.
+ * A `for` loop's variable is a local the body may
+ * read but not assign.
+ *
+ * Methods compile to functions of their own, with
+ * the receiver in slot 0. Names starting with `_`
+ * are instance fields, stored in the instance, and
+ * names starting with `__` are static fields. Those
+ * are shared by every method of the class, so they
+ * are hidden module variables named `Class.__field`.
+ * A lowercase name that is not a local is a call
+ * on `this`, so `count` in a method means
+ * `this.count`.
+ *
+ * Field indices count from the first field a class
+ * declares itself; the VM adds the number of fields
+ * its superclasses hold when the method is bound.
+ *
+ */
+
+/// Which optimizations `compile_with` runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OptLevel {
+    /// Code is generated for the program exactly as parsed.
+    None,
+    /// Constant expressions are folded and statements that
+    /// only evaluate a literal are dropped.
+    Basic,
+    /// Method calls also remember what they found in inline
+    /// caches.
+    #[default]
+    Full,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompileOptions {
+    pub opt_level: OptLevel,
+}
+
+/// Parses and compiles a module with every optimization.
+pub fn compile(src: &str) -> Result<Chunk, Vec<CompileError>> {
+    compile_with(src, CompileOptions::default())
+}
+
+/// Parses and compiles a module.
+///
+/// Every lex error is reported, along with the first parse error
+/// in the tokens that did lex. Resolve errors are only looked for
+/// once the module parses.
+pub fn compile_with(src: &str, options: CompileOptions) -> Result<Chunk, Vec<CompileError>> {
+    compile_reporting(src, options, None)
+}
+
+/// Compiles a module like `compile_with`, but gives `sink` each
+/// error and warning as soon as it is found. Returns the chunk if there were no errors.
+pub fn compile_to_sink(
+    src: &str,
+    options: CompileOptions,
+    sink: &mut dyn DiagnosticSink,
+) -> Option<Chunk> {
+    compile_reporting(src, options, Some(sink)).ok()
+}
+
+fn compile_reporting(
+    src: &str,
+    options: CompileOptions,
+    mut sink: Option<&mut dyn DiagnosticSink>,
+) -> Result<Chunk, Vec<CompileError>> {
+    let (tokens, lex_errors) = lex_collect_errors(src);
+    let mut errors = Vec::new();
+    for error in lex_errors {
+        let error = CompileError::from(error);
+        if let Some(sink) = sink.as_deref_mut() {
+            sink.emit(&error.diagnostic());
+        }
+        errors.push(error);
+    }
+
+    let mut parser = Parser::with_tokens(src, tokens);
+    let parsed = parser.program();
+    if let Some(sink) = sink.as_deref_mut() {
+        for warning in parser.diagnostics() {
+            sink.emit(warning);
+        }
+    }
+    match parsed {
+        Ok(_) if !errors.is_empty() => Err(errors),
+        Ok(program) if options.opt_level == OptLevel::None => {
+            compile_program(&program, options, sink)
+        }
+        Ok(program) => {
+            let program = eliminate_dead_code(fold_program(program));
+            compile_program(&program, options, sink)
+        }
+        Err(error) => {
+            let error = CompileError::from(error);
+            if let Some(sink) = sink {
+                sink.emit(&error.diagnostic());
+            }
+            errors.push(error);
+            Err(errors)
+        }
+    }
+}
+
+fn compile_program(
+    program: &[Stmt],
+    options: CompileOptions,
+    sink: Option<&mut dyn DiagnosticSink>,
+) -> Result<Chunk, Vec<CompileError>> {
+    let mut codegen = Codegen::new(HashSet::new());
+    codegen.inline_caches = options.opt_level == OptLevel::Full;
+    codegen.sink = sink;
+    codegen.statements(program);
+    codegen.chunk.write_op(Op::Null);
+    codegen.chunk.write_op(Op::Return);
+    codegen.finish().map(|(chunk, _)| chunk)
+}
+
+/// Compiles one entry of an interactive session, which may use the
+/// module variables declared by earlier entries. The chunk returns
+/// the value of a trailing expression statement, or null.
+///
+/// `module_variables` gains the entry's declarations only if it compiles.
+pub fn compile_entry(
+    program: &[Stmt],
+    module_variables: &mut HashSet<String>,
+) -> Result<Chunk, Vec<CompileError>> {
+    let mut codegen = Codegen::new(module_variables.clone());
+    match program.split_last() {
+        Some((
+            Stmt {
+                kind: StmtKind::Expression(last),
+                ..
+            },
+            rest,
+        )) => {
+            for stmt in rest {
+                codegen.statement(stmt);
+            }
+            codegen.expression(last);
+        }
+        _ => {
+            for stmt in program {
+                codegen.statement(stmt);
+            }
+            codegen.chunk.write_op(Op::Null);
+        }
+    }
+    codegen.chunk.write_op(Op::Return);
+
+    let (chunk, declared) = codegen.finish()?;
+    *module_variables = declared;
+    Ok(chunk)
+}
+
+struct Local {
+    name: String,
+    depth: usize,
+    // A `for` loop's variable, which the body cannot assign.
+    read_only: bool,
+    // Whether a block uses it, so its upvalue is closed when it
+    // goes out of scope.
+    captured: bool,
+}
+
+// A variable of an enclosing function that the function being
+// compiled uses.
+struct Captured {
+    capture: Capture,
+    read_only: bool,
+}
+
+// A function with a block being compiled inside it.
+struct Enclosing {
+    locals: Vec<Local>,
+    captures: Vec<Captured>,
+}
+
+// A loop whose body is being compiled.
+struct LoopState {
+    // Where `continue` jumps back to.
+    start: usize,
+    // The scope depth outside the body, whose locals a jump
+    // out of the body pops.
+    scope_depth: usize,
+    // The `break` jumps to point past the loop.
+    breaks: Vec<JumpHandle>,
+}
+
+// A class whose methods are being compiled.
+struct ClassState {
+    name: String,
+    fields: Vec<String>,
+    static_fields: Vec<String>,
+    has_superclass: bool,
+    in_static_method: bool,
+    // The static fields whose initializers have not run yet,
+    // while one is being compiled.
+    uninitialized: Vec<String>,
+    // The name of the constructor being compiled, if any.
+    constructor: Option<String>,
+}
+
+struct Codegen<'s> {
+    chunk: ChunkBuilder,
+    locals: Vec<Local>,
+    captures: Vec<Captured>,
+    // The functions that the block being compiled is nested in,
+    // innermost last. Methods see none.
+    enclosing: Vec<Enclosing>,
+    scope_depth: usize,
+    // The loops being compiled in the current function,
+    // innermost last.
+    loops: Vec<LoopState>,
+    module_variables: HashSet<String>,
+    // Module variables that function bodies used before they were
+    // declared, with where. Each must be declared by the end of the
+    // module.
+    forward_references: Vec<(String, Range<usize>)>,
+    // Whether a method or block body is being compiled.
+    in_function: bool,
+    // Whether a method, or a block inside one, is being compiled.
+    in_method: bool,
+    classes: Vec<ClassState>,
+    errors: Vec<CompileError>,
+    // Where errors are reported as they are found, if anywhere.
+    sink: Option<&'s mut dyn DiagnosticSink>,
+    inline_caches: bool,
+    // The variable of the `for` loop being compiled, until
+    // its desugared declaration is reached.
+    loop_variable: Option<String>,
+    // The span of the node being compiled, which the code
+    // written for it is attributed to.
+    span: Range<usize>,
+}
+
+impl Codegen<'_> {
+    fn new(module_variables: HashSet<String>) -> Self {
+        Codegen {
+            chunk: ChunkBuilder::new(),
+            locals: Vec::new(),
+            captures: Vec::new(),
+            enclosing: Vec::new(),
+            scope_depth: 0,
+            loops: Vec::new(),
+            module_variables,
+            forward_references: Vec::new(),
+            in_function: false,
+            in_method: false,
+            classes: Vec::new(),
+            errors: Vec::new(),
+            sink: None,
+            inline_caches: true,
+            loop_variable: None,
+            span: 0..0,
+        }
+    }
+
+    fn finish(mut self) -> Result<(Chunk, HashSet<String>), Vec<CompileError>> {
+        for (name, span) in std::mem::take(&mut self.forward_references) {
+            if !self.module_variables.contains(&name) {
+                self.error(format!("variable '{name}' is not defined"), span);
+            }
+        }
+        self.chunk.uncached = !self.inline_caches;
+        if self.errors.is_empty() {
+            Ok((self.chunk.build(), self.module_variables))
+        } else {
+            Err(self.errors)
+        }
+    }
+
+    fn error(&mut self, message: impl Into<String>, span: Range<usize>) {
+        let error = CompileError::from(ResolveError::new(message, span));
+        if let Some(sink) = self.sink.as_deref_mut() {
+            sink.emit(&error.diagnostic());
+        }
+        self.errors.push(error);
+    }
+
+    /*
+
+        Statements
+
+    */
+    fn statement(&mut self, stmt: &Stmt) {
+        let outer = self.enter(&stmt.span);
+        self.statement_kind(stmt);
+        self.leave(outer);
+    }
+
+    fn statement_kind(&mut self, stmt: &Stmt) {
+        match &stmt.kind {
+            StmtKind::Expression(expr) => {
+                self.expression(expr);
+                self.chunk.write_op(Op::Pop);
+            }
+            StmtKind::Var { name, initializer } => {
+                match initializer {
+                    Some(value) => self.expression(value),
+                    None => self.chunk.write_op(Op::Null),
+                }
+                self.declare(name, stmt.span.clone());
+            }
+            StmtKind::Block(statements) => {
+                self.scope_depth += 1;
+                self.statements(statements);
+                self.end_scope();
+            }
+            StmtKind::For { variable, .. } => {
+                self.loop_variable = Some(variable.clone());
+                self.statement(&desugar_for(stmt.clone()));
+            }
+            StmtKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.expression(condition);
+                let skip_then = self.chunk.emit_jump(Op::JumpIfFalse);
+                self.statement(then_branch);
+                match else_branch {
+                    Some(else_branch) => {
+                        let skip_else = self.chunk.emit_jump(Op::Jump);
+                        self.patch_jump(skip_then, &condition.span);
+                        self.statement(else_branch);
+                        self.patch_jump(skip_else, &condition.span);
+                    }
+                    None => self.patch_jump(skip_then, &condition.span),
+                }
+            }
+            StmtKind::While { condition, body } => {
+                let start = self.chunk.code.len();
+                self.expression(condition);
+                let exit = self.chunk.emit_jump(Op::JumpIfFalse);
+                self.loops.push(LoopState {
+                    start,
+                    scope_depth: self.scope_depth,
+                    breaks: Vec::new(),
+                });
+                self.statement(body);
+                self.emit_loop(start, &condition.span);
+                self.patch_jump(exit, &condition.span);
+                let state = self.loops.pop().expect("pushed above");
+                for jump in state.breaks {
+                    self.patch_jump(jump, &condition.span);
+                }
+            }
+            StmtKind::Return(value) => self.return_statement(value.as_ref(), stmt.span.clone()),
+            StmtKind::Break => {
+                let Some(scope_depth) = self.loops.last().map(|state| state.scope_depth) else {
+                    self.error("'break' can only be used inside a loop", stmt.span.clone());
+                    return;
+                };
+                self.pop_locals_deeper_than(scope_depth);
+                let jump = self.chunk.emit_jump(Op::Jump);
+                self.loops
+                    .last_mut()
+                    .expect("checked above")
+                    .breaks
+                    .push(jump);
+            }
+            StmtKind::Continue => {
+                let Some(state) = self.loops.last() else {
+                    self.error(
+                        "'continue' can only be used inside a loop",
+                        stmt.span.clone(),
+                    );
+                    return;
+                };
+                let (start, scope_depth) = (state.start, state.scope_depth);
+                self.pop_locals_deeper_than(scope_depth);
+                self.emit_loop(start, &stmt.span);
+            }
+            StmtKind::Class(class) => self.class(class, stmt.span.clone()),
+            StmtKind::Import { module, names } => {
+                let module = self.name_constant(module);
+                self.chunk.write_op(Op::ImportModule);
+                self.chunk.write_u16(module);
+                for (name, alias) in names {
+                    let index = self.name_constant(name);
+                    self.chunk.write_op(Op::ImportVariable);
+                    self.chunk.write_u16(module);
+                    self.chunk.write_u16(index);
+                    self.declare(alias.as_ref().unwrap_or(name), stmt.span.clone());
+                }
+            }
+        }
+    }
+
+    // Compiles a list of statements, warning about the first one
+    // that follows a jump out of the list, since it never runs.
+    fn statements(&mut self, statements: &[Stmt]) {
+        let mut jumped = false;
+        let mut warned = false;
+        for stmt in statements {
+            if jumped && !warned {
+                if let Some(sink) = self.sink.as_deref_mut() {
+                    sink.emit(&Diagnostic::warning("unreachable code", stmt.span.clone()));
+                }
+                warned = true;
+            }
+            self.statement(stmt);
+            jumped |= matches!(
+                stmt.kind,
+                StmtKind::Return(_) | StmtKind::Break | StmtKind::Continue
+            );
+        }
+    }
+
+    // Declares `name`, whose initial value is on top of the stack.
+    fn declare(&mut self, name: &str, span: Range<usize>) {
+        if self.scope_depth == 0 {
+            if !self.module_variables.insert(name.to_string()) {
+                self.error(format!("module variable '{name}' is already defined"), span);
+            }
+            let index = self.name_constant(name);
+            self.chunk.write_op(Op::SetGlobal);
+            self.chunk.write_u16(index);
+            self.chunk.write_op(Op::Pop);
+            return;
+        }
+
+        let redeclared = self
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth == self.scope_depth)
+            .any(|local| local.name == name);
+        if redeclared {
+            self.error(
+                format!("variable '{name}' is already declared in this scope"),
+                span.clone(),
+            );
+        }
+        // Slot 0 belongs to the running function.
+        if self.locals.len() >= u8::MAX as usize {
+            self.error("too many local variables", span);
+        }
+        let read_only = self.loop_variable.as_deref() == Some(name);
+        if read_only {
+            self.loop_variable = None;
+        }
+        self.locals.push(Local {
+            name: name.to_string(),
+            depth: self.scope_depth,
+            read_only,
+            captured: false,
+        });
+    }
+
+    fn class(&mut self, class: &ClassDecl, span: Range<usize>) {
+        // Methods may refer to the class by name.
+        let at_top_level = self.scope_depth == 0;
+        if at_top_level && !self.module_variables.insert(class.name.clone()) {
+            let message = format!("module variable '{}' is already defined", class.name);
+            self.error(message, span.clone());
+        }
+
+        if let Some(superclass) = &class.superclass {
+            let superclass = Expr {
+                kind: ExprKind::Variable(superclass.clone()),
+                span: span.clone(),
+            };
+            self.expression(&superclass);
+        }
+        let name = self.name_constant(&class.name);
+        self.chunk.write_op(if class.superclass.is_some() {
+            Op::Subclass
+        } else {
+            Op::Class
+        });
+        self.chunk.write_u16(name);
+        let field_count = self.chunk.code.len();
+        self.chunk.write_u8(0);
+
+        self.classes.push(ClassState {
+            name: class.name.clone(),
+            fields: Vec::new(),
+            static_fields: Vec::new(),
+            has_superclass: class.superclass.is_some(),
+            in_static_method: false,
+            uninitialized: Vec::new(),
+            constructor: None,
+        });
+        // Foreign methods are bound by the host.
+        for member in class.members.iter().filter(|m| !m.is_foreign) {
+            let method = self.method(member);
+            self.constant(Value::Fn(Rc::new(method)));
+            let signature = self.name_constant(&member.signature());
+            self.chunk.write_op(if member.on_class() {
+                Op::StaticMethod
+            } else {
+                Op::Method
+            });
+            self.chunk.write_u16(signature);
+        }
+        let state = self.classes.pop().expect("pushed above");
+        match u8::try_from(state.fields.len()) {
+            Ok(count) => self.chunk.code[field_count] = count,
+            Err(_) => {
+                let message = format!("class '{}' has more than 255 fields", class.name);
+                self.error(message, span.clone());
+            }
+        }
+
+        if at_top_level {
+            let index = self.name_constant(&class.name);
+            self.chunk.write_op(Op::SetGlobal);
+            self.chunk.write_u16(index);
+            self.chunk.write_op(Op::Pop);
+        } else {
+            self.declare(&class.name, span);
+        }
+
+        // Static fields start out null, once per class declaration.
+        for field in &state.static_fields {
+            let index = self.name_constant(&format!("{}.{field}", state.name));
+            self.chunk.write_op(Op::Null);
+            self.chunk.write_op(Op::SetGlobal);
+            self.chunk.write_u16(index);
+            self.chunk.write_op(Op::Pop);
+        }
+
+        // Then their initializers run, in source order, each
+        // seeing only the fields set before it.
+        if !class.static_fields.is_empty() {
+            let in_method = std::mem::replace(&mut self.in_method, false);
+            self.classes.push(ClassState {
+                in_static_method: true,
+                ..state
+            });
+            for (i, field) in class.static_fields.iter().enumerate() {
+                let later = class.static_fields[i..].iter();
+                if let Some(state) = self.classes.last_mut() {
+                    state.uninitialized = later.map(|field| field.name.clone()).collect();
+                }
+                self.expression(&field.value);
+                self.field(&field.name, true, &field.value);
+                self.chunk.write_op(Op::Pop);
+            }
+            self.classes.pop();
+            self.in_method = in_method;
+        }
+    }
+
+    // Compiles a method body into a function whose slot 0 is the
+    // receiver, followed by the parameters. A body written as one
+    // expression on the line of its `{` returns the value of it;
+    // constructors return `this`.
+    fn method(&mut self, member: &ClassMember) -> Function {
+        let is_constructor = member.kind == MemberKind::Constructor;
+        if let Some(class) = self.classes.last_mut() {
+            class.in_static_method = member.is_static;
+            class.constructor = is_constructor.then(|| member.name.clone());
+        }
+        let enclosing = std::mem::take(&mut self.enclosing);
+        let captures = std::mem::take(&mut self.captures);
+        let in_method = std::mem::replace(&mut self.in_method, true);
+        let function = self.function(
+            &member.signature(),
+            &member.params,
+            &member.body,
+            member.expression_body && !is_constructor,
+            is_constructor,
+            &member.span,
+        );
+        self.enclosing = enclosing;
+        self.captures = captures;
+        self.in_method = in_method;
+        function
+    }
+
+    // A block argument compiles like a method whose slot 0 holds
+    // the block itself. It captures the locals of the functions
+    // it is nested in. Inside a method it also sees the class, and
+    // captures `this` to use fields and call methods; elsewhere
+    // `this` and fields are errors inside.
+    fn block_function(
+        &mut self,
+        params: &[String],
+        body: &[Stmt],
+        expression_body: bool,
+        expr: &Expr,
+    ) {
+        let classes = (!self.in_method).then(|| std::mem::take(&mut self.classes));
+        self.enclosing.push(Enclosing {
+            locals: std::mem::take(&mut self.locals),
+            captures: std::mem::take(&mut self.captures),
+        });
+        let mut function = self.function("<fn>", params, body, expression_body, false, &expr.span);
+        let enclosing = self.enclosing.pop().expect("pushed above");
+        self.locals = enclosing.locals;
+        let captures = std::mem::replace(&mut self.captures, enclosing.captures);
+        function.captures = captures.iter().map(|captured| captured.capture).collect();
+        if let Some(classes) = classes {
+            self.classes = classes;
+        }
+
+        let index = self.chunk.add_constant(Value::Fn(Rc::new(function)));
+        self.chunk.write_op(Op::Closure);
+        self.chunk.write_u16(index);
+    }
+
+    fn function(
+        &mut self,
+        name: &str,
+        params: &[String],
+        body: &[Stmt],
+        expression_body: bool,
+        is_constructor: bool,
+        span: &Range<usize>,
+    ) -> Function {
+        let chunk = std::mem::take(&mut self.chunk);
+        let locals = std::mem::take(&mut self.locals);
+        let loop_variable = self.loop_variable.take();
+        let loops = std::mem::take(&mut self.loops);
+        let in_function = std::mem::replace(&mut self.in_function, true);
+        let scope_depth = std::mem::replace(&mut self.scope_depth, 1);
+
+        for param in params {
+            self.declare(param, span.clone());
+        }
+        if is_constructor {
+            self.chunk.write_op(Op::Construct);
+        }
+        match body {
+            [Stmt {
+                kind: StmtKind::Expression(value),
+                ..
+            }] if expression_body => self.expression(value),
+            body => {
+                self.statements(body);
+                if is_constructor {
+                    self.chunk.write_op(Op::GetLocal);
+                    self.chunk.write_u8(0);
+                } else {
+                    self.chunk.write_op(Op::Null);
+                }
+            }
+        }
+        self.chunk.write_op(Op::Return);
+
+        let mut body = std::mem::replace(&mut self.chunk, chunk);
+        body.uncached = !self.inline_caches;
+        self.locals = locals;
+        self.loop_variable = loop_variable;
+        self.loops = loops;
+        self.in_function = in_function;
+        self.scope_depth = scope_depth;
+
+        let arity = u8::try_from(params.len()).unwrap_or_else(|_| {
+            self.error("too many parameters", span.clone());
+            0
+        });
+        Function::new(name, arity, body.build(), MAIN_MODULE)
+    }
+
+    // A constructor returns the new instance, so its `return`
+    // cannot give a value. One in a block returns from the block.
+    fn return_statement(&mut self, value: Option<&Expr>, span: Range<usize>) {
+        let in_constructor = self.enclosing.is_empty()
+            && self
+                .classes
+                .last()
+                .is_some_and(|class| class.constructor.is_some());
+        match value {
+            Some(_) if in_constructor => {
+                self.error("a constructor cannot return a value", span);
+            }
+            Some(value) => self.expression(value),
+            None if in_constructor => {
+                self.chunk.write_op(Op::GetLocal);
+                self.chunk.write_u8(0);
+            }
+            None => self.chunk.write_op(Op::Null),
+        }
+        self.chunk.write_op(Op::Return);
+    }
+
+    // Pops the locals deeper than `depth` off the stack, closing any
+    // upvalues, before a jump out of their scope. They stay declared
+    // for the code that follows the jump.
+    fn pop_locals_deeper_than(&mut self, depth: usize) {
+        for index in (0..self.locals.len()).rev() {
+            if self.locals[index].depth <= depth {
+                break;
+            }
+            let op = if self.locals[index].captured {
+                Op::CloseUpvalue
+            } else {
+                Op::Pop
+            };
+            self.chunk.write_op(op);
+        }
+    }
+
+    fn end_scope(&mut self) {
+        self.scope_depth -= 1;
+        while self
+            .locals
+            .last()
+            .is_some_and(|local| local.depth > self.scope_depth)
+        {
+            let local = self.locals.pop().expect("checked above");
+            self.chunk.write_op(if local.captured {
+                Op::CloseUpvalue
+            } else {
+                Op::Pop
+            });
+        }
+    }
+
+    /*
+
+        Expressions
+
+    */
+    // A chain such as `a + b + c` or `a.b.c` nests on the left as
+    // deep as it is long, and the parser only limits real nesting.
+    // So the left side of a chain is walked in a loop, and only the
+    // operands to the right recurse.
+    fn expression(&mut self, expr: &Expr) {
+        let mut links = Vec::new();
+        let mut first = expr;
+        while let Some(left) = chain_left(first) {
+            links.push((first, self.enter(&first.span)));
+            first = left;
+        }
+        let outer = self.enter(&first.span);
+        self.expression_kind(first);
+        self.leave(outer);
+        for (link, outer) in links.into_iter().rev() {
+            self.link(link);
+            self.leave(outer);
+        }
+    }
+
+    // The rest of a link in a chain, once its left side is pushed.
+    fn link(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Binary { op, right, .. } => {
+                self.expression(right);
+                self.chunk.write_op(binary_op(*op));
+            }
+            ExprKind::Call {
+                name,
+                args,
+                is_getter,
+                ..
+            } => {
+                let signature = if *is_getter {
+                    name.clone()
+                } else {
+                    format!("{name}({})", vec!["_"; args.len()].join(","))
+                };
+                self.call(Op::Invoke, &signature, args, expr);
+            }
+            ExprKind::Subscript { args, .. } => {
+                let signature = format!("[{}]", vec!["_"; args.len()].join(","));
+                self.call(Op::Invoke, &signature, args, expr);
+            }
+            _ => unreachable!("chain_left found a link"),
+        }
+    }
+
+    // Attributes the code written next to `span`, returning the
+    // span it was attributed to before.
+    fn enter(&mut self, span: &Range<usize>) -> Range<usize> {
+        self.chunk.mark_span(span.clone());
+        std::mem::replace(&mut self.span, span.clone())
+    }
+
+    // Goes back to attributing code to `outer`, as the rest of the
+    // enclosing node is written.
+    fn leave(&mut self, outer: Range<usize>) {
+        self.chunk.mark_span(outer.clone());
+        self.span = outer;
+    }
+
+    fn expression_kind(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Literal(literal) => match literal {
+                LiteralValue::Num(n, _) => self.constant(Value::Num(*n)),
+                LiteralValue::Str(s) => self.constant(Value::Str(s.as_str().into())),
+                LiteralValue::Bool(true) => self.chunk.write_op(Op::True),
+                LiteralValue::Bool(false) => self.chunk.write_op(Op::False),
+                LiteralValue::Null => self.chunk.write_op(Op::Null),
+            },
+            ExprKind::List(items) => {
+                self.chunk.write_op(Op::List);
+                for item in items {
+                    self.expression(item);
+                    self.chunk.write_op(Op::ListAppend);
+                }
+            }
+            ExprKind::Map(entries) => {
+                self.chunk.write_op(Op::Map);
+                for (key, value) in entries {
+                    self.expression(key);
+                    self.expression(value);
+                    self.chunk.write_op(Op::MapInsert);
+                }
+            }
+            ExprKind::Variable(name) if name.starts_with('_') => self.field(name, false, expr),
+            ExprKind::Variable(name) if self.is_implicit_call(name) => {
+                self.this(expr);
+                self.call(Op::Invoke, name, &[], expr);
+            }
+            ExprKind::Variable(name) => self.variable(name, Op::GetLocal, Op::GetGlobal, expr),
+            ExprKind::Assign { name, value } if self.is_implicit_call(name) => {
+                self.this(expr);
+                self.call(
+                    Op::Invoke,
+                    &format!("{name}=(_)"),
+                    std::slice::from_ref(value),
+                    expr,
+                );
+            }
+            ExprKind::Assign { name, value } => {
+                self.expression(value);
+                if name.starts_with('_') {
+                    self.field(name, true, expr);
+                } else {
+                    self.variable(name, Op::SetLocal, Op::SetGlobal, expr);
+                }
+            }
+            ExprKind::Unary { op, operand } => {
+                self.expression(operand);
+                self.chunk.write_op(match op {
+                    UnaryOp::Not => Op::Not,
+                    UnaryOp::Negate => Op::Negate,
+                });
+            }
+            ExprKind::Binary {
+                op: op @ (BinaryOp::And | BinaryOp::Or),
+                left,
+                right,
+            } => self.short_circuit(*op, left, right, expr),
+            ExprKind::This if self.classes.is_empty() => {
+                self.error("'this' can only be used inside a method", expr.span.clone())
+            }
+            ExprKind::This => self.this(expr),
+            ExprKind::Binary { .. }
+            | ExprKind::Call {
+                receiver: Some(_), ..
+            }
+            | ExprKind::Subscript { .. } => unreachable!("expression() compiles chain links"),
+            ExprKind::Call {
+                receiver: None,
+                name,
+                args,
+                ..
+            } => {
+                let signature = format!("{name}({})", vec!["_"; args.len()].join(","));
+                if self.classes.is_empty() {
+                    return self.error(
+                        format!("'{signature}' is called without a receiver outside a method"),
+                        expr.span.clone(),
+                    );
+                }
+                self.this(expr);
+                self.call(Op::Invoke, &signature, args, expr);
+            }
+            ExprKind::SubscriptSet { receiver, args } => {
+                let signature = format!("[{}]=(_)", vec!["_"; args.len() - 1].join(","));
+                self.invoke(receiver, &signature, args, expr);
+            }
+            ExprKind::SubscriptUpdate {
+                receiver,
+                args,
+                op,
+                value,
+            } => {
+                let params = vec!["_"; args.len()].join(",");
+                self.expression(receiver);
+                for arg in args {
+                    self.expression(arg);
+                }
+                // Copy the receiver and arguments for the getter,
+                // leaving the originals for the setter.
+                let Ok(depth) = u8::try_from(args.len()) else {
+                    return self.error("too many arguments", expr.span.clone());
+                };
+                for _ in 0..=depth {
+                    self.chunk.write_op(Op::Dup);
+                    self.chunk.write_u8(depth);
+                }
+                self.emit_call(Op::Invoke, &format!("[{params}]"), args.len(), expr);
+                self.expression(value);
+                self.chunk.write_op(binary_op(*op));
+                self.emit_call(Op::Invoke, &format!("[{params}]=(_)"), args.len() + 1, expr);
+            }
+            ExprKind::PropertyUpdate {
+                receiver,
+                name,
+                op,
+                value,
+            } => {
+                self.expression(receiver);
+                // Keep the receiver for the setter.
+                self.chunk.write_op(Op::Dup);
+                self.chunk.write_u8(0);
+                self.emit_call(Op::Invoke, name, 0, expr);
+                self.expression(value);
+                self.chunk.write_op(binary_op(*op));
+                self.emit_call(Op::Invoke, &format!("{name}=(_)"), 1, expr);
+            }
+            ExprKind::Super { name, args, kind } => {
+                self.super_call(name.as_deref(), args, *kind, expr)
+            }
+            ExprKind::Fn {
+                params,
+                body,
+                expression_body,
+            } => self.block_function(params, body, *expression_body, expr),
+        }
+    }
+
+    // Pushes the receiver: slot 0 of a method, and an upvalue
+    // holding it in a block inside one.
+    fn this(&mut self, expr: &Expr) {
+        if self.enclosing.is_empty() {
+            self.chunk.write_op(Op::GetLocal);
+            self.chunk.write_u8(0);
+        } else if let Some(index) = self.capture(0, Capture::Local(0), true, expr) {
+            self.chunk.write_op(Op::GetUpvalue);
+            self.chunk.write_u8(index);
+        }
+    }
+
+    // Inside a method, a lowercase name that is not a local is a
+    // call on `this`, so module variables used there must be
+    // capitalized, as in Wren.
+    fn is_implicit_call(&self, name: &str) -> bool {
+        let is_local = |locals: &[Local]| locals.iter().any(|local| local.name == name);
+        !self.classes.is_empty()
+            && name.starts_with(|c: char| c.is_ascii_lowercase())
+            && !is_local(&self.locals)
+            && !self.enclosing.iter().any(|f| is_local(&f.locals))
+    }
+
+    fn variable(&mut self, name: &str, local: Op, global: Op, expr: &Expr) {
+        if let Some(slot) = self.locals.iter().rposition(|l| l.name == name) {
+            if local == Op::SetLocal && self.locals[slot].read_only {
+                self.error(
+                    format!("cannot assign to loop variable '{name}'"),
+                    expr.span.clone(),
+                );
+            }
+            self.chunk.write_op(local);
+            self.chunk.write_u8(slot as u8 + 1);
+        } else if let Some(index) = self.upvalue(name, expr) {
+            if local == Op::SetLocal && self.captures[index as usize].read_only {
+                self.error(
+                    format!("cannot assign to loop variable '{name}'"),
+                    expr.span.clone(),
+                );
+            }
+            self.chunk.write_op(if local == Op::SetLocal {
+                Op::SetUpvalue
+            } else {
+                Op::GetUpvalue
+            });
+            self.chunk.write_u8(index);
+        } else if self.module_variables.contains(name) || CORE_VARIABLES.contains(&name) {
+            let index = self.name_constant(name);
+            self.chunk.write_op(global);
+            self.chunk.write_u16(index);
+        } else if self.in_function {
+            // A body may run after the rest of the module, so it can
+            // use a module variable declared further down.
+            self.forward_references
+                .push((name.to_string(), expr.span.clone()));
+            let index = self.name_constant(name);
+            self.chunk.write_op(global);
+            self.chunk.write_u16(index);
+        } else {
+            self.error(
+                format!("variable '{name}' is not defined"),
+                expr.span.clone(),
+            );
+        }
+    }
+
+    // The upvalue of the function being compiled for `name`, a
+    // local of a function it is nested in. The first use adds an
+    // upvalue for it to each function in between.
+    fn upvalue(&mut self, name: &str, expr: &Expr) -> Option<u8> {
+        let (level, slot) = self
+            .enclosing
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(level, f)| {
+                let slot = f.locals.iter().rposition(|local| local.name == name)?;
+                Some((level, slot))
+            })?;
+        let local = &mut self.enclosing[level].locals[slot];
+        local.captured = true;
+        let read_only = local.read_only;
+        self.capture(level, Capture::Local(slot as u8 + 1), read_only, expr)
+    }
+
+    // The upvalue of the function being compiled for `capture`, a
+    // slot of the function `level` deep in `enclosing`, adding it
+    // to each function in between.
+    fn capture(
+        &mut self,
+        level: usize,
+        mut capture: Capture,
+        read_only: bool,
+        expr: &Expr,
+    ) -> Option<u8> {
+        for inner in level + 1..=self.enclosing.len() {
+            let captures = match self.enclosing.get_mut(inner) {
+                Some(function) => &mut function.captures,
+                None => &mut self.captures,
+            };
+            let index = match captures.iter().position(|c| c.capture == capture) {
+                Some(index) => index,
+                None => {
+                    captures.push(Captured { capture, read_only });
+                    captures.len() - 1
+                }
+            };
+            let Ok(index) = u8::try_from(index) else {
+                self.error("too many captured variables", expr.span.clone());
+                return None;
+            };
+            capture = Capture::Upvalue(index);
+        }
+        match capture {
+            Capture::Upvalue(index) => Some(index),
+            Capture::Local(_) => unreachable!("the loop runs at least once"),
+        }
+    }
+
+    fn invoke(&mut self, receiver: &Expr, signature: &str, args: &[Expr], expr: &Expr) {
+        self.expression(receiver);
+        self.call(Op::Invoke, signature, args, expr);
+    }
+
+    // Calls `signature` with `op` on the receiver already pushed.
+    fn call(&mut self, op: Op, signature: &str, args: &[Expr], expr: &Expr) {
+        for arg in args {
+            self.expression(arg);
+        }
+        self.emit_call(op, signature, args.len(), expr);
+    }
+
+    // Calls `signature` with `op` on the receiver and `argc`
+    // arguments already pushed.
+    fn emit_call(&mut self, op: Op, signature: &str, argc: usize, expr: &Expr) {
+        let Ok(argc) = u8::try_from(argc) else {
+            return self.error("too many arguments", expr.span.clone());
+        };
+        let signature = self.name_constant(signature);
+        self.chunk.write_op(op);
+        self.chunk.write_u16(signature);
+        self.chunk.write_u8(argc);
+    }
+
+    // A call on `this` that skips the methods of the enclosing
+    // class. The bare `super(args)` chains constructors.
+    fn super_call(&mut self, name: Option<&str>, args: &[Expr], kind: MemberKind, expr: &Expr) {
+        let Some(class) = self.classes.last() else {
+            return self.error(
+                "'super' can only be used inside a method",
+                expr.span.clone(),
+            );
+        };
+        if !class.has_superclass {
+            let message = format!("class '{}' has no superclass to call", class.name);
+            return self.error(message, expr.span.clone());
+        }
+        if class.in_static_method {
+            return self.error(
+                "'super' cannot be used in a static method",
+                expr.span.clone(),
+            );
+        }
+
+        let params = vec!["_"; args.len()].join(",");
+        let (op, signature) = match (name, &class.constructor) {
+            (Some(name), _) if kind == MemberKind::Getter => (Op::SuperInvoke, name.to_string()),
+            (Some(name), _) if kind == MemberKind::Setter => {
+                (Op::SuperInvoke, format!("{name}=({params})"))
+            }
+            (Some(name), _) => (Op::SuperInvoke, format!("{name}({params})")),
+            (None, Some(constructor)) => (Op::SuperConstruct, format!("{constructor}({params})")),
+            (None, None) => {
+                return self.error(
+                    "'super' without a method name can only be used in a constructor",
+                    expr.span.clone(),
+                )
+            }
+        };
+        self.this(expr);
+        self.call(op, &signature, args, expr);
+    }
+
+    // Reads, or with `set` assigns, the field `name` of the
+    // enclosing class.
+    fn field(&mut self, name: &str, set: bool, expr: &Expr) {
+        let Some(class) = self.classes.last_mut() else {
+            let message = format!("field '{name}' can only be used inside a method");
+            return self.error(message, expr.span.clone());
+        };
+
+        if name.starts_with("__") {
+            if !set && class.uninitialized.iter().any(|field| field == name) {
+                let message = format!("static field '{name}' is used before it is initialized");
+                return self.error(message, expr.span.clone());
+            }
+            if !class.static_fields.iter().any(|field| field == name) {
+                class.static_fields.push(name.to_string());
+            }
+            let mangled = format!("{}.{name}", class.name);
+            let index = self.name_constant(&mangled);
+            self.chunk
+                .write_op(if set { Op::SetGlobal } else { Op::GetGlobal });
+            self.chunk.write_u16(index);
+            return;
+        }
+
+        if class.in_static_method {
+            let message = format!("instance field '{name}' cannot be used in a static method");
+            return self.error(message, expr.span.clone());
+        }
+        let index = match class.fields.iter().position(|field| field == name) {
+            Some(index) => index,
+            None => {
+                class.fields.push(name.to_string());
+                class.fields.len() - 1
+            }
+        };
+        let Ok(index) = u8::try_from(index) else {
+            return self.error("too many fields", expr.span.clone());
+        };
+        let op = match (set, self.enclosing.is_empty()) {
+            (false, true) => Op::GetField,
+            (true, true) => Op::SetField,
+            (false, false) => Op::GetFieldOf,
+            (true, false) => Op::SetFieldOf,
+        };
+        if !self.enclosing.is_empty() {
+            self.this(expr);
+        }
+        self.chunk.write_op(op);
+        self.chunk.write_u8(index);
+    }
+
+    // `&&` and `||` skip their right operand when the left decides.
+    fn short_circuit(&mut self, op: BinaryOp, left: &Expr, right: &Expr, expr: &Expr) {
+        self.expression(left);
+        let jump = self
+            .chunk
+            .emit_jump(if op == BinaryOp::And { Op::And } else { Op::Or });
+        self.expression(right);
+        self.patch_jump(jump, &expr.span);
+    }
+
+    /*
+
+        Emit Helpers
+
+    */
+    fn constant(&mut self, value: Value) {
+        let index = self.chunk.add_constant(value);
+        self.chunk.write_op(Op::Constant);
+        self.chunk.write_u16(index);
+    }
+
+    fn name_constant(&mut self, name: &str) -> u16 {
+        let existing = self
+            .chunk
+            .constants
+            .iter()
+            .position(|constant| matches!(constant, Value::Str(s) if &**s == name));
+        match existing {
+            Some(index) => index as u16,
+            None => self.chunk.add_constant(Value::Str(name.into())),
+        }
+    }
+
+    // Jumps back to `start`.
+    fn emit_loop(&mut self, start: usize, span: &Range<usize>) {
+        if self.chunk.emit_loop(start).is_err() {
+            self.error("loop body is too large", span.clone());
+        }
+    }
+
+    // Points `jump` at the current end of the chunk.
+    fn patch_jump(&mut self, jump: JumpHandle, span: &Range<usize>) {
+        if self.chunk.patch_jump(jump).is_err() {
+            self.error("too much code to jump over", span.clone());
+        }
+    }
+}
+
+// The left side of `expr` if it is a link in a chain: an operator
+// other than `&&` and `||`, a call on a receiver or a subscript.
+fn chain_left(expr: &Expr) -> Option<&Expr> {
+    match &expr.kind {
+        ExprKind::Binary { op, left, .. } if !matches!(op, BinaryOp::And | BinaryOp::Or) => {
+            Some(left)
+        }
+        ExprKind::Call {
+            receiver: Some(receiver),
+            ..
+        }
+        | ExprKind::Subscript { receiver, .. } => Some(receiver),
+        _ => None,
+    }
+}
+
+// The instruction for a binary operator other than `&&` and `||`,
+// which jump instead.
+fn binary_op(op: BinaryOp) -> Op {
+    match op {
+        BinaryOp::Multiply => Op::Multiply,
+        BinaryOp::Divide => Op::Divide,
+        BinaryOp::Modulo => Op::Modulo,
+        BinaryOp::Add => Op::Add,
+        BinaryOp::Subtract => Op::Subtract,
+        BinaryOp::InclusiveRange => Op::InclusiveRange,
+        BinaryOp::ExclusiveRange => Op::ExclusiveRange,
+        BinaryOp::LeftShift => Op::LeftShift,
+        BinaryOp::RightShift => Op::RightShift,
+        BinaryOp::BitwiseAnd => Op::BitwiseAnd,
+        BinaryOp::BitwiseXor => Op::BitwiseXor,
+        BinaryOp::BitwiseOr => Op::BitwiseOr,
+        BinaryOp::LessThan => Op::Less,
+        BinaryOp::LessThanEqual => Op::LessEqual,
+        BinaryOp::GreaterThan => Op::Greater,
+        BinaryOp::GreaterThanEqual => Op::GreaterEqual,
+        BinaryOp::Equal => Op::Equal,
+        BinaryOp::NotEqual => Op::NotEqual,
+        BinaryOp::Is => Op::Is,
+        BinaryOp::And | BinaryOp::Or => unreachable!("'&&' and '||' are jumps"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::diagnostic::{Diagnostic, Severity, VecSink};
+    use crate::vm::cfg::instructions;
+    use crate::vm::chunk::Function;
+    use crate::vm::heap::Object;
+    use crate::vm::{CacheStats, RuntimeError, Vm};
+    use std::rc::Rc;
+
+    fn run(src: &str) -> Vm {
+        let chunk = compile(src).unwrap();
+        let mut vm = Vm::new();
+        assert_eq!(vm.run(Rc::new(Function::script(chunk))), Ok(Value::Null));
+        vm
+    }
+
+    fn options(opt_level: OptLevel) -> CompileOptions {
+        CompileOptions { opt_level }
+    }
+
+    #[test]
+    fn opt_level_none_skips_folding() {
+        let chunk = compile_with("var a = 1 + 1", options(OptLevel::None)).unwrap();
+
+        assert_eq!(chunk.constants[..2], [Value::Num(1.0), Value::Num(1.0)]);
+        assert!(chunk.code.contains(&(Op::Add as u8)));
+    }
+
+    #[test]
+    fn opt_level_basic_folds() {
+        let chunk = compile_with("var a = 1 + 1\n3", options(OptLevel::Basic)).unwrap();
+
+        assert_eq!(chunk.constants[0], Value::Num(2.0));
+        assert!(!chunk.code.contains(&(Op::Add as u8)));
+        // The statement `3` was dropped.
+        assert!(!chunk.constants.contains(&Value::Num(3.0)));
+    }
+
+    #[test]
+    fn inline_caches_only_at_full() {
+        let src = "class A { static f { 1 } static g { A.f } } A.g A.g";
+        for (opt_level, hits) in [(OptLevel::Basic, 0), (OptLevel::Full, 1)] {
+            let chunk = compile_with(src, options(opt_level)).unwrap();
+            let mut vm = Vm::new();
+            vm.run(Rc::new(Function::script(chunk))).unwrap();
+            assert_eq!(vm.cache_stats().hits, hits, "{opt_level:?}");
+        }
+    }
+
+    #[test]
+    fn comparisons_follow_ieee() {
+        // Folded at compile time, and with `Nan` at run time.
+        let src = "var Nan = 0.0 / 0.0
+            var a = 0.0 == -0.0
+            var b = (0.0 / 0.0) == (0.0 / 0.0)
+            var c = Nan < 1
+            var d = Nan == Nan
+            var e = Nan != Nan
+            var f = Nan >= Nan
+            var g = (0..Nan) == (0..Nan)
+            var h = (0..1) == (-0..1)";
+        let expected = [true, false, false, false, true, false, false, true];
+        for opt_level in [OptLevel::None, OptLevel::Full] {
+            let chunk = compile_with(src, options(opt_level)).unwrap();
+            let mut vm = Vm::new();
+            vm.run(Rc::new(Function::script(chunk))).unwrap();
+            for (name, expected) in ["a", "b", "c", "d", "e", "f", "g", "h"]
+                .iter()
+                .zip(expected)
+            {
+                assert_eq!(
+                    vm.global(name),
+                    Some(&Value::Bool(expected)),
+                    "{name} at {opt_level:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn unbounded_recursion_overflows() {
+        let chunk = compile("class A { static f(n) { A.f(n + 1) } }\nA.f(0)").unwrap();
+        let mut vm = Vm::new();
+
+        assert_eq!(
+            vm.run(Rc::new(Function::script(chunk))),
+            Err(RuntimeError::StackOverflow)
+        );
+        // The VM is still usable.
+        let chunk = compile("var a = 1").unwrap();
+        assert_eq!(vm.run(Rc::new(Function::script(chunk))), Ok(Value::Null));
+    }
+
+    #[test]
+    fn long_chains_compile() {
+        let sum = format!("var a = 1\nvar b = {}a", "a + ".repeat(5000));
+        assert_eq!(run(&sum).global("b"), Some(&Value::Num(5001.0)));
+
+        let calls = format!("var s = \"x\"{}", ".toString".repeat(5000));
+        assert_eq!(run(&calls).global("s"), Some(&Value::Str("x".into())));
+    }
+
+    #[test]
+    fn chained_assignment() {
+        let vm = run("var a var b a = b = 5");
+
+        assert_eq!(vm.global("a"), Some(&Value::Num(5.0)));
+        assert_eq!(vm.global("b"), Some(&Value::Num(5.0)));
+    }
+
+    #[test]
+    fn assignment_as_subexpression() {
+        let vm = run("var x var y x = (y = 3) + 1");
+
+        assert_eq!(vm.global("x"), Some(&Value::Num(4.0)));
+        assert_eq!(vm.global("y"), Some(&Value::Num(3.0)));
+    }
+
+    #[test]
+    fn local_assignment() {
+        let vm = run("var out { var a = 1 var b = 2 out = a = b + a }");
+
+        assert_eq!(vm.global("out"), Some(&Value::Num(3.0)));
+    }
+
+    #[test]
+    fn unary_operators() {
+        let vm = run("var x = 2 var a = -x * 3 var b = !x var c = !!null");
+
+        assert_eq!(vm.global("a"), Some(&Value::Num(-6.0)));
+        assert_eq!(vm.global("b"), Some(&Value::Bool(false)));
+        assert_eq!(vm.global("c"), Some(&Value::Bool(false)));
+    }
+
+    #[test]
+    fn for_loops() {
+        let vm = run("var sum = 0 for (i in 1..3) { sum = sum + i }");
+
+        assert_eq!(vm.global("sum"), Some(&Value::Num(6.0)));
+    }
+
+    #[test]
+    fn nested_for_loops() {
+        let vm = run("var n = 0 for (i in 1..3) for (j in 0...i) n = n + 1");
+
+        assert_eq!(vm.global("n"), Some(&Value::Num(6.0)));
+    }
+
+    #[test]
+    fn augmented_assignment() {
+        let vm = run("var a = 5 a -= 2 a *= a");
+        assert_eq!(vm.global("a"), Some(&Value::Num(9.0)));
+
+        // The index is evaluated once, so `i` is only incremented once.
+        let vm = run("var m = Map.new()
+            m[1] = 2
+            var i = 0
+            var result = m[i = i + 1] *= 3");
+        assert_eq!(vm.global("i"), Some(&Value::Num(1.0)));
+        assert_eq!(vm.global("result"), Some(&Value::Num(6.0)));
+        let m = vm.global("m").unwrap().clone();
+        assert_eq!(vm.global("result"), Some(&Value::Num(6.0)));
+        assert!(matches!(m, Value::Map(_)));
+    }
+
+    #[test]
+    fn descending_for_loops() {
+        let vm = run("var digits = 0 for (i in 3..1) digits = digits * 10 + i");
+
+        assert_eq!(vm.global("digits"), Some(&Value::Num(321.0)));
+    }
+
+    #[test]
+    fn if_and_while() {
+        let vm = run("
+            var n = 0
+            var i = 0
+            while (i < 5) {
+                i = i + 1
+                if (i % 2 == 0) n = n + 10 else n = n + 1
+            }
+            var small = 0
+            if (n < 10) small = 1
+            ");
+
+        assert_eq!(vm.global("n"), Some(&Value::Num(23.0)));
+        assert_eq!(vm.global("small"), Some(&Value::Num(0.0)));
+    }
+
+    #[test]
+    fn return_statements() {
+        let vm = run("
+            class M {
+                static fib(n) {
+                    if (n < 2) return n
+                    return fib(n - 1) + fib(n - 2)
+                }
+                static sign(n) {
+                    var result = 1
+                    if (n < 0) result = -1
+                    return result
+                }
+                static nothing(n) {
+                    if (n) return
+                    return n
+                }
+                construct new(n) {
+                    _n = n
+                    if (n) return
+                    _n = \"unset\"
+                }
+                n { _n }
+            }
+            var fib = M.fib(10)
+            var sign = M.sign(-3)
+            var nothing = M.nothing(true)
+            var early = M.new(true).n
+            var late = M.new(false).n
+            ");
+
+        assert_eq!(vm.global("fib"), Some(&Value::Num(55.0)));
+        assert_eq!(vm.global("sign"), Some(&Value::Num(-1.0)));
+        assert_eq!(vm.global("nothing"), Some(&Value::Null));
+        assert_eq!(vm.global("early"), Some(&Value::Bool(true)));
+        assert_eq!(vm.global("late"), Some(&Value::Str("unset".into())));
+
+        let errors = compile("class A {\n construct new() { return 1 }\n}").unwrap_err();
+        assert_eq!(errors[0].message(), "a constructor cannot return a value");
+    }
+
+    #[test]
+    fn break_and_continue() {
+        let vm = run("
+            var i = 0
+            var odd = 0
+            while (true) {
+                i = i + 1
+                if (i > 9) break
+                var half = i / 2
+                if (half.floor == half) continue
+                odd = odd + i
+            }
+            var pairs = 0
+            for (a in 1..4) {
+                for (b in 1..4) {
+                    if (b > a) break
+                    pairs = pairs + 1
+                }
+                if (a == 3) break
+            }
+            var kept = 0
+            for (x in 1..4) {
+                var doubled = x * 2
+                if (x == 2) continue
+                if (x == 3) break
+                kept = kept + doubled
+            }
+            ");
+
+        assert_eq!(vm.global("i"), Some(&Value::Num(10.0)));
+        assert_eq!(vm.global("odd"), Some(&Value::Num(25.0)));
+        assert_eq!(vm.global("pairs"), Some(&Value::Num(6.0)));
+        assert_eq!(vm.global("kept"), Some(&Value::Num(2.0)));
+
+        let errors = compile("break\n{ continue }").unwrap_err();
+        assert_eq!(
+            errors[0].message(),
+            "'break' can only be used inside a loop"
+        );
+        assert_eq!(errors[0].span(), 0..5);
+        assert_eq!(
+            errors[1].message(),
+            "'continue' can only be used inside a loop"
+        );
+        // Blocks do not see the loop they are created in.
+        let errors = compile("while (true) (1..2).each { |x| break }").unwrap_err();
+        assert_eq!(
+            errors[0].message(),
+            "'break' can only be used inside a loop"
+        );
+    }
+
+    #[test]
+    fn jumps_pop_the_locals_they_leave() {
+        // Each jump out of the body pops its two locals, but not
+        // `outer`, which is declared outside the loop.
+        let chunk = compile(
+            "{
+                var outer = 0
+                while (outer < 3) {
+                    var a = 1
+                    var b = 2
+                    outer = outer + 1
+                    if (outer == 1) continue
+                    break
+                }
+            }",
+        )
+        .unwrap();
+        let ops: Vec<Op> = instructions(&chunk).unwrap().iter().map(|i| i.op).collect();
+        // The number of pops right before each jump: the `continue`,
+        // the `break`, and the jump back at the end of the body.
+        let pops: Vec<usize> = ops
+            .iter()
+            .enumerate()
+            .filter(|(_, op)| **op == Op::Jump)
+            .map(|(index, _)| {
+                ops[..index]
+                    .iter()
+                    .rev()
+                    .take_while(|op| **op == Op::Pop)
+                    .count()
+            })
+            .collect();
+        assert_eq!(pops, [2, 2, 2]);
+
+        // Locals declared after the loop get the slots the body's
+        // locals were popped from.
+        let vm = run("
+            var Result = null
+            {
+                var before = 1
+                while (true) {
+                    var a = 2
+                    var b = 3
+                    if (true) break
+                }
+                for (i in 1..3) {
+                    var c = 4
+                    if (i < 3) continue
+                }
+                var after = 5
+                Result = before * 10 + after
+            }
+            ");
+        assert_eq!(vm.global("Result"), Some(&Value::Num(15.0)));
+    }
+
+    #[test]
+    fn return_from_loops_and_blocks() {
+        let vm = run("
+            class M {
+                static root(n) {
+                    var i = 0
+                    while (true) {
+                        var square = i * i
+                        if (square >= n) {
+                            var root = i
+                            return root
+                        }
+                        i = i + 1
+                    }
+                }
+                static first(from, to) {
+                    for (i in from..to) {
+                        {
+                            var half = i / 2
+                            if (half.floor == half) return i
+                        }
+                    }
+                    return null
+                }
+                static total() {
+                    var sum = 0
+                    for (i in 1..3) sum = sum + M.first(i, 9)
+                    return sum
+                }
+            }
+            var root = M.root(50)
+            var first = M.first(3, 9)
+            var none = M.first(3, 3)
+            var total = M.total()
+            ");
+
+        assert_eq!(vm.global("root"), Some(&Value::Num(8.0)));
+        assert_eq!(vm.global("first"), Some(&Value::Num(4.0)));
+        assert_eq!(vm.global("none"), Some(&Value::Null));
+        assert_eq!(vm.global("total"), Some(&Value::Num(8.0)));
+    }
+
+    #[test]
+    fn code_after_a_jump_warns() {
+        let warnings = |src: &str| {
+            let mut sink = VecSink::default();
+            assert!(compile_to_sink(src, CompileOptions::default(), &mut sink).is_some());
+            sink.diagnostics
+                .into_iter()
+                .map(|d| (d.severity, d.message, d.span))
+                .collect::<Vec<_>>()
+        };
+
+        let unreachable = |span| vec![(Severity::Warning, "unreachable code".to_string(), span)];
+        let src =
+            "class A {\n static f() {\n  return 1\n  System.print(2)\n  System.print(3)\n }\n}";
+        assert_eq!(warnings(src), unreachable(37..52));
+        assert_eq!(
+            warnings("while (true) {\n break\n System.print(1)\n}"),
+            unreachable(23..38)
+        );
+        assert_eq!(
+            warnings("for (i in 1..2) {\n continue\n var a = i\n}"),
+            unreachable(29..38)
+        );
+
+        // A jump that may not be taken leaves the code after it
+        // reachable.
+        let src = "class A {\n static f(n) {\n  if (n) return 1\n  System.print(2)\n }\n}";
+        assert_eq!(warnings(src), []);
+        assert_eq!(
+            warnings("while (true) {\n if (true) break\n System.print(1)\n}"),
+            []
+        );
+    }
+
+    #[test]
+    fn loop_variable_is_read_only() {
+        let errors = compile("for (i in 1..3) i = 2").unwrap_err();
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message(), "cannot assign to loop variable 'i'");
+        assert_eq!(errors[0].span(), 16..21);
+
+        // A variable declared in the body is a new one.
+        let vm = run("var n = 0 for (i in 1..3) { var i = 2 i = 3 n = n + i }");
+        assert_eq!(vm.global("n"), Some(&Value::Num(9.0)));
+    }
+
+    #[test]
+    fn short_circuit() {
+        let vm = run("var a = null && 1 var b = 2 || 3 var c = 2 && 3");
+
+        assert_eq!(vm.global("a"), Some(&Value::Null));
+        assert_eq!(vm.global("b"), Some(&Value::Num(2.0)));
+        assert_eq!(vm.global("c"), Some(&Value::Num(3.0)));
+    }
+
+    #[test]
+    fn import_runs_module_once() {
+        let mut vm = Vm::new();
+        vm.add_module(
+            "shapes",
+            compile("var sides = 4 var name = \"square\"").unwrap(),
+        );
+
+        let chunk = compile(
+            r#"
+            import "shapes" for sides, name as shape
+            import "shapes" for sides as again
+            var total = sides + again
+            "#,
+        )
+        .unwrap();
+        assert_eq!(vm.run(Rc::new(Function::script(chunk))), Ok(Value::Null));
+
+        assert_eq!(vm.global("total"), Some(&Value::Num(8.0)));
+        assert_eq!(vm.global("shape"), Some(&Value::Str("square".into())));
+        assert_eq!(
+            vm.module_variable("shapes", "sides"),
+            Some(&Value::Num(4.0))
+        );
+        assert_eq!(vm.global("name"), None);
+    }
+
+    #[test]
+    fn import_unknown_module() {
+        let chunk = compile(r#"import "missing""#).unwrap();
+
+        assert_eq!(
+            Vm::new().run(Rc::new(Function::script(chunk))),
+            Err(crate::vm::RuntimeError::UnknownModule("missing".into()))
+        );
+    }
+
+    #[test]
+    fn map_literal() {
+        let vm = run(r#"var m = {1: "a", 0..5: "range", "k": {}}"#);
+
+        let Some(Value::Map(map)) = vm.global("m") else {
+            panic!("expected a map")
+        };
+        let Object::Map(entries) = vm.objects().get(*map) else {
+            unreachable!()
+        };
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[&Value::Num(1.0)], Value::Str("a".into()));
+        let range = Value::Range {
+            from: 0.0,
+            to: 5.0,
+            inclusive: true,
+        };
+        assert_eq!(entries[&range], Value::Str("range".into()));
+    }
+
+    #[test]
+    fn map_key_must_be_a_value_type() {
+        let chunk = compile("var m = {{}: 1}").unwrap();
+
+        assert_eq!(
+            Vm::new().run(Rc::new(Function::script(chunk))),
+            Err(crate::vm::RuntimeError::InvalidArgument(
+                "map key must be a value type"
+            ))
+        );
+    }
+
+    #[test]
+    fn static_fields_are_shared() {
+        let vm = run(r#"
+            class Counter {
+                construct new(name) { _name = name }
+                name { _name }
+                increment() { __count = __count + 1 }
+                static count { __count }
+                static reset() { __count = 0 }
+            }
+            Counter.reset()
+            var a = Counter.new("a")
+            var b = Counter.new("b")
+            a.increment()
+            b.increment()
+            a.increment()
+            var count = Counter.count
+            var name = b.name
+            "#);
+
+        assert_eq!(vm.global("count"), Some(&Value::Num(3.0)));
+        assert_eq!(vm.global("name"), Some(&Value::Str("b".into())));
+        assert_eq!(
+            vm.module_variable("main", "Counter.__count"),
+            Some(&Value::Num(3.0))
+        );
+    }
+
+    #[test]
+    fn classes_are_values() {
+        let vm = run(r#"
+            class Color {
+                construct new(name) { _name = name }
+                name { _name }
+                static named(name) { Color.new(name) }
+                static default { "red" }
+            }
+            var c = Color
+            var made = c.new("blue").name
+            var named = c.named("green").name
+            var fallback = c.default
+            var same = c == Color
+            var isColor = c.new("red") is c
+            "#);
+
+        assert_eq!(vm.global("made"), Some(&Value::Str("blue".into())));
+        assert_eq!(vm.global("named"), Some(&Value::Str("green".into())));
+        assert_eq!(vm.global("fallback"), Some(&Value::Str("red".into())));
+        assert_eq!(vm.global("same"), Some(&Value::Bool(true)));
+        assert_eq!(vm.global("isColor"), Some(&Value::Bool(true)));
+    }
+
+    #[test]
+    fn block_locals_reuse_slots() {
+        let src = "
+            var Result = 0
+            class A {
+                static m(p) {
+                    var a = p
+                    {
+                        var b = 10
+                        a = a + b
+                    }
+                    {
+                        var c = 100
+                        { var d = 1000
+                          a = a + c + d }
+                    }
+                    Result = a
+                }
+            }
+            A.m(1)
+            ";
+        let chunk = compile(src).unwrap();
+        let method = chunk
+            .constants
+            .iter()
+            .find_map(|constant| match constant {
+                Value::Fn(function) if function.name.starts_with("m") => Some(function.clone()),
+                _ => None,
+            })
+            .unwrap();
+        let slots: Vec<u8> = instructions(&method.chunk)
+            .unwrap()
+            .iter()
+            .filter(|i| i.op == Op::GetLocal)
+            .map(|i| method.chunk.read_u8(i.offset + 1).unwrap())
+            .collect();
+
+        // `p` and `a` take slots 1 and 2 after the method; `b`, then
+        // `c` once `b` is gone, take 3, and `d` takes 4.
+        assert_eq!(slots, [1, 2, 3, 2, 3, 4, 2]);
+
+        let vm = run(src);
+        assert_eq!(vm.global("Result"), Some(&Value::Num(1111.0)));
+
+        // Neither block nor method locals outlive their scope. Names
+        // are capitalized so they are not taken as calls on `this`.
+        assert!(compile("class A { static m() { { var B = 1 } B } }").is_err());
+        assert!(compile("class A { static m() { var B = 1 } static n() { B } }").is_err());
+    }
+
+    #[test]
+    fn static_field_starts_null() {
+        let vm = run(r#"
+            class Registry {
+                static first { __first }
+                static first=(value) { __first = value }
+            }
+            var before = Registry.first
+            "#);
+
+        assert_eq!(vm.global("before"), Some(&Value::Null));
+    }
+
+    #[test]
+    fn static_fields_initialize_in_order() {
+        let vm = run(r#"
+            class Config {
+                static __base = 10
+                static __limit = __base * 2
+                static limit { __limit }
+                static base { __base }
+            }
+            var limit = Config.limit
+            var base = Config.base
+            "#);
+
+        assert_eq!(vm.global("limit"), Some(&Value::Num(20.0)));
+        assert_eq!(vm.global("base"), Some(&Value::Num(10.0)));
+    }
+
+    #[test]
+    fn static_field_forward_reference() {
+        let src = "class A {\n  static __a = __b + 1\n  static __b = 1\n}";
+        let errors = compile(src).unwrap_err();
+
+        assert_eq!(
+            errors[0].message(),
+            "static field '__b' is used before it is initialized"
+        );
+        assert_eq!(&src[errors[0].span()], "__b");
+        assert!(compile("class A { static __a = __a }").is_err());
+    }
+
+    #[test]
+    fn trivial_getters_are_inlined() {
+        let src = r#"
+            class Point {
+                construct new(x) { _x = x }
+                x { _x }
+            }
+            class Doubled {
+                construct new(x) { _x = x }
+                x { _x * 2 }
+            }
+            var a = Point.new(3).x
+            var b = Doubled.new(3).x
+            "#;
+        let vm = run(src);
+
+        // Only `Point.x` is trivial; both agree with calling the method.
+        assert_eq!(vm.global("a"), Some(&Value::Num(3.0)));
+        assert_eq!(vm.global("b"), Some(&Value::Num(6.0)));
+        assert_eq!(vm.inlined_getters(), 1);
+    }
+
+    #[test]
+    fn numeric_operators_take_the_fast_path() {
+        let vm = run("var a = 1 var b = 2 var c = a + b var d = a < b");
+
+        assert_eq!(vm.global("c"), Some(&Value::Num(3.0)));
+        assert_eq!(vm.global("d"), Some(&Value::Bool(true)));
+        assert_eq!(vm.cache_stats(), CacheStats::default());
+    }
+
+    #[test]
+    fn operators_dispatch_to_methods() {
+        let vm = run(r#"
+            class Money {
+                construct new(cents) { _cents = cents }
+                cents { _cents }
+                +(other) { Money.new(_cents + other.cents) }
+                <(other) { _cents < other.cents }
+                ==(other) { _cents == other.cents }
+                - { Money.new(-_cents) }
+            }
+            var a = Money.new(150)
+            var b = Money.new(250)
+            var sum = (a + b).cents
+            var less = a < b
+            var equal = a == Money.new(150)
+            var unequal = a != Money.new(150)
+            var negated = (-a).cents
+            var same = a == a
+            var truthy = !a
+        "#);
+
+        assert_eq!(vm.global("sum"), Some(&Value::Num(400.0)));
+        assert_eq!(vm.global("less"), Some(&Value::Bool(true)));
+        assert_eq!(vm.global("equal"), Some(&Value::Bool(true)));
+        // Without `!=(_)`, `!=` compares identity.
+        assert_eq!(vm.global("unequal"), Some(&Value::Bool(true)));
+        assert_eq!(vm.global("negated"), Some(&Value::Num(-150.0)));
+        assert_eq!(vm.global("same"), Some(&Value::Bool(true)));
+        assert_eq!(vm.global("truthy"), Some(&Value::Bool(false)));
+        assert!(vm.cache_stats().misses > 0);
+    }
+
+    #[test]
+    fn errors_stream_to_a_sink() {
+        // Records the messages it is given.
+        #[derive(Default)]
+        struct Recording(Vec<String>);
+
+        impl DiagnosticSink for Recording {
+            fn emit(&mut self, diagnostic: &Diagnostic) {
+                self.0.push(diagnostic.message.clone());
+            }
+        }
+
+        let src = "var a = b\nc = 1\nSystem.print(d)";
+        let mut sink = Recording::default();
+        assert!(compile_to_sink(src, CompileOptions::default(), &mut sink).is_none());
+
+        assert_eq!(
+            sink.0,
+            [
+                "variable 'b' is not defined",
+                "variable 'c' is not defined",
+                "variable 'd' is not defined",
+            ]
+        );
+
+        // Parse warnings are given to the sink too, and lex errors
+        // before anything else.
+        let mut sink = VecSink::default();
+        assert!(compile_to_sink("1 < 2 < 3 $", CompileOptions::default(), &mut sink).is_none());
+        let severities: Vec<Severity> = sink.diagnostics.iter().map(|d| d.severity).collect();
+        assert_eq!(severities, [Severity::Error, Severity::Warning]);
+    }
+
+    #[test]
+    fn missing_operator_method() {
+        let chunk = compile("class A { construct new() {} } A.new() * 2").unwrap();
+
+        assert_eq!(
+            Vm::new().run(Rc::new(Function::script(chunk))),
+            Err(RuntimeError::MethodNotFound {
+                class: "A".into(),
+                signature: "*(_)".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn constructors_match_their_call() {
+        let class = "class Foo {
+                construct new() { _x = 1 }
+                x { _x }
+            }
+            ";
+        let vm = run(&format!("var X\n{class}X = Foo.new().x"));
+        assert_eq!(vm.global("X"), Some(&Value::Num(1.0)));
+
+        // A bare `new` is a getter, and `new(_)` another signature,
+        // neither of which the class has.
+        for (call, signature) in [("Foo.new", "new"), ("Foo.new(1)", "new(_)")] {
+            let chunk = compile(&format!("{class}{call}")).unwrap();
+            assert_eq!(
+                Vm::new().run(Rc::new(Function::script(chunk))),
+                Err(RuntimeError::MethodNotFound {
+                    class: "Foo metaclass".into(),
+                    signature: signature.into(),
+                }),
+                "{call}"
+            );
+        }
+    }
+
+    #[test]
+    fn inline_cache_hits_on_same_class() {
+        let vm = run(r#"
+            class A {
+                construct new() {}
+                value { 1 }
+            }
+            class B {
+                construct new() {}
+                value { 2 }
+            }
+            class Probe {
+                static get(o) { o.value }
+            }
+            var a = A.new()
+            var b = B.new()
+            var r1 = Probe.get(a)
+            var r2 = Probe.get(a)
+            var r3 = Probe.get(a)
+            var r4 = Probe.get(b)
+            var r5 = Probe.get(b)
+            "#);
+
+        assert_eq!(vm.global("r3"), Some(&Value::Num(1.0)));
+        assert_eq!(vm.global("r4"), Some(&Value::Num(2.0)));
+        // Each top level call site runs once and misses. The site in
+        // `get` misses on the first `a`, hits twice, misses when the
+        // class changes to `B`, then hits again.
+        assert_eq!(
+            vm.cache_stats(),
+            crate::vm::CacheStats { hits: 3, misses: 9 }
+        );
+    }
+
+    #[test]
+    fn constructor_chains_to_superclass() {
+        let vm = run(r#"
+            class Point {
+                construct new(x) { _x = x }
+                x { _x }
+                describe() { "point" }
+            }
+            class Point3 is Point {
+                construct new(x, z) {
+                    super(x)
+                    _z = z
+                }
+                z { _z }
+                describe() { "3d " + super.describe() }
+            }
+            var p = Point3.new(1, 3)
+            var x = p.x
+            var z = p.z
+            var d = p.describe()
+        "#);
+
+        assert_eq!(vm.global("x"), Some(&Value::Num(1.0)));
+        assert_eq!(vm.global("z"), Some(&Value::Num(3.0)));
+        assert_eq!(vm.global("d"), Some(&Value::Str("3d point".into())));
+    }
+
+    #[test]
+    fn fiber_yields_and_completes() {
+        let vm = run("
+            var fiber = Fiber.new {
+                Fiber.yield(1)
+                Fiber.yield(2)
+            }
+            var first = fiber.call()
+            var paused = fiber.isDone
+            var second = fiber.call()
+            var done = fiber.isDone
+            var third = fiber.call()
+            var finished = fiber.isDone
+        ");
+
+        assert_eq!(vm.global("first"), Some(&Value::Num(1.0)));
+        assert_eq!(vm.global("paused"), Some(&Value::Bool(false)));
+        assert_eq!(vm.global("second"), Some(&Value::Num(2.0)));
+        assert_eq!(vm.global("done"), Some(&Value::Bool(false)));
+        assert_eq!(vm.global("third"), Some(&Value::Null));
+        assert_eq!(vm.global("finished"), Some(&Value::Bool(true)));
+    }
+
+    #[test]
+    fn blocks_in_methods_see_this() {
+        let vm = run("
+            class Base {
+                construct new() { _base = 100 }
+                base { _base }
+                name { \"base\" }
+            }
+            class Scaler is Base {
+                construct new(f) {
+                    super()
+                    [1].each { |x| _f = f }
+                }
+                f { _f }
+                scale(l) { l.map { |x| x * _f + base }.toList }
+                bump() { [1, 2].each { |x| _f = _f + x } }
+                twice(l) { l.map { |x| [x].map { |y| scale([y])[0] }.toList[0] }.toList }
+                getter { Fn.of { this } }
+                name { Fn.of { super.name + \"!\" }.call() }
+                static self { Fn.of { this } }
+            }
+            class Fn {
+                static of(f) { f }
+            }
+            var s = Scaler.new(2)
+            var Scaled = s.scale([1, 2]).toString
+            s.bump()
+            var Bumped = s.f
+            var Twice = s.twice([1, 2]).toString
+            var Same = s.getter.call() == s
+            var Name = s.name
+            var Static = Scaler.self.call() == Scaler
+            ");
+
+        assert_eq!(vm.global("Scaled"), Some(&Value::Str("[102, 104]".into())));
+        assert_eq!(vm.global("Bumped"), Some(&Value::Num(5.0)));
+        assert_eq!(vm.global("Twice"), Some(&Value::Str("[105, 110]".into())));
+        // The receiver outlives the method call in the block.
+        assert_eq!(vm.global("Same"), Some(&Value::Bool(true)));
+        assert_eq!(vm.global("Name"), Some(&Value::Str("base!".into())));
+        assert_eq!(vm.global("Static"), Some(&Value::Bool(true)));
+
+        // Outside methods there is still no `this`.
+        let errors = compile("var f = Fn.of { _x }").unwrap_err();
+        assert_eq!(
+            errors[0].message(),
+            "field '_x' can only be used inside a method"
+        );
+    }
+
+    #[test]
+    fn blocks_capture_enclosing_locals() {
+        let vm = run("
+            class F {
+                static scale(l, k) { l.map { |x| x * k }.toList }
+                static counter(n) { F.of { n = n + 1 } }
+                static of(f) { f }
+            }
+            var Scaled = F.scale([1, 2, 3], 10).toString
+            var counter = F.counter(5)
+            counter.call()
+            var Counted = counter.call()
+            var Total = 0
+            var Late = 0
+            var Loop = null
+            var Getter = null
+            {
+                var total = 0
+                [1, 2, 3].each { |x| total = total + x }
+                Total = total
+                var fns = []
+                for (i in 1..3) fns.add(F.of { F.of { i } })
+                Loop = fns.map { |f| f.call().call() }.toList.toString
+                var late = 1
+                var get = F.of { late }
+                late = 7
+                Late = get.call()
+                var items = [1, 2]
+                Getter = F.of { items }
+            }
+            System.gc()
+            var Items = Getter.call().toString
+        ");
+
+        assert_eq!(
+            vm.global("Scaled"),
+            Some(&Value::Str("[10, 20, 30]".into()))
+        );
+        assert_eq!(vm.global("Counted"), Some(&Value::Num(7.0)));
+        assert_eq!(vm.global("Total"), Some(&Value::Num(6.0)));
+        assert_eq!(vm.global("Loop"), Some(&Value::Str("[1, 2, 3]".into())));
+        assert_eq!(vm.global("Late"), Some(&Value::Num(7.0)));
+        // The list lives on in the closed upvalue.
+        assert_eq!(vm.global("Items"), Some(&Value::Str("[1, 2]".into())));
+
+        // A jump out of the loop closes the upvalues it leaves behind.
+        let vm = run("
+            class F { static of(f) { f } }
+            var Kept = []
+            for (x in 1..4) {
+                var kept = x
+                Kept.add(F.of { kept })
+                if (x == 2) continue
+                if (x == 3) break
+            }
+            var Values = Kept.map { |f| f.call() }.toList.toString
+            ");
+        assert_eq!(vm.global("Values"), Some(&Value::Str("[1, 2, 3]".into())));
+
+        let errors = compile("{\n for (i in 1..2) [0].each { |x| i = x }\n}").unwrap_err();
+        assert_eq!(errors[0].message(), "cannot assign to loop variable 'i'");
+    }
+
+    #[test]
+    fn fiber_passes_values_both_ways() {
+        let vm = run("
+            var fiber = Fiber.new { |start| Fiber.yield(start + 1) * 10 }
+            var yielded = fiber.call(1)
+            var returned = fiber.call(4)
+        ");
+
+        assert_eq!(vm.global("yielded"), Some(&Value::Num(2.0)));
+        assert_eq!(vm.global("returned"), Some(&Value::Num(40.0)));
+    }
+
+    // A fiber reached by `transfer` has no caller to return to,
+    // so when it finishes the program stops with its value.
+    #[test]
+    fn fiber_transfer_does_not_return() {
+        let chunk = compile(
+            "
+            var other = Fiber.new { 2 }
+            var main = Fiber.new {
+                other.transfer()
+                System.print(3)
+            }
+            main.call()
+            var after = true
+        ",
+        )
+        .unwrap();
+        let mut vm = Vm::new();
+
+        assert_eq!(
+            vm.run(Rc::new(Function::script(chunk))),
+            Ok(Value::Num(2.0))
+        );
+        assert_eq!(vm.global("after"), None);
+    }
+
+    #[test]
+    fn fiber_errors() {
+        let errors = [
+            ("Fiber.yield(1)", "cannot yield from the root fiber"),
+            (
+                "var f = Fiber.new { } f.call() f.call()",
+                "cannot resume a finished fiber",
+            ),
+            ("Fiber.new(1)", "fiber body must be a function"),
+        ];
+        for (src, message) in errors {
+            let chunk = compile(src).unwrap();
+            let error = Vm::new().run(Rc::new(Function::script(chunk))).unwrap_err();
+            assert_eq!(error.to_string(), message, "{src}");
+        }
+    }
+
+    #[test]
+    fn bodies_see_later_module_variables() {
+        let vm = run("
+            class A {
+                static b { B.new() }
+                static count { Count = Count + 1 }
+            }
+            class B {
+                construct new() {}
+            }
+            var Count = 0
+            var IsB = A.b is B
+            A.count
+            ");
+
+        assert_eq!(vm.global("IsB"), Some(&Value::Bool(true)));
+        assert_eq!(vm.global("Count"), Some(&Value::Num(1.0)));
+
+        // They must still be declared somewhere in the module. Top-level
+        // code runs in order, so it cannot look ahead.
+        let errors = compile("class A {\n static b { C }\n}").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message(), "variable 'C' is not defined");
+        assert_eq!(errors[0].span(), 22..23);
+        let errors = compile("var a = B\nvar B = 1").unwrap_err();
+        assert_eq!(errors[0].message(), "variable 'B' is not defined");
+    }
+
+    #[test]
+    fn implicit_this_calls() {
+        let vm = run(r#"
+            class Counter {
+                construct new() { _count = 0 }
+                count { _count }
+                count=(value) { _count = value }
+                bump(by) { count = count + by }
+                twice() {
+                    bump(1)
+                    bump(1)
+                }
+            }
+            var counter = Counter.new()
+            counter.twice()
+            var Result = counter.count
+        "#);
+
+        assert_eq!(vm.global("Result"), Some(&Value::Num(2.0)));
+    }
+
+    #[test]
+    fn implicit_this_prefers_locals() {
+        let vm = run(r#"
+            class A {
+                construct new() {}
+                name { "method" }
+                local(name) { name }
+                getter { name }
+            }
+            var a = A.new().local("param")
+            var b = A.new().getter
+        "#);
+
+        assert_eq!(vm.global("a"), Some(&Value::Str("param".into())));
+        assert_eq!(vm.global("b"), Some(&Value::Str("method".into())));
+
+        let errors = compile("doThing()").unwrap_err();
+        assert_eq!(
+            errors[0].message(),
+            "'doThing()' is called without a receiver outside a method"
+        );
+    }
+
+    #[test]
+    fn super_errors() {
+        let errors = compile("class A {} class B is A { f() { super() } }").unwrap_err();
+        assert_eq!(
+            errors[0].message(),
+            "'super' without a method name can only be used in a constructor"
+        );
+        assert_eq!(errors[0].span(), 32..39);
+
+        let errors = compile("class A { construct new() { super() } }").unwrap_err();
+        assert_eq!(errors[0].message(), "class 'A' has no superclass to call");
+
+        for src in ["var a = super.f()", "var a = super.x", "super.x = 1"] {
+            let errors = compile(src).unwrap_err();
+            assert_eq!(
+                errors[0].message(),
+                "'super' can only be used inside a method",
+                "{src}"
+            );
+        }
+    }
+
+    #[test]
+    fn super_getters_and_setters() {
+        let vm = run(r#"
+            class Base {
+                construct new() { _name = "base" }
+                name { _name }
+                name=(value) { _name = value }
+                rename(value) { name = value }
+            }
+            class Derived is Base {
+                construct new() { super() }
+                name { "derived " + super.name }
+                name=(value) { super.name = value + "!" }
+            }
+            var d = Derived.new()
+            var Before = d.name
+            d.rename("renamed")
+            var After = d.name
+        "#);
+
+        assert_eq!(
+            vm.global("Before"),
+            Some(&Value::Str("derived base".into()))
+        );
+        assert_eq!(
+            vm.global("After"),
+            Some(&Value::Str("derived renamed!".into()))
+        );
+    }
+
+    #[test]
+    fn property_setters() {
+        let vm = run(r#"
+            class Point {
+                construct new() { _x = 0 }
+                x { _x }
+                x=(value) { _x = value }
+                reset() { this.x = 2 }
+            }
+            var p = Point.new()
+            p.x = 3
+            var Set = p.x
+            p.reset()
+            var Reset = p.x
+            p.x += 5
+            p.x *= 2
+            var Updated = p.x
+            var Chained = p.x = 1
+        "#);
+
+        assert_eq!(vm.global("Set"), Some(&Value::Num(3.0)));
+        assert_eq!(vm.global("Reset"), Some(&Value::Num(2.0)));
+        assert_eq!(vm.global("Updated"), Some(&Value::Num(14.0)));
+        assert_eq!(vm.global("Chained"), Some(&Value::Num(1.0)));
+    }
+
+    #[test]
+    fn field_errors() {
+        let errors = compile("class A { static f { _x } }").unwrap_err();
+        assert_eq!(
+            errors[0].message(),
+            "instance field '_x' cannot be used in a static method"
+        );
+
+        let errors = compile("var a = __x").unwrap_err();
+        assert_eq!(errors[0].span(), 8..11);
+
+        // The field count is a byte, so it must not wrap around.
+        let fields = |n: usize| {
+            let names: Vec<_> = (0..n).map(|i| format!("_f{i} = 0")).collect();
+            format!(
+                "class A {{\n  construct new() {{\n{}\n  }}\n}}",
+                names.join("\n")
+            )
+        };
+        assert!(compile(&fields(255)).is_ok());
+        let errors = compile(&fields(256)).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message(), "class 'A' has more than 255 fields");
+    }
+
+    #[test]
+    fn runtime_errors_have_spans() {
+        let src = "var a = 1\nvar b = 2 + a / \"x\"\n";
+        let chunk = compile(src).unwrap();
+        let mut vm = Vm::new();
+
+        assert!(vm.run(Rc::new(Function::script(chunk))).is_err());
+        assert_eq!(vm.error_span().map(|span| &src[span]), Some("a / \"x\""));
+
+        let src = "class A {\n  static f { null.foo }\n}\nA.f";
+        let chunk = compile(src).unwrap();
+        assert!(vm.run(Rc::new(Function::script(chunk))).is_err());
+        assert_eq!(vm.error_span().map(|span| &src[span]), Some("null.foo"));
+    }
+
+    #[test]
+    fn undefined_variable() {
+        let errors = compile("var a = 1 b = a").unwrap_err();
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span(), 10..15);
+    }
+}
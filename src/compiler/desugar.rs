@@ -0,0 +1,153 @@
+use super::ast::{Expr, ExprKind, LiteralValue, Stmt, StmtKind};
+
+/*
+ *
+ * Desugaring
+ *
+ * Rewrites syntax that is shorthand for other
+ * syntax, so code generation only handles the
+ * longhand. As in the reference implementation,
+ *
+ *   for (x in sequence) body
+ *
+ * becomes
+ *
+ *   {
+ *     var seq = sequence
+ *     var iter = null
+ *     while (iter = seq.iterate(iter)) {
+ *       var x = seq.iteratorValue(iter)
+ *       body
+ *     }
+ *   }
+ *
+ * The hidden variables end in a space, so no
+ * program can name them.
+ *
+ */
+
+pub const SEQUENCE: &str = "seq ";
+pub const ITERATOR: &str = "iter ";
+
+/// Lowers a `for` loop into a `while` loop over the sequence's
+/// iterator protocol. Other statements are returned unchanged.
+pub fn desugar_for(stmt: Stmt) -> Stmt {
+    let StmtKind::For {
+        variable,
+        sequence,
+        body,
+    } = stmt.kind
+    else {
+        return stmt;
+    };
+    let span = stmt.span;
+    let at = |kind| Expr {
+        kind,
+        span: span.clone(),
+    };
+    let statement = |kind| Stmt {
+        kind,
+        span: span.clone(),
+    };
+    let call = |name: &str, arg| {
+        at(ExprKind::Call {
+            receiver: Some(Box::new(at(ExprKind::Variable(SEQUENCE.to_string())))),
+            name: name.to_string(),
+            args: vec![arg],
+            is_getter: false,
+        })
+    };
+    let iterator = || at(ExprKind::Variable(ITERATOR.to_string()));
+
+    let condition = at(ExprKind::Assign {
+        name: ITERATOR.to_string(),
+        value: Box::new(call("iterate", iterator())),
+    });
+    let loop_body = statement(StmtKind::Block(vec![
+        statement(StmtKind::Var {
+            name: variable,
+            initializer: Some(call("iteratorValue", iterator())),
+        }),
+        *body,
+    ]));
+
+    statement(StmtKind::Block(vec![
+        statement(StmtKind::Var {
+            name: SEQUENCE.to_string(),
+            initializer: Some(sequence),
+        }),
+        statement(StmtKind::Var {
+            name: ITERATOR.to_string(),
+            initializer: Some(at(ExprKind::Literal(LiteralValue::Null))),
+        }),
+        statement(StmtKind::While {
+            condition,
+            body: Box::new(loop_body),
+        }),
+    ]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::parser::parse_program;
+
+    fn expr(kind: ExprKind) -> Expr {
+        Expr { kind, span: 0..0 }
+    }
+
+    fn stmt(kind: StmtKind) -> Stmt {
+        Stmt { kind, span: 0..0 }
+    }
+
+    fn variable(name: &str) -> Expr {
+        expr(ExprKind::Variable(name.to_string()))
+    }
+
+    fn var(name: &str, initializer: Expr) -> Stmt {
+        stmt(StmtKind::Var {
+            name: name.to_string(),
+            initializer: Some(initializer),
+        })
+    }
+
+    fn sequence_call(name: &str) -> Expr {
+        expr(ExprKind::Call {
+            receiver: Some(Box::new(variable(SEQUENCE))),
+            name: name.to_string(),
+            args: vec![variable(ITERATOR)],
+            is_getter: false,
+        })
+    }
+
+    #[test]
+    fn for_becomes_while() {
+        let mut program = parse_program("for (x in list) x").unwrap();
+        let desugared = desugar_for(program.remove(0));
+
+        let expected = stmt(StmtKind::Block(vec![
+            var(SEQUENCE, variable("list")),
+            var(ITERATOR, expr(ExprKind::Literal(LiteralValue::Null))),
+            stmt(StmtKind::While {
+                condition: expr(ExprKind::Assign {
+                    name: ITERATOR.to_string(),
+                    value: Box::new(sequence_call("iterate")),
+                }),
+                body: Box::new(stmt(StmtKind::Block(vec![
+                    var("x", sequence_call("iteratorValue")),
+                    stmt(StmtKind::Expression(variable("x"))),
+                ]))),
+            }),
+        ]));
+        assert_eq!(desugared, expected);
+        assert_eq!(desugared.span, 0..17);
+    }
+
+    #[test]
+    fn other_statements_are_unchanged() {
+        let mut program = parse_program("var a = 1").unwrap();
+        let stmt = program.remove(0);
+
+        assert_eq!(desugar_for(stmt.clone()), stmt);
+    }
+}
@@ -0,0 +1,287 @@
+use std::fmt::Write;
+use std::ops::Range;
+
+use super::span::LineIndex;
+
+/*
+ *
+ * Diagnostics
+ *
+ * Messages about the source that are reported
+ * to the user without necessarily stopping
+ * compilation.
+ *
+ * A diagnostic points at one primary span, and
+ * may point at secondary spans that explain it,
+ * such as where a duplicated method was first
+ * defined. Rendering underlines the primary span
+ * with `^` and the others with `-`:
+ *
+ *   error: class already defines a method 'f()'
+ *    --> 1:18
+ *     |
+ *   1 | class A { f() {} f() {} }
+ *     |           ------ first defined here
+ *     |                  ^^^^^^ duplicate here
+ *
+ * A `DiagnosticSink` is given each diagnostic as
+ * the compiler finds it, so a long compilation
+ * can report errors before it is done.
+ *
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A span with a message explaining its part in a diagnostic.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub span: Range<usize>,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Range<usize>,
+    /// What to say under the primary span, if anything.
+    pub label: Option<String>,
+    pub secondary: Vec<Label>,
+    pub notes: Vec<String>,
+    pub help: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>, span: Range<usize>) -> Self {
+        Diagnostic::build(Severity::Error, message)
+            .primary_span(span)
+            .finish()
+    }
+
+    pub fn warning(message: impl Into<String>, span: Range<usize>) -> Self {
+        Diagnostic::build(Severity::Warning, message)
+            .primary_span(span)
+            .finish()
+    }
+
+    pub fn build(severity: Severity, message: impl Into<String>) -> DiagnosticBuilder {
+        DiagnosticBuilder {
+            diagnostic: Diagnostic {
+                severity,
+                message: message.into(),
+                span: 0..0,
+                label: None,
+                secondary: Vec::new(),
+                notes: Vec::new(),
+                help: Vec::new(),
+            },
+        }
+    }
+
+    /// Renders the diagnostic against the source it is about,
+    /// quoting each line a span starts on.
+    pub fn render(&self, src: &str) -> String {
+        let mut labels = vec![(&self.span, self.label.as_deref().unwrap_or(""), '^')];
+        for label in &self.secondary {
+            labels.push((&label.span, &label.message, '-'));
+        }
+        labels.sort_by_key(|(span, ..)| span.start);
+
+        let index = LineIndex::new(src);
+        let location = |offset| {
+            index
+                .line_col(offset)
+                .map_or((0, 0), |p| (p.line as usize, p.column as usize))
+        };
+        let last_line = labels
+            .iter()
+            .map(|(span, ..)| location(span.start).0)
+            .max()
+            .unwrap_or(0);
+        let gutter = " ".repeat(last_line.to_string().len());
+
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        let (line, column) = location(self.span.start);
+        let mut out = format!("{severity}: {}\n", self.message);
+        let _ = writeln!(out, "{gutter}--> {line}:{column}");
+        let _ = writeln!(out, "{gutter} |");
+
+        let mut quoted = None;
+        for (span, message, underline) in labels {
+            let line = location(span.start).0;
+            let line_start = src
+                .get(..span.start)
+                .and_then(|before| before.rfind('\n'))
+                .map_or(0, |newline| newline + 1);
+            let text = src
+                .get(line_start..)
+                .and_then(|rest| rest.split('\n').next())
+                .unwrap_or("");
+            if quoted != Some(line) {
+                let _ = writeln!(out, "{line:>width$} | {text}", width = gutter.len());
+                quoted = Some(line);
+            }
+
+            // Spans running past the end of the line are
+            // underlined to its end; empty ones get one mark.
+            let before = src.get(line_start..span.start).unwrap_or("");
+            let marked = src
+                .get(span.start..span.end.min(line_start + text.len()))
+                .unwrap_or("");
+            let padding = before.chars().count();
+            let width = marked.chars().count().max(1);
+            let underline = underline.to_string().repeat(width);
+            let mark = format!("{}{underline} {message}", " ".repeat(padding));
+            let _ = writeln!(out, "{gutter} | {}", mark.trim_end());
+        }
+        for note in &self.notes {
+            let _ = writeln!(out, "{gutter} = note: {note}");
+        }
+        for help in &self.help {
+            let _ = writeln!(out, "{gutter} = help: {help}");
+        }
+        out
+    }
+}
+
+/// Assembles a diagnostic with several spans and remarks.
+#[derive(Debug, Clone)]
+pub struct DiagnosticBuilder {
+    diagnostic: Diagnostic,
+}
+
+impl DiagnosticBuilder {
+    pub fn primary_span(mut self, span: Range<usize>) -> Self {
+        self.diagnostic.span = span;
+        self
+    }
+
+    /// A message under the primary span.
+    pub fn primary_label(mut self, message: impl Into<String>) -> Self {
+        self.diagnostic.label = Some(message.into());
+        self
+    }
+
+    pub fn secondary_span(mut self, span: Range<usize>, message: impl Into<String>) -> Self {
+        self.diagnostic.secondary.push(Label {
+            span,
+            message: message.into(),
+        });
+        self
+    }
+
+    pub fn note(mut self, note: impl Into<String>) -> Self {
+        self.diagnostic.notes.push(note.into());
+        self
+    }
+
+    pub fn help(mut self, help: impl Into<String>) -> Self {
+        self.diagnostic.help.push(help.into());
+        self
+    }
+
+    pub fn finish(self) -> Diagnostic {
+        self.diagnostic
+    }
+}
+
+/// Receives diagnostics as the compiler finds them.
+pub trait DiagnosticSink {
+    fn emit(&mut self, diagnostic: &Diagnostic);
+}
+
+/// Keeps every diagnostic it is given, in order.
+#[derive(Debug, Clone, Default)]
+pub struct VecSink {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl DiagnosticSink for VecSink {
+    fn emit(&mut self, diagnostic: &Diagnostic) {
+        self.diagnostics.push(diagnostic.clone());
+    }
+}
+
+/// Renders each diagnostic to standard error as it arrives.
+#[derive(Debug, Clone, Copy)]
+pub struct StderrSink<'src> {
+    src: &'src str,
+}
+
+impl<'src> StderrSink<'src> {
+    /// A sink for diagnostics about `src`.
+    pub fn new(src: &'src str) -> Self {
+        StderrSink { src }
+    }
+}
+
+impl DiagnosticSink for StderrSink<'_> {
+    fn emit(&mut self, diagnostic: &Diagnostic) {
+        eprint!("{}", diagnostic.render(self.src));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render_primary_and_secondary_spans() {
+        let src = "class A {\n  f() {}\n  f() {}\n}";
+        let diagnostic = Diagnostic::build(Severity::Error, "class already defines 'f()'")
+            .primary_span(21..27)
+            .primary_label("duplicate here")
+            .secondary_span(12..18, "first defined here")
+            .note("methods are looked up by signature")
+            .help("rename one of them")
+            .finish();
+
+        assert_eq!(
+            diagnostic.render(src),
+            [
+                "error: class already defines 'f()'",
+                " --> 3:3",
+                "  |",
+                "2 |   f() {}",
+                "  |   ------ first defined here",
+                "3 |   f() {}",
+                "  |   ^^^^^^ duplicate here",
+                "  = note: methods are looked up by signature",
+                "  = help: rename one of them",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn render_spans_on_one_line() {
+        let src = "var x = y + z";
+        let rendered = Diagnostic::build(Severity::Warning, "odd")
+            .primary_span(12..13)
+            .secondary_span(8..9, "left")
+            .finish()
+            .render(src);
+
+        assert_eq!(
+            rendered,
+            [
+                "warning: odd",
+                " --> 1:13",
+                "  |",
+                "1 | var x = y + z",
+                "  |         - left",
+                "  |             ^",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+}
@@ -0,0 +1,156 @@
+use super::diagnostic::{Diagnostic, Severity};
+use super::lexer::LexError;
+use super::parser::ParseError;
+use super::span::Span;
+
+/*
+ *
+ * Compile Errors
+ *
+ * Every phase of compilation reports its own
+ * error type. `CompileError` wraps whichever
+ * phase failed, so callers handle one type.
+ *
+ */
+
+/// A name or declaration the code generator could not resolve,
+/// such as an undefined variable or a misplaced field.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolveError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl ResolveError {
+    pub fn new(message: impl Into<String>, span: Span) -> Self {
+        ResolveError {
+            message: message.into(),
+            span,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompileError {
+    Lex(LexError),
+    Parse(ParseError),
+    Resolve(ResolveError),
+}
+
+impl CompileError {
+    pub fn span(&self) -> Span {
+        match self {
+            CompileError::Lex(error) => error.span.clone(),
+            CompileError::Parse(error) => error.span(),
+            CompileError::Resolve(error) => error.span.clone(),
+        }
+    }
+
+    /// What went wrong, without the location.
+    pub fn message(&self) -> String {
+        match self {
+            CompileError::Lex(error) => error.kind.to_string(),
+            CompileError::Parse(error) => error.to_string(),
+            CompileError::Resolve(error) => error.message.clone(),
+        }
+    }
+
+    pub fn diagnostic(&self) -> Diagnostic {
+        let diagnostic =
+            Diagnostic::build(Severity::Error, self.message()).primary_span(self.span());
+        match self {
+            CompileError::Parse(ParseError::DuplicateMethod { previous, .. }) => diagnostic
+                .primary_label("duplicate defined here")
+                .secondary_span(previous.clone(), "first defined here")
+                .help("rename one of them or change its parameters"),
+            _ => diagnostic,
+        }
+        .finish()
+    }
+}
+
+impl From<LexError> for CompileError {
+    fn from(error: LexError) -> Self {
+        CompileError::Lex(error)
+    }
+}
+
+// A parser that lexed its own input reports lex errors as
+// `ParseError::Lex`; they are still lex errors.
+impl From<ParseError> for CompileError {
+    fn from(error: ParseError) -> Self {
+        match error {
+            ParseError::Lex(error) => CompileError::Lex(error),
+            error => CompileError::Parse(error),
+        }
+    }
+}
+
+impl From<ResolveError> for CompileError {
+    fn from(error: ResolveError) -> Self {
+        CompileError::Resolve(error)
+    }
+}
+
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let span = self.span();
+        write!(
+            f,
+            "error at {}..{}: {}",
+            span.start,
+            span.end,
+            self.message()
+        )
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::codegen::compile;
+
+    #[test]
+    fn every_phase_is_reported() {
+        let errors = compile("var a = 1 @ var b = (").unwrap_err();
+
+        assert_eq!(
+            errors,
+            [
+                CompileError::Lex(LexError::unexpected(10..11)),
+                CompileError::Parse(ParseError::UnexpectedEnd {
+                    expected: "expression",
+                    span: 21..21,
+                }),
+            ]
+        );
+        assert_eq!(
+            errors[0].to_string(),
+            "error at 10..11: unexpected character"
+        );
+        assert_eq!(
+            errors[1].to_string(),
+            "error at 21..21: expected expression, found end of input"
+        );
+    }
+
+    #[test]
+    fn duplicate_method_points_at_both() {
+        let src = "class A {\n  f() {}\n  f() {}\n}";
+        let errors = compile(src).unwrap_err();
+        let rendered = errors[0].diagnostic().render(src);
+
+        assert!(rendered.contains("2 |   f() {}\n  |   ------ first defined here"));
+        assert!(rendered.contains("3 |   f() {}\n  |   ^^^^^^ duplicate defined here"));
+    }
+
+    #[test]
+    fn resolve_errors_are_collected() {
+        let errors = compile("var a = x\nvar b = y").unwrap_err();
+
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(&errors[1], CompileError::Resolve(error) if error.span == (18..19)));
+    }
+}
@@ -0,0 +1,362 @@
+use super::ast::{BinaryOp, ClassDecl, Expr, ExprKind, LiteralValue, Stmt, StmtKind, UnaryOp};
+
+/*
+ *
+ * Constant Folding
+ *
+ * Replaces operations on literals with their
+ * result before code is generated, so
+ * `60 * 60 * 24` compiles to a single constant.
+ * Only operations whose result the VM would
+ * compute the same way are folded: arithmetic
+ * and comparisons on numbers, concatenation of
+ * strings, equality of literals and `!`.
+ *
+ * The parser limits how deeply expressions nest,
+ * but a long chain such as `1 + 1 + ... + 1` is
+ * parsed by a loop and can still be arbitrarily
+ * deep. Folding stops at `max_depth` and leaves
+ * the rest of the tree as it was, instead of
+ * recursing until the stack overflows.
+ *
+ */
+
+pub const DEFAULT_MAX_DEPTH: usize = 256;
+
+/// Folds the constant expressions of a module.
+pub fn fold_program(program: Vec<Stmt>) -> Vec<Stmt> {
+    let mut folder = Folder::new(DEFAULT_MAX_DEPTH);
+    program.into_iter().map(|stmt| folder.stmt(stmt)).collect()
+}
+
+/// Drops statements that only evaluate a literal, at the top level
+/// and in blocks. Method and block bodies keep theirs, since a body
+/// made of one expression returns it.
+pub fn eliminate_dead_code(program: Vec<Stmt>) -> Vec<Stmt> {
+    program
+        .into_iter()
+        .filter(|stmt| !is_literal_statement(stmt))
+        .map(|stmt| match stmt.kind {
+            StmtKind::Block(statements) => Stmt {
+                kind: StmtKind::Block(eliminate_dead_code(statements)),
+                span: stmt.span,
+            },
+            _ => stmt,
+        })
+        .collect()
+}
+
+fn is_literal_statement(stmt: &Stmt) -> bool {
+    matches!(
+        &stmt.kind,
+        StmtKind::Expression(Expr {
+            kind: ExprKind::Literal(_),
+            ..
+        })
+    )
+}
+
+/// Folds `expr`, leaving the parts nested deeper than the
+/// default limit unfolded.
+pub fn fold(expr: Expr) -> Expr {
+    Folder::new(DEFAULT_MAX_DEPTH).expr(expr)
+}
+
+pub struct Folder {
+    depth: usize,
+    max_depth: usize,
+}
+
+impl Folder {
+    pub fn new(max_depth: usize) -> Self {
+        Folder {
+            depth: 0,
+            max_depth,
+        }
+    }
+
+    pub fn stmt(&mut self, stmt: Stmt) -> Stmt {
+        let kind = match stmt.kind {
+            StmtKind::Expression(expr) => StmtKind::Expression(self.expr(expr)),
+            StmtKind::Var { name, initializer } => StmtKind::Var {
+                name,
+                initializer: initializer.map(|value| self.expr(value)),
+            },
+            StmtKind::Block(statements) => StmtKind::Block(self.stmts(statements)),
+            StmtKind::For {
+                variable,
+                sequence,
+                body,
+            } => StmtKind::For {
+                variable,
+                sequence: self.expr(sequence),
+                body: Box::new(self.stmt(*body)),
+            },
+            StmtKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => StmtKind::If {
+                condition: self.expr(condition),
+                then_branch: Box::new(self.stmt(*then_branch)),
+                else_branch: else_branch.map(|branch| Box::new(self.stmt(*branch))),
+            },
+            StmtKind::While { condition, body } => StmtKind::While {
+                condition: self.expr(condition),
+                body: Box::new(self.stmt(*body)),
+            },
+            StmtKind::Class(class) => StmtKind::Class(ClassDecl {
+                members: class
+                    .members
+                    .into_iter()
+                    .map(|mut member| {
+                        member.body = self.stmts(member.body);
+                        member
+                    })
+                    .collect(),
+                static_fields: class
+                    .static_fields
+                    .into_iter()
+                    .map(|mut field| {
+                        field.value = self.expr(field.value);
+                        field
+                    })
+                    .collect(),
+                ..class
+            }),
+            StmtKind::Return(value) => StmtKind::Return(value.map(|value| self.expr(value))),
+            kind @ (StmtKind::Import { .. } | StmtKind::Break | StmtKind::Continue) => kind,
+        };
+        Stmt {
+            kind,
+            span: stmt.span,
+        }
+    }
+
+    fn stmts(&mut self, statements: Vec<Stmt>) -> Vec<Stmt> {
+        statements.into_iter().map(|stmt| self.stmt(stmt)).collect()
+    }
+
+    pub fn expr(&mut self, expr: Expr) -> Expr {
+        if self.depth >= self.max_depth {
+            return expr;
+        }
+        self.depth += 1;
+        let folded = self.fold(expr);
+        self.depth -= 1;
+        folded
+    }
+
+    fn fold(&mut self, expr: Expr) -> Expr {
+        let kind = match expr.kind {
+            ExprKind::Unary { op, operand } => {
+                let operand = self.expr(*operand);
+                match (op, &operand.kind) {
+                    (UnaryOp::Negate, ExprKind::Literal(LiteralValue::Num(n, _))) => {
+                        ExprKind::Literal(LiteralValue::Num(-n, None))
+                    }
+                    (UnaryOp::Not, ExprKind::Literal(value)) => {
+                        ExprKind::Literal(LiteralValue::Bool(matches!(
+                            value,
+                            LiteralValue::Null | LiteralValue::Bool(false)
+                        )))
+                    }
+                    _ => ExprKind::Unary {
+                        op,
+                        operand: Box::new(operand),
+                    },
+                }
+            }
+            ExprKind::Binary { op, left, right } => {
+                let left = self.expr(*left);
+                let right = self.expr(*right);
+                match (&left.kind, &right.kind) {
+                    (ExprKind::Literal(a), ExprKind::Literal(b)) => match binary(op, a, b) {
+                        Some(value) => ExprKind::Literal(value),
+                        None => ExprKind::Binary {
+                            op,
+                            left: Box::new(left),
+                            right: Box::new(right),
+                        },
+                    },
+                    _ => ExprKind::Binary {
+                        op,
+                        left: Box::new(left),
+                        right: Box::new(right),
+                    },
+                }
+            }
+            ExprKind::List(items) => ExprKind::List(self.exprs(items)),
+            ExprKind::Map(entries) => ExprKind::Map(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (self.expr(key), self.expr(value)))
+                    .collect(),
+            ),
+            ExprKind::Assign { name, value } => ExprKind::Assign {
+                name,
+                value: Box::new(self.expr(*value)),
+            },
+            ExprKind::Call {
+                receiver,
+                name,
+                args,
+                is_getter,
+            } => ExprKind::Call {
+                receiver: receiver.map(|receiver| Box::new(self.expr(*receiver))),
+                name,
+                args: self.exprs(args),
+                is_getter,
+            },
+            ExprKind::Subscript { receiver, args } => ExprKind::Subscript {
+                receiver: Box::new(self.expr(*receiver)),
+                args: self.exprs(args),
+            },
+            ExprKind::SubscriptSet { receiver, args } => ExprKind::SubscriptSet {
+                receiver: Box::new(self.expr(*receiver)),
+                args: self.exprs(args),
+            },
+            ExprKind::SubscriptUpdate {
+                receiver,
+                args,
+                op,
+                value,
+            } => ExprKind::SubscriptUpdate {
+                receiver: Box::new(self.expr(*receiver)),
+                args: self.exprs(args),
+                op,
+                value: Box::new(self.expr(*value)),
+            },
+            ExprKind::PropertyUpdate {
+                receiver,
+                name,
+                op,
+                value,
+            } => ExprKind::PropertyUpdate {
+                receiver: Box::new(self.expr(*receiver)),
+                name,
+                op,
+                value: Box::new(self.expr(*value)),
+            },
+            ExprKind::Super { name, args, kind } => ExprKind::Super {
+                name,
+                args: self.exprs(args),
+                kind,
+            },
+            ExprKind::Fn {
+                params,
+                body,
+                expression_body,
+            } => ExprKind::Fn {
+                params,
+                body: self.stmts(body),
+                expression_body,
+            },
+            kind @ (ExprKind::Literal(_) | ExprKind::Variable(_) | ExprKind::This) => kind,
+        };
+        Expr {
+            kind,
+            span: expr.span,
+        }
+    }
+
+    fn exprs(&mut self, exprs: Vec<Expr>) -> Vec<Expr> {
+        exprs.into_iter().map(|expr| self.expr(expr)).collect()
+    }
+}
+
+// The result of `a op b`, if it is known at compile time.
+fn binary(op: BinaryOp, a: &LiteralValue, b: &LiteralValue) -> Option<LiteralValue> {
+    use LiteralValue::{Bool, Num, Str};
+
+    let value = match (op, a, b) {
+        (BinaryOp::Add, Num(a, _), Num(b, _)) => Num(a + b, None),
+        (BinaryOp::Subtract, Num(a, _), Num(b, _)) => Num(a - b, None),
+        (BinaryOp::Multiply, Num(a, _), Num(b, _)) => Num(a * b, None),
+        (BinaryOp::Divide, Num(a, _), Num(b, _)) => Num(a / b, None),
+        (BinaryOp::Modulo, Num(a, _), Num(b, _)) => Num(a % b, None),
+        (BinaryOp::LessThan, Num(a, _), Num(b, _)) => Bool(a < b),
+        (BinaryOp::LessThanEqual, Num(a, _), Num(b, _)) => Bool(a <= b),
+        (BinaryOp::GreaterThan, Num(a, _), Num(b, _)) => Bool(a > b),
+        (BinaryOp::GreaterThanEqual, Num(a, _), Num(b, _)) => Bool(a >= b),
+        (BinaryOp::Add, Str(a), Str(b)) => Str(format!("{a}{b}")),
+        (BinaryOp::Equal, a, b) => Bool(a == b),
+        (BinaryOp::NotEqual, a, b) => Bool(a != b),
+        _ => return None,
+    };
+    Some(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::parser::Parser;
+
+    fn expr(src: &str) -> Expr {
+        let mut program = Parser::new(src).unwrap().program().unwrap();
+        match program.pop().map(|stmt| stmt.kind) {
+            Some(StmtKind::Expression(expr)) => expr,
+            _ => panic!("expected an expression"),
+        }
+    }
+
+    fn literal(expr: &Expr) -> Option<&LiteralValue> {
+        match &expr.kind {
+            ExprKind::Literal(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn folds_literals() {
+        assert_eq!(
+            literal(&fold(expr("60 * 60 * 24"))),
+            Some(&LiteralValue::Num(86400.0, None))
+        );
+        assert_eq!(
+            literal(&fold(expr(r#""a" + "b" == "ab""#))),
+            Some(&LiteralValue::Bool(true))
+        );
+
+        let partial = fold(expr("x + 2 * 3"));
+        let ExprKind::Binary { right, .. } = &partial.kind else {
+            panic!("expected a binary expression");
+        };
+        assert_eq!(literal(right), Some(&LiteralValue::Num(6.0, None)));
+        assert_eq!(partial.span, 0..9);
+
+        assert_eq!(literal(&fold(expr(r#""a" + 1"#))), None);
+        assert_eq!(
+            literal(&fold(expr("!null == -(1 - 2) > 0"))),
+            Some(&LiteralValue::Bool(true))
+        );
+    }
+
+    #[test]
+    fn folds_moderately_deep_chain() {
+        let src = vec!["1"; 200].join(" + ");
+
+        assert_eq!(
+            literal(&fold(expr(&src))),
+            Some(&LiteralValue::Num(200.0, None))
+        );
+    }
+
+    #[test]
+    fn stops_at_max_depth() {
+        let src = vec!["1"; 2000].join(" + ");
+        let folded = Folder::new(100).expr(expr(&src));
+
+        // The deepest part of the chain was left as parsed,
+        // so nothing above it is constant either.
+        let mut node = &folded;
+        let mut depth = 0;
+        while let ExprKind::Binary { left, right, .. } = &node.kind {
+            assert_eq!(literal(right), Some(&LiteralValue::Num(1.0, None)));
+            node = left;
+            depth += 1;
+        }
+        assert_eq!(depth, 1999);
+        assert_eq!(literal(node), Some(&LiteralValue::Num(1.0, None)));
+    }
+}
@@ -1,4 +1,10 @@
-use logos::Logos;
+use std::collections::HashMap;
+use std::ops::Range;
+
+use logos::{Filter, Logos};
+use unicode_normalization::UnicodeNormalization;
+
+use super::span::{advance, PositionError};
 
 /**
  *
@@ -20,22 +26,40 @@ use logos::Logos;
  *
  */
 
-#[derive(Logos, Debug, PartialEq)]
-#[logos(skip r"[ \t\n\f]+")] //skip whitespace,newlines,etc.
+#[derive(Logos, Debug, Clone, PartialEq)]
+#[logos(skip r"[ \t\r\f]+")] //skip whitespace, but not newlines
+#[logos(extras = LexerExtras)]
+#[logos(error = LexErrorKind)]
 pub enum Token {
     /*
 
         Comments
 
+        A line comment runs to the end of its line.
+        A block comment runs to the end marker that
+        matches its start, so block comments nest,
+        and is one token whatever it contains. Comments are trivia to
+        the parser, except that line comments right
+        before a class or method document it.
+
     */
-    #[token("//")]
+    #[regex(r"//[^\n]*")]
     LineComment,
 
-    #[token("/*")]
-    BlockCommentPrefix,
+    #[token("/*", block_comment)]
+    BlockComment,
+
+    /*
 
-    #[token("*/")]
-    BlockCommentSuffix,
+        Newlines
+
+        Wren separates statements with newlines,
+        so they are tokens. The parser decides
+        where they are insignificant.
+
+    */
+    #[token("\n")]
+    Newline,
 
     /*
 
@@ -138,6 +162,44 @@ pub enum Token {
     #[token(">>")]
     BitwiseRightShift,
 
+    /*
+
+        Augmented Assignment
+
+        Not in Wren itself: `a += b` is sugar for
+        `a = a + b`, which the parser expands.
+
+    */
+    #[token("+=")]
+    PlusAssign,
+
+    #[token("-=")]
+    MinusAssign,
+
+    #[token("*=")]
+    StarAssign,
+
+    #[token("/=")]
+    SlashAssign,
+
+    #[token("%=")]
+    PercentAssign,
+
+    #[token("&=")]
+    BitwiseAndAssign,
+
+    #[token("|=")]
+    BitwiseOrAssign,
+
+    #[token("^=")]
+    BitwiseXORAssign,
+
+    #[token("<<=")]
+    BitwiseLeftShiftAssign,
+
+    #[token(">>=")]
+    BitwiseRightShiftAssign,
+
     /*
 
         Quotation
@@ -175,7 +237,7 @@ pub enum Token {
     #[token(":")]
     Colon,
 
-    #[token("#")]
+    #[token("#", line_directive)]
     HashTag,
     /*
 
@@ -266,23 +328,647 @@ pub enum Token {
     #[regex(r"[a-zA-Z_][a-zA-Z0-9_]*", priority = 3)]
     Identifier,
 
-    #[regex(r"0[xX][0-9a-fA-F]+", priority = 2)]
+    // Digits may be grouped with single underscores,
+    // as in `1_000_000` or `0xFF_FF`.
+    #[regex(r"0[xX][0-9a-fA-F]+(_[0-9a-fA-F]+)*", priority = 2)]
     Hexadecimal,
 
-    #[regex(r"[+-]?\d+", priority = 1)]
-    #[regex(r"[+-]?\d+\.", priority = 1)]
-    #[regex(r"[+-]?\.\d+", priority = 1)]
-    #[regex(r"[+-]?\d+\.\d+", priority = 1)]
+    // Escapes are decoded by the parser.
+    #[regex(r#""([^"\\]|\\.)*""#)]
+    String,
+
+    #[regex(r"[+-]?\d+(_\d+)*", priority = 1)]
+    #[regex(r"[+-]?\d+(_\d+)*\.", priority = 1)]
+    #[regex(r"[+-]?\.\d+(_\d+)*", priority = 1)]
+    #[regex(r"[+-]?\d+(_\d+)*\.\d+(_\d+)*", priority = 1)]
     Number,
 }
 
+/*
+
+    Line Directives
+
+    Preprocessors can emit `#line N "file"` to say
+    that the next line came from line N of another
+    file. When enabled, such a directive at the start
+    of a line is consumed by the lexer and recorded in
+    its extras, so positions can be reported against
+    the original source. When disabled it lexes like
+    any other `#` attribute.
+
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineDirective {
+    // First byte of the line following the directive.
+    pub offset: usize,
+    pub line: u32,
+    pub file: Option<String>,
+}
+
+/*
+
+    Unicode Identifiers
+
+    Identifiers are ASCII, as in Wren, unless Unicode
+    identifiers are enabled. Then an identifier may
+    start with any XID_Start character or `_` and go
+    on with XID_Continue ones, so `café` is a single
+    identifier rather than `caf` and an error.
+
+    Names are NFC normalized, so `café` is the same
+    name whether its accent is precomposed or written
+    as a combining character. Spans still cover the
+    identifier as written.
+
+*/
+#[derive(Debug, Clone, Default)]
+pub struct LexerExtras {
+    pub line_directives: bool,
+    pub directives: Vec<LineDirective>,
+    pub unicode_identifiers: bool,
+}
+
+impl LexerExtras {
+    /// Lexer state with `#line` directives recognized.
+    pub fn with_line_directives() -> Self {
+        LexerExtras {
+            line_directives: true,
+            ..LexerExtras::default()
+        }
+    }
+
+    /// Lexer state with Unicode identifiers accepted.
+    pub fn with_unicode_identifiers() -> Self {
+        LexerExtras {
+            unicode_identifiers: true,
+            ..LexerExtras::default()
+        }
+    }
+
+    /// The file and 1-based line reported for `offset`, after
+    /// applying any directive that precedes it.
+    pub fn location(&self, src: &str, offset: usize) -> Result<(Option<&str>, u32), PositionError> {
+        let newlines = |range: Range<usize>| {
+            let text = src.get(range).ok_or(PositionError::OutOfBounds)?;
+            Ok(text.matches('\n').count())
+        };
+
+        let Some(index) = self.directives.iter().rposition(|d| d.offset <= offset) else {
+            return Ok((None, advance(1, newlines(0..offset)?)?));
+        };
+
+        let directive = &self.directives[index];
+        let file = self.directives[..=index]
+            .iter()
+            .rev()
+            .find_map(|d| d.file.as_deref());
+        let line = advance(directive.line, newlines(directive.offset..offset)?)?;
+        Ok((file, line))
+    }
+}
+
+// Skips the rest of a block comment, with any comments nested in
+// it. One left open runs to the end of the source and is an error.
+fn block_comment(lex: &mut logos::Lexer<Token>) -> Result<(), LexErrorKind> {
+    let rest = lex.remainder().as_bytes();
+    let mut depth = 1;
+    let mut index = 0;
+    while index < rest.len() {
+        match &rest[index..(index + 2).min(rest.len())] {
+            b"/*" => {
+                depth += 1;
+                index += 2;
+            }
+            b"*/" => {
+                depth -= 1;
+                index += 2;
+                if depth == 0 {
+                    lex.bump(index);
+                    return Ok(());
+                }
+            }
+            _ => index += 1,
+        }
+    }
+    lex.bump(rest.len());
+    Err(LexErrorKind::UnterminatedComment)
+}
+
+fn line_directive(lex: &mut logos::Lexer<Token>) -> Filter<()> {
+    if !lex.extras.line_directives {
+        return Filter::Emit(());
+    }
+
+    let start = lex.span().start;
+    let before = &lex.source()[..start];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    if !before[line_start..].chars().all(|c| c == ' ' || c == '\t') {
+        return Filter::Emit(());
+    }
+
+    let rest = lex.remainder();
+    let line = &rest[..rest.find('\n').unwrap_or(rest.len())];
+    let Some((number, file)) = parse_line_directive(line) else {
+        return Filter::Emit(());
+    };
+
+    let consumed = (line.len() + 1).min(rest.len());
+    lex.bump(consumed);
+    let offset = lex.span().end;
+    lex.extras.directives.push(LineDirective {
+        offset,
+        line: number,
+        file,
+    });
+    Filter::Skip
+}
+
+// Parses `line N` or `line N "file"`, the text after the `#`.
+fn parse_line_directive(text: &str) -> Option<(u32, Option<String>)> {
+    let rest = text.strip_prefix("line")?;
+    if !rest.starts_with([' ', '\t']) {
+        return None;
+    }
+    let rest = rest.trim();
+    let (number, file) = match rest.find([' ', '\t']) {
+        Some(end) => (&rest[..end], rest[end..].trim()),
+        None => (rest, ""),
+    };
+
+    let number = number.parse().ok()?;
+    if file.is_empty() {
+        return Some((number, None));
+    }
+    let file = file.strip_prefix('"')?.strip_suffix('"')?;
+    if file.contains('"') {
+        return None;
+    }
+    Some((number, Some(file.to_string())))
+}
+
+/// A token together with the byte range it was lexed from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Range<usize>,
+}
+
+impl SpannedToken {
+    /// The text of the token in `src`, the source it was lexed from.
+    /// Tokens start and end on character boundaries, so this only
+    /// panics if `src` is other source.
+    pub fn text<'a>(&self, src: &'a str) -> &'a str {
+        debug_assert!(
+            src.is_char_boundary(self.span.start) && src.is_char_boundary(self.span.end),
+            "token span {:?} is not on character boundaries",
+            self.span
+        );
+        &src[self.span.clone()]
+    }
+}
+
+/// A slice of source the lexer could not turn into a token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LexError {
+    pub span: Range<usize>,
+    pub kind: LexErrorKind,
+}
+
+/// Why a slice of source did not lex.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LexErrorKind {
+    #[default]
+    UnexpectedCharacter,
+    /// A block comment without its closing `*/`. The error spans
+    /// from the comment to the end of the source.
+    UnterminatedComment,
+}
+
+impl LexError {
+    /// An unexpected character at `span`.
+    pub fn unexpected(span: Range<usize>) -> Self {
+        LexError {
+            span,
+            kind: LexErrorKind::UnexpectedCharacter,
+        }
+    }
+}
+
+impl std::fmt::Display for LexErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LexErrorKind::UnexpectedCharacter => write!(f, "unexpected character"),
+            LexErrorKind::UnterminatedComment => write!(f, "unterminated block comment"),
+        }
+    }
+}
+
+impl std::fmt::Display for LexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at {}..{}", self.kind, self.span.start, self.span.end)
+    }
+}
+
+/**
+ *
+ * Drains the lexer over `src` without stopping at the first error.
+ *
+ * Valid tokens and errors are returned separately, both in
+ * source order, so callers can report every bad character at once.
+ *
+ */
+pub fn lex_collect_errors(src: &str) -> (Vec<SpannedToken>, Vec<LexError>) {
+    lex_collect_errors_with(src, LexerExtras::default())
+}
+
+/// Like `lex_collect_errors`, starting from `extras`, such as
+/// `LexerExtras::with_unicode_identifiers()`.
+pub fn lex_collect_errors_with(
+    src: &str,
+    extras: LexerExtras,
+) -> (Vec<SpannedToken>, Vec<LexError>) {
+    let mut tokens = Vec::new();
+    let mut errors = Vec::new();
+    for result in spanned(Token::lexer_with_extras(src, extras)) {
+        match result {
+            Ok(token) => tokens.push(token),
+            Err(error) => errors.push(error),
+        }
+    }
+    (tokens, errors)
+}
+
+/// A token or lex error as plain data, for editor grammars and
+/// incremental parsers in other languages. `kind` is the name of
+/// the `Token` variant, or `"Error"` for a slice that did not lex.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LexEvent {
+    pub start: usize,
+    pub end: usize,
+    pub kind: String,
+}
+
+/// Every token of `src`, comments included, and every lex error,
+/// in source order.
+pub fn lex_events(src: &str) -> Vec<LexEvent> {
+    spanned(Token::lexer(src))
+        .map(|result| {
+            let (span, kind) = match result {
+                Ok(spanned) => (spanned.span, format!("{:?}", spanned.token)),
+                Err(error) => (error.span, "Error".to_string()),
+            };
+            LexEvent {
+                start: span.start,
+                end: span.end,
+                kind,
+            }
+        })
+        .collect()
+}
+
+/// A broad kind of token, for counting what code is made of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TokenCategory {
+    /// A line comment or a block comment.
+    Comment,
+    Newline,
+    Keyword,
+    Identifier,
+    /// Numbers, strings, `true`, `false` and `null`.
+    Literal,
+    Operator,
+    /// Brackets, separators and the other punctuation.
+    Punctuation,
+}
+
+impl Token {
+    /// The category of the token.
+    pub fn category(&self) -> TokenCategory {
+        use TokenCategory::*;
+
+        match self {
+            Token::LineComment | Token::BlockComment => Comment,
+            Token::Newline => Newline,
+            Token::Identifier => Identifier,
+            Token::Number
+            | Token::Hexadecimal
+            | Token::String
+            | Token::True
+            | Token::False
+            | Token::Null => Literal,
+            Token::For
+            | Token::While
+            | Token::Break
+            | Token::Continue
+            | Token::Return
+            | Token::If
+            | Token::Else
+            | Token::Class
+            | Token::Construct
+            | Token::Static
+            | Token::Super
+            | Token::This
+            | Token::Import
+            | Token::As
+            | Token::Var
+            | Token::Foreign => Keyword,
+            Token::OpenParenthesis
+            | Token::CloseParenthesis
+            | Token::OpenBracket
+            | Token::CloseBracket
+            | Token::OpenBrace
+            | Token::CloseBrace
+            | Token::SingleQuote
+            | Token::DoubleQuote
+            | Token::Dot
+            | Token::Comma
+            | Token::BackSlash
+            | Token::Colon
+            | Token::HashTag => Punctuation,
+            // The rest, including `is`, `in` and `?`, are
+            // operators or assignments.
+            _ => Operator,
+        }
+    }
+}
+
+/// How many tokens of each category `src` has. Slices that do
+/// not lex are not counted.
+pub fn token_histogram(src: &str) -> HashMap<TokenCategory, usize> {
+    let (tokens, _) = lex_collect_errors(src);
+    let mut histogram = HashMap::new();
+    for spanned in tokens {
+        *histogram.entry(spanned.token.category()).or_insert(0) += 1;
+    }
+    histogram
+}
+
+/// The kinds of the tokens in `src`, for tests and tools that do
+/// not need spans. Panics if `src` does not lex.
+pub fn tokens_of(src: &str) -> Vec<Token> {
+    try_tokens_of(src).unwrap_or_else(|error| panic!("{error} in {src:?}"))
+}
+
+/// The kinds of the tokens in `src`, or the first lex error.
+pub fn try_tokens_of(src: &str) -> Result<Vec<Token>, LexError> {
+    spanned(Token::lexer(src))
+        .map(|result| result.map(|spanned| spanned.token))
+        .collect()
+}
+
+/// Lexes `src` starting at byte `start`, with spans still counted
+/// from the start of `src`, so part of a file can be lexed again
+/// after an edit. A `start` past the end or inside a character is
+/// reported as a single empty error there.
+pub fn lex_from(
+    src: &str,
+    start: usize,
+) -> impl Iterator<Item = Result<SpannedToken, LexError>> + '_ {
+    let valid = src.is_char_boundary(start);
+    let mut lex = Token::lexer(src);
+    if valid {
+        lex.bump(start);
+    } else {
+        lex.bump(src.len());
+    }
+    let invalid = (!valid).then_some(Err(LexError::unexpected(start..start)));
+    invalid.into_iter().chain(spanned(lex))
+}
+
+/// Lexes `src`, leaving out trivia: the tokens the parser
+/// looks past, such as comments. Spans are unchanged.
+pub fn significant_tokens(src: &str) -> impl Iterator<Item = Result<SpannedToken, LexError>> + '_ {
+    spanned(Token::lexer(src)).filter(|result| {
+        !result
+            .as_ref()
+            .is_ok_and(|spanned| spanned.token.is_trivia())
+    })
+}
+
+/// Splits lexed tokens into comments and the tokens the parser
+/// reads.
+pub fn partition_trivia(tokens: Vec<SpannedToken>) -> (Vec<SpannedToken>, Vec<SpannedToken>) {
+    tokens
+        .into_iter()
+        .partition(|spanned| spanned.token.is_trivia())
+}
+
+// Pairs each token with its span.
+fn spanned<'src>(
+    mut lex: logos::Lexer<'src, Token>,
+) -> impl Iterator<Item = Result<SpannedToken, LexError>> + 'src {
+    std::iter::from_fn(move || {
+        let result = lex.next()?;
+        let span = lex.span();
+        let src = lex.source();
+
+        // Logos only lexes ASCII identifiers, stopping at the first
+        // other character or failing on it, so a Unicode identifier
+        // is taken whole here. A keyword it starts with is part of it.
+        if lex.extras.unicode_identifiers {
+            let end = unicode_identifier_end(src, span.start);
+            if let Some(end) = end.filter(|&end| end > span.end || result.is_err()) {
+                let extras = std::mem::take(&mut lex.extras);
+                lex = Token::lexer_with_extras(src, extras);
+                lex.bump(end);
+                return Some(Ok(SpannedToken {
+                    token: Token::Identifier,
+                    span: span.start..end,
+                }));
+            }
+        }
+
+        Some(match result {
+            // Without lookahead `1..2` lexes as `1.` `.2`, and
+            // `5.toString` as `5.` `toString`. A number ending in a
+            // dot that another dot or a name follows gives its dot
+            // back, and lexing resumes at the operator or the call.
+            Ok(Token::Number)
+                if src[span.clone()].ends_with('.')
+                    && src[span.end..].starts_with(|c: char| {
+                        c == '.' || c == '_' || unicode_ident::is_xid_start(c)
+                    }) =>
+            {
+                let extras = std::mem::take(&mut lex.extras);
+                lex = Token::lexer_with_extras(src, extras);
+                lex.bump(span.end - 1);
+                Ok(SpannedToken {
+                    token: Token::Number,
+                    span: span.start..span.end - 1,
+                })
+            }
+            Ok(token) => Ok(SpannedToken { token, span }),
+            Err(kind) => Err(LexError { span, kind }),
+        })
+    })
+}
+
+/// The name the identifier `text` stands for: `text` in Unicode
+/// normalization form C.
+pub fn identifier_name(text: &str) -> String {
+    if text.is_ascii() {
+        text.to_string()
+    } else {
+        text.nfc().collect()
+    }
+}
+
+// The end of the identifier at `start`, if one starts there.
+fn unicode_identifier_end(src: &str, start: usize) -> Option<usize> {
+    let rest = &src[start..];
+    let first = rest.chars().next()?;
+    if first != '_' && !unicode_ident::is_xid_start(first) {
+        return None;
+    }
+    let len = rest
+        .char_indices()
+        .skip(1)
+        .find(|(_, c)| !unicode_ident::is_xid_continue(*c))
+        .map_or(rest.len(), |(i, _)| i);
+    Some(start + len)
+}
+
+/*
+
+    Precedence
+
+    Binding powers from the Wren table linked at
+    the bottom of this file. Larger numbers bind
+    tighter, and every binary operator is left
+    associative.
+
+*/
+pub mod precedence {
+    pub const LOWEST: u8 = 1;
+    pub const LOGICAL_OR: u8 = 1;
+    pub const LOGICAL_AND: u8 = 2;
+    pub const EQUALITY: u8 = 3;
+    pub const IS: u8 = 4;
+    pub const COMPARISON: u8 = 5;
+    pub const BITWISE_OR: u8 = 6;
+    pub const BITWISE_XOR: u8 = 7;
+    pub const BITWISE_AND: u8 = 8;
+    pub const SHIFT: u8 = 9;
+    pub const RANGE: u8 = 10;
+    pub const TERM: u8 = 11;
+    pub const FACTOR: u8 = 12;
+    pub const UNARY: u8 = 13;
+    pub const CALL: u8 = 14;
+}
+
+/// Whether a token is one of the named variants, whatever
+/// it carries: `token_is!(token, Number)` or, for the next
+/// token of a parser, `token_is!(self.peek(), Some(Comma | Dot))`.
+macro_rules! token_is {
+    ($token:expr, Some($($variant:ident)|+)) => {
+        matches!($token, Some($($crate::compiler::lexer::Token::$variant { .. })|+))
+    };
+    ($token:expr, $($variant:ident)|+) => {
+        matches!($token, $($crate::compiler::lexer::Token::$variant { .. })|+)
+    };
+}
+pub(crate) use token_is;
+
+impl Token {
+    /// Whether the parser skips this token. Comments are
+    /// trivia; newlines are not, since they end statements.
+    pub fn is_trivia(&self) -> bool {
+        matches!(self, Token::LineComment | Token::BlockComment)
+    }
+
+    /// Whether a class can define this operator as a method.
+    /// `&&`, `||`, `is` and `in` are not method calls.
+    pub fn is_operator_method(&self) -> bool {
+        match self {
+            Token::Negate => true,
+            Token::LogicalAnd | Token::LogicalOr | Token::Is | Token::In => false,
+            token => token.infix_precedence().is_some(),
+        }
+    }
+
+    /// Binding power of this token as a binary operator, if it is one.
+    ///
+    /// `In` is reported as the membership operator; the parser
+    /// handles its role as the for-loop keyword separately.
+    pub fn infix_precedence(&self) -> Option<u8> {
+        use precedence::*;
+
+        let precedence = match self {
+            Token::LogicalOr => LOGICAL_OR,
+            Token::LogicalAnd => LOGICAL_AND,
+            Token::Equals | Token::NotEqual => EQUALITY,
+            Token::Is | Token::In => IS,
+            Token::LesserThan
+            | Token::LesserThanEqualTo
+            | Token::GreaterThan
+            | Token::GreaterThanEqualTo => COMPARISON,
+            Token::BitwiseOr => BITWISE_OR,
+            Token::BitwiseXOR => BITWISE_XOR,
+            Token::BitwiseAnd => BITWISE_AND,
+            Token::BitwiseLeftShift | Token::BitwiseRightShift => SHIFT,
+            Token::InclusiveRange | Token::ExclusiveRange => RANGE,
+            Token::Plus | Token::Minus => TERM,
+            Token::Star | Token::Slash | Token::Percent => FACTOR,
+            _ => return None,
+        };
+        Some(precedence)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    // The tokens of `src` other than newlines.
+    fn significant(src: &str) -> Vec<Token> {
+        let mut tokens = tokens_of(src);
+        tokens.retain(|token| token != &Token::Newline);
+        tokens
+    }
+
+    #[test]
+    fn token_is_ignores_payloads() {
+        assert!(token_is!(Token::Number, Number));
+        assert!(!token_is!(Token::Plus, Number));
+        assert!(token_is!(&Token::Dot, Comma | Dot));
+        assert!(token_is!(Some(&Token::Comma), Some(Comma)));
+        assert!(!token_is!(None::<&Token>, Some(Comma)));
+    }
+
+    #[test]
+    fn tokens_of_kinds() {
+        assert_eq!(
+            tokens_of("1 + 2"),
+            [Token::Number, Token::Plus, Token::Number]
+        );
+        assert_eq!(try_tokens_of("1 @"), Err(LexError::unexpected(2..3)));
+    }
+
+    #[test]
+    fn lex_events_are_flat() {
+        let event = |start, end, kind: &str| LexEvent {
+            start,
+            end,
+            kind: kind.to_string(),
+        };
+
+        assert_eq!(
+            lex_events("x = 1 // one\n@"),
+            [
+                event(0, 1, "Identifier"),
+                event(2, 3, "Assignment"),
+                event(4, 5, "Number"),
+                event(6, 12, "LineComment"),
+                event(12, 13, "Newline"),
+                event(13, 14, "Error"),
+            ]
+        );
+    }
+
     #[test]
     fn identifiers() {
-        for result in super::Token::lexer(
+        for token in significant(
             "hi
         camelCase
         PascalCase
@@ -300,13 +986,13 @@ mod test {
         A1234
         ",
         ) {
-            assert_eq!(result, Ok(Token::Identifier));
+            assert_eq!(token, Token::Identifier);
         }
     }
 
     #[test]
     fn numbers() {
-        for result in super::Token::lexer(
+        for token in significant(
             "123
         456
         123434
@@ -319,27 +1005,30 @@ mod test {
         +.1
         +2.1
         -.5
+        1_000
+        1_000.000_5
         ",
         ) {
-            assert_eq!(result, Ok(Token::Number));
+            assert_eq!(token, Token::Number);
         }
     }
 
     #[test]
     fn hexadecimal() {
-        for result in super::Token::lexer(
+        for token in significant(
             "0x123
         0XABFE
         0x60CD
+        0xFF_FF
         ",
         ) {
-            assert_eq!(result, Ok(Token::Hexadecimal));
+            assert_eq!(token, Token::Hexadecimal);
         }
     }
 
     #[test]
     fn various() {
-        let mut lex = super::Token::lexer(
+        let tokens = significant(
             "
             ok
             Nuh_uh
@@ -361,59 +1050,419 @@ mod test {
         ",
         );
 
-        //ok
-        assert_eq!(lex.next(), Some(Ok(Token::Identifier)));
-        // Nuh_uh
-        assert_eq!(lex.next(), Some(Ok(Token::Identifier)));
-        // 123why
-        assert_eq!(lex.next(), Some(Ok(Token::Number)));
-        assert_eq!(lex.next(), Some(Ok(Token::Identifier)));
-        //JesusNo
-        assert_eq!(lex.next(), Some(Ok(Token::Identifier)));
-        //valid1234
-        assert_eq!(lex.next(), Some(Ok(Token::Identifier)));
-        //_123_WOW
-        assert_eq!(lex.next(), Some(Ok(Token::Identifier)));
-        //LOL123.456LOL
-        assert_eq!(lex.next(), Some(Ok(Token::Identifier)));
-        assert_eq!(lex.next(), Some(Ok(Token::Number)));
-        assert_eq!(lex.next(), Some(Ok(Token::Identifier)));
-        //Why.brother.why
-        assert_eq!(lex.next(), Some(Ok(Token::Identifier)));
-        assert_eq!(lex.next(), Some(Ok(Token::Dot)));
-        assert_eq!(lex.next(), Some(Ok(Token::Identifier)));
-        assert_eq!(lex.next(), Some(Ok(Token::Dot)));
-        assert_eq!(lex.next(), Some(Ok(Token::Identifier)));
-        // wtf.123
-        assert_eq!(lex.next(), Some(Ok(Token::Identifier)));
-        assert_eq!(lex.next(), Some(Ok(Token::Number)));
-        // NAHHH+
-        assert_eq!(lex.next(), Some(Ok(Token::Identifier)));
-        assert_eq!(lex.next(), Some(Ok(Token::Plus)));
-        // NAHHHHH.+
-        assert_eq!(lex.next(), Some(Ok(Token::Identifier)));
-        assert_eq!(lex.next(), Some(Ok(Token::Dot)));
-        assert_eq!(lex.next(), Some(Ok(Token::Plus)));
-        // +.+
-        assert_eq!(lex.next(), Some(Ok(Token::Plus)));
-        assert_eq!(lex.next(), Some(Ok(Token::Dot)));
-        assert_eq!(lex.next(), Some(Ok(Token::Plus)));
-        // :)
-        assert_eq!(lex.next(), Some(Ok(Token::Colon)));
-        assert_eq!(lex.next(), Some(Ok(Token::CloseParenthesis)));
-        // class something {stopIamTiredOfWritingTests}
-        assert_eq!(lex.next(), Some(Ok(Token::Class)));
-        assert_eq!(lex.next(), Some(Ok(Token::Identifier)));
-        assert_eq!(lex.next(), Some(Ok(Token::OpenBrace)));
+        assert_eq!(
+            tokens,
+            [
+                // ok
+                Token::Identifier,
+                // Nuh_uh
+                Token::Identifier,
+                // 123why
+                Token::Number,
+                Token::Identifier,
+                // JesusNo
+                Token::Identifier,
+                // valid1234
+                Token::Identifier,
+                // _123_WOW
+                Token::Identifier,
+                // LOL123.456LOL
+                Token::Identifier,
+                Token::Number,
+                Token::Identifier,
+                // Why.brother.why
+                Token::Identifier,
+                Token::Dot,
+                Token::Identifier,
+                Token::Dot,
+                Token::Identifier,
+                // wtf.123
+                Token::Identifier,
+                Token::Number,
+                // NAHHH+
+                Token::Identifier,
+                Token::Plus,
+                // NAHHHHH.+
+                Token::Identifier,
+                Token::Dot,
+                Token::Plus,
+                // +.+
+                Token::Plus,
+                Token::Dot,
+                Token::Plus,
+                // :)
+                Token::Colon,
+                Token::CloseParenthesis,
+                // class something {stopIamTiredOfWritingTests}
+                Token::Class,
+                Token::Identifier,
+                Token::OpenBrace,
+                Token::Identifier,
+                Token::CloseBrace,
+                // 0x1234
+                Token::Hexadecimal,
+                // 0x_1234
+                Token::Number,
+                Token::Identifier,
+                // _0x_AB
+                Token::Identifier,
+            ]
+        );
+    }
+
+    #[test]
+    fn infix_precedence() {
+        let star = Token::Star.infix_precedence().unwrap();
+        let plus = Token::Plus.infix_precedence().unwrap();
+        let less = Token::LesserThan.infix_precedence().unwrap();
+        let equals = Token::Equals.infix_precedence().unwrap();
+        let or = Token::LogicalOr.infix_precedence().unwrap();
+
+        assert!(star > plus);
+        assert!(plus > less);
+        assert!(less > equals);
+        assert!(equals > or);
+        assert_eq!(Token::Identifier.infix_precedence(), None);
+        assert_eq!(Token::OpenParenthesis.infix_precedence(), None);
+    }
+
+    #[test]
+    fn line_directive() {
+        let src = "a\n#line 100 \"foo.wren\"\nb\nc";
+        let mut lex = Token::lexer_with_extras(src, LexerExtras::with_line_directives());
+
+        let mut tokens = Vec::new();
+        while let Some(token) = lex.next() {
+            tokens.push((token, lex.span()));
+        }
+        assert_eq!(
+            tokens,
+            vec![
+                (Ok(Token::Identifier), 0..1),
+                (Ok(Token::Newline), 1..2),
+                (Ok(Token::Identifier), 23..24),
+                (Ok(Token::Newline), 24..25),
+                (Ok(Token::Identifier), 25..26),
+            ]
+        );
+
+        assert_eq!(lex.extras.location(src, 0), Ok((None, 1)));
+        assert_eq!(lex.extras.location(src, 23), Ok((Some("foo.wren"), 100)));
+        assert_eq!(lex.extras.location(src, 25), Ok((Some("foo.wren"), 101)));
+    }
+
+    #[test]
+    fn line_directive_without_file_keeps_previous_file() {
+        let src = "#line 10 \"a.wren\"\nx\n#line 50\ny";
+        let mut lex = Token::lexer_with_extras(src, LexerExtras::with_line_directives());
+        while lex.next().is_some() {}
+
+        let y = src.rfind('y').unwrap();
+        assert_eq!(lex.extras.location(src, y), Ok((Some("a.wren"), 50)));
+    }
+
+    #[test]
+    fn histogram_by_category() {
+        let src = "// counts\nvar a = b.c(1, \"s\") /* a /* b */ c */\nif (a) a = !true";
+        let histogram = token_histogram(src);
+        let count = |category| histogram.get(&category).copied().unwrap_or(0);
+
+        assert_eq!(count(TokenCategory::Comment), 2);
+        assert_eq!(count(TokenCategory::Newline), 2);
+        assert_eq!(count(TokenCategory::Keyword), 2);
+        assert_eq!(count(TokenCategory::Identifier), 5);
+        assert_eq!(count(TokenCategory::Literal), 3);
+        assert_eq!(count(TokenCategory::Operator), 3);
+        assert_eq!(count(TokenCategory::Punctuation), 6);
+        assert!(token_histogram("").is_empty());
+    }
+
+    #[test]
+    fn token_text() {
+        let src = "foo.bar";
+        let (tokens, _) = lex_collect_errors(src);
+        let names: Vec<_> = tokens
+            .iter()
+            .filter(|t| t.token == Token::Identifier)
+            .map(|t| t.text(src))
+            .collect();
+
+        assert_eq!(names, ["foo", "bar"]);
+        assert_eq!(tokens[1].text(src), ".");
+        let (tokens, _) = lex_collect_errors("\"é\"");
+        assert_eq!(tokens[0].text("\"é\""), "\"é\"");
+    }
+
+    #[test]
+    fn unicode_identifiers() {
+        let unicode = |src| lex_collect_errors_with(src, LexerExtras::with_unicode_identifiers());
+        let identifier = |span| SpannedToken {
+            token: Token::Identifier,
+            span,
+        };
+
+        assert_eq!(unicode("café"), (vec![identifier(0..5)], vec![]));
+        assert_eq!(
+            unicode("été _π2"),
+            (vec![identifier(0..5), identifier(6..10)], vec![])
+        );
+        // A keyword a Unicode identifier starts with is part of it.
+        assert_eq!(unicode("iné"), (vec![identifier(0..4)], vec![]));
+        let (tokens, errors) = unicode("in é + 1");
+        assert_eq!(tokens[0].token, Token::In);
+        assert_eq!(tokens[1], identifier(3..5));
+        assert!(errors.is_empty());
+        // Symbols are still not identifiers.
+        assert_eq!(unicode("→").1, vec![LexError::unexpected(0..3)]);
+
+        let (tokens, errors) = lex_collect_errors("café");
+        assert_eq!(tokens, vec![identifier(0..3)]);
+        assert_eq!(errors, vec![LexError::unexpected(3..5)]);
+    }
+
+    #[test]
+    fn line_directive_disabled_by_default() {
+        let mut lex = Token::lexer("#line 100\nb");
+
+        assert_eq!(lex.next(), Some(Ok(Token::HashTag)));
         assert_eq!(lex.next(), Some(Ok(Token::Identifier)));
-        assert_eq!(lex.next(), Some(Ok(Token::CloseBrace)));
-        // 0x1234
-        assert_eq!(lex.next(), Some(Ok(Token::Hexadecimal)));
-        // 0x_1234
         assert_eq!(lex.next(), Some(Ok(Token::Number)));
+        assert_eq!(lex.next(), Some(Ok(Token::Newline)));
         assert_eq!(lex.next(), Some(Ok(Token::Identifier)));
-        // _0x_AB
+        assert_eq!(lex.extras.location("#line 100\nb", 10), Ok((None, 2)));
+    }
+
+    #[test]
+    fn line_directive_near_u32_max() {
+        let src = "#line 4294967295\nx\ny";
+        let mut lex = Token::lexer_with_extras(src, LexerExtras::with_line_directives());
+        while lex.next().is_some() {}
+
+        let x = src.find('x').unwrap();
+        let y = src.find('y').unwrap();
+        assert_eq!(lex.extras.location(src, x), Ok((None, u32::MAX)));
+        assert_eq!(lex.extras.location(src, y), Err(PositionError::Overflow));
+        assert_eq!(
+            lex.extras.location(src, src.len() + 1),
+            Err(PositionError::OutOfBounds)
+        );
+    }
+
+    #[test]
+    fn line_directive_must_start_a_line() {
+        let mut lex = Token::lexer_with_extras("a #line 100", LexerExtras::with_line_directives());
+
         assert_eq!(lex.next(), Some(Ok(Token::Identifier)));
+        assert_eq!(lex.next(), Some(Ok(Token::HashTag)));
+        assert!(lex.extras.directives.is_empty());
+    }
+
+    #[test]
+    fn strings() {
+        let mut lex = Token::lexer(r#""hello" "with \"escaped\" quotes" "" "unterminated"#);
+
+        assert_eq!(lex.next(), Some(Ok(Token::String)));
+        assert_eq!(lex.slice(), "\"hello\"");
+        assert_eq!(lex.next(), Some(Ok(Token::String)));
+        assert_eq!(lex.slice(), r#""with \"escaped\" quotes""#);
+        assert_eq!(lex.next(), Some(Ok(Token::String)));
+        // An unterminated string is an error.
+        assert_eq!(lex.next(), Some(Err(LexErrorKind::UnexpectedCharacter)));
+    }
+
+    #[test]
+    fn number_before_range() {
+        let tokens = |src| {
+            let (tokens, errors) = lex_collect_errors(src);
+            assert!(errors.is_empty());
+            tokens
+                .into_iter()
+                .map(|t| (t.token, t.span))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            tokens("0..5"),
+            [
+                (Token::Number, 0..1),
+                (Token::InclusiveRange, 1..3),
+                (Token::Number, 3..4)
+            ]
+        );
+        assert_eq!(
+            tokens("10...n"),
+            [
+                (Token::Number, 0..2),
+                (Token::ExclusiveRange, 2..5),
+                (Token::Identifier, 5..6)
+            ]
+        );
+        assert_eq!(
+            tokens("1.5..2"),
+            [
+                (Token::Number, 0..3),
+                (Token::InclusiveRange, 3..5),
+                (Token::Number, 5..6)
+            ]
+        );
+    }
+
+    #[test]
+    fn number_before_call() {
+        assert_eq!(
+            tokens_of("5.toString"),
+            [Token::Number, Token::Dot, Token::Identifier]
+        );
+        assert_eq!(
+            tokens_of("5._x"),
+            [Token::Number, Token::Dot, Token::Identifier]
+        );
+        assert_eq!(
+            tokens_of("1.5.floor"),
+            [Token::Number, Token::Dot, Token::Identifier]
+        );
+        // A trailing dot before anything else is still a float.
+        assert_eq!(
+            tokens_of("5. + 1"),
+            [Token::Number, Token::Plus, Token::Number]
+        );
+    }
+
+    #[test]
+    fn lex_from_offset() {
+        let tokens: Vec<_> = lex_from("var x = 1", 4).collect();
+
+        assert_eq!(
+            tokens,
+            [
+                Ok(SpannedToken {
+                    token: Token::Identifier,
+                    span: 4..5
+                }),
+                Ok(SpannedToken {
+                    token: Token::Assignment,
+                    span: 6..7
+                }),
+                Ok(SpannedToken {
+                    token: Token::Number,
+                    span: 8..9
+                }),
+            ]
+        );
+        assert_eq!(lex_from("var x = 1", 9).count(), 0);
+    }
+
+    #[test]
+    fn lex_from_invalid_offset() {
+        assert_eq!(
+            lex_from("é + 1", 1).collect::<Vec<_>>(),
+            [Err(LexError::unexpected(1..1))]
+        );
+        assert_eq!(
+            lex_from("a", 5).collect::<Vec<_>>(),
+            [Err(LexError::unexpected(5..5))]
+        );
+    }
+
+    #[test]
+    fn block_comments_are_one_token() {
+        assert_eq!(
+            tokens_of("/* see http://x.com */ a\nb"),
+            [
+                Token::BlockComment,
+                Token::Identifier,
+                Token::Newline,
+                Token::Identifier
+            ]
+        );
+        // What a comment holds does not have to lex.
+        assert_eq!(
+            tokens_of("/* café a@b \" /* ' */ */ a"),
+            [Token::BlockComment, Token::Identifier]
+        );
+
+        let (tokens, errors) = lex_collect_errors("a /* b /* c */");
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(
+            errors,
+            [LexError {
+                span: 2..14,
+                kind: LexErrorKind::UnterminatedComment,
+            }]
+        );
+        assert_eq!(errors[0].to_string(), "unterminated block comment at 2..14");
+
+        // A `*/` outside a comment is just a multiplication and
+        // a division.
+        assert_eq!(tokens_of("*/"), [Token::Star, Token::Slash]);
+    }
+
+    #[test]
+    fn significant_tokens_skip_comments() {
+        let src = "a // one\n// two\nb /* x /* don't */ y */ + c";
+        let tokens: Vec<_> = significant_tokens(src).collect();
+
+        assert_eq!(
+            tokens,
+            [
+                Ok(SpannedToken {
+                    token: Token::Identifier,
+                    span: 0..1
+                }),
+                Ok(SpannedToken {
+                    token: Token::Newline,
+                    span: 8..9
+                }),
+                Ok(SpannedToken {
+                    token: Token::Newline,
+                    span: 15..16
+                }),
+                Ok(SpannedToken {
+                    token: Token::Identifier,
+                    span: 16..17
+                }),
+                Ok(SpannedToken {
+                    token: Token::Plus,
+                    span: 40..41
+                }),
+                Ok(SpannedToken {
+                    token: Token::Identifier,
+                    span: 42..43
+                }),
+            ]
+        );
+
+        let (comments, tokens) = partition_trivia(lex_collect_errors(src).0);
+        let spans: Vec<_> = comments.iter().map(|c| c.span.clone()).collect();
+        assert_eq!(spans, [2..8, 9..15, 18..39]);
+        assert_eq!(tokens.len(), 6);
+    }
+
+    #[test]
+    fn collect_errors() {
+        let (tokens, errors) = lex_collect_errors("a @ b $ c");
+
+        assert_eq!(
+            tokens,
+            vec![
+                SpannedToken {
+                    token: Token::Identifier,
+                    span: 0..1
+                },
+                SpannedToken {
+                    token: Token::Identifier,
+                    span: 4..5
+                },
+                SpannedToken {
+                    token: Token::Identifier,
+                    span: 8..9
+                },
+            ]
+        );
+        assert_eq!(
+            errors,
+            vec![LexError::unexpected(2..3), LexError::unexpected(6..7)]
+        );
     }
 }
 
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+
+/*
+ *
+ * Module Loaders
+ *
+ * An import names its module with a string, and
+ * what that string refers to is up to the host.
+ * A loader turns it into source code: the file
+ * system loader reads `<root>/<path>.wren`, and
+ * the map loader looks it up in memory, which
+ * suits tests and embedders.
+ *
+ */
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    NotFound(String),
+    Io { path: String, kind: io::ErrorKind },
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::NotFound(path) => write!(f, "could not find module '{path}'"),
+            LoadError::Io { path, kind } => {
+                write!(f, "could not read module '{path}': {kind}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+pub trait ModuleLoader {
+    /// The source of the module imported as `path`.
+    fn load(&self, path: &str) -> Result<String, LoadError>;
+}
+
+pub struct FileSystemLoader {
+    root: PathBuf,
+}
+
+impl FileSystemLoader {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        FileSystemLoader { root: root.into() }
+    }
+}
+
+impl ModuleLoader for FileSystemLoader {
+    fn load(&self, path: &str) -> Result<String, LoadError> {
+        let file = self.root.join(format!("{path}.wren"));
+        std::fs::read_to_string(file).map_err(|error| match error.kind() {
+            io::ErrorKind::NotFound => LoadError::NotFound(path.to_string()),
+            kind => LoadError::Io {
+                path: path.to_string(),
+                kind,
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MapLoader {
+    modules: HashMap<String, String>,
+}
+
+impl MapLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn module(mut self, path: impl Into<String>, src: impl Into<String>) -> Self {
+        self.modules.insert(path.into(), src.into());
+        self
+    }
+}
+
+impl ModuleLoader for MapLoader {
+    fn load(&self, path: &str) -> Result<String, LoadError> {
+        self.modules
+            .get(path)
+            .cloned()
+            .ok_or_else(|| LoadError::NotFound(path.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn file_system_loader() {
+        let root = std::env::temp_dir().join(format!("wren-loader-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("lib")).unwrap();
+        std::fs::write(root.join("lib/shapes.wren"), "var sides = 4").unwrap();
+
+        let loader = FileSystemLoader::new(&root);
+        assert_eq!(loader.load("lib/shapes"), Ok("var sides = 4".to_string()));
+        assert_eq!(
+            loader.load("missing"),
+            Err(LoadError::NotFound("missing".to_string()))
+        );
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use super::codegen::compile;
+use super::lexer::lex_collect_errors;
+
+/*
+ *
+ * Crash Minimizer
+ *
+ * Shrinks an input that makes the compiler panic,
+ * such as one a fuzzer found, to a small program
+ * that still does, so the bug is easier to see.
+ *
+ * Whole lines are removed first, then tokens, each
+ * with the whitespace before it. Runs of units are
+ * tried from half the input down to one unit at a
+ * time, and a removal is kept whenever the input
+ * still fails. The result is minimal in that no
+ * single line or token can be removed.
+ *
+ * Compilation has no known panics, so this is for
+ * the `debug_assert`s inside it, which only fire
+ * in debug builds.
+ *
+ */
+
+/// Shrinks `input` while compiling it still panics. An input that
+/// compiles without panicking is returned unchanged.
+pub fn minimize_crash(input: &str) -> String {
+    minimize_with(input, |src| {
+        catch_unwind(AssertUnwindSafe(|| {
+            let _ = compile(src);
+        }))
+        .is_err()
+    })
+}
+
+/// Shrinks `input` while `fails` holds for it.
+pub fn minimize_with(input: &str, fails: impl Fn(&str) -> bool) -> String {
+    if !fails(input) {
+        return input.to_string();
+    }
+    let lines = input.split_inclusive('\n').map(str::to_string).collect();
+    let src = remove_runs(lines, &fails);
+    remove_runs(tokens(&src), &fails)
+}
+
+// Splits `src` into its tokens, and the text that did not lex,
+// each with the whitespace in front of it.
+fn tokens(src: &str) -> Vec<String> {
+    let (tokens, errors) = lex_collect_errors(src);
+    let mut ends: Vec<usize> = tokens
+        .iter()
+        .map(|token| token.span.end)
+        .chain(errors.iter().map(|error| error.span.end))
+        .collect();
+    ends.sort_unstable();
+    ends.push(src.len());
+
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    for end in ends {
+        if end > start {
+            pieces.push(src[start..end].to_string());
+            start = end;
+        }
+    }
+    pieces
+}
+
+// Removes runs of `units` while what is left still fails,
+// halving the length of the runs tried until single units
+// can no longer be removed.
+fn remove_runs(mut units: Vec<String>, fails: &impl Fn(&str) -> bool) -> String {
+    let mut size = (units.len() / 2).max(1);
+    loop {
+        let mut removed = false;
+        let mut start = 0;
+        while start < units.len() {
+            let end = (start + size).min(units.len());
+            let candidate = [&units[..start], &units[end..]].concat().concat();
+            if fails(&candidate) {
+                units.drain(start..end);
+                removed = true;
+            } else {
+                start = end;
+            }
+        }
+        if !removed {
+            if size == 1 {
+                return units.concat();
+            }
+            size /= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::ast::StmtKind;
+    use crate::compiler::parser::parse_program;
+
+    // Stands in for a compiler bug: panics on any program that
+    // declares a class named `Bad`.
+    fn synthetic_assertion(src: &str) -> bool {
+        catch_unwind(|| {
+            if let Ok(program) = parse_program(src) {
+                assert!(!program.iter().any(
+                    |stmt| matches!(&stmt.kind, StmtKind::Class(class) if class.name == "Bad")
+                ));
+            }
+        })
+        .is_err()
+    }
+
+    #[test]
+    fn shrinks_to_the_failing_part() {
+        let mut lines: Vec<String> = (0..40).map(|i| format!("var a{i} = {i} + 1")).collect();
+        lines.insert(
+            20,
+            "class Bad {\n  construct new() { _x = 1 }\n  x { _x * 2 }\n}".to_string(),
+        );
+        let input = lines.join("\n");
+        assert!(synthetic_assertion(&input));
+
+        let minimized = minimize_with(&input, synthetic_assertion);
+
+        assert_eq!(minimized, "class Bad {}");
+        assert!(synthetic_assertion(&minimized));
+    }
+
+    #[test]
+    fn passing_input_is_unchanged() {
+        let input = "var a = 1\nSystem.print(a)\n";
+
+        assert_eq!(minimize_crash(input), input);
+    }
+}
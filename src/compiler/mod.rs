@@ -1,2 +1,12 @@
-mod lexer;
-mod parser;
+pub mod ast;
+pub mod codegen;
+pub mod desugar;
+pub mod diagnostic;
+pub mod error;
+pub mod fold;
+pub mod lexer;
+pub mod loader;
+pub mod minimize;
+pub mod modules;
+pub mod parser;
+pub mod span;
@@ -0,0 +1,394 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+
+use super::ast::{Stmt, StmtKind};
+use super::codegen::compile;
+use super::error::{CompileError, ResolveError};
+use super::loader::{LoadError, ModuleLoader};
+use super::parser::parse_program;
+use crate::vm::chunk::{Chunk, MAIN_MODULE};
+
+/*
+ *
+ * Module Graph
+ *
+ * Tracks the modules of a project and the imports
+ * between them, recompiling only what changed.
+ *
+ * Each module gets a fingerprint hashing its own
+ * source together with the fingerprints of the
+ * modules it imports. A module is dirty when its
+ * fingerprint differs from the one it was last
+ * compiled with, so editing a module dirties it
+ * and everything that imports it, directly or not.
+ *
+ * Imports of modules that are not in the graph are
+ * left to be resolved at runtime, unless the graph
+ * is filled from a `ModuleLoader` with `load`.
+ * `compile_imports` does that for a script, so a
+ * VM can be given its modules before it runs.
+ *
+ */
+
+pub type ModuleName = String;
+
+struct Module {
+    src: String,
+    src_hash: u64,
+    imports: Vec<(ModuleName, Range<usize>)>,
+    compiled: Option<u64>,
+}
+
+#[derive(Default)]
+pub struct ModuleGraph {
+    modules: BTreeMap<ModuleName, Module>,
+}
+
+impl ModuleGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a module, or replaces the source of an existing one.
+    pub fn add_module(&mut self, name: impl Into<ModuleName>, src: impl Into<String>) {
+        let src = src.into();
+        // Modules that fail to parse report it when compiled.
+        let mut imports = Vec::new();
+        if let Ok(program) = parse_program(&src) {
+            collect_imports(&program, &mut imports);
+        }
+
+        let name = name.into();
+        let compiled = self.modules.get(&name).and_then(|module| module.compiled);
+        self.modules.insert(
+            name,
+            Module {
+                src_hash: hash(&src),
+                src,
+                imports,
+                compiled,
+            },
+        );
+    }
+
+    /// Adds the module `name` from `loader`, together with every
+    /// module it imports, directly or not, that is not in the graph.
+    pub fn load(&mut self, name: &str, loader: &dyn ModuleLoader) -> Result<(), LoadError> {
+        let mut pending = vec![name.to_string()];
+        let mut seen = HashSet::new();
+        while let Some(name) = pending.pop() {
+            if !seen.insert(name.clone()) {
+                continue;
+            }
+            if !self.modules.contains_key(&name) {
+                let src = loader.load(&name)?;
+                self.add_module(name.clone(), src);
+            }
+            pending.extend(
+                self.modules[&name]
+                    .imports
+                    .iter()
+                    .map(|(import, _)| import.clone()),
+            );
+        }
+        Ok(())
+    }
+
+    /// Compiles every module whose source, or the source of anything
+    /// it imports, changed since the last call. Modules come back in
+    /// dependency order, imported modules before their importers.
+    pub fn compile_dirty(&mut self) -> Vec<(ModuleName, Result<Chunk, Vec<CompileError>>)> {
+        let mut fingerprints: HashMap<&str, u64> = HashMap::new();
+        let mut dirty = Vec::new();
+
+        for component in self.components() {
+            let cycle = component.len() > 1
+                || self.modules[component[0]]
+                    .imports
+                    .iter()
+                    .any(|(import, _)| import == component[0]);
+
+            for &name in &component {
+                let module = &self.modules[name];
+                let mut hasher = DefaultHasher::new();
+                module.src_hash.hash(&mut hasher);
+                if !cycle {
+                    for (import, _) in &module.imports {
+                        fingerprints.get(import.as_str()).hash(&mut hasher);
+                    }
+                }
+                let fingerprint = hasher.finish();
+                fingerprints.insert(name, fingerprint);
+
+                if module.compiled == Some(fingerprint) {
+                    continue;
+                }
+                let result = if cycle {
+                    Err(vec![self.cycle_error(name, &component).into()])
+                } else {
+                    compile(&module.src)
+                };
+                dirty.push((name.to_string(), fingerprint, result));
+            }
+        }
+
+        dirty
+            .into_iter()
+            .map(|(name, fingerprint, result)| {
+                if let Some(module) = self.modules.get_mut(&name) {
+                    module.compiled = Some(fingerprint);
+                }
+                (name, result)
+            })
+            .collect()
+    }
+
+    /// The source of the module `name`, if it is in the graph.
+    pub fn source(&self, name: &str) -> Option<&str> {
+        self.modules.get(name).map(|module| module.src.as_str())
+    }
+
+    fn cycle_error(&self, name: &str, component: &[&str]) -> ResolveError {
+        let (import, span) = self.modules[name]
+            .imports
+            .iter()
+            .find(|(import, _)| component.contains(&import.as_str()))
+            .expect("modules in a cycle import each other");
+        ResolveError::new(
+            format!("import cycle: '{name}' imports '{import}', which imports it back"),
+            span.clone(),
+        )
+    }
+
+    // Strongly connected components of the import graph (Tarjan),
+    // ordered so that every component comes after those it imports.
+    fn components(&self) -> Vec<Vec<&str>> {
+        struct State<'a> {
+            index: HashMap<&'a str, usize>,
+            lowlink: HashMap<&'a str, usize>,
+            stack: Vec<&'a str>,
+            components: Vec<Vec<&'a str>>,
+        }
+
+        fn visit<'a>(graph: &'a ModuleGraph, name: &'a str, state: &mut State<'a>) {
+            let index = state.index.len();
+            state.index.insert(name, index);
+            state.lowlink.insert(name, index);
+            state.stack.push(name);
+
+            for (import, _) in &graph.modules[name].imports {
+                let Some((import, _)) = graph.modules.get_key_value(import) else {
+                    continue;
+                };
+                if !state.index.contains_key(import.as_str()) {
+                    visit(graph, import, state);
+                    let low = state.lowlink[name].min(state.lowlink[import.as_str()]);
+                    state.lowlink.insert(name, low);
+                } else if state.stack.contains(&import.as_str()) {
+                    let low = state.lowlink[name].min(state.index[import.as_str()]);
+                    state.lowlink.insert(name, low);
+                }
+            }
+
+            if state.lowlink[name] == state.index[name] {
+                let start = state.stack.iter().rposition(|&n| n == name).unwrap();
+                let mut component = state.stack.split_off(start);
+                component.sort_unstable();
+                state.components.push(component);
+            }
+        }
+
+        let mut state = State {
+            index: HashMap::new(),
+            lowlink: HashMap::new(),
+            stack: Vec::new(),
+            components: Vec::new(),
+        };
+        for name in self.modules.keys() {
+            if !state.index.contains_key(name.as_str()) {
+                visit(self, name, &mut state);
+            }
+        }
+        state.components
+    }
+}
+
+/// Why the modules a script imports could not be compiled.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImportError {
+    Load(LoadError),
+    /// The module `module`, whose source is `src`, does not compile.
+    Compile {
+        module: ModuleName,
+        src: String,
+        errors: Vec<CompileError>,
+    },
+}
+
+/// Compiles every module that the script `src` imports, directly or
+/// not, reading each from `loader`. The modules come back ready for
+/// `Vm::add_module`, imported modules before their importers; the
+/// script itself is left to the caller.
+pub fn compile_imports(
+    src: &str,
+    loader: &dyn ModuleLoader,
+) -> Result<Vec<(ModuleName, Chunk)>, ImportError> {
+    let mut graph = ModuleGraph::new();
+    graph.add_module(MAIN_MODULE, src);
+    graph.load(MAIN_MODULE, loader).map_err(ImportError::Load)?;
+
+    let mut modules = Vec::new();
+    for (name, result) in graph.compile_dirty() {
+        if name == MAIN_MODULE {
+            continue;
+        }
+        match result {
+            Ok(chunk) => modules.push((name, chunk)),
+            Err(errors) => {
+                let src = graph.source(&name).unwrap_or_default().to_string();
+                return Err(ImportError::Compile {
+                    module: name,
+                    src,
+                    errors,
+                });
+            }
+        }
+    }
+    Ok(modules)
+}
+
+fn collect_imports(statements: &[Stmt], imports: &mut Vec<(ModuleName, Range<usize>)>) {
+    for stmt in statements {
+        match &stmt.kind {
+            StmtKind::Import { module, .. } => imports.push((module.clone(), stmt.span.clone())),
+            StmtKind::Block(body) => collect_imports(body, imports),
+            StmtKind::For { body, .. } | StmtKind::While { body, .. } => {
+                collect_imports(std::slice::from_ref(body), imports)
+            }
+            StmtKind::If {
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                collect_imports(std::slice::from_ref(then_branch), imports);
+                if let Some(branch) = else_branch {
+                    collect_imports(std::slice::from_ref(branch), imports);
+                }
+            }
+            StmtKind::Class(class) => {
+                for member in &class.members {
+                    collect_imports(&member.body, imports);
+                }
+            }
+            StmtKind::Expression(_)
+            | StmtKind::Var { .. }
+            | StmtKind::Return(_)
+            | StmtKind::Break
+            | StmtKind::Continue => {}
+        }
+    }
+}
+
+fn hash(src: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    src.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::loader::MapLoader;
+
+    fn compiled(graph: &mut ModuleGraph) -> Vec<ModuleName> {
+        graph
+            .compile_dirty()
+            .into_iter()
+            .map(|(name, result)| {
+                assert!(result.is_ok(), "{name} failed to compile");
+                name
+            })
+            .collect()
+    }
+
+    #[test]
+    fn recompiles_only_dependents() {
+        let mut graph = ModuleGraph::new();
+        graph.add_module("app", r#"import "shapes" for sides var n = sides"#);
+        graph.add_module("shapes", "var sides = 4");
+        assert_eq!(compiled(&mut graph), ["shapes", "app"]);
+        assert!(compiled(&mut graph).is_empty());
+
+        graph.add_module("shapes", "var sides = 3");
+        assert_eq!(compiled(&mut graph), ["shapes", "app"]);
+
+        graph.add_module("app", r#"import "shapes" for sides var n = sides * 2"#);
+        assert_eq!(compiled(&mut graph), ["app"]);
+
+        // Re-adding identical source changes nothing.
+        graph.add_module("shapes", "var sides = 3");
+        assert!(compiled(&mut graph).is_empty());
+    }
+
+    #[test]
+    fn load_follows_imports() {
+        let loader = MapLoader::new()
+            .module("app", r#"import "shapes" for sides var n = sides"#)
+            .module("shapes", r#"import "util" var sides = 4"#)
+            .module("util", "var unused = 0");
+
+        let mut graph = ModuleGraph::new();
+        graph.load("app", &loader).unwrap();
+        assert_eq!(compiled(&mut graph), ["util", "shapes", "app"]);
+
+        let loader = MapLoader::new().module("app", r#"import "missing""#);
+        assert_eq!(
+            ModuleGraph::new().load("app", &loader),
+            Err(LoadError::NotFound("missing".to_string()))
+        );
+    }
+
+    #[test]
+    fn compile_imports_from_a_loader() {
+        let loader = MapLoader::new()
+            .module("shapes", r#"import "util" for four var sides = four"#)
+            .module("util", "var four = 4");
+        let src = r#"import "shapes" for sides System.print(sides)"#;
+
+        let modules = compile_imports(src, &loader).unwrap();
+        let names: Vec<_> = modules.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["util", "shapes"]);
+
+        let loader = MapLoader::new().module("shapes", "var sides = four");
+        let Err(ImportError::Compile { module, errors, .. }) = compile_imports(src, &loader) else {
+            panic!("expected a compile error");
+        };
+        assert_eq!(module, "shapes");
+        assert_eq!(errors[0].span(), 12..16);
+
+        assert_eq!(
+            compile_imports(src, &MapLoader::new()).err(),
+            Some(ImportError::Load(LoadError::NotFound("shapes".to_string())))
+        );
+    }
+
+    #[test]
+    fn import_cycle() {
+        let mut graph = ModuleGraph::new();
+        graph.add_module("a", r#"import "b""#);
+        graph.add_module("b", r#"var x = 1 import "a""#);
+        graph.add_module("c", r#"import "a""#);
+
+        let results = graph.compile_dirty();
+        let names: Vec<_> = results.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["a", "b", "c"]);
+
+        let Err(errors) = &results[1].1 else {
+            panic!("expected a cycle error")
+        };
+        assert_eq!(errors[0].span(), 10..20);
+        assert!(results[2].1.is_ok());
+    }
+}
@@ -1 +1,3357 @@
+use std::ops::Range;
 
+use super::ast::{
+    Attribute, BinaryOp, ClassDecl, ClassMember, Expr, ExprKind, LiteralValue, MemberKind,
+    StaticField, Stmt, StmtKind, UnaryOp,
+};
+use super::diagnostic::Diagnostic;
+use super::error::CompileError;
+use super::lexer::precedence::{CALL, COMPARISON, LOWEST, UNARY};
+use super::lexer::{
+    identifier_name, lex_collect_errors, partition_trivia, token_is, LexError, SpannedToken, Token,
+};
+use super::span::merge;
+
+/*
+ *
+ * Parser
+ *
+ * A recursive descent parser for statements,
+ * with a Pratt parser for expressions.
+ *
+ * Binding powers come from
+ * `Token::infix_precedence`, with larger
+ * numbers binding tighter.
+ *
+ * Nesting is limited to `max_depth` so that
+ * pathological input fails with `TooDeep`
+ * instead of overflowing the stack. Only real
+ * nesting counts: parentheses, unary operators
+ * and right operands, not the length of a flat
+ * chain such as `a + a + a` or `a.b.c`.
+ *
+ * Calls, methods and blocks take at most
+ * `max_arguments` arguments, 16 as in Wren.
+ *
+ */
+
+pub const DEFAULT_MAX_DEPTH: usize = 256;
+
+pub const DEFAULT_MAX_ARGUMENTS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    Lex(LexError),
+
+    UnexpectedToken {
+        expected: &'static str,
+        found: Token,
+        span: Range<usize>,
+    },
+
+    UnexpectedEnd {
+        expected: &'static str,
+        span: Range<usize>,
+    },
+
+    InvalidAssignmentTarget {
+        span: Range<usize>,
+    },
+
+    DuplicateMethod {
+        signature: String,
+        span: Range<usize>,
+        // The member first defined with the signature.
+        previous: Range<usize>,
+    },
+
+    TooDeep {
+        span: Range<usize>,
+    },
+
+    // A `static` or `foreign` that cannot apply to a constructor:
+    // every constructor makes an instance, and a foreign one is
+    // implemented by the host, so has no body.
+    InvalidConstructor {
+        modifier: Token,
+        span: Range<usize>,
+    },
+
+    // The first argument or parameter past the limit.
+    TooManyArguments {
+        span: Range<usize>,
+    },
+
+    // A backslash followed by a character that is not an escape,
+    // under `EscapePolicy::Strict`, or a `\x`, `\u` or `\U` escape
+    // without enough hex digits.
+    InvalidEscape {
+        span: Range<usize>,
+    },
+
+    // A keyword that begins a statement, such as `break`, where
+    // an expression was expected, as in `var x = break`.
+    NotAnExpression {
+        token: Token,
+        span: Range<usize>,
+    },
+}
+
+impl ParseError {
+    pub fn span(&self) -> Range<usize> {
+        match self {
+            ParseError::Lex(error) => error.span.clone(),
+            ParseError::UnexpectedToken { span, .. }
+            | ParseError::UnexpectedEnd { span, .. }
+            | ParseError::InvalidAssignmentTarget { span }
+            | ParseError::TooDeep { span }
+            | ParseError::InvalidConstructor { span, .. }
+            | ParseError::TooManyArguments { span }
+            | ParseError::NotAnExpression { span, .. }
+            | ParseError::InvalidEscape { span }
+            | ParseError::DuplicateMethod { span, .. } => span.clone(),
+        }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Lex(error) => write!(f, "{error}"),
+            ParseError::UnexpectedToken {
+                expected, found, ..
+            } => write!(f, "expected {expected}, found {found:?}"),
+            ParseError::UnexpectedEnd { expected, .. } => {
+                write!(f, "expected {expected}, found end of input")
+            }
+            ParseError::InvalidAssignmentTarget { .. } => {
+                write!(f, "invalid assignment target")
+            }
+            ParseError::DuplicateMethod { signature, .. } => {
+                write!(f, "class already defines a method '{signature}'")
+            }
+            ParseError::TooDeep { .. } => write!(f, "code is nested too deeply"),
+            ParseError::InvalidConstructor { modifier, .. } => match modifier {
+                Token::Static => write!(f, "constructors cannot be static"),
+                _ => write!(f, "foreign constructors cannot have a body"),
+            },
+            ParseError::TooManyArguments { .. } => write!(f, "too many arguments"),
+            ParseError::InvalidEscape { .. } => write!(f, "invalid escape in string"),
+            ParseError::NotAnExpression { token, .. } => {
+                let keyword = match token {
+                    Token::Break => "break",
+                    Token::Continue => "continue",
+                    Token::Return => "return",
+                    Token::If => "if",
+                    Token::While => "while",
+                    Token::For => "for",
+                    _ => "this keyword",
+                };
+                write!(f, "'{keyword}' is a statement, not an expression")
+            }
+        }
+    }
+}
+
+type ParseResult<T> = Result<T, ParseError>;
+
+/// What a backslash before a character that is not an escape
+/// means in a string literal.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EscapePolicy {
+    /// It is an error, as in Wren.
+    #[default]
+    Strict,
+    /// The character stands for itself, so `"\q"` is `"q"`.
+    Lenient,
+}
+
+/// What `Parser::parse` does on a syntax error.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ParserMode {
+    /// Stop at the first error, as a build should.
+    #[default]
+    Strict,
+    /// Skip to the next statement and keep parsing, so an
+    /// editor can report every error at once.
+    Recover,
+}
+
+/// A position in the token stream to return to with `Parser::rewind`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    current: usize,
+    groupings: usize,
+    depth: usize,
+    diagnostics: usize,
+}
+
+pub struct Parser<'src> {
+    src: &'src str,
+    tokens: Vec<SpannedToken>,
+    // The trivia `significant_tokens` would leave out, kept
+    // apart from the tokens parsed. Comments document the
+    // declarations below them.
+    comments: Vec<SpannedToken>,
+    current: usize,
+    lints: bool,
+    mode: ParserMode,
+    escapes: EscapePolicy,
+    diagnostics: Vec<Diagnostic>,
+    depth: usize,
+    max_depth: usize,
+    max_arguments: usize,
+    // How many parentheses, brackets and map literals enclose the
+    // current token. Newlines only separate statements at zero.
+    groupings: usize,
+}
+
+/// Lexes and parses a whole program, stopping at the first error.
+pub fn parse_program(src: &str) -> ParseResult<Vec<Stmt>> {
+    Parser::new(src)?.program()
+}
+
+/// Lexes and parses a whole program in `mode`. Strict parsing
+/// returns only the first error; recovering returns every error,
+/// lex errors first.
+pub fn parse_program_with(src: &str, mode: ParserMode) -> Result<Vec<Stmt>, Vec<ParseError>> {
+    let (tokens, lex_errors) = lex_collect_errors(src);
+    let mut errors: Vec<_> = lex_errors.into_iter().map(ParseError::Lex).collect();
+    if mode == ParserMode::Strict && !errors.is_empty() {
+        errors.truncate(1);
+        return Err(errors);
+    }
+    match Parser::with_tokens(src, tokens).mode(mode).parse() {
+        Ok(_) if !errors.is_empty() => Err(errors),
+        Ok(program) => Ok(program),
+        Err(parse_errors) => {
+            errors.extend(parse_errors);
+            Err(errors)
+        }
+    }
+}
+
+/// Lexes and parses `src` as a single expression, such as a
+/// value in a host's configuration. Tokens left after the
+/// expression, other than newlines, are an error.
+pub fn parse_expr_str(src: &str) -> Result<Expr, Vec<Diagnostic>> {
+    let (tokens, lex_errors) = lex_collect_errors(src);
+    if !lex_errors.is_empty() {
+        return Err(lex_errors
+            .into_iter()
+            .map(|error| CompileError::from(error).diagnostic())
+            .collect());
+    }
+    let mut parser = Parser::with_tokens(src, tokens);
+    parser.skip_newlines();
+    let expr = parser.expression().and_then(|expr| {
+        parser.skip_newlines();
+        match parser.current_token() {
+            Some(spanned) => Err(ParseError::UnexpectedToken {
+                expected: "end of expression",
+                found: spanned.token.clone(),
+                span: spanned.span.clone(),
+            }),
+            None => Ok(expr),
+        }
+    });
+    expr.map_err(|error| vec![CompileError::from(error).diagnostic()])
+}
+
+impl<'src> Parser<'src> {
+    pub fn new(src: &'src str) -> ParseResult<Self> {
+        let (tokens, errors) = lex_collect_errors(src);
+        if let Some(error) = errors.into_iter().next() {
+            return Err(ParseError::Lex(error));
+        }
+        Ok(Self::with_tokens(src, tokens))
+    }
+
+    /// A parser over tokens already lexed from `src`, for callers
+    /// that report lex errors themselves.
+    pub fn with_tokens(src: &'src str, tokens: Vec<SpannedToken>) -> Self {
+        let (comments, tokens) = partition_trivia(tokens);
+        Parser {
+            src,
+            tokens,
+            comments,
+            current: 0,
+            lints: true,
+            mode: ParserMode::Strict,
+            escapes: EscapePolicy::Strict,
+            diagnostics: Vec::new(),
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_arguments: DEFAULT_MAX_ARGUMENTS,
+            groupings: 0,
+        }
+    }
+
+    /// Enables or disables the non-fatal lints. They are on by default.
+    pub fn lints(mut self, enabled: bool) -> Self {
+        self.lints = enabled;
+        self
+    }
+
+    /// Whether `parse` stops at the first error. Strict by default.
+    pub fn mode(mut self, mode: ParserMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// How unknown escapes in strings are treated. Strict by default.
+    pub fn escapes(mut self, policy: EscapePolicy) -> Self {
+        self.escapes = policy;
+        self
+    }
+
+    /// Limits how deeply statements and expressions may nest.
+    pub fn max_depth(mut self, limit: usize) -> Self {
+        self.max_depth = limit;
+        self
+    }
+
+    /// Limits how many arguments a call, method or block may take.
+    pub fn max_arguments(mut self, limit: usize) -> Self {
+        self.max_arguments = limit;
+        self
+    }
+
+    /// Where the parser is now, so it can try one reading of the
+    /// tokens ahead and `rewind` to try another.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            current: self.current,
+            groupings: self.groupings,
+            depth: self.depth,
+            diagnostics: self.diagnostics.len(),
+        }
+    }
+
+    /// Returns to `checkpoint`, dropping any warnings reported since.
+    pub fn rewind(&mut self, checkpoint: Checkpoint) {
+        self.current = checkpoint.current;
+        self.groupings = checkpoint.groupings;
+        self.depth = checkpoint.depth;
+        self.diagnostics.truncate(checkpoint.diagnostics);
+    }
+
+    /// Warnings collected while parsing.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    pub fn program(&mut self) -> ParseResult<Vec<Stmt>> {
+        let mut statements = Vec::new();
+        loop {
+            self.skip_newlines();
+            if self.at_end() {
+                break;
+            }
+            statements.push(self.statement()?);
+        }
+        Ok(statements)
+    }
+
+    /// Parses the whole program as the mode says: stopping at the
+    /// first error, or collecting every error.
+    pub fn parse(&mut self) -> Result<Vec<Stmt>, Vec<ParseError>> {
+        let mut statements = Vec::new();
+        let mut errors = Vec::new();
+        loop {
+            self.skip_newlines();
+            if self.at_end() {
+                break;
+            }
+            match self.statement() {
+                Ok(stmt) => statements.push(stmt),
+                Err(error) => {
+                    errors.push(error);
+                    if self.mode == ParserMode::Strict {
+                        break;
+                    }
+                    self.synchronize();
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(statements)
+        } else {
+            Err(errors)
+        }
+    }
+
+    // Skips to where the next statement probably starts: the end
+    // of the line, or a keyword that only begins statements. The
+    // token that failed is skipped unless it is one of those.
+    fn synchronize(&mut self) {
+        let boundary = |token: &Token| {
+            matches!(
+                token,
+                Token::Newline
+                    | Token::Class
+                    | Token::Var
+                    | Token::Import
+                    | Token::For
+                    | Token::If
+                    | Token::While
+            )
+        };
+        if !self.peek().is_some_and(boundary) {
+            self.advance();
+        }
+        while self.peek().is_some_and(|token| !boundary(token)) {
+            self.advance();
+        }
+    }
+
+    /*
+
+        Statements
+
+    */
+    fn statement(&mut self) -> ParseResult<Stmt> {
+        self.nested(Self::statement_kind)
+    }
+
+    fn statement_kind(&mut self) -> ParseResult<Stmt> {
+        match self.peek() {
+            Some(Token::For) => self.for_statement(),
+            Some(Token::If) => self.if_statement(),
+            Some(Token::While) => self.while_statement(),
+            Some(Token::Var) => self.var_statement(),
+            Some(Token::Class) => self.class_declaration(),
+            Some(Token::Import) => self.import_statement(),
+            Some(Token::Return) => self.return_statement(),
+            Some(Token::Break) => self.jump_statement(Token::Break, StmtKind::Break),
+            Some(Token::Continue) => self.jump_statement(Token::Continue, StmtKind::Continue),
+            Some(Token::OpenBrace) => self.block(),
+            _ => {
+                let expr = self.expression()?;
+                Ok(Stmt {
+                    span: expr.span.clone(),
+                    kind: StmtKind::Expression(expr),
+                })
+            }
+        }
+    }
+
+    fn var_statement(&mut self) -> ParseResult<Stmt> {
+        let keyword = self.expect(Token::Var, "'var'")?;
+        let name = self.expect(Token::Identifier, "variable name")?;
+        let mut span = merge(&keyword, &name);
+
+        let initializer = if token_is!(self.peek(), Some(Assignment)) {
+            self.advance();
+            self.skip_newlines();
+            let value = self.expression()?;
+            span = merge(&span, &value.span);
+            Some(value)
+        } else {
+            None
+        };
+
+        Ok(Stmt {
+            kind: StmtKind::Var {
+                name: self.identifier(name),
+                initializer,
+            },
+            span,
+        })
+    }
+
+    fn import_statement(&mut self) -> ParseResult<Stmt> {
+        let keyword = self.expect(Token::Import, "'import'")?;
+        let module = self.expect(Token::String, "module name string")?;
+        let mut span = merge(&keyword, &module);
+
+        let mut names = Vec::new();
+        if token_is!(self.peek(), Some(For)) {
+            self.advance();
+            loop {
+                let name = self.expect(Token::Identifier, "variable name to import")?;
+                span = merge(&span, &name);
+                let alias = if token_is!(self.peek(), Some(As)) {
+                    self.advance();
+                    let alias = self.expect(Token::Identifier, "alias after 'as'")?;
+                    span = merge(&span, &alias);
+                    Some(self.identifier(alias))
+                } else {
+                    None
+                };
+                names.push((self.identifier(name), alias));
+
+                if !token_is!(self.peek(), Some(Comma)) {
+                    break;
+                }
+                self.advance();
+            }
+        }
+
+        Ok(Stmt {
+            kind: StmtKind::Import {
+                module: self.string(module)?,
+                names,
+            },
+            span,
+        })
+    }
+
+    fn class_declaration(&mut self) -> ParseResult<Stmt> {
+        let keyword = self.expect(Token::Class, "'class'")?;
+        let doc = self.doc_comment(keyword.start);
+        let name = self.expect(Token::Identifier, "class name")?;
+
+        let superclass = if token_is!(self.peek(), Some(Is)) {
+            self.advance();
+            let superclass = self.expect(Token::Identifier, "superclass name")?;
+            Some(self.identifier(superclass))
+        } else {
+            None
+        };
+
+        self.expect(Token::OpenBrace, "'{' before class body")?;
+        let mut members = Vec::new();
+        let mut static_fields = Vec::new();
+        loop {
+            self.skip_newlines();
+            if self.at_end() || token_is!(self.peek(), Some(CloseBrace)) {
+                break;
+            }
+            if self.at_static_field() {
+                static_fields.push(self.static_field()?);
+            } else {
+                members.push(self.class_member()?);
+            }
+        }
+        let close = self.expect(Token::CloseBrace, "'}' after class body")?;
+
+        validate_members(&members)?;
+
+        Ok(Stmt {
+            kind: StmtKind::Class(ClassDecl {
+                name: self.identifier(name),
+                doc,
+                superclass,
+                members,
+                static_fields,
+            }),
+            span: merge(&keyword, &close),
+        })
+    }
+
+    // Whether `static __name =` comes next. A setter's `=` is
+    // followed by `(`, and its name cannot be a static field's.
+    fn at_static_field(&self) -> bool {
+        let mut next = self.tokens[self.position().min(self.tokens.len())..].iter();
+        token_is!(next.next().map(|t| &t.token), Some(Static))
+            && next
+                .next()
+                .is_some_and(|t| t.token == Token::Identifier && t.text(self.src).starts_with("__"))
+            && token_is!(next.next().map(|t| &t.token), Some(Assignment))
+    }
+
+    // `static __name = value`, a static field's initial value.
+    fn static_field(&mut self) -> ParseResult<StaticField> {
+        let keyword = self.expect(Token::Static, "'static'")?;
+        let name = self.expect(Token::Identifier, "static field name")?;
+        self.expect(Token::Assignment, "'=' after static field")?;
+        let value = self.expression()?;
+        Ok(StaticField {
+            name: self.identifier(name),
+            span: merge(&keyword, &value.span),
+            value,
+        })
+    }
+
+    fn class_member(&mut self) -> ParseResult<ClassMember> {
+        let first = self.current_token().map(|t| t.span.clone());
+        let doc = first.as_ref().and_then(|span| self.doc_comment(span.start));
+        let attributes = self.attributes()?;
+
+        // Foreign methods are implemented by the host and have
+        // no body.
+        let foreign_keyword = self.keyword(Token::Foreign);
+        let is_foreign = foreign_keyword.is_some();
+        let static_keyword = self.keyword(Token::Static);
+        let is_static = static_keyword.is_some();
+        let is_constructor = token_is!(self.peek(), Some(Construct));
+        if is_constructor {
+            self.advance();
+            if let Some(span) = static_keyword {
+                return Err(ParseError::InvalidConstructor {
+                    modifier: Token::Static,
+                    span,
+                });
+            }
+        }
+
+        // Operators are methods too: `+(other) { }` defines
+        // the infix `+`, and `- { }` the prefix one.
+        let name = match self.current_token() {
+            Some(spanned) if !is_constructor && spanned.token.is_operator_method() => {
+                let span = spanned.span.clone();
+                self.advance();
+                span
+            }
+            _ => self.expect(Token::Identifier, "method name")?,
+        };
+        let (kind, params) = match self.peek() {
+            _ if is_constructor => (MemberKind::Constructor, self.parameters()?),
+            Some(Token::Assignment) => {
+                self.advance();
+                self.expect(Token::OpenParenthesis, "'(' after '='")?;
+                let param = self.grouped(|p| {
+                    let param = p.expect(Token::Identifier, "setter parameter")?;
+                    p.expect(Token::CloseParenthesis, "')' after setter parameter")?;
+                    Ok(param)
+                })?;
+                (MemberKind::Setter, vec![self.identifier(param)])
+            }
+            Some(Token::OpenParenthesis) => (MemberKind::Method, self.parameters()?),
+            _ => (MemberKind::Getter, Vec::new()),
+        };
+
+        if let Some(span) = foreign_keyword.filter(|_| is_constructor) {
+            if token_is!(self.peek(), Some(OpenBrace)) {
+                return Err(ParseError::InvalidConstructor {
+                    modifier: Token::Foreign,
+                    span,
+                });
+            }
+        }
+        let (statements, expression_body, last) = if is_foreign {
+            (
+                Vec::new(),
+                false,
+                self.tokens[self.current - 1].span.clone(),
+            )
+        } else {
+            self.expect(Token::OpenBrace, "'{'")?;
+            let (statements, close, expression_body) = self.block_body()?;
+            (statements, expression_body, close)
+        };
+
+        Ok(ClassMember {
+            kind,
+            name: self.identifier(name.clone()),
+            params,
+            body: statements,
+            expression_body,
+            is_static,
+            is_foreign,
+            attributes,
+            doc,
+            span: merge(first.as_ref().unwrap_or(&name), &last),
+        })
+    }
+
+    // The attributes before a class member, each on its own line:
+    // `#key`, `#key = value` or `#group(key, key = value)`, with
+    // a `!` after the `#` for those kept at runtime.
+    fn attributes(&mut self) -> ParseResult<Vec<Attribute>> {
+        let mut attributes = Vec::new();
+        while token_is!(self.peek(), Some(HashTag)) {
+            self.advance();
+            let runtime = token_is!(self.peek(), Some(Negate));
+            if runtime {
+                self.advance();
+            }
+            let name = self.expect(Token::Identifier, "attribute name")?;
+            let (value, group) = if token_is!(self.peek(), Some(OpenParenthesis)) {
+                self.advance();
+                let group = self.grouped(|p| {
+                    let mut group = Vec::new();
+                    loop {
+                        let key = p.expect(Token::Identifier, "attribute name")?;
+                        group.push((p.identifier(key), p.attribute_value()?));
+                        if !token_is!(p.peek(), Some(Comma)) {
+                            break;
+                        }
+                        p.advance();
+                    }
+                    p.expect(Token::CloseParenthesis, "')' after attributes")?;
+                    Ok(group)
+                })?;
+                (None, group)
+            } else {
+                (self.attribute_value()?, Vec::new())
+            };
+            attributes.push(Attribute {
+                name: self.identifier(name),
+                value,
+                group,
+                runtime,
+            });
+            self.skip_newlines();
+        }
+        Ok(attributes)
+    }
+
+    // The `= value` after an attribute's name, if there is one.
+    fn attribute_value(&mut self) -> ParseResult<Option<LiteralValue>> {
+        if !token_is!(self.peek(), Some(Assignment)) {
+            return Ok(None);
+        }
+        self.advance();
+        let Some(spanned) = self.current_token().cloned() else {
+            return Err(self.unexpected_end("attribute value"));
+        };
+        let value = match spanned.token {
+            Token::Number | Token::Hexadecimal => self.number_literal(spanned),
+            Token::String => LiteralValue::Str(self.string(spanned.span)?),
+            Token::Identifier => LiteralValue::Str(spanned.text(self.src).to_string()),
+            Token::True => LiteralValue::Bool(true),
+            Token::False => LiteralValue::Bool(false),
+            Token::Null => LiteralValue::Null,
+            found => {
+                return Err(ParseError::UnexpectedToken {
+                    expected: "attribute value",
+                    found,
+                    span: spanned.span,
+                })
+            }
+        };
+        self.advance();
+        Ok(Some(value))
+    }
+
+    fn parameters(&mut self) -> ParseResult<Vec<String>> {
+        self.expect(Token::OpenParenthesis, "'(' before parameters")?;
+        self.grouped(|p| {
+            let mut params = Vec::new();
+            if !token_is!(p.peek(), Some(CloseParenthesis)) {
+                loop {
+                    let param = p.expect(Token::Identifier, "parameter name")?;
+                    p.check_arguments(params.len() + 1, &param)?;
+                    params.push(p.identifier(param));
+                    if !token_is!(p.peek(), Some(Comma)) {
+                        break;
+                    }
+                    p.advance();
+                }
+            }
+            p.expect(Token::CloseParenthesis, "')' after parameters")?;
+            Ok(params)
+        })
+    }
+
+    fn block(&mut self) -> ParseResult<Stmt> {
+        let open = self.expect(Token::OpenBrace, "'{'")?;
+        let (statements, close, _) = self.block_body()?;
+
+        Ok(Stmt {
+            kind: StmtKind::Block(statements),
+            span: merge(&open, &close),
+        })
+    }
+
+    // The statements of a block up to and including its `}`. They
+    // are separated by newlines again, even when the block sits
+    // inside parentheses. Also tells whether the body is a single
+    // expression with no newline after the `{`, which method and
+    // block bodies return.
+    fn block_body(&mut self) -> ParseResult<(Vec<Stmt>, Range<usize>, bool)> {
+        self.with_groupings(0, |p| {
+            let on_one_line = !token_is!(p.peek(), Some(Newline));
+            let mut statements = Vec::new();
+            loop {
+                p.skip_newlines();
+                if p.at_end() || token_is!(p.peek(), Some(CloseBrace)) {
+                    break;
+                }
+                statements.push(p.statement()?);
+            }
+            let close = p.expect(Token::CloseBrace, "'}'")?;
+            let expression_body = on_one_line
+                && matches!(
+                    statements.as_slice(),
+                    [Stmt {
+                        kind: StmtKind::Expression(_),
+                        ..
+                    }]
+                );
+            Ok((statements, close, expression_body))
+        })
+    }
+
+    // The `in` directly after the loop variable is always
+    // the loop keyword. Any `in` inside the sequence
+    // expression is the membership operator.
+    fn for_statement(&mut self) -> ParseResult<Stmt> {
+        let keyword = self.expect(Token::For, "'for'")?;
+        self.expect(Token::OpenParenthesis, "'(' after 'for'")?;
+        let (name, sequence) = self.grouped(|p| {
+            let name = p.expect(Token::Identifier, "loop variable name")?;
+            p.expect(Token::In, "'in' after loop variable")?;
+            let sequence = p.expression()?;
+            p.expect(Token::CloseParenthesis, "')' after loop sequence")?;
+            Ok((name, sequence))
+        })?;
+        self.skip_newlines();
+        let body = self.statement()?;
+
+        Ok(Stmt {
+            span: merge(&keyword, &body.span),
+            kind: StmtKind::For {
+                variable: self.identifier(name),
+                sequence,
+                body: Box::new(body),
+            },
+        })
+    }
+
+    // `if (condition) statement`, optionally followed on the same
+    // line by `else statement`.
+    fn if_statement(&mut self) -> ParseResult<Stmt> {
+        let keyword = self.expect(Token::If, "'if'")?;
+        let condition = self.condition("'(' after 'if'", "')' after if condition")?;
+        self.skip_newlines();
+        let then_branch = self.statement()?;
+        let mut span = merge(&keyword, &then_branch.span);
+
+        let else_branch = if token_is!(self.peek(), Some(Else)) {
+            self.advance();
+            self.skip_newlines();
+            let else_branch = self.statement()?;
+            span = merge(&span, &else_branch.span);
+            Some(Box::new(else_branch))
+        } else {
+            None
+        };
+
+        Ok(Stmt {
+            kind: StmtKind::If {
+                condition,
+                then_branch: Box::new(then_branch),
+                else_branch,
+            },
+            span,
+        })
+    }
+
+    fn while_statement(&mut self) -> ParseResult<Stmt> {
+        let keyword = self.expect(Token::While, "'while'")?;
+        let condition = self.condition("'(' after 'while'", "')' after while condition")?;
+        self.skip_newlines();
+        let body = self.statement()?;
+
+        Ok(Stmt {
+            span: merge(&keyword, &body.span),
+            kind: StmtKind::While {
+                condition,
+                body: Box::new(body),
+            },
+        })
+    }
+
+    // `return`, with a value unless the line or block ends first.
+    fn return_statement(&mut self) -> ParseResult<Stmt> {
+        let keyword = self.expect(Token::Return, "'return'")?;
+        if self.at_end() || token_is!(self.peek(), Some(Newline | CloseBrace)) {
+            return Ok(Stmt {
+                kind: StmtKind::Return(None),
+                span: keyword,
+            });
+        }
+
+        let value = self.expression()?;
+        Ok(Stmt {
+            span: merge(&keyword, &value.span),
+            kind: StmtKind::Return(Some(value)),
+        })
+    }
+
+    fn jump_statement(&mut self, token: Token, kind: StmtKind) -> ParseResult<Stmt> {
+        let span = self.expect(token, "jump keyword")?;
+        Ok(Stmt { kind, span })
+    }
+
+    // The parenthesized condition of an `if` or `while`.
+    fn condition(&mut self, open: &'static str, close: &'static str) -> ParseResult<Expr> {
+        self.expect(Token::OpenParenthesis, open)?;
+        self.grouped(|p| {
+            let condition = p.expression()?;
+            p.expect(Token::CloseParenthesis, close)?;
+            Ok(condition)
+        })
+    }
+
+    /*
+
+        Expressions
+
+    */
+    pub fn expression(&mut self) -> ParseResult<Expr> {
+        self.nested(Self::assignment)
+    }
+
+    // Assignment binds loosest and is right associative.
+    //
+    // `a op= b` expands to `a = a op b`. On a subscript it is
+    // kept as one node, so the receiver and index are only
+    // evaluated once.
+    fn assignment(&mut self) -> ParseResult<Expr> {
+        let target = self.parse_precedence(LOWEST)?;
+        let op = match self.peek() {
+            Some(Token::Assignment) => None,
+            Some(token) if compound_op(token).is_some() => compound_op(token),
+            _ => return Ok(target),
+        };
+        // Every target ends with a name or a `]`, so one that ends
+        // with `)` is in parentheses, as in `(a) = 1`, which Wren
+        // does not allow.
+        if self.src[..target.span.end].ends_with(')') {
+            return Err(ParseError::InvalidAssignmentTarget { span: target.span });
+        }
+        self.advance();
+        self.skip_newlines();
+        let value = self.nested(|p| p.assignment())?;
+        assign(target, op, value)
+    }
+
+    fn parse_precedence(&mut self, min: u8) -> ParseResult<Expr> {
+        let mut left = self.prefix()?;
+        // Whether `left` is a comparison built by this loop,
+        // as opposed to one wrapped in parentheses.
+        let mut left_is_comparison = false;
+
+        loop {
+            // A line starting with `.` continues a method chain.
+            if CALL >= min && self.dot_on_next_line() {
+                self.skip_newlines();
+            }
+            if token_is!(self.peek(), Some(Dot)) && CALL >= min {
+                left = self.method_call(left)?;
+                left_is_comparison = false;
+                continue;
+            }
+            if token_is!(self.peek(), Some(OpenBracket)) && CALL >= min {
+                left = self.subscript(left)?;
+                left_is_comparison = false;
+                continue;
+            }
+
+            self.split_signed_number();
+            let Some((precedence, token)) = self
+                .peek()
+                .and_then(|t| t.infix_precedence().map(|p| (p, t.clone())))
+            else {
+                break;
+            };
+            if precedence < min {
+                break;
+            }
+            left = self.infix(left, token, precedence, left_is_comparison)?;
+            left_is_comparison = precedence == COMPARISON;
+        }
+
+        Ok(left)
+    }
+
+    // The operator `token` and its right operand, applied to `left`.
+    fn infix(
+        &mut self,
+        left: Expr,
+        token: Token,
+        precedence: u8,
+        left_is_comparison: bool,
+    ) -> ParseResult<Expr> {
+        self.advance();
+        // An operator at the end of a line continues
+        // the expression on the next.
+        self.skip_newlines();
+
+        // All binary operators are left associative, so only
+        // the right operand nests deeper.
+        let right = self.nested(|p| p.parse_precedence(precedence + 1))?;
+        let span = merge(&left.span, &right.span);
+
+        let is_comparison = precedence == COMPARISON;
+        if self.lints && is_comparison && left_is_comparison {
+            self.diagnostics.push(Diagnostic::warning(
+                "chained comparison compares the result of a comparison; \
+                 Wren evaluates `a < b < c` as `(a < b) < c`",
+                span.clone(),
+            ));
+        }
+
+        let kind = match token {
+            // `a in b` is sugar for `b.contains(a)`.
+            Token::In => ExprKind::Call {
+                receiver: Some(Box::new(right)),
+                name: "contains".to_string(),
+                args: vec![left],
+                is_getter: false,
+            },
+            _ => ExprKind::Binary {
+                op: binary_op(&token),
+                left: Box::new(left),
+                right: Box::new(right),
+            },
+        };
+        Ok(Expr { kind, span })
+    }
+
+    fn prefix(&mut self) -> ParseResult<Expr> {
+        let Some(spanned) = self.current_token().cloned() else {
+            return Err(self.unexpected_end("expression"));
+        };
+        let span = spanned.span.clone();
+
+        let kind = match spanned.token {
+            Token::Number if self.negated_call() => {
+                self.split_signed_number();
+                return self.unary();
+            }
+            Token::Number | Token::Hexadecimal => {
+                ExprKind::Literal(self.number_literal(spanned.clone()))
+            }
+            Token::String => ExprKind::Literal(LiteralValue::Str(self.string(span.clone())?)),
+            Token::True => ExprKind::Literal(LiteralValue::Bool(true)),
+            Token::False => ExprKind::Literal(LiteralValue::Bool(false)),
+            Token::Null => ExprKind::Literal(LiteralValue::Null),
+            Token::This => ExprKind::This,
+            Token::Identifier => return self.name(),
+            Token::OpenBracket => return self.list_literal(),
+            Token::OpenBrace => return self.map_literal(),
+            Token::Super => return self.super_call(),
+            Token::Negate | Token::Minus => return self.unary(),
+            Token::OpenParenthesis => {
+                self.advance();
+                let (inner, close) = self.grouped(|p| {
+                    let inner = p.expression()?;
+                    let close = p.expect(Token::CloseParenthesis, "')' after expression")?;
+                    Ok((inner, close))
+                })?;
+                return Ok(Expr {
+                    kind: inner.kind,
+                    span: merge(&span, &close),
+                });
+            }
+            token @ (Token::Break
+            | Token::Continue
+            | Token::Return
+            | Token::If
+            | Token::While
+            | Token::For) => return Err(ParseError::NotAnExpression { token, span }),
+            found => {
+                return Err(ParseError::UnexpectedToken {
+                    expected: "expression",
+                    found,
+                    span,
+                })
+            }
+        };
+
+        self.advance();
+        Ok(Expr { kind, span })
+    }
+
+    // `[a, b, ...]`, allowing a trailing comma.
+    fn list_literal(&mut self) -> ParseResult<Expr> {
+        let open = self.expect(Token::OpenBracket, "'['")?;
+        let (items, close) = self.grouped(|p| {
+            let mut items = Vec::new();
+            while !token_is!(p.peek(), Some(CloseBracket)) {
+                items.push(p.expression()?);
+
+                if !token_is!(p.peek(), Some(Comma)) {
+                    break;
+                }
+                p.advance();
+            }
+            let close = p.expect(Token::CloseBracket, "']' after list elements")?;
+            Ok((items, close))
+        })?;
+
+        Ok(Expr {
+            kind: ExprKind::List(items),
+            span: merge(&open, &close),
+        })
+    }
+
+    // `{key: value, ...}`, allowing a trailing comma.
+    fn map_literal(&mut self) -> ParseResult<Expr> {
+        let open = self.expect(Token::OpenBrace, "'{'")?;
+        let (entries, close) = self.grouped(|p| {
+            let mut entries = Vec::new();
+            while !token_is!(p.peek(), Some(CloseBrace)) {
+                let key = p.expression()?;
+                p.expect(Token::Colon, "':' after map key")?;
+                let value = p.expression()?;
+                entries.push((key, value));
+
+                if !token_is!(p.peek(), Some(Comma)) {
+                    break;
+                }
+                p.advance();
+            }
+            let close = p.expect(Token::CloseBrace, "'}' after map entries")?;
+            Ok((entries, close))
+        })?;
+
+        Ok(Expr {
+            kind: ExprKind::Map(entries),
+            span: merge(&open, &close),
+        })
+    }
+
+    // `{ |a, b| statements }`, where the parameters are optional.
+    fn block_argument(&mut self) -> ParseResult<Expr> {
+        let open = self.expect(Token::OpenBrace, "'{'")?;
+        let mut params = Vec::new();
+        if token_is!(self.peek(), Some(BitwiseOr)) {
+            self.advance();
+            loop {
+                let param = self.expect(Token::Identifier, "block parameter name")?;
+                self.check_arguments(params.len() + 1, &param)?;
+                params.push(self.identifier(param));
+                if !token_is!(self.peek(), Some(Comma)) {
+                    break;
+                }
+                self.advance();
+            }
+            self.expect(Token::BitwiseOr, "'|' after block parameters")?;
+        }
+        let (body, close, expression_body) = self.block_body()?;
+
+        Ok(Expr {
+            kind: ExprKind::Fn {
+                params,
+                body,
+                expression_body,
+            },
+            span: merge(&open, &close),
+        })
+    }
+
+    // `super(args)`, `super.name(args)` or the getter call
+    // `super.name`, which `assign` turns into a setter call
+    // when it is assigned. Where they may appear is checked
+    // by codegen.
+    fn super_call(&mut self) -> ParseResult<Expr> {
+        let keyword = self.expect(Token::Super, "'super'")?;
+        let name = if token_is!(self.peek(), Some(Dot)) {
+            self.advance();
+            let name = self.expect(Token::Identifier, "method name after '.'")?;
+            if !token_is!(self.peek(), Some(OpenParenthesis)) {
+                return Ok(Expr {
+                    kind: ExprKind::Super {
+                        name: Some(self.identifier(name.clone())),
+                        args: Vec::new(),
+                        kind: MemberKind::Getter,
+                    },
+                    span: merge(&keyword, &name),
+                });
+            }
+            Some(self.identifier(name))
+        } else {
+            None
+        };
+        self.expect(Token::OpenParenthesis, "'(' after 'super'")?;
+        let (args, close) =
+            self.grouped(|p| p.arguments(Token::CloseParenthesis, "')' after arguments"))?;
+
+        let kind = match name {
+            Some(_) => MemberKind::Method,
+            None => MemberKind::Constructor,
+        };
+        Ok(Expr {
+            kind: ExprKind::Super { name, args, kind },
+            span: merge(&keyword, &close),
+        })
+    }
+
+    // `receiver.name` or `receiver.name(args)`, either of which
+    // may be followed by a block argument.
+    fn method_call(&mut self, receiver: Expr) -> ParseResult<Expr> {
+        self.expect(Token::Dot, "'.'")?;
+        let name = self.expect(Token::Identifier, "method name after '.'")?;
+        let mut last = name.clone();
+
+        let mut is_getter = !token_is!(self.peek(), Some(OpenParenthesis));
+        let mut args = Vec::new();
+        if !is_getter {
+            self.advance();
+            (args, last) =
+                self.grouped(|p| p.arguments(Token::CloseParenthesis, "')' after arguments"))?;
+        }
+        if token_is!(self.peek(), Some(OpenBrace)) {
+            let block = self.block_argument()?;
+            last = block.span.clone();
+            self.check_arguments(args.len() + 1, &block.span)?;
+            args.push(block);
+            is_getter = false;
+        }
+
+        Ok(Expr {
+            span: merge(&receiver.span, &last),
+            kind: ExprKind::Call {
+                receiver: Some(Box::new(receiver)),
+                name: self.identifier(name),
+                args,
+                is_getter,
+            },
+        })
+    }
+
+    // `receiver[args]`, taking at least one argument.
+    fn subscript(&mut self, receiver: Expr) -> ParseResult<Expr> {
+        self.expect(Token::OpenBracket, "'['")?;
+        let (args, close) = self.grouped(|p| {
+            if let Some(spanned) = p.current_token() {
+                if token_is!(spanned.token, CloseBracket) {
+                    return Err(ParseError::UnexpectedToken {
+                        expected: "subscript argument",
+                        found: Token::CloseBracket,
+                        span: spanned.span.clone(),
+                    });
+                }
+            }
+            p.arguments(Token::CloseBracket, "']' after subscript arguments")
+        })?;
+
+        Ok(Expr {
+            span: merge(&receiver.span, &close),
+            kind: ExprKind::Subscript {
+                receiver: Box::new(receiver),
+                args,
+            },
+        })
+    }
+
+    // Comma separated expressions up to and including `close`,
+    // returning them with the span of the closing token. Unlike
+    // map literals, argument lists cannot end with a comma.
+    fn arguments(
+        &mut self,
+        close: Token,
+        expected: &'static str,
+    ) -> ParseResult<(Vec<Expr>, Range<usize>)> {
+        let mut args = Vec::new();
+        if self.peek() != Some(&close) {
+            loop {
+                let arg = self.expression()?;
+                self.check_arguments(args.len() + 1, &arg.span)?;
+                args.push(arg);
+                if !token_is!(self.peek(), Some(Comma)) {
+                    break;
+                }
+                self.advance();
+                if let Some(spanned) = self.current_token().filter(|s| s.token == close) {
+                    return Err(ParseError::UnexpectedToken {
+                        expected: "argument after ','",
+                        found: spanned.token.clone(),
+                        span: spanned.span.clone(),
+                    });
+                }
+            }
+        }
+        let close = self.expect(close, expected)?;
+        Ok((args, close))
+    }
+
+    // A number token's value, keeping the text it was written as.
+    fn number_literal(&self, spanned: SpannedToken) -> LiteralValue {
+        let raw = spanned.text(self.src).to_string();
+        let value = match spanned.token {
+            Token::Hexadecimal => self.hexadecimal(spanned.span),
+            _ => self.number(spanned.span),
+        };
+        LiteralValue::Num(value, Some(raw))
+    }
+
+    fn number(&self, span: Range<usize>) -> f64 {
+        // The lexer only produces well formed decimal
+        // literals for this token, with underscores only
+        // between digits.
+        let text = &self.src[span];
+        if text.contains('_') {
+            text.replace('_', "").parse().unwrap_or(f64::NAN)
+        } else {
+            text.parse().unwrap_or(f64::NAN)
+        }
+    }
+
+    // Strips the quotes and decodes escapes: those of Wren, plus
+    // `\r`. What an escape Wren does not define does depends on
+    // the escape policy; a `\x`, `\u` or `\U` without enough hex
+    // digits is an error either way.
+    //
+    // String values are UTF-8, as the source is, so every
+    // escape decodes to a character; `\0` is the null
+    // character and stays in the string like any other, and
+    // `\xff` is the character U+00FF rather than a lone byte.
+    fn string(&self, span: Range<usize>) -> ParseResult<String> {
+        let start = span.start + 1;
+        let text = &self.src[start..span.end - 1];
+        let mut value = String::with_capacity(text.len());
+        let mut chars = text.char_indices();
+        while let Some((offset, c)) = chars.next() {
+            if c != '\\' {
+                value.push(c);
+                continue;
+            }
+            let at = start + offset;
+            match chars.next() {
+                Some((_, 'n')) => value.push('\n'),
+                Some((_, 't')) => value.push('\t'),
+                Some((_, 'r')) => value.push('\r'),
+                Some((_, '0')) => value.push('\0'),
+                Some((_, 'a')) => value.push('\x07'),
+                Some((_, 'b')) => value.push('\x08'),
+                Some((_, 'e')) => value.push('\x1b'),
+                Some((_, 'f')) => value.push('\x0c'),
+                Some((_, 'v')) => value.push('\x0b'),
+                Some((_, '"')) => value.push('"'),
+                Some((_, '\\')) => value.push('\\'),
+                Some((_, '%')) => value.push('%'),
+                Some((_, kind @ ('x' | 'u' | 'U'))) => {
+                    let digits = match kind {
+                        'x' => 2,
+                        'u' => 4,
+                        _ => 8,
+                    };
+                    let hex = text[offset + 2..].get(..digits).unwrap_or_default();
+                    let decoded = (hex.len() == digits
+                        && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+                    .then(|| u32::from_str_radix(hex, 16).ok())
+                    .flatten()
+                    .and_then(char::from_u32);
+                    let Some(decoded) = decoded else {
+                        return Err(ParseError::InvalidEscape {
+                            span: at..(at + 2 + digits).min(span.end - 1),
+                        });
+                    };
+                    value.push(decoded);
+                    chars.nth(digits - 1);
+                }
+                Some((_, other)) if self.escapes == EscapePolicy::Lenient => value.push(other),
+                Some((_, other)) => {
+                    return Err(ParseError::InvalidEscape {
+                        span: at..at + 1 + other.len_utf8(),
+                    });
+                }
+                None => value.push('\\'),
+            }
+        }
+        Ok(value)
+    }
+
+    // Underscores are not hex digits, so they are skipped.
+    fn hexadecimal(&self, span: Range<usize>) -> f64 {
+        self.src[span][2..]
+            .chars()
+            .filter_map(|c| c.to_digit(16))
+            .fold(0.0, |value, digit| value * 16.0 + f64::from(digit))
+    }
+
+    // A variable, or `name(args)`, a method call without a
+    // receiver, which targets `this` inside a method.
+    fn name(&mut self) -> ParseResult<Expr> {
+        let span = self.expect(Token::Identifier, "name")?;
+        let name = self.identifier(span.clone());
+        if !token_is!(self.peek(), Some(OpenParenthesis)) {
+            return Ok(Expr {
+                kind: ExprKind::Variable(name),
+                span,
+            });
+        }
+
+        self.advance();
+        let (args, close) =
+            self.grouped(|p| p.arguments(Token::CloseParenthesis, "')' after arguments"))?;
+        Ok(Expr {
+            kind: ExprKind::Call {
+                receiver: None,
+                name,
+                args,
+                is_getter: false,
+            },
+            span: merge(&span, &close),
+        })
+    }
+
+    fn unary(&mut self) -> ParseResult<Expr> {
+        let Some(spanned) = self.current_token().cloned() else {
+            return Err(self.unexpected_end("expression"));
+        };
+        let op = match spanned.token {
+            Token::Negate => UnaryOp::Not,
+            _ => UnaryOp::Negate,
+        };
+        self.advance();
+        let operand = self.nested(|p| p.parse_precedence(UNARY))?;
+
+        Ok(Expr {
+            span: merge(&spanned.span, &operand.span),
+            kind: ExprKind::Unary {
+                op,
+                operand: Box::new(operand),
+            },
+        })
+    }
+
+    // Whether the current token is a negative number a call
+    // follows, as in `-1.abs`. Unary minus binds looser than the
+    // call, so its sign is split off and negates the result.
+    fn negated_call(&self) -> bool {
+        let index = self.position();
+        self.src.as_bytes()[self.tokens[index].span.start] == b'-'
+            && token_is!(self.tokens.get(index + 1).map(|t| &t.token), Some(Dot))
+    }
+
+    // The lexer folds a leading sign into number
+    // literals, so `a -1` arrives as `a`, `-1`.
+    // In infix position the sign is an operator.
+    fn split_signed_number(&mut self) {
+        let index = self.position();
+        let Some(spanned) = self.tokens.get(index) else {
+            return;
+        };
+        if !token_is!(spanned.token, Number) {
+            return;
+        }
+
+        let span = spanned.span.clone();
+        let operator = match self.src.as_bytes()[span.start] {
+            b'+' => Token::Plus,
+            b'-' => Token::Minus,
+            _ => return,
+        };
+
+        self.tokens[index] = SpannedToken {
+            token: operator,
+            span: span.start..span.start + 1,
+        };
+        self.tokens.insert(
+            index + 1,
+            SpannedToken {
+                token: Token::Number,
+                span: span.start + 1..span.end,
+            },
+        );
+    }
+
+    /*
+
+        Token Helpers
+
+    */
+    // The index of the next token to parse. Newlines inside
+    // a grouping are insignificant and looked past.
+    fn position(&self) -> usize {
+        let mut index = self.current;
+        if self.groupings > 0 {
+            while token_is!(self.tokens.get(index).map(|t| &t.token), Some(Newline)) {
+                index += 1;
+            }
+        }
+        index
+    }
+
+    // The comment lines directly above the line `start` is on,
+    // without their slashes. A blank line ends the block.
+    fn doc_comment(&self, start: usize) -> Option<String> {
+        let mut lines = Vec::new();
+        let mut next = start;
+        let above = self.comments.partition_point(|c| c.span.end <= start);
+        for comment in self.comments[..above].iter().rev() {
+            let between = &self.src[comment.span.end..next];
+            let line_start = self.src[..comment.span.start]
+                .rfind('\n')
+                .map_or(0, |newline| newline + 1);
+            let leading = self.src[line_start..comment.span.start].trim().is_empty();
+            if comment.token != Token::LineComment
+                || between.matches('\n').count() != 1
+                || !between.trim().is_empty()
+                || !leading
+            {
+                break;
+            }
+            let text = &self.src[comment.span.clone()];
+            let text = text
+                .strip_prefix("///")
+                .or_else(|| text.strip_prefix("//"))
+                .unwrap_or(text);
+            lines.push(text.strip_prefix(' ').unwrap_or(text).trim_end());
+            next = line_start;
+        }
+        if lines.is_empty() {
+            return None;
+        }
+        lines.reverse();
+        Some(lines.join("\n"))
+    }
+
+    fn current_token(&self) -> Option<&SpannedToken> {
+        self.tokens.get(self.position())
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.current_token().map(|t| &t.token)
+    }
+
+    fn at_end(&self) -> bool {
+        self.position() >= self.tokens.len()
+    }
+
+    fn advance(&mut self) {
+        self.current = self.position() + 1;
+    }
+
+    // Whether newlines and then a `.` come next.
+    fn dot_on_next_line(&self) -> bool {
+        let mut rest = self.tokens[self.position().min(self.tokens.len())..].iter();
+        if !token_is!(rest.next().map(|t| &t.token), Some(Newline)) {
+            return false;
+        }
+        let next = rest.find(|t| !token_is!(t.token, Newline));
+        token_is!(next.map(|t| &t.token), Some(Dot))
+    }
+
+    fn skip_newlines(&mut self) {
+        while token_is!(self.peek(), Some(Newline)) {
+            self.current += 1;
+        }
+    }
+
+    // Runs `parse` inside one more grouping.
+    fn grouped<T>(&mut self, parse: impl FnOnce(&mut Self) -> ParseResult<T>) -> ParseResult<T> {
+        self.with_groupings(self.groupings + 1, parse)
+    }
+
+    fn with_groupings<T>(
+        &mut self,
+        groupings: usize,
+        parse: impl FnOnce(&mut Self) -> ParseResult<T>,
+    ) -> ParseResult<T> {
+        let outer = std::mem::replace(&mut self.groupings, groupings);
+        let result = parse(self);
+        self.groupings = outer;
+        result
+    }
+
+    // The name an identifier at `span` stands for.
+    fn identifier(&self, span: Range<usize>) -> String {
+        identifier_name(&self.src[span])
+    }
+
+    fn expect(&mut self, token: Token, expected: &'static str) -> ParseResult<Range<usize>> {
+        match self.current_token() {
+            Some(spanned) if spanned.token == token => {
+                let span = spanned.span.clone();
+                self.advance();
+                Ok(span)
+            }
+            Some(spanned) => Err(ParseError::UnexpectedToken {
+                expected,
+                found: spanned.token.clone(),
+                span: spanned.span.clone(),
+            }),
+            None => Err(self.unexpected_end(expected)),
+        }
+    }
+
+    // Runs `parse` one level deeper, failing once `max_depth` is reached.
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> ParseResult<T>) -> ParseResult<T> {
+        if self.depth >= self.max_depth {
+            let span = match self.current_token() {
+                Some(spanned) => spanned.span.clone(),
+                None => self.src.len()..self.src.len(),
+            };
+            return Err(ParseError::TooDeep { span });
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    // Consumes `keyword` if it is next, returning its span.
+    fn keyword(&mut self, keyword: Token) -> Option<Range<usize>> {
+        let spanned = self.current_token().filter(|s| s.token == keyword)?;
+        let span = spanned.span.clone();
+        self.advance();
+        Some(span)
+    }
+
+    // Fails at `span` when it holds argument number `count` and
+    // that is past the limit.
+    fn check_arguments(&self, count: usize, span: &Range<usize>) -> ParseResult<()> {
+        if count > self.max_arguments {
+            return Err(ParseError::TooManyArguments { span: span.clone() });
+        }
+        Ok(())
+    }
+
+    fn unexpected_end(&self, expected: &'static str) -> ParseError {
+        ParseError::UnexpectedEnd {
+            expected,
+            span: self.src.len()..self.src.len(),
+        }
+    }
+}
+
+// A class may not define two members with the same
+// signature on the same side (class or instance). A
+// getter `foo` and a setter `foo=(_)` can coexist
+// because their signatures differ.
+fn validate_members(members: &[ClassMember]) -> ParseResult<()> {
+    let mut seen = std::collections::HashMap::new();
+    for member in members {
+        let signature = member.signature();
+        let key = (member.on_class(), signature.clone());
+        if let Some(previous) = seen.insert(key, member.span.clone()) {
+            return Err(ParseError::DuplicateMethod {
+                signature,
+                span: member.span.clone(),
+                previous,
+            });
+        }
+    }
+    Ok(())
+}
+
+// `target = value`, or `target op= value` with `op`. Kept
+// out of `assignment`, which recurses, to keep its frame small.
+fn assign(target: Expr, op: Option<BinaryOp>, value: Expr) -> ParseResult<Expr> {
+    let span = merge(&target.span, &value.span);
+
+    match (target.kind, op) {
+        (ExprKind::Variable(name), Some(op)) => {
+            let current = Expr {
+                kind: ExprKind::Variable(name.clone()),
+                span: target.span,
+            };
+            let value = Expr {
+                kind: ExprKind::Binary {
+                    op,
+                    left: Box::new(current),
+                    right: Box::new(value),
+                },
+                span: span.clone(),
+            };
+            Ok(Expr {
+                kind: ExprKind::Assign {
+                    name,
+                    value: Box::new(value),
+                },
+                span,
+            })
+        }
+        (ExprKind::Subscript { receiver, args }, Some(op)) => Ok(Expr {
+            kind: ExprKind::SubscriptUpdate {
+                receiver,
+                args,
+                op,
+                value: Box::new(value),
+            },
+            span,
+        }),
+        (
+            ExprKind::Call {
+                receiver: Some(receiver),
+                name,
+                is_getter: true,
+                ..
+            },
+            Some(op),
+        ) => Ok(Expr {
+            kind: ExprKind::PropertyUpdate {
+                receiver,
+                name,
+                op,
+                value: Box::new(value),
+            },
+            span,
+        }),
+        (ExprKind::Variable(name), None) => Ok(Expr {
+            kind: ExprKind::Assign {
+                name,
+                value: Box::new(value),
+            },
+            span,
+        }),
+        (ExprKind::Subscript { receiver, mut args }, None) => {
+            args.push(value);
+            Ok(Expr {
+                kind: ExprKind::SubscriptSet { receiver, args },
+                span,
+            })
+        }
+        // `a.name = value` calls the setter `name=(_)`.
+        (
+            ExprKind::Call {
+                receiver: Some(receiver),
+                name,
+                is_getter: true,
+                ..
+            },
+            None,
+        ) => Ok(Expr {
+            kind: ExprKind::Call {
+                receiver: Some(receiver),
+                name: format!("{name}="),
+                args: vec![value],
+                is_getter: false,
+            },
+            span,
+        }),
+        (
+            ExprKind::Super {
+                name: Some(name),
+                kind: MemberKind::Getter,
+                ..
+            },
+            None,
+        ) => Ok(Expr {
+            kind: ExprKind::Super {
+                name: Some(name),
+                args: vec![value],
+                kind: MemberKind::Setter,
+            },
+            span,
+        }),
+        _ => Err(ParseError::InvalidAssignmentTarget { span: target.span }),
+    }
+}
+
+// The operator of an augmented assignment such as `+=`.
+fn compound_op(token: &Token) -> Option<BinaryOp> {
+    let op = match token {
+        Token::PlusAssign => BinaryOp::Add,
+        Token::MinusAssign => BinaryOp::Subtract,
+        Token::StarAssign => BinaryOp::Multiply,
+        Token::SlashAssign => BinaryOp::Divide,
+        Token::PercentAssign => BinaryOp::Modulo,
+        Token::BitwiseAndAssign => BinaryOp::BitwiseAnd,
+        Token::BitwiseOrAssign => BinaryOp::BitwiseOr,
+        Token::BitwiseXORAssign => BinaryOp::BitwiseXor,
+        Token::BitwiseLeftShiftAssign => BinaryOp::LeftShift,
+        Token::BitwiseRightShiftAssign => BinaryOp::RightShift,
+        _ => return None,
+    };
+    Some(op)
+}
+
+fn binary_op(token: &Token) -> BinaryOp {
+    match token {
+        Token::LogicalOr => BinaryOp::Or,
+        Token::LogicalAnd => BinaryOp::And,
+        Token::Equals => BinaryOp::Equal,
+        Token::NotEqual => BinaryOp::NotEqual,
+        Token::Is => BinaryOp::Is,
+        Token::LesserThan => BinaryOp::LessThan,
+        Token::LesserThanEqualTo => BinaryOp::LessThanEqual,
+        Token::GreaterThan => BinaryOp::GreaterThan,
+        Token::GreaterThanEqualTo => BinaryOp::GreaterThanEqual,
+        Token::BitwiseOr => BinaryOp::BitwiseOr,
+        Token::BitwiseXOR => BinaryOp::BitwiseXor,
+        Token::BitwiseAnd => BinaryOp::BitwiseAnd,
+        Token::BitwiseLeftShift => BinaryOp::LeftShift,
+        Token::BitwiseRightShift => BinaryOp::RightShift,
+        Token::InclusiveRange => BinaryOp::InclusiveRange,
+        Token::ExclusiveRange => BinaryOp::ExclusiveRange,
+        Token::Plus => BinaryOp::Add,
+        Token::Minus => BinaryOp::Subtract,
+        Token::Star => BinaryOp::Multiply,
+        Token::Slash => BinaryOp::Divide,
+        Token::Percent => BinaryOp::Modulo,
+        _ => unreachable!("not a binary operator: {token:?}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::ast::diff;
+    use crate::compiler::diagnostic::Severity;
+    use crate::compiler::lexer::LexErrorKind;
+
+    fn variable(name: &str, span: Range<usize>) -> Expr {
+        Expr {
+            kind: ExprKind::Variable(name.to_string()),
+            span,
+        }
+    }
+
+    #[test]
+    fn expression_strings() {
+        let expr = parse_expr_str("1 + 2 * 3\n").unwrap();
+        assert_eq!(expr.span, 0..9);
+        assert!(matches!(expr.kind, ExprKind::Binary { .. }));
+
+        let errors = parse_expr_str("1 +").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span, 3..3);
+
+        let errors = parse_expr_str("1 2").unwrap_err();
+        assert_eq!(errors[0].span, 2..3);
+        assert!(errors[0].message.contains("end of expression"));
+
+        assert!(parse_expr_str("1 @").is_err());
+    }
+
+    #[test]
+    fn for_loop() {
+        let program = parse_program("for (x in y) x").unwrap();
+
+        assert_eq!(
+            program,
+            vec![Stmt {
+                kind: StmtKind::For {
+                    variable: "x".to_string(),
+                    sequence: variable("y", 10..11),
+                    body: Box::new(Stmt {
+                        kind: StmtKind::Expression(variable("x", 13..14)),
+                        span: 13..14,
+                    }),
+                },
+                span: 0..14,
+            }]
+        );
+    }
+
+    #[test]
+    fn membership() {
+        let program = parse_program("a in b").unwrap();
+
+        assert_eq!(
+            program,
+            vec![Stmt {
+                kind: StmtKind::Expression(Expr {
+                    kind: ExprKind::Call {
+                        receiver: Some(Box::new(variable("b", 5..6))),
+                        name: "contains".to_string(),
+                        args: vec![variable("a", 0..1)],
+                        is_getter: false,
+                    },
+                    span: 0..6,
+                }),
+                span: 0..6,
+            }]
+        );
+    }
+
+    #[test]
+    fn membership_in_loop_sequence() {
+        let program = parse_program("for (x in a in b) {}").unwrap();
+
+        let StmtKind::For { sequence, body, .. } = &program[0].kind else {
+            panic!("expected a for loop, got {:?}", program[0]);
+        };
+        assert!(matches!(
+            &sequence.kind,
+            ExprKind::Call { name, .. } if name == "contains"
+        ));
+        assert_eq!(body.kind, StmtKind::Block(vec![]));
+    }
+
+    fn call(receiver: Expr, name: &str, args: Vec<Expr>, is_getter: bool) -> Expr {
+        Expr {
+            kind: ExprKind::Call {
+                receiver: Some(Box::new(receiver)),
+                name: name.to_string(),
+                args,
+                is_getter,
+            },
+            span: 0..0,
+        }
+    }
+
+    fn expr(src: &str) -> Expr {
+        Parser::new(src).unwrap().expression().unwrap()
+    }
+
+    fn unary(op: UnaryOp, operand: Expr) -> Expr {
+        Expr {
+            kind: ExprKind::Unary {
+                op,
+                operand: Box::new(operand),
+            },
+            span: 0..0,
+        }
+    }
+
+    fn binary(op: BinaryOp, left: Expr, right: Expr) -> Expr {
+        Expr {
+            kind: ExprKind::Binary {
+                op,
+                left: Box::new(left),
+                right: Box::new(right),
+            },
+            span: 0..0,
+        }
+    }
+
+    #[test]
+    fn call_without_receiver() {
+        assert_eq!(
+            expr("doThing(1, x)"),
+            Expr {
+                kind: ExprKind::Call {
+                    receiver: None,
+                    name: "doThing".to_string(),
+                    args: vec![
+                        Expr {
+                            kind: ExprKind::Literal(LiteralValue::Num(1.0, None)),
+                            span: 0..0,
+                        },
+                        variable("x", 0..0),
+                    ],
+                    is_getter: false,
+                },
+                span: 0..0,
+            }
+        );
+        assert_eq!(expr("doThing(1, x)").span, 0..13);
+        assert_eq!(expr("name").kind, ExprKind::Variable("name".to_string()));
+    }
+
+    #[test]
+    fn unary_operators() {
+        let x = || variable("x", 0..0);
+        let a = || variable("a", 0..0);
+        let b = || variable("b", 0..0);
+
+        assert_eq!(expr("!!x"), unary(UnaryOp::Not, unary(UnaryOp::Not, x())));
+        assert_eq!(expr("!!x").span, 0..3);
+        assert_eq!(
+            expr("-a * b"),
+            binary(BinaryOp::Multiply, unary(UnaryOp::Negate, a()), b())
+        );
+        assert_eq!(
+            expr("!a.isEmpty"),
+            unary(UnaryOp::Not, call(a(), "isEmpty", vec![], true))
+        );
+        assert_eq!(
+            expr("!a == b"),
+            binary(BinaryOp::Equal, unary(UnaryOp::Not, a()), b())
+        );
+        assert_eq!(
+            expr("-a.b"),
+            unary(UnaryOp::Negate, call(a(), "b", vec![], true))
+        );
+        assert_eq!(
+            expr("a - -b"),
+            binary(BinaryOp::Subtract, a(), unary(UnaryOp::Negate, b()))
+        );
+
+        // The lexer reads `-1` as one number, but a call on it
+        // still happens before the negation.
+        let one = Expr {
+            kind: ExprKind::Literal(LiteralValue::Num(1.0, None)),
+            span: 0..0,
+        };
+        assert_eq!(
+            expr("-1.abs"),
+            unary(UnaryOp::Negate, call(one, "abs", vec![], true))
+        );
+        assert_eq!(expr("-1.abs").span, 0..6);
+    }
+
+    #[test]
+    fn getter_and_method_call() {
+        let getter = expr("obj.length");
+        let method = expr("obj.length()");
+
+        assert_eq!(getter, call(variable("obj", 0..3), "length", vec![], true));
+        assert_eq!(method, call(variable("obj", 0..3), "length", vec![], false));
+        assert_eq!(
+            diff(&getter, &method),
+            Some("expr.is_getter: true != false".to_string())
+        );
+        assert_eq!(method.span, 0..12);
+    }
+
+    #[test]
+    fn method_call_arguments() {
+        assert_eq!(
+            expr("list.insert(0, x)"),
+            call(
+                variable("list", 0..4),
+                "insert",
+                vec![
+                    Expr {
+                        kind: ExprKind::Literal(LiteralValue::Num(0.0, None)),
+                        span: 12..13,
+                    },
+                    variable("x", 15..16),
+                ],
+                false,
+            )
+        );
+    }
+
+    #[test]
+    fn multiline_arguments() {
+        let ExprKind::Call { args, .. } = expr("f.call(\n  a,\n  b\n)").kind else {
+            panic!("expected a call");
+        };
+        assert_eq!(args, [variable("a", 10..11), variable("b", 15..16)]);
+
+        assert!(matches!(
+            parse_program("f.call(\n  a\n  b\n)"),
+            Err(ParseError::UnexpectedToken {
+                expected: "')' after arguments",
+                found: Token::Identifier,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn trailing_comma_in_arguments() {
+        for src in ["f.call(a, b,)", "f.call(\n  a,\n)", "list[0,]"] {
+            assert!(
+                matches!(
+                    parse_program(src),
+                    Err(ParseError::UnexpectedToken {
+                        expected: "argument after ','",
+                        ..
+                    })
+                ),
+                "{src}"
+            );
+        }
+    }
+
+    #[test]
+    fn getter_binds_tighter_than_operators() {
+        assert_eq!(
+            expr("obj.length + 1"),
+            Expr {
+                kind: ExprKind::Binary {
+                    op: BinaryOp::Add,
+                    left: Box::new(call(variable("obj", 0..3), "length", vec![], true)),
+                    right: Box::new(Expr {
+                        kind: ExprKind::Literal(LiteralValue::Num(1.0, None)),
+                        span: 13..14,
+                    }),
+                },
+                span: 0..14,
+            }
+        );
+    }
+
+    fn assign(name: &str, value: Expr) -> Expr {
+        Expr {
+            kind: ExprKind::Assign {
+                name: name.to_string(),
+                value: Box::new(value),
+            },
+            span: 0..0,
+        }
+    }
+
+    fn num(n: f64) -> Expr {
+        Expr {
+            kind: ExprKind::Literal(LiteralValue::Num(n, None)),
+            span: 0..0,
+        }
+    }
+
+    #[test]
+    fn calls_on_literals() {
+        for (src, receiver, method) in [
+            ("5.toString", 0..1, "toString"),
+            ("\"hi\".count", 0..4, "count"),
+            ("(1..3).max", 0..6, "max"),
+        ] {
+            let ExprKind::Call {
+                receiver: Some(target),
+                name,
+                is_getter: true,
+                ..
+            } = expr(src).kind
+            else {
+                panic!("expected a getter call in {src:?}");
+            };
+            assert_eq!(target.span, receiver, "{src:?}");
+            assert_eq!(name, method);
+        }
+        assert!(matches!(
+            expr("5.toString").kind,
+            ExprKind::Call { receiver: Some(n), .. }
+                if matches!(n.kind, ExprKind::Literal(LiteralValue::Num(n, _)) if n == 5.0)
+        ));
+    }
+
+    #[test]
+    fn constructor_calls_need_parentheses() {
+        let ExprKind::Call {
+            name,
+            args,
+            is_getter,
+            ..
+        } = expr("Foo.new()").kind
+        else {
+            panic!("expected a call");
+        };
+        assert_eq!(name, "new");
+        assert!(args.is_empty() && !is_getter);
+
+        // Without them, `new` is a getter on the class.
+        assert!(matches!(
+            expr("Foo.new").kind,
+            ExprKind::Call { name, args, is_getter: true, .. } if name == "new" && args.is_empty()
+        ));
+    }
+
+    #[test]
+    fn block_argument() {
+        let ExprKind::Call {
+            name,
+            args,
+            is_getter,
+            ..
+        } = expr("list.map { |x, i| x * i }").kind
+        else {
+            panic!("expected a call");
+        };
+        assert_eq!(name, "map");
+        assert!(!is_getter);
+        let [Expr {
+            kind:
+                ExprKind::Fn {
+                    params,
+                    body,
+                    expression_body,
+                },
+            span,
+        }] = args.as_slice()
+        else {
+            panic!("expected a block argument");
+        };
+        assert_eq!(params, &["x", "i"]);
+        assert_eq!(body.len(), 1);
+        assert!(*expression_body);
+        assert_eq!(*span, 9..25);
+
+        let ExprKind::Call { args, .. } = expr("f.call(1) {\n  a\n  b\n}").kind else {
+            panic!("expected a call");
+        };
+        assert_eq!(args.len(), 2);
+        assert!(
+            matches!(&args[1].kind, ExprKind::Fn { params, body, .. } if params.is_empty() && body.len() == 2)
+        );
+
+        // Only a lone expression on the line of the `{` is returned.
+        let ExprKind::Call { args, .. } = expr("f.call {\n  a\n}").kind else {
+            panic!("expected a call");
+        };
+        assert!(matches!(
+            &args[0].kind,
+            ExprKind::Fn {
+                expression_body: false,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn subscript() {
+        let subscript = |receiver, args| Expr {
+            kind: ExprKind::Subscript {
+                receiver: Box::new(receiver),
+                args,
+            },
+            span: 0..0,
+        };
+
+        assert_eq!(
+            expr("list[1].count"),
+            call(
+                subscript(variable("list", 0..0), vec![num(1.0)]),
+                "count",
+                vec![],
+                true
+            )
+        );
+        assert_eq!(
+            expr("grid[x, y]"),
+            subscript(
+                variable("grid", 0..0),
+                vec![variable("x", 0..0), variable("y", 0..0)]
+            )
+        );
+        assert!(matches!(
+            Parser::new("a[]").unwrap().expression(),
+            Err(ParseError::UnexpectedToken { span, .. }) if span == (2..3)
+        ));
+    }
+
+    #[test]
+    fn subscript_setter() {
+        assert_eq!(
+            expr("m[0] = 5"),
+            Expr {
+                kind: ExprKind::SubscriptSet {
+                    receiver: Box::new(variable("m", 0..0)),
+                    args: vec![num(0.0), num(5.0)],
+                },
+                span: 0..8,
+            }
+        );
+        assert_eq!(
+            expr("m[0]"),
+            Expr {
+                kind: ExprKind::Subscript {
+                    receiver: Box::new(variable("m", 0..0)),
+                    args: vec![num(0.0)],
+                },
+                span: 0..4,
+            }
+        );
+        assert!(matches!(
+            expr("m[0] = n[1] = 2").kind,
+            ExprKind::SubscriptSet { args, .. }
+                if matches!(&args[1].kind, ExprKind::SubscriptSet { .. })
+        ));
+    }
+
+    #[test]
+    fn property_setter() {
+        assert_eq!(
+            expr("a.x = 3"),
+            call(variable("a", 0..0), "x=", vec![num(3.0)], false)
+        );
+        assert_eq!(
+            expr("this.x = a.y = 2"),
+            call(
+                Expr {
+                    kind: ExprKind::This,
+                    span: 0..0,
+                },
+                "x=",
+                vec![call(variable("a", 0..0), "y=", vec![num(2.0)], false)],
+                false
+            )
+        );
+    }
+
+    #[test]
+    fn augmented_assignment() {
+        assert_eq!(
+            expr("a += 2"),
+            assign("a", binary(BinaryOp::Add, variable("a", 0..0), num(2.0)))
+        );
+        assert_eq!(
+            expr("a <<= b -= 1"),
+            assign(
+                "a",
+                binary(
+                    BinaryOp::LeftShift,
+                    variable("a", 0..0),
+                    assign(
+                        "b",
+                        binary(BinaryOp::Subtract, variable("b", 0..0), num(1.0))
+                    )
+                )
+            )
+        );
+        assert_eq!(
+            expr("m[i] *= 3").kind,
+            ExprKind::SubscriptUpdate {
+                receiver: Box::new(variable("m", 0..0)),
+                args: vec![variable("i", 0..0)],
+                op: BinaryOp::Multiply,
+                value: Box::new(num(3.0)),
+            }
+        );
+        assert_eq!(
+            expr("a.b += 1").kind,
+            ExprKind::PropertyUpdate {
+                receiver: Box::new(variable("a", 0..0)),
+                name: "b".to_string(),
+                op: BinaryOp::Add,
+                value: Box::new(num(1.0)),
+            }
+        );
+        assert_eq!(
+            Parser::new("a.b() += 1").unwrap().expression(),
+            Err(ParseError::InvalidAssignmentTarget { span: 0..5 })
+        );
+        assert_eq!(
+            Parser::new("(a) = 1").unwrap().expression(),
+            Err(ParseError::InvalidAssignmentTarget { span: 0..3 })
+        );
+        assert_eq!(
+            Parser::new("(a.b) += 1").unwrap().expression(),
+            Err(ParseError::InvalidAssignmentTarget { span: 0..5 })
+        );
+        // The receiver of a setter may be parenthesized.
+        assert!(Parser::new("(a).b = 1").unwrap().expression().is_ok());
+        assert!(Parser::new("(a)[0] = 1").unwrap().expression().is_ok());
+    }
+
+    #[test]
+    fn list_literal() {
+        assert_eq!(
+            expr("[1, [2],\n  a.b,\n]").kind,
+            ExprKind::List(vec![
+                num(1.0),
+                Expr {
+                    kind: ExprKind::List(vec![num(2.0)]),
+                    span: 0..0,
+                },
+                call(variable("a", 0..0), "b", vec![], true),
+            ])
+        );
+        assert_eq!(expr("[]").kind, ExprKind::List(vec![]));
+        assert_eq!(
+            expr("[1].count"),
+            call(
+                Expr {
+                    kind: ExprKind::List(vec![num(1.0)]),
+                    span: 0..0,
+                },
+                "count",
+                vec![],
+                true
+            )
+        );
+        assert!(Parser::new("[1 2]").unwrap().expression().is_err());
+        assert!(Parser::new("[,]").unwrap().expression().is_err());
+    }
+
+    #[test]
+    fn map_literal_keys_are_expressions() {
+        let map = expr(r#"{ 1: "a", 0..5: "range", Color.red: "x", }"#);
+        let str = |s: &str| Expr {
+            kind: ExprKind::Literal(LiteralValue::Str(s.to_string())),
+            span: 0..0,
+        };
+        let range = Expr {
+            kind: ExprKind::Binary {
+                op: BinaryOp::InclusiveRange,
+                left: Box::new(num(0.0)),
+                right: Box::new(num(5.0)),
+            },
+            span: 0..0,
+        };
+
+        assert_eq!(
+            map.kind,
+            ExprKind::Map(vec![
+                (num(1.0), str("a")),
+                (range, str("range")),
+                (call(variable("Color", 0..0), "red", vec![], true), str("x")),
+            ])
+        );
+        assert_eq!(expr("{}").kind, ExprKind::Map(vec![]));
+        assert!(Parser::new("{1 2}").unwrap().expression().is_err());
+    }
+
+    #[test]
+    fn braces_start_blocks_in_statement_position() {
+        let program = parse_program("{ }\nvar m = {}\nf.call({})").unwrap();
+
+        assert_eq!(program[0].kind, StmtKind::Block(vec![]));
+        let StmtKind::Var {
+            initializer: Some(value),
+            ..
+        } = &program[1].kind
+        else {
+            panic!("expected a variable, got {:?}", program[1]);
+        };
+        assert_eq!(value.kind, ExprKind::Map(vec![]));
+        let StmtKind::Expression(call) = &program[2].kind else {
+            panic!("expected a call, got {:?}", program[2]);
+        };
+        let ExprKind::Call { args, .. } = &call.kind else {
+            panic!("expected a call, got {call:?}");
+        };
+        assert_eq!(args[0].kind, ExprKind::Map(vec![]));
+
+        assert!(parse_program("{1: 2}").is_err());
+        assert!(parse_program("({1: 2})").is_ok());
+    }
+
+    #[test]
+    fn if_and_while_statements() {
+        let program = parse_program("if (a) b else c\nif (a)\n  b\nwhile (a) { b }").unwrap();
+
+        assert_eq!(
+            program[0].kind,
+            StmtKind::If {
+                condition: variable("a", 0..0),
+                then_branch: Box::new(Stmt {
+                    kind: StmtKind::Expression(variable("b", 0..0)),
+                    span: 0..0,
+                }),
+                else_branch: Some(Box::new(Stmt {
+                    kind: StmtKind::Expression(variable("c", 0..0)),
+                    span: 0..0,
+                })),
+            }
+        );
+        assert_eq!(program[0].span, 0..15);
+        assert!(matches!(
+            program[1].kind,
+            StmtKind::If {
+                else_branch: None,
+                ..
+            }
+        ));
+        assert!(matches!(program[2].kind, StmtKind::While { .. }));
+        assert!(parse_program("if (a) {\n} else {\n}").is_ok());
+    }
+
+    #[test]
+    fn control_flow_is_not_an_expression() {
+        assert_eq!(
+            parse_program("var x = if (c) 1 else 2"),
+            Err(ParseError::NotAnExpression {
+                token: Token::If,
+                span: 8..10,
+            })
+        );
+        assert!(matches!(
+            parse_program("var x = while (c) 1"),
+            Err(ParseError::NotAnExpression {
+                token: Token::While,
+                ..
+            })
+        ));
+        let error = parse_program("f(for (i in a) i)").unwrap_err();
+        assert_eq!(error.to_string(), "'for' is a statement, not an expression");
+    }
+
+    #[test]
+    fn method_chains_continue_across_lines() {
+        let program =
+            parse_program("list\n  .map { |x| x * 2 }\n\n  .where { |x| x > 2 }\nb").unwrap();
+
+        assert_eq!(program.len(), 2);
+        let StmtKind::Expression(chain) = &program[0].kind else {
+            panic!("expected an expression, got {:?}", program[0]);
+        };
+        let ExprKind::Call {
+            receiver: Some(receiver),
+            name,
+            ..
+        } = &chain.kind
+        else {
+            panic!("expected a call, got {chain:?}");
+        };
+        assert_eq!(name, "where");
+        assert!(matches!(&receiver.kind, ExprKind::Call { name, .. } if name == "map"));
+        assert_eq!(chain.span, 0..49);
+
+        // Any other line still starts a new statement.
+        assert_eq!(parse_program("a\n-b").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn assignment_is_right_associative() {
+        assert_eq!(expr("a = b = 5"), assign("a", assign("b", num(5.0))));
+    }
+
+    #[test]
+    fn assignment_as_subexpression() {
+        assert_eq!(
+            expr("x = (y = 3) + 1"),
+            assign(
+                "x",
+                Expr {
+                    kind: ExprKind::Binary {
+                        op: BinaryOp::Add,
+                        left: Box::new(assign("y", num(3.0))),
+                        right: Box::new(num(1.0)),
+                    },
+                    span: 0..0,
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn invalid_assignment_target() {
+        assert_eq!(
+            Parser::new("a + b = c").unwrap().expression(),
+            Err(ParseError::InvalidAssignmentTarget { span: 0..5 })
+        );
+    }
+
+    fn statements(src: &str) -> Vec<StmtKind> {
+        parse_program(src)
+            .unwrap()
+            .into_iter()
+            .map(|stmt| stmt.kind)
+            .collect()
+    }
+
+    #[test]
+    fn jump_statements() {
+        let program = parse_program("if (n < 2) return n\nreturn\n{ return }").unwrap();
+        let StmtKind::If { then_branch, .. } = &program[0].kind else {
+            panic!("expected an if, got {:?}", program[0].kind);
+        };
+        assert_eq!(
+            then_branch.kind,
+            StmtKind::Return(Some(variable("n", 18..19)))
+        );
+        assert_eq!(then_branch.span, 11..19);
+        assert_eq!(program[1].kind, StmtKind::Return(None));
+        assert_eq!(program[1].span, 20..26);
+        assert_eq!(
+            program[2].kind,
+            StmtKind::Block(vec![Stmt {
+                kind: StmtKind::Return(None),
+                span: 29..35,
+            }])
+        );
+
+        let program = parse_program("while (a) {\n break\n continue\n}").unwrap();
+        let StmtKind::While { body, .. } = &program[0].kind else {
+            panic!("expected a while, got {:?}", program[0].kind);
+        };
+        let StmtKind::Block(statements) = &body.kind else {
+            panic!("expected a block, got {:?}", body.kind);
+        };
+        let kinds: Vec<_> = statements.iter().map(|stmt| &stmt.kind).collect();
+        assert_eq!(kinds, [&StmtKind::Break, &StmtKind::Continue]);
+    }
+
+    #[test]
+    fn statement_keywords_are_not_expressions() {
+        assert_eq!(
+            parse_program("var x = break"),
+            Err(ParseError::NotAnExpression {
+                token: Token::Break,
+                span: 8..13,
+            })
+        );
+        let error = parse_program("1 + return").unwrap_err();
+        assert_eq!(
+            error,
+            ParseError::NotAnExpression {
+                token: Token::Return,
+                span: 4..10,
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "'return' is a statement, not an expression"
+        );
+        assert!(matches!(
+            parse_program("f(continue)"),
+            Err(ParseError::NotAnExpression {
+                token: Token::Continue,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn strict_and_recovering_modes() {
+        let src = "var = 1\nvar b = 2\n1 +\nvar c = ) var d = 3";
+
+        let strict = parse_program_with(src, ParserMode::Strict).unwrap_err();
+        assert_eq!(
+            strict,
+            vec![ParseError::UnexpectedToken {
+                expected: "variable name",
+                found: Token::Assignment,
+                span: 4..5,
+            }]
+        );
+
+        let recovered = parse_program_with(src, ParserMode::Recover).unwrap_err();
+        let spans: Vec<_> = recovered.iter().map(ParseError::span).collect();
+        assert_eq!(spans, vec![4..5, 22..25, 30..31]);
+        assert_eq!(recovered[0], strict[0]);
+
+        assert_eq!(
+            parse_program_with("var a = 1 @", ParserMode::Recover).unwrap_err(),
+            vec![ParseError::Lex(LexError::unexpected(10..11))]
+        );
+        assert!(parse_program_with("var a = 1", ParserMode::Recover).is_ok());
+    }
+
+    #[test]
+    fn newline_separates_statements() {
+        assert_eq!(
+            statements("a\n b"),
+            [
+                StmtKind::Expression(variable("a", 0..1)),
+                StmtKind::Expression(variable("b", 3..4)),
+            ]
+        );
+        assert_eq!(
+            statements("a\n-1"),
+            [
+                StmtKind::Expression(variable("a", 0..1)),
+                StmtKind::Expression(Expr {
+                    kind: ExprKind::Literal(LiteralValue::Num(-1.0, None)),
+                    span: 2..4,
+                }),
+            ]
+        );
+        assert_eq!(statements("\n\n{ a\n\n b }\n").len(), 1);
+    }
+
+    #[test]
+    fn newline_inside_grouping_continues() {
+        let sum = |offset| Expr {
+            kind: ExprKind::Binary {
+                op: BinaryOp::Add,
+                left: Box::new(variable("a", offset..offset + 1)),
+                right: Box::new(variable("b", offset + 5..offset + 6)),
+            },
+            span: offset..offset + 6,
+        };
+        assert_eq!(
+            statements("(a +\n b)"),
+            [StmtKind::Expression(Expr {
+                kind: sum(1).kind,
+                span: 0..8,
+            })]
+        );
+        // An operator at the end of a line also continues at
+        // statement level.
+        assert_eq!(statements("a +\n b"), [StmtKind::Expression(sum(0))]);
+        assert_eq!(statements("(a\n + b)").len(), 1);
+
+        assert_eq!(statements("f.call(\n  a,\n  b\n)").len(), 1);
+        assert_eq!(statements("var m = {\n  1: 2,\n  3: 4,\n}").len(), 1);
+        assert_eq!(statements("x[\n 1\n]").len(), 1);
+    }
+
+    #[test]
+    fn block_inside_grouping_separates_statements() {
+        let decl = class("class A {\n  f(x,\n    y) {\n    x\n    y\n  }\n}");
+        assert_eq!(decl.members[0].params, ["x", "y"]);
+        assert_eq!(decl.members[0].body.len(), 2);
+    }
+
+    #[test]
+    fn var_statement() {
+        assert_eq!(
+            parse_program("var a = 1 var b").unwrap(),
+            vec![
+                Stmt {
+                    kind: StmtKind::Var {
+                        name: "a".to_string(),
+                        initializer: Some(num(1.0)),
+                    },
+                    span: 0..9,
+                },
+                Stmt {
+                    kind: StmtKind::Var {
+                        name: "b".to_string(),
+                        initializer: None,
+                    },
+                    span: 10..15,
+                },
+            ]
+        );
+    }
+
+    fn class(src: &str) -> ClassDecl {
+        match parse_program(src).unwrap().remove(0).kind {
+            StmtKind::Class(class) => class,
+            other => panic!("expected a class, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn class_members() {
+        let class = class(
+            "class Point is Object {
+                construct new(x, y) {}
+                x {}
+                x=(value) {}
+                moveBy(dx, dy) {}
+                static origin {}
+            }",
+        );
+
+        assert_eq!(class.name, "Point");
+        assert_eq!(class.superclass.as_deref(), Some("Object"));
+        let signatures: Vec<_> = class
+            .members
+            .iter()
+            .map(|m| (m.kind, m.signature(), m.is_static))
+            .collect();
+        assert_eq!(
+            signatures,
+            vec![
+                (MemberKind::Constructor, "new(_,_)".to_string(), false),
+                (MemberKind::Getter, "x".to_string(), false),
+                (MemberKind::Setter, "x=(_)".to_string(), false),
+                (MemberKind::Method, "moveBy(_,_)".to_string(), false),
+                (MemberKind::Getter, "origin".to_string(), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn expression_bodies() {
+        let class = class("class A {\n  one { 1 }\n  two {\n    2\n  }\n  three { a = 3 }\n}");
+        let bodies: Vec<_> = class
+            .members
+            .iter()
+            .map(|m| (m.name.as_str(), m.expression_body))
+            .collect();
+        assert_eq!(bodies, [("one", true), ("two", false), ("three", true)]);
+    }
+
+    #[test]
+    fn mixed_members_with_attributes() {
+        let class = class(
+            r#"class Shape {
+                #!count = 2
+                static count {}
+                #doc = "Makes a shape."
+                construct new() {}
+                #group(visible, order = 1)
+                area() {}
+                name {}
+                name=(value) {}
+                +(other) {}
+                -{}
+                foreign static load(path)
+            }"#,
+        );
+
+        let members: Vec<_> = class
+            .members
+            .iter()
+            .map(|m| (m.kind, m.signature(), m.is_static, m.is_foreign))
+            .collect();
+        assert_eq!(
+            members,
+            vec![
+                (MemberKind::Getter, "count".to_string(), true, false),
+                (MemberKind::Constructor, "new()".to_string(), false, false),
+                (MemberKind::Method, "area()".to_string(), false, false),
+                (MemberKind::Getter, "name".to_string(), false, false),
+                (MemberKind::Setter, "name=(_)".to_string(), false, false),
+                (MemberKind::Method, "+(_)".to_string(), false, false),
+                (MemberKind::Getter, "-".to_string(), false, false),
+                (MemberKind::Method, "load(_)".to_string(), true, true),
+            ]
+        );
+        assert_eq!(
+            class.members[0].attributes,
+            vec![Attribute {
+                name: "count".to_string(),
+                value: Some(LiteralValue::Num(2.0, None)),
+                group: Vec::new(),
+                runtime: true,
+            }]
+        );
+        assert_eq!(
+            class.members[1].attributes[0].value,
+            Some(LiteralValue::Str("Makes a shape.".to_string()))
+        );
+        assert_eq!(
+            class.members[2].attributes[0].group,
+            vec![
+                ("visible".to_string(), None),
+                ("order".to_string(), Some(LiteralValue::Num(1.0, None))),
+            ]
+        );
+        assert!(class.members[3].attributes.is_empty());
+    }
+
+    #[test]
+    fn doc_comments() {
+        let class = class(
+            "// Not about the class.
+
+            /// A point in the plane.
+            class Point {
+                /// Moves the point by the given offsets,
+                /// in place.
+                static moveBy(dx, dy) {} // Trailing.
+                // Not about x, either.
+
+                x {}
+            }",
+        );
+
+        assert_eq!(class.doc.as_deref(), Some("A point in the plane."));
+        assert_eq!(
+            class.members[0].doc.as_deref(),
+            Some("Moves the point by the given offsets,\nin place.")
+        );
+        assert_eq!(class.members[1].doc, None);
+
+        // Block comments are skipped, but do not document.
+        let commented = self::class("/* Not a doc. */\nclass A { f { 1 /* a /* b */ */ + 2 } }");
+        assert_eq!(commented.doc, None);
+        assert!(matches!(
+            &commented.members[0].body[0].kind,
+            StmtKind::Expression(Expr {
+                kind: ExprKind::Binary { .. },
+                ..
+            })
+        ));
+
+        assert!(parse_program("/* café a@b */ 1").is_ok());
+        assert_eq!(
+            parse_program("1 /* never closed"),
+            Err(ParseError::Lex(LexError {
+                span: 2..17,
+                kind: LexErrorKind::UnterminatedComment,
+            }))
+        );
+    }
+
+    #[test]
+    fn constructor_chains_to_superclass() {
+        let decl = class("class B is A { construct new(a) { super(a) _b = a } }");
+
+        assert_eq!(decl.superclass.as_deref(), Some("A"));
+        let body = &decl.members[0].body;
+        assert_eq!(body.len(), 2);
+        assert_eq!(
+            body[0].kind,
+            StmtKind::Expression(Expr {
+                kind: ExprKind::Super {
+                    name: None,
+                    args: vec![variable("a", 39..40)],
+                    kind: MemberKind::Constructor,
+                },
+                span: 33..41,
+            })
+        );
+
+        let Stmt {
+            kind: StmtKind::Expression(call),
+            ..
+        } = &class("class B is A { f { super.f(1, 2).g } }").members[0].body[0]
+        else {
+            panic!("expected an expression statement");
+        };
+        let ExprKind::Call { receiver, name, .. } = &call.kind else {
+            panic!("expected a call on the super call");
+        };
+        assert_eq!(name, "g");
+        assert!(matches!(
+            &receiver.as_deref().unwrap().kind,
+            ExprKind::Super { name: Some(name), args, kind: MemberKind::Method }
+                if name == "f" && args.len() == 2
+        ));
+    }
+
+    #[test]
+    fn super_getter_and_setter() {
+        let decl = class("class B is A { f { super.name } g { super.name = 5 } }");
+        let [getter, setter] = [0, 1].map(|i| match &decl.members[i].body[0].kind {
+            StmtKind::Expression(expr) => expr.clone(),
+            kind => panic!("expected an expression, found {kind:?}"),
+        });
+
+        assert_eq!(
+            getter,
+            Expr {
+                kind: ExprKind::Super {
+                    name: Some("name".into()),
+                    args: vec![],
+                    kind: MemberKind::Getter,
+                },
+                span: 19..29,
+            }
+        );
+        let ExprKind::Super {
+            name: Some(name),
+            args,
+            kind: MemberKind::Setter,
+        } = setter.kind
+        else {
+            panic!("expected a super setter call");
+        };
+        assert_eq!(name, "name");
+        assert!(matches!(
+            args.as_slice(),
+            [Expr { kind: ExprKind::Literal(LiteralValue::Num(n, _)), .. }] if *n == 5.0
+        ));
+        assert_eq!(setter.span, 36..50);
+
+        // Only the setter form can be assigned to.
+        assert!(matches!(
+            parse_program("class B is A { f { super.g() = 1 } }"),
+            Err(ParseError::InvalidAssignmentTarget { .. })
+        ));
+    }
+
+    #[test]
+    fn duplicate_method() {
+        let src = "class A { foo() {} foo() {} }";
+
+        assert_eq!(
+            parse_program(src),
+            Err(ParseError::DuplicateMethod {
+                signature: "foo()".to_string(),
+                span: 19..27,
+                previous: 10..18,
+            })
+        );
+    }
+
+    #[test]
+    fn operator_members() {
+        let class = class("class V { +(other) {} - {} -(other) {} ==(other) {} ! {} }");
+        let signatures: Vec<_> = class.members.iter().map(|m| m.signature()).collect();
+
+        assert_eq!(signatures, ["+(_)", "-", "-(_)", "==(_)", "!"]);
+        assert!(parse_program("class V { &&(other) {} }").is_err());
+    }
+
+    #[test]
+    fn getter_and_setter_coexist() {
+        let class = class("class A { foo {} foo=(value) {} foo() {} static foo {} }");
+
+        assert_eq!(class.members.len(), 4);
+    }
+
+    #[test]
+    fn duplicate_constructor() {
+        let src = "class A {\n  construct new() {}\n  construct new() {}\n}";
+
+        assert_eq!(
+            parse_program(src),
+            Err(ParseError::DuplicateMethod {
+                signature: "new()".to_string(),
+                span: 33..51,
+                previous: 12..30,
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_constructors() {
+        assert_eq!(
+            parse_program("class A { static construct new() {} }"),
+            Err(ParseError::InvalidConstructor {
+                modifier: Token::Static,
+                span: 10..16,
+            })
+        );
+        assert_eq!(
+            parse_program("class A { foreign construct new() {} }"),
+            Err(ParseError::InvalidConstructor {
+                modifier: Token::Foreign,
+                span: 10..17,
+            })
+        );
+        assert!(parse_program("class A {\n  foreign construct new()\n}").is_ok());
+    }
+
+    #[test]
+    fn number_literals() {
+        let cases = [
+            ("0xFF", 255.0),
+            ("0xff_ff", 65535.0),
+            ("1_000.5", 1000.5),
+            ("1_000_000", 1e6),
+            (".5", 0.5),
+        ];
+        for (src, value) in cases {
+            assert_eq!(
+                expr(src).kind,
+                ExprKind::Literal(LiteralValue::Num(value, None)),
+                "{src}"
+            );
+        }
+    }
+
+    #[test]
+    fn number_literals_keep_their_text() {
+        let ExprKind::Literal(LiteralValue::Num(value, raw)) = expr("1_000").kind else {
+            panic!("expected a number");
+        };
+        assert_eq!(value, 1000.0);
+        assert_eq!(raw.as_deref(), Some("1_000"));
+
+        let ExprKind::Literal(LiteralValue::Num(_, raw)) = expr("0xff").kind else {
+            panic!("expected a number");
+        };
+        assert_eq!(raw.as_deref(), Some("0xff"));
+    }
+
+    #[test]
+    fn string_literal() {
+        assert_eq!(
+            expr(r#""a \"quoted\"\n\tline""#).kind,
+            ExprKind::Literal(LiteralValue::Str("a \"quoted\"\n\tline".to_string()))
+        );
+    }
+
+    #[test]
+    fn null_escape() {
+        let ExprKind::Literal(LiteralValue::Str(value)) = expr(r#""a\0b""#).kind else {
+            panic!("expected a string");
+        };
+
+        assert_eq!(value, "a\0b");
+        assert_eq!(value.len(), 3);
+    }
+
+    #[test]
+    fn wren_escapes() {
+        let string = |src| match expr(src).kind {
+            ExprKind::Literal(LiteralValue::Str(value)) => value,
+            kind => panic!("expected a string, got {kind:?}"),
+        };
+
+        assert_eq!(string(r#""\a\b\e\f\v\%""#), "\x07\x08\x1b\x0c\x0b%");
+        assert_eq!(string(r#""\x41\x7a!""#), "Az!");
+        assert_eq!(string(r#""\u00e9\u20AC""#), "é€");
+        assert_eq!(string(r#""\U0001F600.""#), "\u{1f600}.");
+        for (src, span) in [
+            (r#""\x4""#, 1..4),
+            (r#""\xg1""#, 1..5),
+            (r#""a\u12""#, 2..6),
+            (r#""\UFFFFFFFF""#, 1..11),
+            (r#""\ud800""#, 1..7),
+        ] {
+            let error = Parser::new(src).unwrap().expression();
+            assert_eq!(error, Err(ParseError::InvalidEscape { span }), "{src}");
+        }
+    }
+
+    #[test]
+    fn unknown_escapes() {
+        let parse = |policy| {
+            Parser::new(r#""a\qé""#)
+                .unwrap()
+                .escapes(policy)
+                .expression()
+        };
+
+        assert_eq!(
+            parse(EscapePolicy::Strict),
+            Err(ParseError::InvalidEscape { span: 2..4 })
+        );
+        assert_eq!(
+            parse(EscapePolicy::Lenient).map(|expr| expr.kind),
+            Ok(ExprKind::Literal(LiteralValue::Str("aqé".to_string())))
+        );
+        // Wren's own escapes decode the same under either policy.
+        let escape = Parser::new(r#""\e[0m""#)
+            .unwrap()
+            .escapes(EscapePolicy::Lenient)
+            .expression();
+        assert_eq!(
+            escape.map(|expr| expr.kind),
+            Ok(ExprKind::Literal(LiteralValue::Str("\x1b[0m".to_string())))
+        );
+        assert_eq!(
+            parse_program(r#"import "a\é""#),
+            Err(ParseError::InvalidEscape { span: 9..12 })
+        );
+    }
+
+    #[test]
+    fn import_statement() {
+        let program = parse_program(r#"import "a" import "b" for X, Y as Z"#).unwrap();
+
+        assert_eq!(
+            program[0].kind,
+            StmtKind::Import {
+                module: "a".to_string(),
+                names: vec![],
+            }
+        );
+        assert_eq!(
+            program[1].kind,
+            StmtKind::Import {
+                module: "b".to_string(),
+                names: vec![
+                    ("X".to_string(), None),
+                    ("Y".to_string(), Some("Z".to_string()))
+                ],
+            }
+        );
+        assert_eq!(program[1].span, 11..35);
+    }
+
+    #[test]
+    fn import_name_lists() {
+        let program = parse_program(r#"import "foo" for A, B, C"#).unwrap();
+        assert_eq!(
+            program[0].kind,
+            StmtKind::Import {
+                module: "foo".to_string(),
+                names: vec![
+                    ("A".to_string(), None),
+                    ("B".to_string(), None),
+                    ("C".to_string(), None)
+                ],
+            }
+        );
+
+        assert_eq!(
+            parse_program(r#"import "foo" for"#),
+            Err(ParseError::UnexpectedEnd {
+                expected: "variable name to import",
+                span: 16..16
+            })
+        );
+        assert_eq!(
+            parse_program("import \"foo\" for\nvar a = 1"),
+            Err(ParseError::UnexpectedToken {
+                expected: "variable name to import",
+                found: Token::Newline,
+                span: 16..17
+            })
+        );
+        assert!(parse_program(r#"import "foo" for A,"#).is_err());
+    }
+
+    #[test]
+    fn chained_comparison_warns() {
+        let mut parser = Parser::new("a < b < c").unwrap();
+        let expr = parser.expression().unwrap();
+
+        let ExprKind::Binary {
+            op: BinaryOp::LessThan,
+            left,
+            right,
+        } = expr.kind
+        else {
+            panic!("expected a comparison, got {expr:?}");
+        };
+        assert!(matches!(
+            left.kind,
+            ExprKind::Binary {
+                op: BinaryOp::LessThan,
+                ..
+            }
+        ));
+        assert_eq!(*right, variable("c", 8..9));
+
+        assert_eq!(parser.diagnostics().len(), 1);
+        assert_eq!(parser.diagnostics()[0].severity, Severity::Warning);
+        assert_eq!(parser.diagnostics()[0].span, 0..9);
+    }
+
+    #[test]
+    fn parenthesized_comparison_does_not_warn() {
+        let mut parser = Parser::new("(a < b) < c").unwrap();
+        parser.expression().unwrap();
+        assert!(parser.diagnostics().is_empty());
+
+        let mut parser = Parser::new("a < b < c").unwrap().lints(false);
+        parser.expression().unwrap();
+        assert!(parser.diagnostics().is_empty());
+    }
+
+    #[test]
+    fn signed_number_in_infix_position() {
+        let program = parse_program("a -1").unwrap();
+
+        assert!(matches!(
+            &program[0].kind,
+            StmtKind::Expression(Expr {
+                kind: ExprKind::Binary {
+                    op: BinaryOp::Subtract,
+                    ..
+                },
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn unicode_names_are_normalized() {
+        use crate::compiler::lexer::{lex_collect_errors_with, LexerExtras};
+
+        // Precomposed, then with a combining accent.
+        let src = "var caf\u{e9} = 1\ncafe\u{301}";
+        let (tokens, errors) =
+            lex_collect_errors_with(src, LexerExtras::with_unicode_identifiers());
+        assert!(errors.is_empty());
+        let program = Parser::with_tokens(src, tokens).program().unwrap();
+
+        let StmtKind::Var { name, .. } = &program[0].kind else {
+            panic!("expected a variable, got {:?}", program[0]);
+        };
+        assert_eq!(name, "caf\u{e9}");
+        assert_eq!(
+            program[1].kind,
+            StmtKind::Expression(variable("caf\u{e9}", 0..0))
+        );
+        assert_eq!(program[1].span, 14..20);
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error() {
+        let depth = 100_000;
+        let src = format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+
+        assert!(matches!(
+            parse_program(&src),
+            Err(ParseError::TooDeep { span }) if span == (255..256)
+        ));
+
+        let blocks = format!("{}{}", "{".repeat(depth), "}".repeat(depth));
+        assert!(matches!(
+            parse_program(&blocks),
+            Err(ParseError::TooDeep { .. })
+        ));
+
+        // Flat chains are as long as they like; only their right
+        // operands nest.
+        let sum = format!("System.print({}a)", "a + ".repeat(5000));
+        assert!(parse_program(&sum).is_ok());
+        let calls = format!("a{}[0]", ".b".repeat(5000));
+        assert!(parse_program(&calls).is_ok());
+        let unary = format!("{}a", "!".repeat(5000));
+        assert!(matches!(
+            parse_program(&unary),
+            Err(ParseError::TooDeep { .. })
+        ));
+
+        let assignments = format!("{}1", "a = ".repeat(5000));
+        assert!(matches!(
+            parse_program(&assignments),
+            Err(ParseError::TooDeep { .. })
+        ));
+
+        let shallow = format!("{}1{}", "(".repeat(10), ")".repeat(10));
+        let mut parser = Parser::new(&shallow).unwrap().max_depth(5);
+        assert!(matches!(parser.program(), Err(ParseError::TooDeep { .. })));
+    }
+
+    #[test]
+    fn rewind_replays_tokens() {
+        let mut parser = Parser::new("a + (b\n) * c").unwrap();
+        parser.advance();
+        let checkpoint = parser.checkpoint();
+
+        let read = |parser: &mut Parser| -> Vec<SpannedToken> {
+            (0..3)
+                .map(|_| {
+                    let token = parser.current_token().unwrap().clone();
+                    parser.advance();
+                    token
+                })
+                .collect()
+        };
+        let first = read(&mut parser);
+        parser.rewind(checkpoint);
+
+        assert_eq!(parser.checkpoint(), checkpoint);
+        assert_eq!(read(&mut parser), first);
+        assert_eq!(first[2].span, 5..6);
+
+        // What follows a rewind parses as if nothing had been read.
+        parser.rewind(checkpoint);
+        parser.advance();
+        let expr = parser.expression().unwrap();
+        assert_eq!(expr.span, 4..12);
+    }
+
+    #[test]
+    fn argument_limit() {
+        let names = |n: usize| {
+            (0..n)
+                .map(|i| format!("a{i}"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        assert!(parse_program(&format!("f.call({})", names(16))).is_ok());
+        assert!(parse_program(&format!("class A {{ m({}) {{}} }}", names(16))).is_ok());
+
+        // `a16` starts at 77, after sixteen names and separators.
+        assert_eq!(
+            parse_program(&format!("f.call({})", names(17))),
+            Err(ParseError::TooManyArguments { span: 77..80 })
+        );
+        assert!(matches!(
+            parse_program(&format!("class A {{ m({}) {{}} }}", names(17))),
+            Err(ParseError::TooManyArguments { .. })
+        ));
+        assert!(matches!(
+            parse_program(&format!("f.call({}) {{}}", names(16))),
+            Err(ParseError::TooManyArguments { .. })
+        ));
+        assert!(matches!(
+            parse_program(&format!("Fn.new {{ |{}| }}", names(17))),
+            Err(ParseError::TooManyArguments { .. })
+        ));
+
+        let mut parser = Parser::new("f.call(1, 2, 3)").unwrap().max_arguments(2);
+        assert_eq!(
+            parser.program(),
+            Err(ParseError::TooManyArguments { span: 13..14 })
+        );
+    }
+
+    // Parses random sequences of tokens and stray characters,
+    // which must produce errors rather than panics.
+    #[test]
+    fn random_input_never_panics() {
+        const FRAGMENTS: &[&str] = &[
+            "(",
+            ")",
+            "{",
+            "}",
+            "[",
+            "]",
+            ".",
+            ",",
+            "=",
+            "+",
+            "-",
+            "*",
+            "<",
+            "..",
+            "...",
+            "==",
+            "&&",
+            "||",
+            "in",
+            "is",
+            "for",
+            "var",
+            "class",
+            "import",
+            "static",
+            "construct",
+            "as",
+            "this",
+            "null",
+            "true",
+            "a",
+            "b",
+            "1",
+            "-2",
+            "0x1f",
+            "1.5",
+            "\"s\"",
+            "\"",
+            "#",
+            "@",
+            "é",
+            "\n",
+        ];
+
+        // xorshift64, so runs are reproducible without extra dependencies.
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for _ in 0..10_000 {
+            let len = next() % 40;
+            let src: Vec<&str> = (0..len)
+                .map(|_| FRAGMENTS[(next() % FRAGMENTS.len() as u64) as usize])
+                .collect();
+            let src = src.join(if next() % 2 == 0 { " " } else { "" });
+            let _ = parse_program(&src);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use std::ops::Range;
+
+/*
+ *
+ * Spans
+ *
+ * Byte ranges into the source, end exclusive.
+ * Nodes are spanned from their first token to
+ * their last, which `merge` computes from the
+ * spans of their parts.
+ *
+ * Lines and columns are `u32`, so computing them
+ * is checked: a position past `u32::MAX` is an
+ * error rather than a silently wrapped number.
+ *
+ * `position` scans the source up to the offset.
+ * Code looking up many offsets in one source builds
+ * a `LineIndex` instead, which finds the line with
+ * a binary search over where each line starts.
+ *
+ */
+
+pub type Span = Range<usize>;
+
+/// The smallest span covering both `a` and `b`.
+pub fn merge(a: &Span, b: &Span) -> Span {
+    a.start.min(b.start)..a.end.max(b.end)
+}
+
+pub fn len(span: &Span) -> usize {
+    span.end.saturating_sub(span.start)
+}
+
+/// Whether `offset` falls inside `span`. An empty span, such
+/// as the one reported at the end of input, contains its start.
+pub fn contains(span: &Span, offset: usize) -> bool {
+    span.contains(&offset) || (span.start == span.end && offset == span.start)
+}
+
+/// A 1-based line and column, counting columns in characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub line: u32,
+    pub column: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PositionError {
+    /// The offset is past the end of the source or not on a
+    /// character boundary.
+    OutOfBounds,
+    /// The line or column does not fit in a `u32`.
+    Overflow,
+}
+
+impl std::fmt::Display for PositionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PositionError::OutOfBounds => write!(f, "offset is outside the source"),
+            PositionError::Overflow => {
+                write!(f, "position does not fit in a 32-bit line or column")
+            }
+        }
+    }
+}
+
+/// The position of byte `offset` in `src`.
+pub fn position(src: &str, offset: usize) -> Result<Position, PositionError> {
+    let before = src.get(..offset).ok_or(PositionError::OutOfBounds)?;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    Ok(Position {
+        line: advance(1, before.matches('\n').count())?,
+        column: advance(1, before[line_start..].chars().count())?,
+    })
+}
+
+/// The offsets lines start at in a source, for looking up many
+/// positions in it.
+#[derive(Debug, Clone)]
+pub struct LineIndex<'src> {
+    src: &'src str,
+    line_starts: Vec<usize>,
+}
+
+impl<'src> LineIndex<'src> {
+    pub fn new(src: &'src str) -> Self {
+        let newlines = src.match_indices('\n').map(|(i, _)| i + 1);
+        LineIndex {
+            src,
+            line_starts: std::iter::once(0).chain(newlines).collect(),
+        }
+    }
+
+    /// The position of byte `offset`, the same as `position` gives.
+    pub fn line_col(&self, offset: usize) -> Result<Position, PositionError> {
+        if !self.src.is_char_boundary(offset) {
+            return Err(PositionError::OutOfBounds);
+        }
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next) => next - 1,
+        };
+        let column = self.src[self.line_starts[line]..offset].chars().count();
+        Ok(Position {
+            line: advance(1, line)?,
+            column: advance(1, column)?,
+        })
+    }
+}
+
+/// `base` moved forward by `by` lines or columns.
+pub fn advance(base: u32, by: usize) -> Result<u32, PositionError> {
+    u32::try_from(by)
+        .ok()
+        .and_then(|by| base.checked_add(by))
+        .ok_or(PositionError::Overflow)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn merge_covers_both() {
+        assert_eq!(merge(&(2..5), &(8..10)), 2..10);
+        assert_eq!(merge(&(8..10), &(2..5)), 2..10);
+        assert_eq!(merge(&(2..10), &(4..6)), 2..10);
+        assert_eq!(len(&merge(&(2..5), &(8..10))), 8);
+    }
+
+    #[test]
+    fn contains_at_boundaries() {
+        assert!(!contains(&(2..5), 1));
+        assert!(contains(&(2..5), 2));
+        assert!(contains(&(2..5), 4));
+        assert!(!contains(&(2..5), 5));
+
+        assert!(contains(&(3..3), 3));
+        assert!(!contains(&(3..3), 4));
+        assert_eq!(len(&(3..3)), 0);
+    }
+
+    #[test]
+    fn position_of_offset() {
+        let src = "a\nbé\nc";
+        assert_eq!(position(src, 0), Ok(Position { line: 1, column: 1 }));
+        assert_eq!(position(src, 5), Ok(Position { line: 2, column: 3 }));
+        assert_eq!(
+            position(src, src.len()),
+            Ok(Position { line: 3, column: 2 })
+        );
+        assert_eq!(position(src, 4), Err(PositionError::OutOfBounds));
+        assert_eq!(position(src, 100), Err(PositionError::OutOfBounds));
+    }
+
+    #[test]
+    fn line_index_matches_position() {
+        for src in ["a\nbé\n\nc", "a\nbé\n\nc\n", "", "\n"] {
+            let index = LineIndex::new(src);
+            for offset in 0..=src.len() + 1 {
+                assert_eq!(
+                    index.line_col(offset),
+                    position(src, offset),
+                    "{src:?} {offset}"
+                );
+            }
+        }
+
+        let index = LineIndex::new("ab\ncd\n");
+        assert_eq!(index.line_col(4), Ok(Position { line: 2, column: 2 }));
+        assert_eq!(index.line_col(6), Ok(Position { line: 3, column: 1 }));
+        assert_eq!(
+            LineIndex::new("ab\ncd").line_col(5),
+            Ok(Position { line: 2, column: 3 })
+        );
+    }
+
+    #[test]
+    fn advance_near_u32_max() {
+        assert_eq!(advance(u32::MAX - 1, 1), Ok(u32::MAX));
+        assert_eq!(advance(u32::MAX, 1), Err(PositionError::Overflow));
+        assert_eq!(advance(1, u32::MAX as usize), Err(PositionError::Overflow));
+        if let Some(lines) = (u32::MAX as usize).checked_add(10) {
+            assert_eq!(advance(0, lines), Err(PositionError::Overflow));
+        }
+    }
+}
@@ -1,5 +1,143 @@
-mod compiler;
+use std::path::Path;
+use std::process::ExitCode;
+use std::rc::Rc;
 
-fn main() {
-    println!("Hello, world!");
+use wren_lift::compiler::codegen::{compile_to_sink, CompileOptions};
+use wren_lift::compiler::diagnostic::{Diagnostic, StderrSink};
+use wren_lift::compiler::error::CompileError;
+use wren_lift::compiler::lexer::lex_collect_errors;
+use wren_lift::compiler::loader::FileSystemLoader;
+use wren_lift::compiler::modules::{compile_imports, ImportError};
+use wren_lift::compiler::parser::parse_program;
+use wren_lift::vm::chunk::{Chunk, Function};
+use wren_lift::vm::disasm::disassemble;
+use wren_lift::vm::Vm;
+
+/*
+ *
+ * Command Line
+ *
+ *   wren-jit run <file>     runs a script
+ *   wren-jit lex <file>     lists its tokens
+ *   wren-jit parse <file>   dumps its syntax tree
+ *   wren-jit disasm <file>  lists its bytecode
+ *
+ * A script run imports modules from the files
+ * next to it: `import "a/b"` reads `a/b.wren`
+ * in the script's directory.
+ *
+ * Exit codes follow the reference implementation:
+ * 64 for bad usage, 65 when the script does not
+ * compile, 66 when it cannot be read and 70 when
+ * it fails at runtime.
+ *
+ */
+
+const USAGE: &str = "usage: wren-jit <run|lex|parse|disasm> <file>";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [command, path] = &args[..] else {
+        eprintln!("{USAGE}");
+        return ExitCode::from(64);
+    };
+    let src = match std::fs::read_to_string(path) {
+        Ok(src) => src,
+        Err(error) => {
+            eprintln!("could not read '{path}': {error}");
+            return ExitCode::from(66);
+        }
+    };
+    match command.as_str() {
+        "run" => run(path, &src),
+        "lex" => lex(&src),
+        "parse" => parse(&src),
+        "disasm" => disasm(&src),
+        _ => {
+            eprintln!("{USAGE}");
+            ExitCode::from(64)
+        }
+    }
+}
+
+fn run(path: &str, src: &str) -> ExitCode {
+    let Some(chunk) = compile_reporting(src) else {
+        return ExitCode::from(65);
+    };
+    let root = Path::new(path).parent().unwrap_or(Path::new("."));
+    let modules = match compile_imports(src, &FileSystemLoader::new(root)) {
+        Ok(modules) => modules,
+        Err(ImportError::Load(error)) => {
+            eprintln!("{error}");
+            return ExitCode::from(66);
+        }
+        Err(ImportError::Compile {
+            module,
+            src,
+            errors,
+        }) => {
+            eprintln!("in module '{module}':");
+            return report(&src, &errors);
+        }
+    };
+    let mut vm = Vm::new();
+    for (name, chunk) in modules {
+        vm.add_module(&name, chunk);
+    }
+    match vm.run(Rc::new(Function::script(chunk))) {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(error) => {
+            match vm.error_span() {
+                Some(span) => eprint!("{}", Diagnostic::error(error.to_string(), span).render(src)),
+                None => eprintln!("runtime error: {error}"),
+            }
+            ExitCode::from(70)
+        }
+    }
+}
+
+fn lex(src: &str) -> ExitCode {
+    let (tokens, errors) = lex_collect_errors(src);
+    for token in tokens {
+        let text = token.text(src);
+        println!("{:?} {:?} {text:?}", token.span, token.token);
+    }
+    if errors.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        let errors: Vec<CompileError> = errors.into_iter().map(CompileError::from).collect();
+        report(src, &errors)
+    }
+}
+
+fn parse(src: &str) -> ExitCode {
+    match parse_program(src) {
+        Ok(program) => {
+            println!("{program:#?}");
+            ExitCode::SUCCESS
+        }
+        Err(error) => report(src, &[error.into()]),
+    }
+}
+
+fn disasm(src: &str) -> ExitCode {
+    match compile_reporting(src) {
+        Some(chunk) => {
+            print!("{}", disassemble("<script>", &chunk));
+            ExitCode::SUCCESS
+        }
+        None => ExitCode::from(65),
+    }
+}
+
+// Compiles `src`, printing its errors and warnings as they are found.
+fn compile_reporting(src: &str) -> Option<Chunk> {
+    compile_to_sink(src, CompileOptions::default(), &mut StderrSink::new(src))
+}
+
+fn report(src: &str, errors: &[CompileError]) -> ExitCode {
+    for error in errors {
+        eprint!("{}", error.diagnostic().render(src));
+    }
+    ExitCode::from(65)
 }
@@ -0,0 +1,177 @@
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use crate::compiler::ast::{Stmt, StmtKind};
+use crate::compiler::codegen::compile_entry;
+use crate::compiler::diagnostic::Diagnostic;
+use crate::compiler::error::CompileError;
+use crate::compiler::parser::{parse_expr_str, parse_program, ParseError};
+use crate::vm::chunk::Function;
+use crate::vm::value::Value;
+use crate::vm::{RuntimeError, Vm};
+
+/*
+ *
+ * REPL
+ *
+ * Evaluates lines typed into an interactive shell
+ * in the main module of a single VM, so variables
+ * declared by one line are visible to the next.
+ *
+ * A line that ends in the middle of a statement,
+ * such as inside an unclosed block, is kept and
+ * evaluated together with the lines that follow.
+ *
+ * Hosts evaluating a lone expression, such as a
+ * configuration value, can use `eval_expr_str`
+ * instead, which runs it in a VM of its own.
+ *
+ */
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplError {
+    /// The input so far is incomplete; keep reading lines.
+    NeedMoreInput,
+    Compile(Vec<CompileError>),
+    Runtime(RuntimeError),
+}
+
+pub struct Repl {
+    vm: Vm,
+    module_variables: HashSet<String>,
+    pending: String,
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        Self::with_vm(Vm::new())
+    }
+
+    /// A REPL running on `vm`, e.g. one with its output redirected.
+    pub fn with_vm(vm: Vm) -> Self {
+        Repl {
+            vm,
+            module_variables: HashSet::new(),
+            pending: String::new(),
+        }
+    }
+
+    pub fn vm(&self) -> &Vm {
+        &self.vm
+    }
+
+    /// Evaluates `line`, after any lines still waiting for more input,
+    /// returning the value of a trailing expression.
+    pub fn eval_line(&mut self, line: &str) -> Result<Value, ReplError> {
+        self.pending.push_str(line);
+        self.pending.push('\n');
+
+        let program = match parse_program(&self.pending) {
+            Ok(program) => program,
+            Err(ParseError::UnexpectedEnd { .. }) => return Err(ReplError::NeedMoreInput),
+            Err(error) => {
+                self.pending.clear();
+                return Err(ReplError::Compile(vec![error.into()]));
+            }
+        };
+        self.pending.clear();
+
+        let chunk =
+            compile_entry(&program, &mut self.module_variables).map_err(ReplError::Compile)?;
+        self.vm
+            .run(Rc::new(Function::script(chunk)))
+            .map_err(ReplError::Runtime)
+    }
+}
+
+/// Evaluates the expression `src` in a fresh VM, with no variables
+/// but the core ones. Runtime errors are reported at the part of
+/// the expression that failed.
+pub fn eval_expr_str(src: &str) -> Result<Value, Vec<Diagnostic>> {
+    let expr = parse_expr_str(src)?;
+    let span = expr.span.clone();
+    let program = [Stmt {
+        kind: StmtKind::Expression(expr),
+        span: span.clone(),
+    }];
+    let chunk = compile_entry(&program, &mut HashSet::new()).map_err(|errors| {
+        errors
+            .iter()
+            .map(CompileError::diagnostic)
+            .collect::<Vec<_>>()
+    })?;
+    let mut vm = Vm::new();
+    vm.run(Rc::new(Function::script(chunk))).map_err(|error| {
+        let span = vm.error_span().unwrap_or(span);
+        vec![Diagnostic::error(error.to_string(), span)]
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn session_keeps_variables() {
+        let mut repl = Repl::new();
+
+        assert_eq!(repl.eval_line("var x = 1"), Ok(Value::Null));
+        assert_eq!(repl.eval_line("x + 1"), Ok(Value::Num(2.0)));
+        assert_eq!(repl.eval_line("var y = x * 10 y"), Ok(Value::Num(10.0)));
+        assert!(matches!(repl.eval_line("z"), Err(ReplError::Compile(_))));
+        assert!(matches!(
+            repl.eval_line("var x = 2"),
+            Err(ReplError::Compile(_))
+        ));
+        assert_eq!(repl.eval_line("x = x + y"), Ok(Value::Num(11.0)));
+        assert_eq!(repl.vm().global("x"), Some(&Value::Num(11.0)));
+    }
+
+    #[test]
+    fn incomplete_input_continues() {
+        let mut repl = Repl::new();
+        repl.eval_line("var total = 0").unwrap();
+
+        assert_eq!(repl.eval_line("{"), Err(ReplError::NeedMoreInput));
+        assert_eq!(repl.eval_line("  var a = 2"), Err(ReplError::NeedMoreInput));
+        assert_eq!(
+            repl.eval_line("  total = a + 3"),
+            Err(ReplError::NeedMoreInput)
+        );
+        assert_eq!(repl.eval_line("}"), Ok(Value::Null));
+        assert_eq!(repl.eval_line("total"), Ok(Value::Num(5.0)));
+    }
+
+    #[test]
+    fn runtime_error_keeps_session() {
+        let mut repl = Repl::new();
+        repl.eval_line("var s = \"abc\"").unwrap();
+
+        assert!(matches!(
+            repl.eval_line("s[10]"),
+            Err(ReplError::Runtime(RuntimeError::IndexOutOfBounds))
+        ));
+        assert_eq!(repl.eval_line("s.count"), Ok(Value::Num(3.0)));
+    }
+
+    #[test]
+    fn eval_expression_string() {
+        assert_eq!(eval_expr_str("1 + 2 * 3"), Ok(Value::Num(7.0)));
+        assert_eq!(eval_expr_str("\"ab\".count"), Ok(Value::Num(2.0)));
+
+        let errors = eval_expr_str("1 + x").unwrap_err();
+        assert_eq!(errors[0].span, 4..5);
+
+        let errors = eval_expr_str("1 + \"a\"[4]").unwrap_err();
+        assert_eq!(
+            errors[0].message,
+            RuntimeError::IndexOutOfBounds.to_string()
+        );
+    }
+}
@@ -0,0 +1,1471 @@
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use super::chunk::{Chunk, Function, Op, CORE_MODULE};
+use super::heap::{GcRef, Heap, Instance, Object};
+use super::interpreter::{equals, RuntimeError, Vm};
+use super::map::OrderedMap;
+use super::value::{Class, Value};
+
+/*
+ *
+ * Builtins
+ *
+ * Methods of the core classes, implemented natively
+ * and dispatched on the type of the receiver and
+ * the signature of the call.
+ *
+ * Strings are indexed by Unicode code point, so
+ * `"héllo"[1]` is `"é"` even though it takes two
+ * bytes.
+ *
+ * The core classes, such as `System`, are defined
+ * in the core module with foreign methods.
+ *
+ * Lists are indexed like strings, from the front or,
+ * with negative indices, from the back. Positions
+ * for `insert` include the end, so `-1` appends.
+ *
+ * Sequences follow Wren's iterator protocol, which
+ * `for` loops use: `iterate(iterator)` takes null
+ * to start and returns the next iterator, or false
+ * once done, and `iteratorValue(iterator)` returns
+ * the element at it. A range's iterator is the
+ * number itself, and a list's is the index. A
+ * string's is the byte offset of a code point, so
+ * iterating it yields each code point as a string
+ * of its own. `bytes` and `codePoints` are views
+ * of a string yielding numbers instead; as in Wren
+ * they forward to methods of the string such as
+ * `byteAt_(_)`.
+ *
+ * A map keeps its entries in the order their keys
+ * were first inserted: `keys` and `values` are
+ * lists in that order, and iterating a map yields
+ * a `MapEntry` for each entry, with `key` and
+ * `value` getters.
+ *
+ * Lists, maps, ranges and strings inherit from
+ * `Sequence`, as classes written in Wren can. Its
+ * methods, such as `map(_)`, `where(_)` and
+ * `toList`, call back into Wren, so the interpreter
+ * implements them over the protocol above.
+ *
+ * Every value is an `Object`, and a method none of
+ * the above define falls back to the ones `Object`
+ * has: `toString`, `type`, `==(_)`, `!=(_)` and
+ * `is(_)`.
+ *
+ */
+
+type RunResult<T> = Result<T, RuntimeError>;
+
+/// Variables of the core module, visible from every module.
+pub const CORE_VARIABLES: &[&str] = &[
+    "Bool",
+    "Class",
+    "Fiber",
+    "Fn",
+    "List",
+    "Map",
+    "MapEntry",
+    "MapSequence",
+    "Null",
+    "Num",
+    "Object",
+    "Range",
+    "Sequence",
+    "String",
+    "StringByteSequence",
+    "StringCodePointSequence",
+    "System",
+    "WhereSequence",
+];
+
+// Classes of the built-in values, which have no methods of
+// their own on the class yet but can be tested with `is`.
+const VALUE_CLASSES: &[&str] = &["Bool", "Class", "Fn", "Null", "Num", "Object"];
+
+pub(super) fn load_core(vm: &mut Vm) {
+    for name in VALUE_CLASSES {
+        let class = Value::Class(Rc::new(Class::new(name, 0)));
+        vm.define_module_variable(CORE_MODULE, name, class);
+    }
+
+    // The sequence classes, whose methods the interpreter
+    // implements. A `MapSequence` or `WhereSequence` holds the
+    // sequence it wraps and the function.
+    let sequence_class = Rc::new(Class::new("Sequence", 0));
+    vm.define_module_variable(
+        CORE_MODULE,
+        "Sequence",
+        Value::Class(sequence_class.clone()),
+    );
+    for (name, field_count) in [
+        ("MapSequence", 2),
+        ("WhereSequence", 2),
+        ("Range", 0),
+        ("String", 0),
+    ] {
+        let class = Class::subclass(name, field_count, sequence_class.clone());
+        vm.define_module_variable(CORE_MODULE, name, Value::Class(Rc::new(class)));
+    }
+
+    // The views `bytes` and `codePoints` return, which hold the
+    // string and forward each method to one of its own.
+    for (name, count, iterate, value) in [
+        (
+            "StringByteSequence",
+            "byteCount_",
+            "iterateByte_(_)",
+            "byteAt_(_)",
+        ),
+        (
+            "StringCodePointSequence",
+            "count",
+            "iterate(_)",
+            "codePointAt_(_)",
+        ),
+    ] {
+        let class = Class::subclass(name, 1, sequence_class.clone());
+        for (signature, arity, target) in [
+            ("count", 0, count),
+            ("iterate(_)", 1, iterate),
+            ("iteratorValue(_)", 1, value),
+            ("[_]", 1, value),
+        ] {
+            class.bind_method(signature, Rc::new(forwarder(signature, arity, target)));
+        }
+        vm.define_module_variable(CORE_MODULE, name, Value::Class(Rc::new(class)));
+    }
+
+    let list = Class::subclass("List", 0, sequence_class.clone());
+    vm.define_module_variable(CORE_MODULE, "List", Value::Class(Rc::new(list)));
+    vm.bind_foreign("List", "new()", |vm, _| {
+        Ok(Value::List(vm.alloc(Object::List(Vec::new()))))
+    });
+
+    let map = Class::subclass("Map", 0, sequence_class);
+
+    // The entries a map yields when iterated, whose `key` and
+    // `value` getters read its two fields.
+    let entry = Class::new("MapEntry", 2);
+    for (index, name) in ["key", "value"].into_iter().enumerate() {
+        let mut chunk = Chunk::new();
+        chunk.write_op(Op::GetField);
+        chunk.write_u8(index as u8);
+        chunk.write_op(Op::Return);
+        entry.bind_method(name, Rc::new(Function::new(name, 0, chunk, CORE_MODULE)));
+    }
+    vm.define_module_variable(CORE_MODULE, "MapEntry", Value::Class(Rc::new(entry)));
+    vm.define_module_variable(CORE_MODULE, "Map", Value::Class(Rc::new(map)));
+    vm.bind_foreign("Map", "new()", |vm, _| {
+        Ok(Value::Map(vm.alloc(Object::Map(OrderedMap::new()))))
+    });
+
+    let system = Class::new("System", 0);
+    vm.define_module_variable(CORE_MODULE, "System", Value::Class(Rc::new(system)));
+    vm.bind_foreign("System", "clock", |vm, _| Ok(Value::Num(vm.now())));
+    vm.bind_foreign("System", "gc()", |vm, _| {
+        vm.collect();
+        Ok(Value::Null)
+    });
+    vm.bind_foreign("System", "print(_)", |vm, args| {
+        let text = display(vm, &args[0])?;
+        write(vm, &format!("{text}\n"))?;
+        Ok(args[0].clone())
+    });
+    vm.bind_foreign("System", "write(_)", |vm, args| {
+        let text = display(vm, &args[0])?;
+        write(vm, &text)?;
+        Ok(args[0].clone())
+    });
+    vm.bind_foreign("System", "printAll(_)", |vm, args| {
+        let mut line = String::new();
+        for item in sequence(vm.objects(), &args[0])? {
+            line.push_str(&display(vm, &item)?);
+        }
+        line.push('\n');
+        write(vm, &line)?;
+        Ok(Value::Null)
+    });
+}
+
+fn write(vm: &mut Vm, text: &str) -> RunResult<()> {
+    let writer = vm.writer();
+    writer
+        .write_all(text.as_bytes())
+        .and_then(|()| writer.flush())
+        .map_err(|error| RuntimeError::Output(error.kind()))
+}
+
+// The elements of a list or range.
+fn sequence(heap: &Heap, value: &Value) -> RunResult<Vec<Value>> {
+    match *value {
+        Value::List(list) => match heap.get(list) {
+            Object::List(items) => Ok(items.clone()),
+            _ => unreachable!("list handle refers to another object"),
+        },
+        Value::Range {
+            from,
+            to,
+            inclusive,
+        } => {
+            let step = if from <= to { 1.0 } else { -1.0 };
+            let mut items = Vec::new();
+            let mut n = from;
+            while if inclusive {
+                n * step <= to * step
+            } else {
+                n * step < to * step
+            } {
+                items.push(Value::Num(n));
+                n += step;
+            }
+            Ok(items)
+        }
+        _ => Err(RuntimeError::InvalidArgument("argument must be a sequence")),
+    }
+}
+
+/// The text `System.print` writes for `value`, with every instance
+/// written as `instance of` its class.
+pub(super) fn to_string(heap: &Heap, value: &Value) -> String {
+    let mut out = String::new();
+    write_value(heap, value, &HashMap::new(), &mut out, &mut Vec::new());
+    out
+}
+
+/// Like `to_string`, but writing the instances in `value` whose
+/// class defines `toString` with what that returns.
+pub(super) fn display(vm: &mut Vm, value: &Value) -> RunResult<String> {
+    let mut written = HashMap::new();
+    for handle in instances_with_to_string(vm.objects(), value) {
+        let text = vm.call_method(Value::Instance(handle), "toString", &[])?;
+        written.insert(handle, to_string(vm.objects(), &text));
+    }
+    let mut out = String::new();
+    write_value(vm.objects(), value, &written, &mut out, &mut Vec::new());
+    Ok(out)
+}
+
+// The instances that `value` is or that its lists and maps hold,
+// each once, whose class defines `toString`.
+fn instances_with_to_string(heap: &Heap, value: &Value) -> Vec<GcRef> {
+    let mut found = Vec::new();
+    let mut seen = HashSet::new();
+    let mut pending = vec![value.clone()];
+    while let Some(value) = pending.pop() {
+        let (Value::Instance(handle) | Value::List(handle) | Value::Map(handle)) = value else {
+            continue;
+        };
+        if !seen.insert(handle) {
+            continue;
+        }
+        match heap.get(handle) {
+            Object::Instance(instance) => {
+                if instance.class.method("toString").is_some() {
+                    found.push(handle);
+                }
+            }
+            Object::List(items) => pending.extend(items.iter().rev().cloned()),
+            Object::Map(entries) => {
+                for (key, value) in entries.iter() {
+                    pending.push(key.clone());
+                    pending.push(value.clone());
+                }
+            }
+            Object::Fiber(_) | Object::Upvalue(_) => {}
+        }
+    }
+    found
+}
+
+// `enclosing` holds the collections being written, so
+// one that contains itself is written as `[...]`. Instances
+// in `written` are written as the text given there.
+fn write_value(
+    heap: &Heap,
+    value: &Value,
+    written: &HashMap<GcRef, String>,
+    out: &mut String,
+    enclosing: &mut Vec<GcRef>,
+) {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Value::Num(n) => out.push_str(&num_to_string(*n)),
+        Value::Str(s) => out.push_str(s),
+        Value::Range {
+            from,
+            to,
+            inclusive,
+        } => {
+            let dots = if *inclusive { ".." } else { "..." };
+            out.push_str(&format!(
+                "{}{dots}{}",
+                num_to_string(*from),
+                num_to_string(*to)
+            ));
+        }
+        Value::Fn(_) => out.push_str("Fn"),
+        Value::Class(class) => out.push_str(&class.name),
+        Value::Instance(handle) if written.contains_key(handle) => out.push_str(&written[handle]),
+        Value::Instance(_) | Value::Fiber(_) => {
+            out.push_str("instance of ");
+            out.push_str(&class_name(heap, value));
+        }
+        Value::List(handle) | Value::Map(handle) if enclosing.contains(handle) => {
+            out.push_str(if matches!(value, Value::List(_)) {
+                "[...]"
+            } else {
+                "{...}"
+            })
+        }
+        Value::List(handle) | Value::Map(handle) => {
+            enclosing.push(*handle);
+            match heap.get(*handle) {
+                Object::List(items) => {
+                    out.push('[');
+                    for (i, item) in items.iter().enumerate() {
+                        if i > 0 {
+                            out.push_str(", ");
+                        }
+                        write_value(heap, item, written, out, enclosing);
+                    }
+                    out.push(']');
+                }
+                Object::Instance(_) | Object::Fiber(_) | Object::Upvalue(_) => {
+                    unreachable!("lists and maps refer to lists and maps")
+                }
+                Object::Map(entries) => {
+                    out.push('{');
+                    for (i, (key, value)) in entries.iter().enumerate() {
+                        if i > 0 {
+                            out.push_str(", ");
+                        }
+                        write_value(heap, key, written, out, enclosing);
+                        out.push_str(": ");
+                        write_value(heap, value, written, out, enclosing);
+                    }
+                    out.push('}');
+                }
+            }
+            enclosing.pop();
+        }
+    }
+}
+
+/// Formats `n` the way Wren does, as C's `%.14g`: 14 significant
+/// digits without trailing zeros, switching to an exponent for
+/// very large and very small magnitudes.
+pub fn num_to_string(n: f64) -> String {
+    if n.is_nan() {
+        return "nan".to_string();
+    }
+    if n.is_infinite() {
+        return if n > 0.0 { "infinity" } else { "-infinity" }.to_string();
+    }
+    if n == 0.0 {
+        return if n.is_sign_negative() { "-0" } else { "0" }.to_string();
+    }
+
+    // The exponent after rounding to 14 digits decides the form.
+    let scientific = format!("{n:.13e}");
+    let (mantissa, exponent) = scientific
+        .split_once('e')
+        .expect("exponent formatting has an 'e'");
+    let exponent: i32 = exponent.parse().expect("exponent is an integer");
+    if (-4..14).contains(&exponent) {
+        let decimals = (13 - exponent) as usize;
+        trim_fraction(&format!("{n:.decimals$}")).to_string()
+    } else {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!(
+            "{}e{sign}{:02}",
+            trim_fraction(mantissa),
+            exponent.unsigned_abs()
+        )
+    }
+}
+
+// Drops the trailing zeros of a fraction, and the point if
+// nothing is left after it.
+fn trim_fraction(digits: &str) -> &str {
+    if digits.contains('.') {
+        digits.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        digits
+    }
+}
+
+pub(super) fn invoke(
+    heap: &mut Heap,
+    receiver: &Value,
+    signature: &str,
+    args: &[Value],
+) -> RunResult<Value> {
+    match receiver {
+        Value::Bool(b) => bool(*b, signature, args),
+        Value::Num(n) => num(*n, signature, args),
+        Value::Str(s) => string(s, signature, args),
+        Value::Range {
+            from,
+            to,
+            inclusive,
+        } => range(*from, *to, *inclusive, signature, args),
+        Value::List(handle) => list(heap, *handle, signature, args),
+        Value::Map(handle) => map(heap, *handle, signature, args),
+        _ => Err(not_found(heap, receiver, signature)),
+    }
+}
+
+/// The methods of `Object`, which every value has. `None` if
+/// `signature` is not one of them.
+pub(super) fn object(
+    vm: &mut Vm,
+    receiver: &Value,
+    signature: &str,
+    args: &[Value],
+) -> Option<RunResult<Value>> {
+    let result = match (signature, args) {
+        // Reached by an instance only when its class defines no
+        // `toString`, or through `super.toString`.
+        ("toString", []) if matches!(receiver, Value::Instance(_)) => {
+            Ok(Value::Str(to_string(vm.objects(), receiver).into()))
+        }
+        ("toString", []) => display(vm, receiver).map(|text| Value::Str(text.into())),
+        ("type", []) => Ok(type_of(vm, receiver)),
+        ("==(_)", [other]) => Ok(Value::Bool(equals(receiver, other))),
+        ("!=(_)", [other]) => Ok(Value::Bool(!equals(receiver, other))),
+        ("is(_)", [class]) => is(vm, receiver, class).map(Value::Bool),
+        _ => return None,
+    };
+    Some(result)
+}
+
+// The class of `value`: an instance's own class, or the core
+// class a built-in value is named after.
+fn type_of(vm: &Vm, value: &Value) -> Value {
+    match value {
+        Value::Instance(handle) => Value::Class(instance_class(vm.objects(), *handle).clone()),
+        value => vm
+            .module_variable(CORE_MODULE, value.class_name())
+            .cloned()
+            .expect("the core module defines every value class"),
+    }
+}
+
+// `&&` and `||` short-circuit in the compiled code, so they are
+// not methods.
+fn bool(b: bool, signature: &str, args: &[Value]) -> RunResult<Value> {
+    match (signature, args) {
+        ("!", []) => Ok(Value::Bool(!b)),
+        ("toString", []) => Ok(Value::Str(if b { "true" } else { "false" }.into())),
+        _ => Err(RuntimeError::MethodNotFound {
+            class: "Bool".into(),
+            signature: signature.into(),
+        }),
+    }
+}
+
+fn num(n: f64, signature: &str, args: &[Value]) -> RunResult<Value> {
+    match (signature, args) {
+        ("toString", []) => Ok(Value::Str(num_to_string(n).into())),
+        ("abs", []) => Ok(Value::Num(n.abs())),
+        ("ceil", []) => Ok(Value::Num(n.ceil())),
+        ("floor", []) => Ok(Value::Num(n.floor())),
+        // Halves round away from zero, as C's `round` does.
+        ("round", []) => Ok(Value::Num(n.round())),
+        ("sqrt", []) => Ok(Value::Num(n.sqrt())),
+        _ => Err(RuntimeError::MethodNotFound {
+            class: "Num".into(),
+            signature: signature.into(),
+        }),
+    }
+}
+
+fn string(s: &Rc<str>, signature: &str, args: &[Value]) -> RunResult<Value> {
+    match (signature, args) {
+        ("count", []) => Ok(Value::Num(s.chars().count() as f64)),
+        ("[_]", [index]) => {
+            let index = index_of(index, s.chars().count())?;
+            let c = s.chars().nth(index).expect("index is in bounds");
+            Ok(Value::Str(c.to_string().into()))
+        }
+        ("contains(_)", [other]) => Ok(Value::Bool(s.contains(&**string_arg(other)?))),
+        ("startsWith(_)", [other]) => Ok(Value::Bool(s.starts_with(&**string_arg(other)?))),
+        ("iterate(_)", [iterator]) => {
+            let next = match iterator {
+                Value::Null => 0,
+                _ => {
+                    let at = code_point_start(s, iterator)?;
+                    at + s[at..].chars().next().map_or(0, char::len_utf8)
+                }
+            };
+            Ok(if next < s.len() {
+                Value::Num(next as f64)
+            } else {
+                Value::Bool(false)
+            })
+        }
+        ("iteratorValue(_)", [iterator]) => {
+            let at = code_point_start(s, iterator)?;
+            let c = s[at..].chars().next().expect("offset is before the end");
+            Ok(Value::Str(c.to_string().into()))
+        }
+        ("byteCount_", []) => Ok(Value::Num(s.len() as f64)),
+        ("byteAt_(_)", [index]) => Ok(Value::Num(s.as_bytes()[index_of(index, s.len())?] as f64)),
+        ("iterateByte_(_)", [iterator]) => {
+            let next = match iterator {
+                Value::Null => 0,
+                _ => index_of(iterator, s.len())? + 1,
+            };
+            Ok(if next < s.len() {
+                Value::Num(next as f64)
+            } else {
+                Value::Bool(false)
+            })
+        }
+        // -1 for an offset inside a code point, as in Wren.
+        ("codePointAt_(_)", [index]) => {
+            let at = index_of(index, s.len())?;
+            Ok(Value::Num(
+                match s.get(at..).and_then(|rest| rest.chars().next()) {
+                    Some(c) => c as u32 as f64,
+                    None => -1.0,
+                },
+            ))
+        }
+        ("+(_)", [other]) => {
+            let other = string_arg(other)
+                .map_err(|_| RuntimeError::InvalidArgument("right operand must be a string"))?;
+            Ok(Value::Str(format!("{s}{other}").into()))
+        }
+        _ => Err(RuntimeError::MethodNotFound {
+            class: "String".into(),
+            signature: signature.into(),
+        }),
+    }
+}
+
+// Ranges count down when `to` is below `from`, so `3..1`
+// yields 3, 2 and 1.
+fn range(from: f64, to: f64, inclusive: bool, signature: &str, args: &[Value]) -> RunResult<Value> {
+    match (signature, args) {
+        ("from", []) => Ok(Value::Num(from)),
+        ("to", []) => Ok(Value::Num(to)),
+        ("isInclusive", []) => Ok(Value::Bool(inclusive)),
+        ("min", []) => Ok(Value::Num(from.min(to))),
+        ("max", []) => Ok(Value::Num(from.max(to))),
+        ("iterate(_)", [iterator]) => {
+            let next = match iterator {
+                Value::Null => from,
+                Value::Num(n) if from <= to => n + 1.0,
+                Value::Num(n) => n - 1.0,
+                _ => return Err(RuntimeError::InvalidArgument("iterator must be a number")),
+            };
+            let in_range = match (from <= to, inclusive) {
+                (true, true) => next <= to,
+                (true, false) => next < to,
+                (false, true) => next >= to,
+                (false, false) => next > to,
+            };
+            Ok(if in_range {
+                Value::Num(next)
+            } else {
+                Value::Bool(false)
+            })
+        }
+        ("iteratorValue(_)", [iterator]) => Ok(iterator.clone()),
+        _ => Err(RuntimeError::MethodNotFound {
+            class: "Range".into(),
+            signature: signature.into(),
+        }),
+    }
+}
+
+fn list(heap: &mut Heap, list: GcRef, signature: &str, args: &[Value]) -> RunResult<Value> {
+    let Object::List(items) = heap.get_mut(list) else {
+        unreachable!("list handle refers to another object")
+    };
+    match (signature, args) {
+        ("count", []) => Ok(Value::Num(items.len() as f64)),
+        ("[_]", [index]) => Ok(items[index_of(index, items.len())?].clone()),
+        ("[_]=(_)", [index, item]) => {
+            let index = index_of(index, items.len())?;
+            items[index] = item.clone();
+            Ok(item.clone())
+        }
+        ("add(_)", [item]) => {
+            items.push(item.clone());
+            Ok(item.clone())
+        }
+        ("insert(_,_)", [index, item]) => {
+            let index = index_of(index, items.len() + 1)?;
+            items.insert(index, item.clone());
+            Ok(item.clone())
+        }
+        ("removeAt(_)", [index]) => Ok(items.remove(index_of(index, items.len())?)),
+        ("iterate(_)", [iterator]) => {
+            let next = match iterator {
+                Value::Null => 0,
+                iterator => index_of(iterator, items.len())? + 1,
+            };
+            Ok(if next < items.len() {
+                Value::Num(next as f64)
+            } else {
+                Value::Bool(false)
+            })
+        }
+        ("iteratorValue(_)", [index]) => Ok(items[index_of(index, items.len())?].clone()),
+        ("clear()", []) => {
+            items.clear();
+            Ok(Value::Null)
+        }
+        _ => Err(RuntimeError::MethodNotFound {
+            class: "List".into(),
+            signature: signature.into(),
+        }),
+    }
+}
+
+fn map(heap: &mut Heap, map: GcRef, signature: &str, args: &[Value]) -> RunResult<Value> {
+    let Object::Map(entries) = heap.get_mut(map) else {
+        unreachable!("map handle refers to another object")
+    };
+    match (signature, args) {
+        ("count", []) => Ok(Value::Num(entries.len() as f64)),
+        ("[_]", [key]) => Ok(entries.get(key).cloned().unwrap_or(Value::Null)),
+        ("[_]=(_)", [key, value]) => {
+            entries.insert(key.clone(), value.clone());
+            Ok(value.clone())
+        }
+        ("containsKey(_)", [key]) => Ok(Value::Bool(entries.contains_key(key))),
+        // The iterator is the position of an entry. Its value is
+        // a `MapEntry`, which `map_entry` makes.
+        ("iterate(_)", [iterator]) => {
+            let next = match iterator {
+                Value::Null => 0,
+                iterator => index_of(iterator, entries.len())? + 1,
+            };
+            Ok(if next < entries.len() {
+                Value::Num(next as f64)
+            } else {
+                Value::Bool(false)
+            })
+        }
+        ("remove(_)", [key]) => Ok(entries.remove(key).unwrap_or(Value::Null)),
+        ("clear()", []) => {
+            entries.clear();
+            Ok(Value::Null)
+        }
+        ("keys", []) => {
+            let keys = entries.keys().cloned().collect();
+            Ok(Value::List(heap.alloc(Object::List(keys))))
+        }
+        ("values", []) => {
+            let values = entries.values().cloned().collect();
+            Ok(Value::List(heap.alloc(Object::List(values))))
+        }
+        _ => Err(RuntimeError::MethodNotFound {
+            class: "Map".into(),
+            signature: signature.into(),
+        }),
+    }
+}
+
+/// `iteratorValue(_)` of a map: the entry at the position
+/// `iterator`, as a `MapEntry` holding its key and value.
+pub(super) fn map_entry(vm: &mut Vm, map: GcRef, iterator: &Value) -> RunResult<Value> {
+    let Object::Map(entries) = vm.objects().get(map) else {
+        unreachable!("map handle refers to another object")
+    };
+    let (key, value) = entries
+        .get_index(index_of(iterator, entries.len())?)
+        .expect("index is in bounds");
+    let fields = vec![key.clone(), value.clone()];
+    let Some(Value::Class(class)) = vm.module_variable(CORE_MODULE, "MapEntry").cloned() else {
+        unreachable!("the core module defines MapEntry")
+    };
+    Ok(Value::Instance(
+        vm.alloc(Object::Instance(Instance { class, fields })),
+    ))
+}
+
+/// `bytes` or `codePoints` of `string`: a view of it that
+/// yields its bytes or code points as numbers.
+pub(super) fn string_view(vm: &mut Vm, string: Rc<str>, signature: &str) -> Value {
+    let name = match signature {
+        "bytes" => "StringByteSequence",
+        _ => "StringCodePointSequence",
+    };
+    let Some(Value::Class(class)) = vm.module_variable(CORE_MODULE, name).cloned() else {
+        unreachable!("the core module defines {name}")
+    };
+    let fields = vec![Value::Str(string)];
+    Value::Instance(vm.alloc(Object::Instance(Instance { class, fields })))
+}
+
+// A method that calls `target` on the receiver's first field
+// with its own arguments.
+fn forwarder(signature: &str, arity: u8, target: &str) -> Function {
+    let mut chunk = Chunk::new();
+    let target = chunk.add_constant(Value::Str(target.into()));
+    chunk.write_op(Op::GetField);
+    chunk.write_u8(0);
+    for slot in 1..=arity {
+        chunk.write_op(Op::GetLocal);
+        chunk.write_u8(slot);
+    }
+    chunk.write_op(Op::Invoke);
+    chunk.write_u16(target);
+    chunk.write_u8(arity);
+    chunk.write_op(Op::Return);
+    Function::new(signature, arity, chunk, CORE_MODULE)
+}
+
+// Resolves a string's iterator to the byte offset of the code
+// point it is at.
+fn code_point_start(s: &str, iterator: &Value) -> RunResult<usize> {
+    let at = index_of(iterator, s.len())?;
+    if !s.is_char_boundary(at) {
+        return Err(RuntimeError::InvalidArgument(
+            "iterator must be at the start of a code point",
+        ));
+    }
+    Ok(at)
+}
+
+fn string_arg(value: &Value) -> RunResult<&Rc<str>> {
+    match value {
+        Value::Str(s) => Ok(s),
+        _ => Err(RuntimeError::InvalidArgument("argument must be a string")),
+    }
+}
+
+// Resolves a subscript into `0..count`; negative
+// indices count back from the end.
+fn index_of(index: &Value, count: usize) -> RunResult<usize> {
+    let Value::Num(n) = *index else {
+        return Err(RuntimeError::InvalidArgument("subscript must be a number"));
+    };
+    if n.fract() != 0.0 {
+        return Err(RuntimeError::InvalidArgument(
+            "subscript must be an integer",
+        ));
+    }
+    let index = if n < 0.0 { n + count as f64 } else { n };
+    if index < 0.0 || index >= count as f64 {
+        return Err(RuntimeError::IndexOutOfBounds);
+    }
+    Ok(index as usize)
+}
+
+pub(super) fn not_found(heap: &Heap, receiver: &Value, signature: &str) -> RuntimeError {
+    RuntimeError::MethodNotFound {
+        class: class_name(heap, receiver),
+        signature: signature.into(),
+    }
+}
+
+fn class_name(heap: &Heap, value: &Value) -> Rc<str> {
+    match value {
+        Value::Class(class) => format!("{} metaclass", class.name).into(),
+        Value::Instance(handle) => instance_class(heap, *handle).name.clone(),
+        value => value.class_name().into(),
+    }
+}
+
+fn instance_class(heap: &Heap, handle: GcRef) -> &Rc<Class> {
+    match heap.get(handle) {
+        Object::Instance(instance) => &instance.class,
+        _ => unreachable!("instance handle refers to another object"),
+    }
+}
+
+// `value is class`. An instance is also an instance of its
+// class's superclasses; built-in values are only instances of
+// the core class they are named after, not of a class a module
+// declares with the same name. Everything is an `Object`.
+pub(super) fn is(vm: &Vm, value: &Value, class: &Value) -> RunResult<bool> {
+    let Value::Class(class) = class else {
+        return Err(RuntimeError::InvalidArgument(
+            "right operand of 'is' must be a class",
+        ));
+    };
+    if matches!(
+        vm.module_variable(CORE_MODULE, "Object"),
+        Some(Value::Class(object)) if Rc::ptr_eq(object, class)
+    ) {
+        return Ok(true);
+    }
+    let mut current = match value {
+        Value::Instance(handle) => Some(instance_class(vm.objects(), *handle)),
+        value => match vm.module_variable(CORE_MODULE, value.class_name()) {
+            Some(Value::Class(core)) => Some(core),
+            _ => None,
+        },
+    };
+    while let Some(candidate) = current {
+        if Rc::ptr_eq(candidate, class) {
+            return Ok(true);
+        }
+        current = candidate.superclass.as_ref();
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::codegen::compile;
+    use crate::vm::chunk::Function;
+    use std::cell::RefCell;
+
+    fn eval(src: &str) -> Result<Value, RuntimeError> {
+        let chunk = compile(&format!("var result = {src}")).unwrap();
+        let mut vm = Vm::new();
+        vm.run(Rc::new(Function::script(chunk)))?;
+        Ok(vm.global("result").unwrap().clone())
+    }
+
+    fn str(s: &str) -> Value {
+        Value::Str(s.into())
+    }
+
+    #[test]
+    fn count_is_in_code_points() {
+        assert_eq!(eval(r#""héllo".count"#), Ok(Value::Num(5.0)));
+        assert_eq!(eval(r#""".count"#), Ok(Value::Num(0.0)));
+        assert_eq!(eval(r#""a\0b".count"#), Ok(Value::Num(3.0)));
+        assert_eq!(eval(r#""a\0b"[1]"#), Ok(str("\0")));
+    }
+
+    #[test]
+    fn subscript_is_in_code_points() {
+        assert_eq!(eval(r#""héllo"[1]"#), Ok(str("é")));
+        assert_eq!(eval(r#""héllo"[2]"#), Ok(str("l")));
+        assert_eq!(eval(r#""héllo"[-1]"#), Ok(str("o")));
+        assert_eq!(eval(r#""héllo"[5]"#), Err(RuntimeError::IndexOutOfBounds));
+        assert_eq!(
+            eval(r#""héllo"[0.5]"#),
+            Err(RuntimeError::InvalidArgument(
+                "subscript must be an integer"
+            ))
+        );
+    }
+
+    #[test]
+    fn contains_and_starts_with() {
+        assert_eq!(eval(r#""abc".contains("b")"#), Ok(Value::Bool(true)));
+        assert_eq!(eval(r#""abc".contains("d")"#), Ok(Value::Bool(false)));
+        assert_eq!(eval(r#""b" in "abc""#), Ok(Value::Bool(true)));
+        assert_eq!(eval(r#""héllo".startsWith("hé")"#), Ok(Value::Bool(true)));
+        assert_eq!(
+            eval(r#""abc".contains(1)"#),
+            Err(RuntimeError::InvalidArgument("argument must be a string"))
+        );
+    }
+
+    #[test]
+    fn concatenation() {
+        assert_eq!(eval(r#""hé" + "llo""#), Ok(str("héllo")));
+        assert_eq!(
+            eval(r#""a" + 1"#),
+            Err(RuntimeError::InvalidArgument(
+                "right operand must be a string"
+            ))
+        );
+    }
+
+    #[test]
+    fn bool_methods() {
+        assert_eq!(eval("!true"), Ok(Value::Bool(false)));
+        assert_eq!(eval("!!false"), Ok(Value::Bool(false)));
+        assert_eq!(eval("true == true"), Ok(Value::Bool(true)));
+        assert_eq!(eval("true != false"), Ok(Value::Bool(true)));
+        assert_eq!(eval("true == 1"), Ok(Value::Bool(false)));
+        assert_eq!(eval("true && false"), Ok(Value::Bool(false)));
+        assert_eq!(eval("false.toString"), Ok(str("false")));
+        assert_eq!(eval("true.type == Bool"), Ok(Value::Bool(true)));
+        assert_eq!(eval("true.type.toString"), Ok(str("Bool")));
+        assert_eq!(eval("false is Bool"), Ok(Value::Bool(true)));
+    }
+
+    #[test]
+    fn methods_on_literals() {
+        assert_eq!(eval("5.toString"), Ok(str("5")));
+        assert_eq!(eval("2.5.floor"), Ok(Value::Num(2.0)));
+        assert_eq!(eval("\"hi\".count"), Ok(Value::Num(2.0)));
+        assert_eq!(eval("(1..3).max"), Ok(Value::Num(3.0)));
+        assert_eq!(eval("(3..1).min"), Ok(Value::Num(1.0)));
+        // Negation applies to the result of the call.
+        assert_eq!(eval("-1.abs"), Ok(Value::Num(-1.0)));
+        assert_eq!(eval("-2.5.floor"), Ok(Value::Num(-2.0)));
+        assert_eq!(eval("-2.5 .floor"), Ok(Value::Num(-2.0)));
+        assert_eq!(eval("3 -1.abs"), Ok(Value::Num(2.0)));
+    }
+
+    #[test]
+    fn strings_iterate_by_code_point() {
+        assert_eq!(
+            output("for (c in \"héllo\") System.print(c)"),
+            "h\né\nl\nl\no\n"
+        );
+        assert_eq!(eval("\"héllo\".toList.count"), Ok(Value::Num(5.0)));
+        assert_eq!(eval("\"\".iterate(null)"), Ok(Value::Bool(false)));
+        // The iterator is the byte offset, so it skips 2 past `é`.
+        assert_eq!(eval("\"héllo\".iterate(1)"), Ok(Value::Num(3.0)));
+        assert!(matches!(
+            eval("\"héllo\".iteratorValue(2)"),
+            Err(RuntimeError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn string_bytes_and_code_points() {
+        assert_eq!(eval("\"é\".bytes.count"), Ok(Value::Num(2.0)));
+        assert_eq!(eval("\"é\".bytes[1]"), Ok(Value::Num(0xa9 as f64)));
+        assert_eq!(eval("\"é\".codePoints.count"), Ok(Value::Num(1.0)));
+        assert_eq!(eval("\"é\".codePoints[0]"), Ok(Value::Num(0xe9 as f64)));
+        // An offset inside a code point has none.
+        assert_eq!(eval("\"é\".codePoints[1]"), Ok(Value::Num(-1.0)));
+        assert_eq!(
+            output("for (b in \"hé\".bytes) System.print(b)"),
+            "104\n195\n169\n"
+        );
+        assert_eq!(
+            output("System.print(\"hé\".codePoints.map { |c| c + 1 }.toList)"),
+            "[105, 234]\n"
+        );
+        assert_eq!(eval("\"hé\".bytes is Sequence"), Ok(Value::Bool(true)));
+    }
+
+    #[test]
+    fn num_methods() {
+        assert_eq!(eval("(-1.5).round"), Ok(Value::Num(-2.0)));
+        assert_eq!(eval("(2.5).round"), Ok(Value::Num(3.0)));
+        assert_eq!(eval("(1.4).round"), Ok(Value::Num(1.0)));
+        assert_eq!(eval("(-1.5).floor"), Ok(Value::Num(-2.0)));
+        assert_eq!(eval("(1.5).floor"), Ok(Value::Num(1.0)));
+        assert_eq!(eval("(-1.5).ceil"), Ok(Value::Num(-1.0)));
+        assert_eq!(eval("(1.2).ceil"), Ok(Value::Num(2.0)));
+        assert_eq!(eval("(-3).abs"), Ok(Value::Num(3.0)));
+        assert_eq!(eval("(16).sqrt"), Ok(Value::Num(4.0)));
+
+        let Ok(Value::Num(zero)) = eval("(-0).abs") else {
+            panic!("abs returns a number");
+        };
+        assert!(zero == 0.0 && zero.is_sign_positive());
+        assert!(matches!(eval("(-1).sqrt"), Ok(Value::Num(n)) if n.is_nan()));
+    }
+
+    #[test]
+    fn unknown_method() {
+        assert_eq!(
+            eval(r#""abc".reverse"#),
+            Err(RuntimeError::MethodNotFound {
+                class: "String".into(),
+                signature: "reverse".into()
+            })
+        );
+    }
+
+    #[test]
+    fn list_methods() {
+        let run = |src: &str| output(&format!("var list = List.new()\n{src}"));
+
+        assert_eq!(
+            run("System.print(list.add(1))\nlist.add(2)\nSystem.print(list)\nSystem.print(list.count)"),
+            "1\n[1, 2]\n2\n"
+        );
+        assert_eq!(
+            run("list.add(1)\nlist.insert(0, 0)\nlist.insert(2, 3)\nlist.insert(-2, 2)\nlist.insert(-1, 4)\nSystem.print(list)"),
+            "[0, 1, 2, 3, 4]\n"
+        );
+        assert_eq!(
+            run("list.add(1)\nlist.add(2)\nlist.add(3)\nSystem.print(list.removeAt(-1))\nSystem.print(list.removeAt(0))\nSystem.print(list)\nSystem.print(list.count)"),
+            "3\n1\n[2]\n1\n"
+        );
+        assert_eq!(
+            run("list.add(1)\nlist.add(2)\nlist.clear()\nSystem.print(list)\nSystem.print(list.count)"),
+            "[]\n0\n"
+        );
+        assert_eq!(
+            run("list.add(1)\nlist.add(2)\nSystem.print(list[-1] = 3)\nlist[0] = list[1]\nSystem.print(list)"),
+            "3\n[3, 3]\n"
+        );
+    }
+
+    #[test]
+    fn list_literals() {
+        assert_eq!(eval("[1,2].count"), Ok(Value::Num(2.0)));
+        assert_eq!(eval("[].count"), Ok(Value::Num(0.0)));
+        assert_eq!(
+            eval("[1 + 1, [\"a\"],\n  null,\n].toString"),
+            Ok(str("[2, [a], null]"))
+        );
+        assert_eq!(
+            output("var a = [1]\nvar b = [1]\nb[0] = 2\nSystem.print(a)"),
+            "[1]\n"
+        );
+    }
+
+    #[test]
+    fn map_methods() {
+        let run = |src: &str| output(&format!("var map = {{\"a\": 1, \"b\": 2}}\n{src}"));
+
+        assert_eq!(
+            run("System.print(map.count)\nSystem.print(map[\"a\"])\nSystem.print(map[\"z\"])"),
+            "2\n1\nnull\n"
+        );
+        assert_eq!(
+            run("map[\"a\"] = 3\nmap[\"c\"] = 4\nSystem.print(map[\"a\"] + map[\"c\"])\nSystem.print(map.count)"),
+            "7\n3\n"
+        );
+        assert_eq!(
+            run("System.print(map.containsKey(\"a\"))\nSystem.print(map.containsKey(\"z\"))"),
+            "true\nfalse\n"
+        );
+        assert_eq!(
+            run("System.print(map.remove(\"a\"))\nSystem.print(map.remove(\"a\"))\nSystem.print(map.containsKey(\"a\"))\nSystem.print(map.count)"),
+            "1\nnull\nfalse\n1\n"
+        );
+        assert_eq!(
+            run("map.remove(\"b\")\nvar keys = map.keys\nfor (key in keys) System.print(key)\nSystem.print(map.values)"),
+            "a\n[1]\n"
+        );
+        assert_eq!(
+            output("var map = Map.new()\nmap.clear()\nSystem.print(map)\nSystem.print(map.count)"),
+            "{}\n0\n"
+        );
+    }
+
+    #[test]
+    fn maps_iterate_in_insertion_order() {
+        let src = r#"
+            var m = Map.new()
+            m["zeta"] = 4
+            m["alpha"] = 5
+            m["mid"] = 3
+            m["beta"] = 4
+            m.remove("alpha")
+            m["alpha"] = 0
+            m["zeta"] = 9
+            for (entry in m) System.print(entry.key + "=" + entry.value.toString)
+            System.print(m.keys)
+            System.print(m)
+            "#;
+        let expected = "zeta=9\nmid=3\nbeta=4\nalpha=0\n\
+            [zeta, mid, beta, alpha]\n\
+            {zeta: 9, mid: 3, beta: 4, alpha: 0}\n";
+        for _ in 0..3 {
+            assert_eq!(output(src), expected);
+        }
+    }
+
+    #[test]
+    fn map_keys_and_values() {
+        let mut heap = Heap::new();
+        let entries = (0..3).map(|n| (Value::Num(n as f64), Value::Num(n as f64 * 10.0)));
+        let map = Value::Map(heap.alloc(Object::Map(entries.collect())));
+        let sorted = |heap: &mut Heap, signature| {
+            let list = invoke(heap, &map, signature, &[]).unwrap();
+            let mut nums: Vec<f64> = sequence(heap, &list)
+                .unwrap()
+                .iter()
+                .map(|value| match value {
+                    Value::Num(n) => *n,
+                    other => panic!("expected a number, got {other:?}"),
+                })
+                .collect();
+            nums.sort_by(f64::total_cmp);
+            nums
+        };
+
+        assert_eq!(sorted(&mut heap, "keys"), [0.0, 1.0, 2.0]);
+        assert_eq!(sorted(&mut heap, "values"), [0.0, 10.0, 20.0]);
+        invoke(&mut heap, &map, "[_]=(_)", &[Value::Num(1.0), Value::Null]).unwrap();
+        assert_eq!(
+            invoke(&mut heap, &map, "[_]", &[Value::Num(1.0)]),
+            Ok(Value::Null)
+        );
+        assert_eq!(
+            invoke(&mut heap, &map, "containsKey(_)", &[Value::Num(1.0)]),
+            Ok(Value::Bool(true))
+        );
+    }
+
+    #[test]
+    fn every_value_is_an_object() {
+        assert_eq!(eval("(5).type == Num"), Ok(Value::Bool(true)));
+        assert_eq!(eval("(5).type.toString"), Ok(str("Num")));
+        assert_eq!(eval(r#""a" == "a""#), Ok(Value::Bool(true)));
+        assert_eq!(eval("[1] != [2]"), Ok(Value::Bool(true)));
+        assert_eq!(eval("null.toString"), Ok(str("null")));
+        assert_eq!(eval("(1..2).toString"), Ok(str("1..2")));
+        assert_eq!(eval("Num.type == Class"), Ok(Value::Bool(true)));
+        assert_eq!(eval("{1: 2} is Object"), Ok(Value::Bool(true)));
+        assert_eq!(
+            eval("null.foo"),
+            Err(RuntimeError::MethodNotFound {
+                class: "Null".into(),
+                signature: "foo".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn printing_calls_to_string() {
+        assert_eq!(
+            output(
+                r#"
+                class Point {
+                    construct new(x) { _x = x }
+                    toString { "(" + _x.toString + ")" }
+                }
+                class Plain {
+                    construct new() {}
+                }
+                class Loud is Point {
+                    construct new(x) { super(x) }
+                    toString { super.toString + "!" }
+                }
+                var p = Point.new(1)
+                System.print(p)
+                System.write(p)
+                System.print(" written")
+                System.printAll([p, "-", Plain.new()])
+                System.print([p, {"k": p}, Plain.new()])
+                System.print([p].toString)
+                System.print(Loud.new(2))
+                "#
+            ),
+            "(1)\n(1) written\n(1)-instance of Plain\n[(1), {k: (1)}, instance of Plain]\n\
+             [(1)]\n(2)!\n"
+        );
+    }
+
+    // Runs the iterator protocol on `sequence` the way a `for`
+    // loop does, collecting the values.
+    fn iterate(heap: &mut Heap, sequence: &Value) -> Vec<Value> {
+        let mut values = Vec::new();
+        let mut iterator = Value::Null;
+        loop {
+            iterator = invoke(heap, sequence, "iterate(_)", &[iterator]).unwrap();
+            if iterator == Value::Bool(false) {
+                return values;
+            }
+            let value = invoke(heap, sequence, "iteratorValue(_)", &[iterator.clone()]);
+            values.push(value.unwrap());
+        }
+    }
+
+    #[test]
+    fn range_iteration() {
+        let mut heap = Heap::new();
+        let range = |from, to, inclusive| Value::Range {
+            from,
+            to,
+            inclusive,
+        };
+        let nums = |ns: &[f64]| ns.iter().map(|&n| Value::Num(n)).collect::<Vec<_>>();
+
+        assert_eq!(
+            iterate(&mut heap, &range(1.0, 3.0, true)),
+            nums(&[1.0, 2.0, 3.0])
+        );
+        assert_eq!(
+            iterate(&mut heap, &range(1.0, 3.0, false)),
+            nums(&[1.0, 2.0])
+        );
+        assert_eq!(
+            iterate(&mut heap, &range(3.0, 1.0, true)),
+            nums(&[3.0, 2.0, 1.0])
+        );
+        assert_eq!(
+            iterate(&mut heap, &range(3.0, 1.0, false)),
+            nums(&[3.0, 2.0])
+        );
+        assert_eq!(iterate(&mut heap, &range(1.0, 1.0, true)), nums(&[1.0]));
+        assert_eq!(iterate(&mut heap, &range(1.0, 1.0, false)), nums(&[]));
+    }
+
+    #[test]
+    fn list_iteration() {
+        let mut heap = Heap::new();
+        let items = vec![Value::Str("a".into()), Value::Num(2.0)];
+        let list = Value::List(heap.alloc(Object::List(items.clone())));
+
+        assert_eq!(iterate(&mut heap, &list), items);
+
+        let empty = Value::List(heap.alloc(Object::List(Vec::new())));
+        assert_eq!(iterate(&mut heap, &empty), []);
+    }
+
+    #[test]
+    fn list_index_errors() {
+        let error = |src: &str| {
+            let chunk = compile(&format!("var list = List.new()\nlist.add(1)\n{src}")).unwrap();
+            Vm::new().run(Rc::new(Function::script(chunk))).unwrap_err()
+        };
+
+        assert_eq!(error("list.removeAt(1)"), RuntimeError::IndexOutOfBounds);
+        assert_eq!(error("list.insert(2, 0)"), RuntimeError::IndexOutOfBounds);
+        assert_eq!(error("list.insert(-3, 0)"), RuntimeError::IndexOutOfBounds);
+        assert_eq!(error("list[-2]"), RuntimeError::IndexOutOfBounds);
+    }
+
+    // A sink tests can read back after the VM wrote to it.
+    #[derive(Clone, Default)]
+    struct Captured(Rc<RefCell<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn output(src: &str) -> String {
+        let captured = Captured::default();
+        let mut vm = Vm::new().output(Box::new(captured.clone()));
+        let chunk = compile(src).unwrap();
+        vm.run(Rc::new(Function::script(chunk))).unwrap();
+        let bytes = captured.0.borrow().clone();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn system_print() {
+        assert_eq!(output(r#"System.print("hi")"#), "hi\n");
+        assert_eq!(
+            output(r#"System.write("a") System.write(1.5) System.print(null)"#),
+            "a1.5null\n"
+        );
+        assert_eq!(output("System.printAll(1..3)"), "123\n");
+        assert_eq!(output("System.print({1: 2})"), "{1: 2}\n");
+        // A `//` inside a block comment does not hide the code after it.
+        assert_eq!(
+            output("/* see http://x.com */ System.print(1)\nSystem.print(2)"),
+            "1\n2\n"
+        );
+    }
+
+    #[test]
+    fn numbers_format_like_wren() {
+        let cases = [
+            (5.0, "5"),
+            (5.5, "5.5"),
+            (-5.5, "-5.5"),
+            (0.1, "0.1"),
+            (1e100, "1e+100"),
+            (1.5e-7, "1.5e-07"),
+            (0.0001, "0.0001"),
+            (123456789012345.0, "1.2345678901234e+14"),
+            (12345678901234.0, "12345678901234"),
+            (1.0 / 3.0, "0.33333333333333"),
+            (-0.0, "-0"),
+            (f64::INFINITY, "infinity"),
+            (f64::NEG_INFINITY, "-infinity"),
+            (f64::NAN, "nan"),
+        ];
+        for (n, expected) in cases {
+            assert_eq!(num_to_string(n), expected, "formatting {n}");
+        }
+        assert_eq!(output("System.print(1 / 3)"), "0.33333333333333\n");
+        assert_eq!(eval("(2.50).toString"), Ok(str("2.5")));
+    }
+
+    #[test]
+    fn system_clock() {
+        let ticks = Rc::new(RefCell::new(0.0));
+        let clock = ticks.clone();
+        let mut vm = Vm::new().clock(Box::new(move || {
+            *clock.borrow_mut() += 0.5;
+            *clock.borrow()
+        }));
+        let chunk = compile("var a = System.clock\nvar b = System.clock").unwrap();
+        vm.run(Rc::new(Function::script(chunk))).unwrap();
+
+        assert_eq!(vm.global("a"), Some(&Value::Num(0.5)));
+        assert_eq!(vm.global("b"), Some(&Value::Num(1.0)));
+
+        let now = eval("System.clock - System.clock").unwrap();
+        assert!(matches!(now, Value::Num(n) if n <= 0.0));
+    }
+
+    #[test]
+    fn system_gc() {
+        let mut vm = Vm::new();
+        let chunk = compile("var kept = List.new()\nList.new()\nList.new()\nSystem.gc()").unwrap();
+        vm.run(Rc::new(Function::script(chunk))).unwrap();
+
+        assert_eq!(vm.objects().live_objects(), 1);
+    }
+
+    #[test]
+    fn print_returns_its_argument() {
+        assert_eq!(
+            output(r#"System.print(System.print("x") + "y")"#),
+            "x\nxy\n"
+        );
+    }
+
+    #[test]
+    fn only_one_line_bodies_return_their_value() {
+        assert_eq!(
+            output(
+                r#"
+                class A {
+                  static one { "one" }
+                  static go() {
+                    System.print("hi")
+                  }
+                }
+                System.print(A.one)
+                System.print(A.go())
+                System.print((1..2).map { |x| x * 2 }.toList)
+                System.print((1..2).map { |x|
+                  x
+                }.toList)
+                "#
+            ),
+            "one\nhi\nnull\n[2, 4]\n[null, null]\n"
+        );
+    }
+
+    #[test]
+    fn is_checks_the_class() {
+        assert_eq!(
+            output(
+                r#"
+                System.print(5 is Num)
+                System.print("x" is String)
+                System.print(null is Null)
+                System.print([1] is List)
+                System.print(5 is String)
+                "#
+            ),
+            "true\ntrue\ntrue\ntrue\nfalse\n"
+        );
+    }
+
+    #[test]
+    fn is_follows_superclasses() {
+        assert_eq!(
+            output(
+                "
+                class A {}
+                class B is A { construct new() {} }
+                class Num {}
+                System.print(B.new() is A)
+                System.print(B.new() is Num)
+                System.print(5 is Num)
+                "
+            ),
+            "true\nfalse\nfalse\n"
+        );
+
+        let chunk = compile("5 is 5").unwrap();
+        assert_eq!(
+            Vm::new().run(Rc::new(Function::script(chunk))),
+            Err(RuntimeError::InvalidArgument(
+                "right operand of 'is' must be a class"
+            ))
+        );
+    }
+
+    #[test]
+    fn unknown_static_method() {
+        let chunk = compile("System.shout(1)").unwrap();
+
+        assert_eq!(
+            Vm::new().run(Rc::new(Function::script(chunk))),
+            Err(RuntimeError::MethodNotFound {
+                class: "System metaclass".into(),
+                signature: "shout(_)".into()
+            })
+        );
+    }
+
+    #[test]
+    fn sequence_methods() {
+        assert_eq!(
+            eval("(1..5).map { |x| x * 2 }.toList.toString"),
+            Ok(str("[2, 4, 6, 8, 10]"))
+        );
+        assert_eq!(
+            eval("[1, 2, 3].where { |x| x > 1 }.toList.toString"),
+            Ok(str("[2, 3]"))
+        );
+        assert_eq!(eval("(1..4).reduce { |a, b| a + b }"), Ok(Value::Num(10.0)));
+        assert_eq!(
+            eval("(1..4).reduce(10) { |a, b| a + b }"),
+            Ok(Value::Num(20.0))
+        );
+        assert_eq!(eval("(1..4).count"), Ok(Value::Num(4.0)));
+        assert_eq!(eval("(1..4).count { |x| x > 2 }"), Ok(Value::Num(2.0)));
+        assert_eq!(eval("(1..4).contains(3)"), Ok(Value::Bool(true)));
+        assert_eq!(eval("(1...1).isEmpty"), Ok(Value::Bool(true)));
+        assert_eq!(eval("(1..3).any { |x| x > 2 }"), Ok(Value::Bool(true)));
+        assert_eq!(eval("(1..3).all { |x| x > 2 }"), Ok(Value::Bool(false)));
+        assert_eq!(eval("[1] is Sequence"), Ok(Value::Bool(true)));
+        assert_eq!(
+            eval("(1...1).reduce { |a, b| a + b }"),
+            Err(RuntimeError::InvalidArgument(
+                "cannot reduce an empty sequence"
+            ))
+        );
+    }
+
+    #[test]
+    fn map_and_where_are_lazy() {
+        assert_eq!(
+            output(
+                "var doubled = (1..3).map { |x| System.print(x) * 2 }
+                System.print(\"mapped\")
+                for (x in doubled.where { |x| x > 2 }) System.print(x)
+                "
+            ),
+            // As in Wren, an element passing `where` is mapped again
+            // when the loop reads it.
+            "mapped\n1\n2\n2\n4\n3\n3\n6\n"
+        );
+    }
+
+    #[test]
+    fn deeply_nested_sequences_overflow() {
+        let src = "var S = 1..1
+            for (i in 1..10000) S = S.map { |x| x }
+            var result = S.toList";
+        let chunk = compile(src).unwrap();
+
+        assert_eq!(
+            Vm::new().run(Rc::new(Function::script(chunk))),
+            Err(RuntimeError::StackOverflow)
+        );
+    }
+
+    #[test]
+    fn classes_can_inherit_sequence_methods() {
+        assert_eq!(
+            output(
+                "class Countdown is Sequence {
+                    construct new(n) { _n = n }
+                    iterate(i) { i == null && _n || i > 1 && i - 1 }
+                    iteratorValue(i) { i }
+                }
+                System.print(Countdown.new(3).map { |x| x * 10 }.toList)
+                System.print(Countdown.new(3).contains(2))
+                "
+            ),
+            "[30, 20, 10]\ntrue\n"
+        );
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::BTreeSet;
+
+use super::chunk::{Chunk, Op};
+
+/*
+ *
+ * Control Flow Graph
+ *
+ * Splits a chunk into basic blocks: runs of
+ * instructions that are only entered at their
+ * first instruction and only left after their
+ * last. A block starts at the chunk's start, at
+ * every jump target and after every jump or
+ * return, and its successors are the blocks
+ * control can reach next.
+ *
+ * Building the graph decodes every instruction,
+ * so it also finds jumps that land outside the
+ * chunk or inside another instruction's operands.
+ *
+ */
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    /// Byte offset of the first instruction.
+    pub start: usize,
+    /// Byte offset just past the last instruction.
+    pub end: usize,
+    /// Indices of the blocks control may go to next, the
+    /// fallthrough first.
+    pub successors: Vec<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cfg {
+    /// The blocks in the order they appear in the chunk.
+    pub blocks: Vec<BasicBlock>,
+}
+
+impl Cfg {
+    /// The index of the block starting at `offset`, if one does.
+    pub fn block_at(&self, offset: usize) -> Option<usize> {
+        self.blocks
+            .binary_search_by_key(&offset, |block| block.start)
+            .ok()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CfgError {
+    /// A byte where an instruction should start is not an opcode.
+    UnknownOp { offset: usize, byte: u8 },
+    /// The instruction at `offset` runs past the end of the chunk.
+    Truncated { offset: usize },
+    /// The jump at `offset` goes outside the chunk.
+    JumpOutOfBounds { offset: usize, target: isize },
+    /// The jump at `offset` lands inside another instruction.
+    JumpIntoInstruction { offset: usize, target: usize },
+}
+
+impl std::fmt::Display for CfgError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CfgError::UnknownOp { offset, byte } => {
+                write!(f, "unknown opcode {byte} at {offset}")
+            }
+            CfgError::Truncated { offset } => {
+                write!(f, "instruction at {offset} is cut off")
+            }
+            CfgError::JumpOutOfBounds { offset, target } => {
+                write!(f, "jump at {offset} goes to {target}, outside the chunk")
+            }
+            CfgError::JumpIntoInstruction { offset, target } => {
+                write!(
+                    f,
+                    "jump at {offset} lands inside the instruction at {target}"
+                )
+            }
+        }
+    }
+}
+
+/// One decoded instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instruction {
+    pub offset: usize,
+    pub op: Op,
+    /// Byte offset of the next instruction.
+    pub next: usize,
+}
+
+/// Decodes the instructions of `chunk` in order.
+pub fn instructions(chunk: &Chunk) -> Result<Vec<Instruction>, CfgError> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let byte = chunk.code[offset];
+        let op = Op::from_byte(byte).ok_or(CfgError::UnknownOp { offset, byte })?;
+        let next = offset + 1 + op.operand_len();
+        if next > chunk.code.len() {
+            return Err(CfgError::Truncated { offset });
+        }
+        instructions.push(Instruction { offset, op, next });
+        offset = next;
+    }
+    Ok(instructions)
+}
+
+/// Where the jump `instruction` goes, checked to be the start of
+/// one of `instructions`. `None` if it is not a jump.
+pub fn jump_target(
+    chunk: &Chunk,
+    instruction: &Instruction,
+    instructions: &[Instruction],
+) -> Result<Option<usize>, CfgError> {
+    if !matches!(
+        instruction.op,
+        Op::Jump | Op::JumpIfFalse | Op::And | Op::Or
+    ) {
+        return Ok(None);
+    }
+    let offset = instruction.offset;
+    let jump = chunk
+        .read_i16(offset + 1)
+        .ok_or(CfgError::Truncated { offset })?;
+    let target = instruction.next as isize + jump as isize;
+    if target < 0 || target as usize >= chunk.code.len() {
+        return Err(CfgError::JumpOutOfBounds { offset, target });
+    }
+    let target = target as usize;
+    match instructions.binary_search_by_key(&target, |i| i.offset) {
+        Ok(_) => Ok(Some(target)),
+        Err(after) => Err(CfgError::JumpIntoInstruction {
+            offset,
+            target: instructions[after - 1].offset,
+        }),
+    }
+}
+
+pub fn build_cfg(chunk: &Chunk) -> Result<Cfg, CfgError> {
+    let instructions = instructions(chunk)?;
+
+    let mut leaders = BTreeSet::new();
+    if !instructions.is_empty() {
+        leaders.insert(0);
+    }
+    let mut targets = Vec::with_capacity(instructions.len());
+    for instruction in &instructions {
+        let target = jump_target(chunk, instruction, &instructions)?;
+        if let Some(target) = target {
+            leaders.insert(target);
+        }
+        if (target.is_some() || instruction.op == Op::Return) && instruction.next < chunk.code.len()
+        {
+            leaders.insert(instruction.next);
+        }
+        targets.push(target);
+    }
+
+    let starts: Vec<usize> = leaders.into_iter().collect();
+    let index_of = |offset: usize| {
+        starts
+            .binary_search(&offset)
+            .expect("jump targets start blocks")
+    };
+    let mut blocks = Vec::with_capacity(starts.len());
+    for (i, &start) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).copied().unwrap_or(chunk.code.len());
+        let last = instructions
+            .binary_search_by_key(&end, |instruction| instruction.next)
+            .expect("blocks end after an instruction");
+        let falls_through = !matches!(instructions[last].op, Op::Jump | Op::Return);
+
+        let mut successors = Vec::new();
+        if falls_through && end < chunk.code.len() {
+            successors.push(i + 1);
+        }
+        if let Some(target) = targets[last] {
+            successors.push(index_of(target));
+        }
+        blocks.push(BasicBlock {
+            start,
+            end,
+            successors,
+        });
+    }
+    Ok(Cfg { blocks })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vm::chunk::ChunkBuilder;
+
+    // `if (true) null else false`, with both branches popped.
+    fn if_else() -> Chunk {
+        let mut builder = ChunkBuilder::new();
+        let to_else = builder.op(Op::True).emit_jump(Op::JumpIfFalse);
+        let to_end = builder.op(Op::Null).op(Op::Pop).emit_jump(Op::Jump);
+        builder.patch_jump(to_else).unwrap();
+        builder.op(Op::False).op(Op::Pop);
+        builder.patch_jump(to_end).unwrap();
+        builder.op(Op::Null).op(Op::Return);
+        builder.build()
+    }
+
+    #[test]
+    fn if_else_has_four_blocks() {
+        let cfg = build_cfg(&if_else()).unwrap();
+        let block = |start, end, successors: &[usize]| BasicBlock {
+            start,
+            end,
+            successors: successors.to_vec(),
+        };
+
+        assert_eq!(
+            cfg.blocks,
+            vec![
+                block(0, 4, &[1, 2]),
+                block(4, 9, &[3]),
+                block(9, 11, &[3]),
+                block(11, 13, &[]),
+            ]
+        );
+        assert_eq!(cfg.block_at(9), Some(2));
+        assert_eq!(cfg.block_at(10), None);
+    }
+
+    #[test]
+    fn loop_edges_go_back() {
+        let mut builder = ChunkBuilder::new();
+        builder.op(Op::Null);
+        let start = builder.code.len();
+        let exit = builder.op(Op::True).emit_jump(Op::JumpIfFalse);
+        builder.emit_loop(start).unwrap();
+        builder.patch_jump(exit).unwrap();
+        builder.op(Op::Return);
+        let cfg = build_cfg(&builder.build()).unwrap();
+
+        let successors: Vec<_> = cfg.blocks.iter().map(|b| b.successors.clone()).collect();
+        assert_eq!(successors, vec![vec![1], vec![2, 3], vec![1], vec![]]);
+    }
+
+    #[test]
+    fn malformed_jumps() {
+        let mut into_operand = ChunkBuilder::new();
+        let constant = into_operand.add_constant(crate::vm::value::Value::Null);
+        into_operand.op(Op::Jump).u16(1);
+        into_operand.op(Op::Constant).u16(constant).op(Op::Return);
+        assert_eq!(
+            build_cfg(&into_operand.build()),
+            Err(CfgError::JumpIntoInstruction {
+                offset: 0,
+                target: 3
+            })
+        );
+
+        let mut past_end = ChunkBuilder::new();
+        past_end.op(Op::Jump).u16(5).op(Op::Return);
+        assert_eq!(
+            build_cfg(&past_end.build()),
+            Err(CfgError::JumpOutOfBounds {
+                offset: 0,
+                target: 8
+            })
+        );
+
+        let mut truncated = ChunkBuilder::new();
+        truncated.op(Op::Null).op(Op::Jump).u8(0);
+        assert_eq!(
+            build_cfg(&truncated.build()),
+            Err(CfgError::Truncated { offset: 1 })
+        );
+    }
+}
@@ -0,0 +1,594 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::rc::{Rc, Weak};
+
+use super::heap::GcRef;
+use super::value::{Class, Value};
+
+/*
+ *
+ * Bytecode
+ *
+ * A chunk is a flat byte vector of opcodes, each
+ * followed by its operands, plus a constant table
+ * and a map from its code to the source spans it
+ * was compiled from.
+ *
+ * Operand encoding:
+ *  u8  = one byte
+ *  u16 = two bytes, little endian
+ *  i16 = jump offset relative to the byte after
+ *        the operand
+ *
+ */
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    // u16 constant index
+    Constant,
+    Null,
+    True,
+    False,
+    Pop,
+    // u8 depth; pushes a copy of the value that many
+    // slots below the top, so 0 copies the top
+    Dup,
+
+    // u8 slot, relative to the frame's base slot
+    GetLocal,
+    SetLocal,
+
+    // u16 constant index of the variable name
+    GetGlobal,
+    SetGlobal,
+
+    // u8 index into the running function's upvalues
+    GetUpvalue,
+    SetUpvalue,
+    // pops the top of the stack, closing the upvalues
+    // that refer to its slot
+    CloseUpvalue,
+
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Negate,
+    Not,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+    // pops a class and a value, pushing whether the
+    // value is an instance of the class
+    Is,
+    BitwiseAnd,
+    BitwiseOr,
+    BitwiseXor,
+    LeftShift,
+    RightShift,
+    InclusiveRange,
+    ExclusiveRange,
+
+    // pushes a new empty map
+    Map,
+    // pops a value and a key, inserting them
+    // into the map beneath
+    MapInsert,
+    // pushes a new empty list
+    List,
+    // pops a value, appending it to the list beneath
+    ListAppend,
+
+    // u16 constant index of the module name,
+    // runs the module if it has not run yet
+    ImportModule,
+    // u16 module name, u16 variable name
+    ImportVariable,
+
+    // u16 constant index of the class name, u8 field
+    // count; pushes a new class
+    Class,
+    // like `Class`, but pops the superclass first; the
+    // field count excludes the superclass's fields
+    Subclass,
+    // u16 constant index of the signature; pops a
+    // function and binds it on the class beneath
+    Method,
+    StaticMethod,
+    // replaces slot 0, the class being constructed,
+    // with a new instance of it; a constructor called
+    // through `super` already has its instance there
+    Construct,
+    // u8 index of a field of `this`, in slot 0, counted
+    // from the first field the method's class declares
+    GetField,
+    SetField,
+    // like `GetField` and `SetField`, but of the instance
+    // popped off the top, for blocks, which hold `this`
+    // in an upvalue
+    GetFieldOf,
+    SetFieldOf,
+
+    // i16 offset
+    Jump,
+    // i16 offset, pops the condition
+    JumpIfFalse,
+    // i16 offset, jumps keeping the operand if it is
+    // falsy (`And`) or truthy (`Or`), else pops it
+    And,
+    Or,
+
+    // u16 constant index of a function, pushed bound
+    // to the module of the running function
+    Closure,
+    // u8 argument count
+    Call,
+    // u16 constant index of the signature, u8 argument
+    // count; the receiver sits below the arguments
+    Invoke,
+    // like `Invoke`, but calls the method of the superclass
+    // of the class defining the current method, or for
+    // `SuperConstruct` its constructor
+    SuperInvoke,
+    SuperConstruct,
+    Return,
+}
+
+impl Op {
+    const ALL: [Op; 59] = [
+        Op::Constant,
+        Op::Null,
+        Op::True,
+        Op::False,
+        Op::Pop,
+        Op::Dup,
+        Op::GetLocal,
+        Op::SetLocal,
+        Op::GetGlobal,
+        Op::SetGlobal,
+        Op::GetUpvalue,
+        Op::SetUpvalue,
+        Op::CloseUpvalue,
+        Op::Add,
+        Op::Subtract,
+        Op::Multiply,
+        Op::Divide,
+        Op::Modulo,
+        Op::Negate,
+        Op::Not,
+        Op::Less,
+        Op::LessEqual,
+        Op::Greater,
+        Op::GreaterEqual,
+        Op::Equal,
+        Op::NotEqual,
+        Op::Is,
+        Op::BitwiseAnd,
+        Op::BitwiseOr,
+        Op::BitwiseXor,
+        Op::LeftShift,
+        Op::RightShift,
+        Op::InclusiveRange,
+        Op::ExclusiveRange,
+        Op::Map,
+        Op::MapInsert,
+        Op::List,
+        Op::ListAppend,
+        Op::ImportModule,
+        Op::ImportVariable,
+        Op::Class,
+        Op::Subclass,
+        Op::Method,
+        Op::StaticMethod,
+        Op::Construct,
+        Op::GetField,
+        Op::SetField,
+        Op::GetFieldOf,
+        Op::SetFieldOf,
+        Op::Jump,
+        Op::JumpIfFalse,
+        Op::And,
+        Op::Or,
+        Op::Closure,
+        Op::Call,
+        Op::Invoke,
+        Op::SuperInvoke,
+        Op::SuperConstruct,
+        Op::Return,
+    ];
+
+    pub fn from_byte(byte: u8) -> Option<Op> {
+        Op::ALL.get(byte as usize).copied()
+    }
+
+    /// Number of operand bytes following the opcode.
+    pub fn operand_len(self) -> usize {
+        match self {
+            Op::Constant
+            | Op::GetGlobal
+            | Op::SetGlobal
+            | Op::Jump
+            | Op::JumpIfFalse
+            | Op::And
+            | Op::Or
+            | Op::ImportModule
+            | Op::Closure
+            | Op::Method
+            | Op::StaticMethod => 2,
+            Op::Invoke | Op::SuperInvoke | Op::SuperConstruct | Op::Class | Op::Subclass => 3,
+            Op::ImportVariable => 4,
+            Op::Dup
+            | Op::GetLocal
+            | Op::SetLocal
+            | Op::GetUpvalue
+            | Op::SetUpvalue
+            | Op::GetField
+            | Op::SetField
+            | Op::GetFieldOf
+            | Op::SetFieldOf
+            | Op::Call => 1,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Chunk {
+    pub code: Vec<u8>,
+    pub constants: Vec<Value>,
+    /// Whether method calls in this chunk skip the inline cache
+    /// and look the method up every time.
+    pub uncached: bool,
+    /// Where the code came from, as `(offset, span)` pairs in order
+    /// of offset: the code from each offset to the next was compiled
+    /// from `span` of the module's source.
+    pub spans: Vec<(usize, Range<usize>)>,
+}
+
+impl Chunk {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write_op(&mut self, op: Op) {
+        self.code.push(op as u8);
+    }
+
+    pub fn write_u8(&mut self, byte: u8) {
+        self.code.push(byte);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_i16(&mut self, value: i16) {
+        self.code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Adds `value` to the constant table, returning its index.
+    pub fn add_constant(&mut self, value: Value) -> u16 {
+        self.constants.push(value);
+        (self.constants.len() - 1) as u16
+    }
+
+    /// Attributes the code written from now on to `span`.
+    pub fn mark_span(&mut self, span: Range<usize>) {
+        let offset = self.code.len();
+        match self.spans.last_mut() {
+            Some((_, last)) if *last == span => {}
+            Some((at, last)) if *at == offset => *last = span,
+            _ => self.spans.push((offset, span)),
+        }
+    }
+
+    /// The source span of the instruction that `ip` is in.
+    pub fn span_at(&self, ip: usize) -> Option<Range<usize>> {
+        if ip >= self.code.len() {
+            return None;
+        }
+        let next = self.spans.partition_point(|(offset, _)| *offset <= ip);
+        let (_, span) = self.spans.get(next.checked_sub(1)?)?;
+        Some(span.clone())
+    }
+
+    pub fn read_u8(&self, offset: usize) -> Option<u8> {
+        self.code.get(offset).copied()
+    }
+
+    pub fn read_u16(&self, offset: usize) -> Option<u16> {
+        let bytes = self.code.get(offset..offset + 2)?;
+        Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_i16(&self, offset: usize) -> Option<i16> {
+        self.read_u16(offset).map(|value| value as i16)
+    }
+}
+
+/// The offset of a jump emitted by `ChunkBuilder`, until it is patched.
+#[must_use = "a jump must be patched once its target is known"]
+#[derive(Debug, PartialEq, Eq)]
+pub struct JumpHandle {
+    operand: usize,
+}
+
+/// A jump whose target is further than an i16 offset reaches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JumpTooFar;
+
+/// Writes a chunk, computing jump offsets so callers only name
+/// where jumps go. It derefs to the chunk being written:
+///
+///   let exit = builder.op(Op::True).emit_jump(Op::JumpIfFalse);
+///   builder.op(Op::Null).op(Op::Pop);
+///   builder.patch_jump(exit)?;
+#[derive(Debug, Clone, Default)]
+pub struct ChunkBuilder {
+    chunk: Chunk,
+}
+
+impl ChunkBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn op(&mut self, op: Op) -> &mut Self {
+        self.chunk.write_op(op);
+        self
+    }
+
+    pub fn u8(&mut self, byte: u8) -> &mut Self {
+        self.chunk.write_u8(byte);
+        self
+    }
+
+    pub fn u16(&mut self, value: u16) -> &mut Self {
+        self.chunk.write_u16(value);
+        self
+    }
+
+    /// Emits `op` with an offset to be patched once the code it
+    /// jumps to is reached.
+    pub fn emit_jump(&mut self, op: Op) -> JumpHandle {
+        self.chunk.write_op(op);
+        self.chunk.write_i16(0);
+        JumpHandle {
+            operand: self.chunk.code.len() - 2,
+        }
+    }
+
+    /// Points `jump` at the end of the code written so far.
+    pub fn patch_jump(&mut self, jump: JumpHandle) -> Result<(), JumpTooFar> {
+        let offset = self.chunk.code.len() - (jump.operand + 2);
+        let offset = i16::try_from(offset).map_err(|_| JumpTooFar)?;
+        self.chunk.code[jump.operand..jump.operand + 2].copy_from_slice(&offset.to_le_bytes());
+        Ok(())
+    }
+
+    /// Emits a jump back to `start`.
+    pub fn emit_loop(&mut self, start: usize) -> Result<(), JumpTooFar> {
+        self.chunk.write_op(Op::Jump);
+        let end = self.chunk.code.len() + 2;
+        let offset = i16::try_from(start as isize - end as isize).map_err(|_| JumpTooFar)?;
+        self.chunk.write_i16(offset);
+        Ok(())
+    }
+
+    pub fn build(self) -> Chunk {
+        self.chunk
+    }
+}
+
+impl std::ops::Deref for ChunkBuilder {
+    type Target = Chunk;
+
+    fn deref(&self) -> &Chunk {
+        &self.chunk
+    }
+}
+
+impl std::ops::DerefMut for ChunkBuilder {
+    fn deref_mut(&mut self) -> &mut Chunk {
+        &mut self.chunk
+    }
+}
+
+pub const MAIN_MODULE: &str = "main";
+
+/// Holds the core classes, visible from every module.
+pub const CORE_MODULE: &str = "core";
+
+/// A compiled function. Slot 0 of its frame holds the
+/// callee itself, followed by one slot per parameter.
+///
+/// Module variables are looked up in the module the
+/// function was defined in. A block's function lists
+/// the variables of enclosing functions it uses in
+/// `captures`; `Closure` makes a copy of it holding
+/// an upvalue for each.
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name: Rc<str>,
+    pub arity: u8,
+    pub chunk: Chunk,
+    pub module: Rc<str>,
+    pub cache: InlineCache,
+    /// For methods, the superclass of the class they were bound
+    /// on, which `super` calls go to.
+    pub superclass: Option<Rc<Class>>,
+    /// For methods, how many fields the superclasses hold before
+    /// those of the class the method was bound on. Blocks created
+    /// in a method share its superclass and field offset.
+    pub field_offset: usize,
+    /// For blocks, where each upvalue is found when the block is
+    /// created, in the order `GetUpvalue` numbers them.
+    pub captures: Vec<Capture>,
+    /// For closures, the variables they captured.
+    pub upvalues: Vec<GcRef>,
+}
+
+/// Where a block finds a variable it captures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capture {
+    /// The local in this slot of the function creating the block.
+    Local(u8),
+    /// This upvalue of the function creating the block.
+    Upvalue(u8),
+}
+
+impl Function {
+    pub fn new(name: &str, arity: u8, chunk: Chunk, module: &str) -> Self {
+        Function {
+            name: name.into(),
+            arity,
+            chunk,
+            module: module.into(),
+            cache: InlineCache::default(),
+            superclass: None,
+            field_offset: 0,
+            captures: Vec::new(),
+            upvalues: Vec::new(),
+        }
+    }
+
+    /// Wraps the compiled body of the main module.
+    pub fn script(chunk: Chunk) -> Self {
+        Self::module(MAIN_MODULE, chunk)
+    }
+
+    /// The field a getter like `x { _x }` returns, if its body does
+    /// nothing but load that field of `this`.
+    pub fn field_getter(&self) -> Option<usize> {
+        match self.chunk.code[..] {
+            [op, index, ret]
+                if op == Op::GetField as u8 && ret == Op::Return as u8 && self.arity == 0 =>
+            {
+                Some(self.field_offset + index as usize)
+            }
+            _ => None,
+        }
+    }
+
+    /// Wraps the compiled body of the module `name`.
+    pub fn module(name: &str, chunk: Chunk) -> Self {
+        Self::new("<script>", 0, chunk, name)
+    }
+}
+
+/// A monomorphic inline cache for each method call site of a
+/// function, keyed by the offset of its `Invoke`. Each entry
+/// remembers the last class a method was found on, so calling
+/// again on the same class skips the lookup.
+///
+/// Entries hold their class weakly, since the class owns the
+/// methods that own the caches.
+#[derive(Debug, Clone, Default)]
+pub struct InlineCache {
+    entries: RefCell<HashMap<usize, CacheEntry>>,
+}
+
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    class: Weak<Class>,
+    on_class: bool,
+    version: u32,
+    method: Rc<Function>,
+}
+
+impl InlineCache {
+    /// The method cached at `site` if it was resolved on `class`,
+    /// as it is now.
+    pub fn get(&self, site: usize, class: &Rc<Class>, on_class: bool) -> Option<Rc<Function>> {
+        let entries = self.entries.borrow();
+        let entry = entries.get(&site)?;
+        let hit = entry.class.as_ptr() == Rc::as_ptr(class)
+            && entry.on_class == on_class
+            && entry.version == class.version();
+        hit.then(|| entry.method.clone())
+    }
+
+    pub fn insert(&self, site: usize, class: &Rc<Class>, on_class: bool, method: Rc<Function>) {
+        let entry = CacheEntry {
+            class: Rc::downgrade(class),
+            on_class,
+            version: class.version(),
+            method,
+        };
+        self.entries.borrow_mut().insert(site, entry);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn opcodes_round_trip() {
+        for (byte, op) in Op::ALL.iter().enumerate() {
+            assert_eq!(*op as u8, byte as u8);
+            assert_eq!(Op::from_byte(byte as u8), Some(*op));
+        }
+        assert_eq!(Op::from_byte(Op::ALL.len() as u8), None);
+    }
+
+    #[test]
+    fn forward_jump() {
+        let mut builder = ChunkBuilder::new();
+        let jump = builder.emit_jump(Op::JumpIfFalse);
+        builder.op(Op::Null).op(Op::Pop).op(Op::True);
+        builder.patch_jump(jump).unwrap();
+        let chunk = builder.build();
+
+        assert_eq!(chunk.read_i16(1), Some(3));
+    }
+
+    #[test]
+    fn backward_jump() {
+        let mut builder = ChunkBuilder::new();
+        builder.op(Op::Null);
+        let start = builder.code.len();
+        builder.op(Op::True).op(Op::Pop);
+        builder.emit_loop(start).unwrap();
+
+        // Back over the loop's two ops and its own three bytes.
+        assert_eq!(builder.read_i16(4), Some(-5));
+    }
+
+    #[test]
+    fn spans_cover_instructions() {
+        let mut chunk = Chunk::new();
+        chunk.mark_span(0..5);
+        chunk.write_op(Op::Constant);
+        chunk.write_u16(0);
+        // Only the last span marked at an offset is kept.
+        chunk.mark_span(6..7);
+        chunk.mark_span(0..9);
+        chunk.write_op(Op::Negate);
+        chunk.mark_span(0..9);
+        chunk.write_op(Op::Return);
+
+        assert_eq!(chunk.spans, [(0, 0..5), (3, 0..9)]);
+        assert_eq!(chunk.span_at(0), Some(0..5));
+        assert_eq!(chunk.span_at(2), Some(0..5));
+        assert_eq!(chunk.span_at(4), Some(0..9));
+        assert_eq!(chunk.span_at(5), None);
+        assert_eq!(Chunk::new().span_at(0), None);
+    }
+
+    #[test]
+    fn jump_too_far() {
+        let mut builder = ChunkBuilder::new();
+        let jump = builder.emit_jump(Op::Jump);
+        builder.code.resize(40_000, Op::Null as u8);
+
+        assert_eq!(builder.patch_jump(jump), Err(JumpTooFar));
+        assert_eq!(builder.emit_loop(0), Err(JumpTooFar));
+    }
+}
@@ -0,0 +1,164 @@
+use std::fmt::Write;
+
+use super::builtins::num_to_string;
+use super::cfg::instructions;
+use super::chunk::{Chunk, Op};
+use super::value::Value;
+
+/*
+ *
+ * Disassembler
+ *
+ * Lists the instructions of a chunk one per line,
+ * with the byte offset, the opcode and its operands.
+ * Constant operands are followed by the constant
+ * they name, and jumps by the offset they go to.
+ *
+ * Functions among the constants, such as methods
+ * and blocks, are listed after the chunk that
+ * holds them.
+ *
+ */
+
+/// The listing of `chunk`, headed by `name`.
+pub fn disassemble(name: &str, chunk: &Chunk) -> String {
+    let mut out = String::new();
+    write_chunk(name, chunk, &mut out);
+    out
+}
+
+fn write_chunk(name: &str, chunk: &Chunk, out: &mut String) {
+    let _ = writeln!(out, "== {name} ==");
+    match instructions(chunk) {
+        Ok(instructions) => {
+            for instruction in instructions {
+                let _ = write!(out, "{:04} {:?}", instruction.offset, instruction.op);
+                operands(chunk, instruction.offset, instruction.op, out);
+                out.push('\n');
+            }
+        }
+        Err(error) => {
+            let _ = writeln!(out, "error: {error}");
+        }
+    }
+    for constant in &chunk.constants {
+        if let Value::Fn(function) = constant {
+            out.push('\n');
+            write_chunk(&function.name, &function.chunk, out);
+        }
+    }
+}
+
+// Decoded instructions have all their operand bytes.
+fn operands(chunk: &Chunk, offset: usize, op: Op, out: &mut String) {
+    let u8_at = |at| chunk.read_u8(offset + at).unwrap_or_default();
+    let u16_at = |at| chunk.read_u16(offset + at).unwrap_or_default();
+    let constant = |index: u16| match chunk.constants.get(index as usize) {
+        Some(value) => describe(value),
+        None => "?".to_string(),
+    };
+    let _ = match op {
+        Op::Constant
+        | Op::GetGlobal
+        | Op::SetGlobal
+        | Op::ImportModule
+        | Op::Method
+        | Op::StaticMethod
+        | Op::Closure => write!(out, " {} ({})", u16_at(1), constant(u16_at(1))),
+        Op::Class | Op::Subclass => write!(
+            out,
+            " {} ({}) fields {}",
+            u16_at(1),
+            constant(u16_at(1)),
+            u8_at(3)
+        ),
+        Op::Invoke | Op::SuperInvoke | Op::SuperConstruct => write!(
+            out,
+            " {} ({}) args {}",
+            u16_at(1),
+            constant(u16_at(1)),
+            u8_at(3)
+        ),
+        Op::ImportVariable => write!(
+            out,
+            " {} ({}) {} ({})",
+            u16_at(1),
+            constant(u16_at(1)),
+            u16_at(3),
+            constant(u16_at(3))
+        ),
+        Op::Dup
+        | Op::GetLocal
+        | Op::SetLocal
+        | Op::GetUpvalue
+        | Op::SetUpvalue
+        | Op::GetField
+        | Op::SetField
+        | Op::GetFieldOf
+        | Op::SetFieldOf
+        | Op::Call => write!(out, " {}", u8_at(1)),
+        Op::Jump | Op::JumpIfFalse | Op::And | Op::Or => {
+            let jump = chunk.read_i16(offset + 1).unwrap_or_default();
+            let target = (offset + 3) as isize + jump as isize;
+            write!(out, " {jump} -> {target}")
+        }
+        _ => Ok(()),
+    };
+}
+
+fn describe(value: &Value) -> String {
+    match value {
+        Value::Num(n) => num_to_string(*n),
+        Value::Str(s) => format!("{s:?}"),
+        Value::Fn(function) => format!("fn {}", function.name),
+        value => value.class_name().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::codegen::{compile_with, CompileOptions, OptLevel};
+
+    #[test]
+    fn lists_instructions_and_functions() {
+        let options = CompileOptions {
+            opt_level: OptLevel::None,
+        };
+        let chunk = compile_with("var a = 1 && 2 class A { f { 3 } }", options).unwrap();
+
+        assert_eq!(
+            disassemble("<script>", &chunk),
+            "== <script> ==
+0000 Constant 0 (1)
+0003 And 3 -> 9
+0006 Constant 1 (2)
+0009 SetGlobal 2 (\"a\")
+0012 Pop
+0013 Class 3 (\"A\") fields 0
+0017 Constant 4 (fn f)
+0020 Method 5 (\"f\")
+0023 SetGlobal 3 (\"A\")
+0026 Pop
+0027 Null
+0028 Return
+
+== f ==
+0000 Constant 0 (3)
+0003 Return
+"
+        );
+    }
+
+    #[test]
+    fn reports_malformed_code() {
+        let mut chunk = Chunk::new();
+        chunk.write_op(Op::Null);
+        chunk.code.push(200);
+
+        assert_eq!(
+            disassemble("bad", &chunk),
+            "== bad ==\nerror: unknown opcode 200 at 1\n"
+        );
+    }
+}
@@ -0,0 +1,72 @@
+use std::rc::Rc;
+
+use super::chunk::Function;
+use super::heap::GcRef;
+use super::interpreter::CallFrame;
+use super::value::Value;
+
+/*
+ *
+ * Fibers
+ *
+ * A fiber is a call stack of its own. Only one
+ * runs at a time, on the VM's stack; the others
+ * keep theirs in their context until resumed.
+ *
+ * `call` resumes a fiber and remembers who called
+ * it, so `Fiber.yield` and returning from the fiber
+ * hand a value back to the caller. `transfer`
+ * switches without remembering, so when a fiber
+ * reached that way finishes, the VM stops running.
+ *
+ * The VM starts out in the root fiber, which has
+ * no object and cannot be resumed by the program.
+ *
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FiberRef {
+    Root,
+    Fiber(GcRef),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    pub stack: Vec<Value>,
+    pub frames: Vec<CallFrame>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FiberState {
+    /// Created but never run.
+    New,
+    /// Yielded, or transferred away from.
+    Suspended,
+    /// Running, or waiting on a fiber it called.
+    Active,
+    Done,
+}
+
+#[derive(Debug, Clone)]
+pub struct Fiber {
+    pub function: Rc<Function>,
+    pub context: Context,
+    pub caller: Option<FiberRef>,
+    pub state: FiberState,
+}
+
+impl Fiber {
+    pub fn new(function: Rc<Function>) -> Self {
+        Fiber {
+            function,
+            context: Context::default(),
+            caller: None,
+            state: FiberState::New,
+        }
+    }
+
+    /// The values this fiber keeps alive while it is not running.
+    pub fn roots(&self) -> impl Iterator<Item = &Value> {
+        self.context.stack.iter()
+    }
+}
@@ -0,0 +1,300 @@
+use std::rc::Rc;
+
+use super::fiber::{Fiber, FiberRef};
+use super::map::OrderedMap;
+use super::value::{Class, Value};
+
+/*
+ *
+ * Heap
+ *
+ * Owns the mutable objects (instances, lists, maps,
+ * fibers and upvalues) that values refer to through
+ * `GcRef` handles. These can form cycles, so they are
+ * reclaimed with a simple non-incremental
+ * mark-sweep collector.
+ *
+ * Strings are immutable and cannot form cycles,
+ * so they stay reference counted inside `Value`.
+ *
+ */
+
+pub const DEFAULT_THRESHOLD: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GcRef(u32);
+
+#[derive(Debug, Clone)]
+pub enum Object {
+    Instance(Instance),
+    List(Vec<Value>),
+    Map(OrderedMap),
+    Fiber(Fiber),
+    Upvalue(Upvalue),
+}
+
+/// A variable captured by a block. It stays in its slot of the
+/// stack of the fiber that declared it until it goes out of
+/// scope, and is then moved here.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Upvalue {
+    Open { fiber: FiberRef, slot: usize },
+    Closed(Value),
+}
+
+#[derive(Debug, Clone)]
+pub struct Instance {
+    pub class: Rc<Class>,
+    pub fields: Vec<Value>,
+}
+
+#[derive(Debug)]
+struct Slot {
+    object: Object,
+    marked: bool,
+}
+
+#[derive(Debug)]
+pub struct Heap {
+    slots: Vec<Option<Slot>>,
+    free: Vec<u32>,
+    live: usize,
+    threshold: usize,
+    initial_threshold: usize,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self::with_threshold(DEFAULT_THRESHOLD)
+    }
+
+    /// A heap that asks for a collection once `threshold` objects are live.
+    pub fn with_threshold(threshold: usize) -> Self {
+        Heap {
+            slots: Vec::new(),
+            free: Vec::new(),
+            live: 0,
+            threshold,
+            initial_threshold: threshold,
+        }
+    }
+
+    pub fn alloc(&mut self, object: Object) -> GcRef {
+        let slot = Some(Slot {
+            object,
+            marked: false,
+        });
+        self.live += 1;
+
+        match self.free.pop() {
+            Some(index) => {
+                self.slots[index as usize] = slot;
+                GcRef(index)
+            }
+            None => {
+                self.slots.push(slot);
+                GcRef((self.slots.len() - 1) as u32)
+            }
+        }
+    }
+
+    pub fn get(&self, handle: GcRef) -> &Object {
+        match &self.slots[handle.0 as usize] {
+            Some(slot) => &slot.object,
+            None => panic!("use of freed object {handle:?}"),
+        }
+    }
+
+    pub fn get_mut(&mut self, handle: GcRef) -> &mut Object {
+        match &mut self.slots[handle.0 as usize] {
+            Some(slot) => &mut slot.object,
+            None => panic!("use of freed object {handle:?}"),
+        }
+    }
+
+    /// Number of objects currently allocated.
+    pub fn live_objects(&self) -> usize {
+        self.live
+    }
+
+    /// Whether enough objects have piled up to warrant a collection.
+    pub fn should_collect(&self) -> bool {
+        self.live >= self.threshold
+    }
+
+    /// Frees every object not reachable from `roots`, returning how many were freed.
+    pub fn collect<'a>(&mut self, roots: impl IntoIterator<Item = &'a Value>) -> usize {
+        self.collect_with(roots, [])
+    }
+
+    /// Like `collect`, also keeping the objects in `handles`.
+    pub fn collect_with<'a>(
+        &mut self,
+        roots: impl IntoIterator<Item = &'a Value>,
+        handles: impl IntoIterator<Item = GcRef>,
+    ) -> usize {
+        let mut pending: Vec<GcRef> = handles.into_iter().collect();
+        for root in roots {
+            push_handles(root, &mut pending);
+        }
+
+        while let Some(handle) = pending.pop() {
+            let Some(slot) = &mut self.slots[handle.0 as usize] else {
+                continue;
+            };
+            if slot.marked {
+                continue;
+            }
+            slot.marked = true;
+
+            match &slot.object {
+                Object::Instance(instance) => {
+                    for field in &instance.fields {
+                        push_handles(field, &mut pending);
+                    }
+                }
+                Object::List(items) => {
+                    for item in items {
+                        push_handles(item, &mut pending);
+                    }
+                }
+                Object::Map(entries) => {
+                    for (key, value) in entries.iter() {
+                        push_handles(key, &mut pending);
+                        push_handles(value, &mut pending);
+                    }
+                }
+                Object::Fiber(fiber) => {
+                    for root in fiber.roots() {
+                        push_handles(root, &mut pending);
+                    }
+                    pending.extend(&fiber.function.upvalues);
+                    if let Some(FiberRef::Fiber(caller)) = fiber.caller {
+                        pending.push(caller);
+                    }
+                }
+                // An open upvalue's variable is on a stack, which
+                // is traced as a root or with its fiber.
+                Object::Upvalue(Upvalue::Open { fiber, .. }) => {
+                    if let FiberRef::Fiber(fiber) = fiber {
+                        pending.push(*fiber);
+                    }
+                }
+                Object::Upvalue(Upvalue::Closed(value)) => push_handles(value, &mut pending),
+            }
+        }
+
+        let mut freed = 0;
+        for (index, entry) in self.slots.iter_mut().enumerate() {
+            match entry {
+                Some(slot) if slot.marked => slot.marked = false,
+                Some(_) => {
+                    *entry = None;
+                    self.free.push(index as u32);
+                    freed += 1;
+                }
+                None => {}
+            }
+        }
+
+        self.live -= freed;
+        self.threshold = (self.live * 2).max(self.initial_threshold);
+        freed
+    }
+}
+
+// Adds the objects `value` refers to directly to `pending`.
+fn push_handles(value: &Value, pending: &mut Vec<GcRef>) {
+    match value {
+        Value::Instance(handle)
+        | Value::List(handle)
+        | Value::Map(handle)
+        | Value::Fiber(handle) => pending.push(*handle),
+        Value::Fn(function) => pending.extend(&function.upvalues),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vm::chunk::{Chunk, Function, MAIN_MODULE};
+
+    #[test]
+    fn collect_frees_unreachable_lists() {
+        let mut heap = Heap::new();
+        let kept = Value::List(heap.alloc(Object::List(vec![Value::Num(1.0)])));
+        for i in 0..1000 {
+            heap.alloc(Object::List(vec![Value::Num(i as f64)]));
+        }
+        assert_eq!(heap.live_objects(), 1001);
+
+        assert_eq!(heap.collect([&kept]), 1000);
+        assert_eq!(heap.live_objects(), 1);
+
+        let Value::List(handle) = kept else {
+            unreachable!()
+        };
+        assert!(matches!(heap.get(handle), Object::List(items) if items == &[Value::Num(1.0)]));
+    }
+
+    #[test]
+    fn collect_traces_nested_and_cyclic_objects() {
+        let mut heap = Heap::new();
+        let inner = heap.alloc(Object::Map(OrderedMap::new()));
+        let outer = heap.alloc(Object::List(vec![Value::Map(inner)]));
+        // The map refers back to the list that holds it.
+        if let Object::Map(entries) = heap.get_mut(inner) {
+            entries.insert(Value::Num(0.0), Value::List(outer));
+        }
+
+        assert_eq!(heap.collect([&Value::List(outer)]), 0);
+        assert_eq!(heap.live_objects(), 2);
+
+        assert_eq!(heap.collect([]), 2);
+        assert_eq!(heap.live_objects(), 0);
+    }
+
+    #[test]
+    fn collect_traces_closures_through_upvalues() {
+        let mut heap = Heap::new();
+        let list = heap.alloc(Object::List(Vec::new()));
+        let upvalue = heap.alloc(Object::Upvalue(Upvalue::Closed(Value::List(list))));
+        let mut closure = Function::new("<fn>", 0, Chunk::new(), MAIN_MODULE);
+        closure.upvalues.push(upvalue);
+
+        assert_eq!(heap.collect([&Value::Fn(Rc::new(closure))]), 0);
+        assert_eq!(heap.collect_with([], [upvalue]), 0);
+        assert_eq!(heap.collect([]), 2);
+    }
+
+    #[test]
+    fn freed_slots_are_reused() {
+        let mut heap = Heap::new();
+        let first = heap.alloc(Object::List(Vec::new()));
+        heap.collect([]);
+
+        assert_eq!(heap.alloc(Object::List(Vec::new())), first);
+    }
+
+    #[test]
+    fn threshold() {
+        let mut heap = Heap::with_threshold(4);
+        for _ in 0..3 {
+            heap.alloc(Object::List(Vec::new()));
+        }
+        assert!(!heap.should_collect());
+
+        heap.alloc(Object::List(Vec::new()));
+        assert!(heap.should_collect());
+
+        heap.collect([]);
+        assert!(!heap.should_collect());
+    }
+}
@@ -0,0 +1,1743 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::ops::Range;
+use std::rc::Rc;
+use std::time::Instant;
+
+use super::builtins;
+use super::chunk::{Capture, Chunk, Function, Op, CORE_MODULE, MAIN_MODULE};
+use super::fiber::{Context, Fiber, FiberRef, FiberState};
+use super::heap::{GcRef, Heap, Instance, Object, Upvalue};
+use super::map::OrderedMap;
+use super::value::{Class, TypeMismatch, Value};
+
+/*
+ *
+ * Interpreter
+ *
+ * A stack machine executing chunks. Each call
+ * pushes a frame whose base slot holds the callee,
+ * followed by its arguments and then its locals.
+ *
+ * Module variables are kept per module. A module
+ * registered with `add_module` runs the first time
+ * it is imported. Variables not found in a module
+ * are looked up in the core module.
+ *
+ * Foreign methods are native functions bound to a
+ * class name and signature, called with the VM and
+ * the arguments.
+ *
+ * Calls between Wren functions push frames on the
+ * VM's own stack, up to `max_frames` of them, so
+ * deep recursion fails with `StackOverflow` rather
+ * than overflowing the native stack. Native methods
+ * that call back into Wren do recurse natively, and
+ * are limited by `max_reentry`.
+ *
+ * The stack and frames belong to the running fiber.
+ * Switching fibers swaps them with those saved in
+ * the fiber resumed.
+ *
+ * A block reaches the variables it captured through
+ * upvalues. An upvalue refers to the variable's slot
+ * until its scope ends or its function returns, and
+ * then holds the value itself, so a block and the
+ * function that created it share the variable.
+ *
+ * A VM given a trace sink writes a line there
+ * before each instruction: its offset, opcode and
+ * the stack it finds. Without one the only cost is
+ * a check per instruction.
+ *
+ * When a run fails, the VM keeps the source span
+ * of the instruction that failed, from the source
+ * map of its chunk.
+ *
+ */
+
+pub const DEFAULT_MAX_FRAMES: usize = 1024;
+pub const DEFAULT_MAX_REENTRY: usize = 100;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    StackOverflow,
+    NotCallable,
+    WrongArity { expected: u8, found: u8 },
+    OperandMustBeNumber,
+    UndefinedVariable(Rc<str>),
+    UnknownModule(Rc<str>),
+    MethodNotFound { class: Rc<str>, signature: Rc<str> },
+    InvalidArgument(&'static str),
+    WrongType(TypeMismatch),
+    IndexOutOfBounds,
+    Output(std::io::ErrorKind),
+    InvalidBytecode,
+}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuntimeError::StackOverflow => write!(f, "stack overflow"),
+            RuntimeError::NotCallable => write!(f, "value is not callable"),
+            RuntimeError::WrongArity { expected, found } => {
+                write!(f, "expected {expected} arguments, got {found}")
+            }
+            RuntimeError::OperandMustBeNumber => write!(f, "operand must be a number"),
+            RuntimeError::UndefinedVariable(name) => write!(f, "undefined variable '{name}'"),
+            RuntimeError::UnknownModule(name) => write!(f, "could not find module '{name}'"),
+            RuntimeError::MethodNotFound { class, signature } => {
+                write!(f, "{class} does not implement '{signature}'")
+            }
+            RuntimeError::InvalidArgument(message) => write!(f, "{message}"),
+            RuntimeError::WrongType(mismatch) => write!(f, "{mismatch}"),
+            RuntimeError::IndexOutOfBounds => write!(f, "subscript out of bounds"),
+            RuntimeError::Output(kind) => write!(f, "could not write output: {kind}"),
+            RuntimeError::InvalidBytecode => write!(f, "invalid bytecode"),
+        }
+    }
+}
+
+// Lets foreign methods convert their arguments with `?`.
+impl From<TypeMismatch> for RuntimeError {
+    fn from(mismatch: TypeMismatch) -> Self {
+        RuntimeError::WrongType(mismatch)
+    }
+}
+
+type RunResult<T> = Result<T, RuntimeError>;
+
+pub type ForeignMethod = fn(&mut Vm, &[Value]) -> RunResult<Value>;
+
+#[derive(Debug, Clone)]
+pub struct CallFrame {
+    pub func: Rc<Function>,
+    pub ip: usize,
+    pub base_slot: usize,
+}
+
+pub struct Vm {
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    globals: HashMap<Rc<str>, HashMap<Rc<str>, Value>>,
+    pending_modules: HashMap<Rc<str>, Rc<Function>>,
+    foreign_methods: HashMap<Rc<str>, HashMap<Rc<str>, ForeignMethod>>,
+    output: Box<dyn Write>,
+    trace: Option<Box<dyn Write>>,
+    heap: Heap,
+    max_frames: usize,
+    // How deeply native code, such as `toList` iterating a sequence,
+    // may call back into the VM, and how deeply it has so far. Each
+    // level holds a native stack frame, unlike calls within the VM.
+    max_reentry: usize,
+    reentry: usize,
+    inlined_getters: u64,
+    cache_stats: CacheStats,
+    fiber: FiberRef,
+    // The upvalues still referring to a slot, of any fiber.
+    open_upvalues: Vec<GcRef>,
+    // The root fiber's stack and frames while another fiber runs.
+    root: Context,
+    fiber_class: Rc<Class>,
+    // Seconds since some fixed point, for `System.clock`.
+    clock: Box<dyn FnMut() -> f64>,
+    error_span: Option<Range<usize>>,
+}
+
+/// How often method lookups at call sites were answered by their
+/// inline cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        let mut vm = Vm {
+            stack: Vec::new(),
+            frames: Vec::new(),
+            globals: HashMap::new(),
+            pending_modules: HashMap::new(),
+            foreign_methods: HashMap::new(),
+            output: Box::new(std::io::stdout()),
+            trace: None,
+            heap: Heap::new(),
+            max_frames: DEFAULT_MAX_FRAMES,
+            max_reentry: DEFAULT_MAX_REENTRY,
+            reentry: 0,
+            inlined_getters: 0,
+            cache_stats: CacheStats::default(),
+            fiber: FiberRef::Root,
+            open_upvalues: Vec::new(),
+            root: Context::default(),
+            fiber_class: Rc::new(Class::new("Fiber", 0)),
+            clock: {
+                let start = Instant::now();
+                Box::new(move || start.elapsed().as_secs_f64())
+            },
+            error_span: None,
+        };
+        builtins::load_core(&mut vm);
+        let fiber_class = Value::Class(vm.fiber_class.clone());
+        vm.define_module_variable(CORE_MODULE, "Fiber", fiber_class);
+        vm
+    }
+
+    /// Replaces where `System.print` and friends write, stdout by default.
+    pub fn output(mut self, sink: Box<dyn Write>) -> Self {
+        self.output = sink;
+        self
+    }
+
+    /// Traces every instruction the VM runs to `sink`.
+    pub fn trace(mut self, sink: Box<dyn Write>) -> Self {
+        self.trace = Some(sink);
+        self
+    }
+
+    pub fn writer(&mut self) -> &mut dyn Write {
+        &mut *self.output
+    }
+
+    /// Binds `method` as the static method `signature` of `class`.
+    pub fn bind_foreign(&mut self, class: &str, signature: &str, method: ForeignMethod) {
+        self.foreign_methods
+            .entry(class.into())
+            .or_default()
+            .insert(signature.into(), method);
+    }
+
+    /// Replaces what `System.clock` reads, by default the seconds
+    /// since the VM was created.
+    pub fn clock(mut self, clock: Box<dyn FnMut() -> f64>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// The time `System.clock` returns now.
+    pub fn now(&mut self) -> f64 {
+        (self.clock)()
+    }
+
+    /// Replaces the heap, e.g. to use a different collection threshold.
+    pub fn heap(mut self, heap: Heap) -> Self {
+        self.heap = heap;
+        self
+    }
+
+    pub fn objects(&self) -> &Heap {
+        &self.heap
+    }
+
+    /// Allocates `object`, first collecting garbage if the heap is due.
+    pub fn alloc(&mut self, object: Object) -> GcRef {
+        if self.heap.should_collect() {
+            self.collect();
+        }
+        self.heap.alloc(object)
+    }
+
+    /// Frees every object unreachable from the stacks and globals.
+    pub fn collect(&mut self) -> usize {
+        let current = match self.fiber {
+            FiberRef::Fiber(handle) => Some(Value::Fiber(handle)),
+            FiberRef::Root => None,
+        };
+        self.heap.collect_with(
+            self.stack
+                .iter()
+                .chain(&self.root.stack)
+                .chain(&current)
+                .chain(self.globals.values().flat_map(|m| m.values())),
+            self.open_upvalues.iter().copied(),
+        )
+    }
+
+    /// How many getter calls were answered by reading the field directly.
+    pub fn inlined_getters(&self) -> u64 {
+        self.inlined_getters
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache_stats
+    }
+
+    /// Limits how deeply calls may nest before `StackOverflow`.
+    pub fn max_frames(mut self, limit: usize) -> Self {
+        self.max_frames = limit;
+        self
+    }
+
+    /// Limits how deeply native methods that call back into the VM,
+    /// such as those of sequences, may nest before `StackOverflow`.
+    /// These use the native stack, so the limit keeps deep nesting
+    /// from overflowing it.
+    pub fn max_reentry(mut self, limit: usize) -> Self {
+        self.max_reentry = limit;
+        self
+    }
+
+    /// Defines a variable in the main module.
+    pub fn define_global(&mut self, name: &str, value: Value) {
+        self.define_module_variable(MAIN_MODULE, name, value);
+    }
+
+    pub fn define_module_variable(&mut self, module: &str, name: &str, value: Value) {
+        self.globals
+            .entry(module.into())
+            .or_default()
+            .insert(name.into(), value);
+    }
+
+    /// A variable of the main module.
+    pub fn global(&self, name: &str) -> Option<&Value> {
+        self.module_variable(MAIN_MODULE, name)
+    }
+
+    pub fn module_variable(&self, module: &str, name: &str) -> Option<&Value> {
+        self.globals.get(module)?.get(name)
+    }
+
+    /// The source span of the instruction the last failed `run` failed
+    /// at, if its chunk has one. It is in the source of the module the
+    /// failing function was compiled in.
+    pub fn error_span(&self) -> Option<Range<usize>> {
+        self.error_span.clone()
+    }
+
+    /// Registers a compiled module to run when it is first imported.
+    pub fn add_module(&mut self, name: &str, chunk: Chunk) {
+        self.pending_modules
+            .insert(name.into(), Rc::new(Function::module(name, chunk)));
+    }
+
+    /// Calls `function` with no arguments and returns its result.
+    pub fn run(&mut self, function: Rc<Function>) -> RunResult<Value> {
+        let depth = self.frames.len();
+        let base_slot = self.stack.len();
+        self.stack.push(Value::Fn(function.clone()));
+
+        let result = self
+            .push_frame(function, base_slot, 0)
+            .and_then(|()| self.execute(depth));
+        self.error_span = None;
+        if result.is_err() {
+            // The frame's ip is past the opcode of the instruction
+            // that failed, and not past its last operand.
+            self.error_span = self
+                .frames
+                .last()
+                .and_then(|frame| frame.func.chunk.span_at(frame.ip.saturating_sub(1)));
+            self.leave_fibers();
+            self.close_upvalues(FiberRef::Root, base_slot);
+            self.frames.truncate(depth);
+            self.stack.truncate(base_slot);
+        }
+        result
+    }
+
+    fn push_frame(&mut self, func: Rc<Function>, base_slot: usize, argc: u8) -> RunResult<()> {
+        if func.arity != argc {
+            return Err(RuntimeError::WrongArity {
+                expected: func.arity,
+                found: argc,
+            });
+        }
+        if self.frames.len() >= self.max_frames {
+            return Err(RuntimeError::StackOverflow);
+        }
+        self.frames.push(CallFrame {
+            func,
+            ip: 0,
+            base_slot,
+        });
+        Ok(())
+    }
+
+    // Runs until the frame count drops back to `depth`.
+    fn execute(&mut self, depth: usize) -> RunResult<Value> {
+        loop {
+            if self.trace.is_some() {
+                self.trace_instruction()?;
+            }
+            let op = Op::from_byte(self.read_u8()?).ok_or(RuntimeError::InvalidBytecode)?;
+
+            match op {
+                Op::Constant => {
+                    let index = self.read_u16()?;
+                    let value = self.constant(index)?;
+                    self.stack.push(value);
+                }
+                Op::Null => self.stack.push(Value::Null),
+                Op::True => self.stack.push(Value::Bool(true)),
+                Op::False => self.stack.push(Value::Bool(false)),
+                Op::Pop => {
+                    self.pop()?;
+                }
+                Op::Dup => {
+                    let depth = self.read_u8()? as usize;
+                    let value = self
+                        .stack
+                        .len()
+                        .checked_sub(depth + 1)
+                        .map(|slot| self.stack[slot].clone())
+                        .ok_or(RuntimeError::InvalidBytecode)?;
+                    self.stack.push(value);
+                }
+
+                Op::GetLocal => {
+                    let slot = self.slot()?;
+                    let value = self.stack[slot].clone();
+                    self.stack.push(value);
+                }
+                Op::SetLocal => {
+                    let slot = self.slot()?;
+                    self.stack[slot] = self.peek()?.clone();
+                }
+                Op::GetUpvalue => {
+                    let handle = self.upvalue()?;
+                    let value = match self.heap.get(handle) {
+                        Object::Upvalue(Upvalue::Open { fiber, slot }) => {
+                            let (fiber, slot) = (*fiber, *slot);
+                            self.stack_of(fiber)[slot].clone()
+                        }
+                        Object::Upvalue(Upvalue::Closed(value)) => value.clone(),
+                        _ => return Err(RuntimeError::InvalidBytecode),
+                    };
+                    self.stack.push(value);
+                }
+                Op::SetUpvalue => {
+                    let handle = self.upvalue()?;
+                    let value = self.peek()?.clone();
+                    match self.heap.get_mut(handle) {
+                        Object::Upvalue(Upvalue::Open { fiber, slot }) => {
+                            let (fiber, slot) = (*fiber, *slot);
+                            self.stack_of(fiber)[slot] = value;
+                        }
+                        Object::Upvalue(Upvalue::Closed(closed)) => *closed = value,
+                        _ => return Err(RuntimeError::InvalidBytecode),
+                    }
+                }
+                Op::CloseUpvalue => {
+                    let top = self
+                        .stack
+                        .len()
+                        .checked_sub(1)
+                        .ok_or(RuntimeError::InvalidBytecode)?;
+                    self.close_upvalues(self.fiber, top);
+                    self.pop()?;
+                }
+                Op::GetGlobal => {
+                    let name = self.name()?;
+                    let module = self.frame()?.func.module.clone();
+                    let value = self.variable(&module, name)?;
+                    self.stack.push(value);
+                }
+                Op::SetGlobal => {
+                    let name = self.name()?;
+                    let module = self.frame()?.func.module.clone();
+                    let value = self.peek()?.clone();
+                    self.globals.entry(module).or_default().insert(name, value);
+                }
+                Op::ImportModule => {
+                    let module = self.name()?;
+                    self.import(module)?;
+                }
+                Op::ImportVariable => {
+                    let module = self.name()?;
+                    let name = self.name()?;
+                    let value = self.variable(&module, name)?;
+                    self.stack.push(value);
+                }
+
+                // Strings overload `+`; numbers take the fast path.
+                Op::Add => match self.stack.as_slice() {
+                    [.., Value::Str(_), _] => self.invoke("+(_)", 1, None)?,
+                    _ => self.numeric("+(_)", |a, b| Value::Num(a + b))?,
+                },
+                Op::Subtract => self.numeric("-(_)", |a, b| Value::Num(a - b))?,
+                Op::Multiply => self.numeric("*(_)", |a, b| Value::Num(a * b))?,
+                Op::Divide => self.numeric("/(_)", |a, b| Value::Num(a / b))?,
+                Op::Modulo => self.numeric("%(_)", |a, b| Value::Num(a % b))?,
+                Op::Negate => match self.stack.last() {
+                    Some(Value::Instance(_)) => self.operator("-", 0)?,
+                    _ => match self.pop()? {
+                        Value::Num(n) => self.stack.push(Value::Num(-n)),
+                        _ => return Err(RuntimeError::OperandMustBeNumber),
+                    },
+                },
+                Op::Not if self.overloads(0, "!") => self.operator("!", 0)?,
+                Op::Not => {
+                    let value = self.pop()?;
+                    self.stack.push(Value::Bool(!is_truthy(&value)));
+                }
+                Op::Less => self.numeric("<(_)", |a, b| Value::Bool(a < b))?,
+                Op::LessEqual => self.numeric("<=(_)", |a, b| Value::Bool(a <= b))?,
+                Op::Greater => self.numeric(">(_)", |a, b| Value::Bool(a > b))?,
+                Op::GreaterEqual => self.numeric(">=(_)", |a, b| Value::Bool(a >= b))?,
+                Op::Equal if self.overloads(1, "==(_)") => self.operator("==(_)", 1)?,
+                Op::Equal => {
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    self.stack.push(Value::Bool(equals(&a, &b)));
+                }
+                Op::NotEqual if self.overloads(1, "!=(_)") => self.operator("!=(_)", 1)?,
+                Op::NotEqual => {
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    self.stack.push(Value::Bool(!equals(&a, &b)));
+                }
+                Op::Is => {
+                    let class = self.pop()?;
+                    let value = self.pop()?;
+                    let is = builtins::is(self, &value, &class)?;
+                    self.stack.push(Value::Bool(is));
+                }
+
+                Op::BitwiseAnd => self.bitwise("&(_)", |a, b| a & b)?,
+                Op::BitwiseOr => self.bitwise("|(_)", |a, b| a | b)?,
+                Op::BitwiseXor => self.bitwise("^(_)", |a, b| a ^ b)?,
+                Op::LeftShift => self.bitwise("<<(_)", |a, b| a.wrapping_shl(b))?,
+                Op::RightShift => self.bitwise(">>(_)", |a, b| a.wrapping_shr(b))?,
+                Op::InclusiveRange => self.range(true)?,
+                Op::ExclusiveRange => self.range(false)?,
+
+                Op::Map => {
+                    let map = self.alloc(Object::Map(OrderedMap::new()));
+                    self.stack.push(Value::Map(map));
+                }
+                Op::MapInsert => {
+                    let value = self.pop()?;
+                    let key = self.pop()?;
+                    if matches!(
+                        key,
+                        Value::Fn(_)
+                            | Value::Instance(_)
+                            | Value::List(_)
+                            | Value::Map(_)
+                            | Value::Fiber(_)
+                    ) {
+                        return Err(RuntimeError::InvalidArgument(
+                            "map key must be a value type",
+                        ));
+                    }
+                    let Value::Map(map) = *self.peek()? else {
+                        return Err(RuntimeError::InvalidBytecode);
+                    };
+                    if let Object::Map(entries) = self.heap.get_mut(map) {
+                        entries.insert(key, value);
+                    }
+                }
+
+                Op::List => {
+                    let list = self.alloc(Object::List(Vec::new()));
+                    self.stack.push(Value::List(list));
+                }
+                Op::ListAppend => {
+                    let value = self.pop()?;
+                    let Value::List(list) = *self.peek()? else {
+                        return Err(RuntimeError::InvalidBytecode);
+                    };
+                    if let Object::List(items) = self.heap.get_mut(list) {
+                        items.push(value);
+                    }
+                }
+
+                Op::Class => {
+                    let name = self.name()?;
+                    let field_count = self.read_u8()? as usize;
+                    let class = Class::new(&name, field_count);
+                    self.stack.push(Value::Class(Rc::new(class)));
+                }
+                Op::Subclass => {
+                    let name = self.name()?;
+                    let field_count = self.read_u8()? as usize;
+                    let Value::Class(superclass) = self.pop()? else {
+                        return Err(RuntimeError::InvalidArgument("superclass must be a class"));
+                    };
+                    let class = Class::subclass(&name, field_count, superclass);
+                    self.stack.push(Value::Class(Rc::new(class)));
+                }
+                Op::Method | Op::StaticMethod => {
+                    let signature = self.name()?;
+                    let Value::Fn(method) = self.pop()? else {
+                        return Err(RuntimeError::InvalidBytecode);
+                    };
+                    let Value::Class(class) = self.peek()?.clone() else {
+                        return Err(RuntimeError::InvalidBytecode);
+                    };
+                    // Methods see the variables of the module
+                    // defining the class.
+                    let module = self.frame()?.func.module.clone();
+                    let mut method =
+                        Function::new(&method.name, method.arity, method.chunk.clone(), &module);
+                    method.superclass = class.superclass.clone();
+                    method.field_offset = class.superclass.as_ref().map_or(0, |s| s.field_count);
+                    let method = Rc::new(method);
+                    if op == Op::Method {
+                        class.bind_method(&signature, method);
+                    } else {
+                        class.bind_static_method(&signature, method);
+                    }
+                }
+                Op::Construct => {
+                    let base_slot = self.frame()?.base_slot;
+                    let class = match &self.stack[base_slot] {
+                        Value::Class(class) => class.clone(),
+                        Value::Instance(_) => continue,
+                        _ => return Err(RuntimeError::InvalidBytecode),
+                    };
+                    let fields = vec![Value::Null; class.field_count];
+                    let instance = self.alloc(Object::Instance(Instance { class, fields }));
+                    self.stack[base_slot] = Value::Instance(instance);
+                }
+                Op::GetField => {
+                    let index = self.read_u8()? as usize + self.frame()?.func.field_offset;
+                    let value = self.this_fields()?.get(index).cloned();
+                    self.stack.push(value.ok_or(RuntimeError::InvalidBytecode)?);
+                }
+                Op::SetField => {
+                    let index = self.read_u8()? as usize + self.frame()?.func.field_offset;
+                    let value = self.peek()?.clone();
+                    let field = self
+                        .this_fields()?
+                        .get_mut(index)
+                        .ok_or(RuntimeError::InvalidBytecode)?;
+                    *field = value;
+                }
+                Op::GetFieldOf => {
+                    let index = self.read_u8()? as usize + self.frame()?.func.field_offset;
+                    let instance = self.pop()?;
+                    let value = self.fields(&instance)?.get(index).cloned();
+                    self.stack.push(value.ok_or(RuntimeError::InvalidBytecode)?);
+                }
+                Op::SetFieldOf => {
+                    let index = self.read_u8()? as usize + self.frame()?.func.field_offset;
+                    let instance = self.pop()?;
+                    let value = self.peek()?.clone();
+                    let field = self
+                        .fields(&instance)?
+                        .get_mut(index)
+                        .ok_or(RuntimeError::InvalidBytecode)?;
+                    *field = value;
+                }
+
+                Op::Jump => {
+                    let offset = self.read_i16()?;
+                    self.jump(offset)?;
+                }
+                Op::JumpIfFalse => {
+                    let offset = self.read_i16()?;
+                    if !is_truthy(&self.pop()?) {
+                        self.jump(offset)?;
+                    }
+                }
+                Op::And | Op::Or => {
+                    let offset = self.read_i16()?;
+                    if is_truthy(self.peek()?) == (op == Op::Or) {
+                        self.jump(offset)?;
+                    } else {
+                        self.pop()?;
+                    }
+                }
+
+                Op::Call => {
+                    let argc = self.read_u8()?;
+                    let base_slot = self
+                        .stack
+                        .len()
+                        .checked_sub(argc as usize + 1)
+                        .ok_or(RuntimeError::InvalidBytecode)?;
+                    match &self.stack[base_slot] {
+                        Value::Fn(func) => self.push_frame(func.clone(), base_slot, argc)?,
+                        _ => return Err(RuntimeError::NotCallable),
+                    }
+                }
+                Op::Closure => {
+                    let index = self.read_u16()?;
+                    let Value::Fn(function) = self.constant(index)? else {
+                        return Err(RuntimeError::InvalidBytecode);
+                    };
+                    let creator = self.frame()?.func.clone();
+                    let function = if function.module == creator.module
+                        && function.captures.is_empty()
+                    {
+                        function
+                    } else {
+                        let chunk = function.chunk.clone();
+                        let mut closure =
+                            Function::new(&function.name, function.arity, chunk, &creator.module);
+                        closure.superclass = creator.superclass.clone();
+                        closure.field_offset = creator.field_offset;
+                        closure.upvalues = self.capture(&function.captures)?;
+                        Rc::new(closure)
+                    };
+                    self.stack.push(Value::Fn(function));
+                }
+                Op::Invoke => {
+                    let site = self.call_site()?;
+                    let signature = self.name()?;
+                    let argc = self.read_u8()?;
+                    self.invoke(&signature, argc, site)?;
+                }
+                Op::SuperInvoke | Op::SuperConstruct => {
+                    let signature = self.name()?;
+                    let argc = self.read_u8()?;
+                    let superclass = self
+                        .frame()?
+                        .func
+                        .superclass
+                        .clone()
+                        .ok_or(RuntimeError::InvalidBytecode)?;
+                    let (method, class) = if op == Op::SuperConstruct {
+                        let class = format!("{} metaclass", superclass.name);
+                        (superclass.static_method(&signature), class.into())
+                    } else {
+                        (superclass.method(&signature), superclass.name.clone())
+                    };
+                    let method = method.ok_or(RuntimeError::MethodNotFound { class, signature })?;
+                    let base = self
+                        .stack
+                        .len()
+                        .checked_sub(argc as usize + 1)
+                        .ok_or(RuntimeError::InvalidBytecode)?;
+                    self.push_frame(method, base, argc)?;
+                }
+                Op::Return => {
+                    let result = self.pop()?;
+                    let frame = self.frames.pop().ok_or(RuntimeError::InvalidBytecode)?;
+                    self.close_upvalues(self.fiber, frame.base_slot);
+                    self.stack.truncate(frame.base_slot);
+                    match self.fiber {
+                        FiberRef::Root if self.frames.len() == depth => return Ok(result),
+                        FiberRef::Fiber(handle) if self.frames.is_empty() => {
+                            let fiber = self.fiber_mut(handle);
+                            fiber.state = FiberState::Done;
+                            match fiber.caller.take() {
+                                Some(caller) => self.switch(caller, result)?,
+                                // Nothing called the fiber, so running stops.
+                                None => {
+                                    self.leave_fibers();
+                                    let base_slot = self
+                                        .frames
+                                        .get(depth)
+                                        .ok_or(RuntimeError::InvalidBytecode)?
+                                        .base_slot;
+                                    self.frames.truncate(depth);
+                                    self.stack.truncate(base_slot);
+                                    return Ok(result);
+                                }
+                            }
+                        }
+                        // A call from native code inside a fiber.
+                        FiberRef::Fiber(_) if depth > 0 && self.frames.len() == depth => {
+                            return Ok(result)
+                        }
+                        _ => self.stack.push(result),
+                    }
+                }
+            }
+        }
+    }
+
+    // Calls the method `signature` on the receiver below the
+    // top `argc` values. Methods written in Wren get a new frame
+    // whose return replaces these values with the result; native
+    // methods replace them right away.
+    //
+    // `site` is the offset of the calling `Invoke` in the current
+    // function, whose inline cache is consulted first.
+    fn invoke(&mut self, signature: &str, argc: u8, site: Option<usize>) -> RunResult<()> {
+        let base = self
+            .stack
+            .len()
+            .checked_sub(argc as usize + 1)
+            .ok_or(RuntimeError::InvalidBytecode)?;
+        match &self.stack[base] {
+            Value::Fn(function) if signature.starts_with("call(") => {
+                return self.push_frame(function.clone(), base, argc);
+            }
+            Value::Fiber(_) => return self.fiber_method(signature, base),
+            Value::Map(map) if signature == "iteratorValue(_)" && argc == 1 => {
+                let entry = builtins::map_entry(self, *map, &self.stack[base + 1].clone())?;
+                self.stack.truncate(base);
+                self.stack.push(entry);
+                return Ok(());
+            }
+            Value::Str(string) if argc == 0 && matches!(signature, "bytes" | "codePoints") => {
+                let view = builtins::string_view(self, string.clone(), signature);
+                self.stack.truncate(base);
+                self.stack.push(view);
+                return Ok(());
+            }
+            Value::Class(class) if Rc::ptr_eq(class, &self.fiber_class) => {
+                return self.fiber_method(signature, base);
+            }
+            _ => {}
+        }
+        let class = match &self.stack[base] {
+            Value::Class(class) => Some((class.clone(), true)),
+            Value::Instance(handle) => match self.heap.get(*handle) {
+                Object::Instance(instance) => Some((instance.class.clone(), false)),
+                _ => return Err(RuntimeError::InvalidBytecode),
+            },
+            _ => None,
+        };
+        let method = match class {
+            Some((class, on_class)) => self.find_method(&class, on_class, signature, site)?,
+            None => None,
+        };
+        if let Some(method) = method {
+            // Trivial getters are inlined: the field is read
+            // from the receiver without pushing a frame.
+            if let (Some(index), Value::Instance(handle)) =
+                (method.field_getter(), &self.stack[base])
+            {
+                if let Object::Instance(instance) = self.heap.get(*handle) {
+                    if let Some(value) = instance.fields.get(index).cloned() {
+                        self.inlined_getters += 1;
+                        self.stack.truncate(base);
+                        self.stack.push(value);
+                        return Ok(());
+                    }
+                }
+            }
+            return self.push_frame(method, base, argc);
+        }
+
+        let result = match &self.stack[base] {
+            Value::Class(class) => {
+                let method = self
+                    .foreign_methods
+                    .get(&class.name)
+                    .and_then(|methods| methods.get(signature))
+                    .copied();
+                match method {
+                    Some(method) => {
+                        let args = self.stack[base + 1..].to_vec();
+                        method(self, &args)?
+                    }
+                    None => self.object_method(signature, base)?,
+                }
+            }
+            Value::Instance(_) => match self.sequence_method(signature, base)? {
+                Some(result) => result,
+                None => self.object_method(signature, base)?,
+            },
+            receiver => {
+                match builtins::invoke(&mut self.heap, receiver, signature, &self.stack[base + 1..])
+                {
+                    Err(RuntimeError::MethodNotFound { .. }) => {
+                        match self.sequence_method(signature, base)? {
+                            Some(result) => result,
+                            None => self.object_method(signature, base)?,
+                        }
+                    }
+                    result => result?,
+                }
+            }
+        };
+        self.stack.truncate(base);
+        self.stack.push(result);
+        Ok(())
+    }
+
+    // Calls the method `signature` on `receiver` from native code,
+    // running any Wren code it reaches until it returns.
+    pub(super) fn call_method(
+        &mut self,
+        receiver: Value,
+        signature: &str,
+        args: &[Value],
+    ) -> RunResult<Value> {
+        if self.reentry >= self.max_reentry {
+            return Err(RuntimeError::StackOverflow);
+        }
+        self.reentry += 1;
+        let depth = self.frames.len();
+        self.stack.push(receiver);
+        self.stack.extend_from_slice(args);
+        let result = self
+            .invoke(signature, args.len() as u8, None)
+            .and_then(|()| {
+                if self.frames.len() > depth {
+                    self.execute(depth)
+                } else {
+                    self.pop()
+                }
+            });
+        self.reentry -= 1;
+        result
+    }
+
+    // Advances `iterator` over `sequence` and returns the value it
+    // reaches, or `None` once the sequence is done.
+    fn next_value(&mut self, sequence: &Value, iterator: &mut Value) -> RunResult<Option<Value>> {
+        *iterator = self.call_method(
+            sequence.clone(),
+            "iterate(_)",
+            std::slice::from_ref(iterator),
+        )?;
+        if !is_truthy(iterator) {
+            return Ok(None);
+        }
+        let value = self.call_method(
+            sequence.clone(),
+            "iteratorValue(_)",
+            std::slice::from_ref(iterator),
+        )?;
+        Ok(Some(value))
+    }
+
+    // The methods of `Sequence`, for a receiver that inherits from
+    // it and whose class does not define `signature`, or `None`.
+    // They only use `iterate(_)` and `iteratorValue(_)`, so they
+    // work for any sequence, with results that a running `map` or
+    // `where` block allocates kept on the stack.
+    //
+    // As in Wren, `map(_)` and `where(_)` are lazy: they return a
+    // `MapSequence` or `WhereSequence` holding the receiver and the
+    // function, which is only called as that is iterated.
+    fn sequence_method(&mut self, signature: &str, base: usize) -> RunResult<Option<Value>> {
+        let receiver = self.stack[base].clone();
+        let args = self.stack[base + 1..].to_vec();
+        let Some(Value::Class(sequence_class)) = self.module_variable(CORE_MODULE, "Sequence")
+        else {
+            return Ok(None);
+        };
+        if !builtins::is(self, &receiver, &Value::Class(sequence_class.clone()))? {
+            return Ok(None);
+        }
+        if let Some(result) = self.lazy_sequence_method(&receiver, signature, &args)? {
+            return Ok(Some(result));
+        }
+
+        let result = match (signature, args.as_slice()) {
+            ("map(_)" | "where(_)", [function]) => {
+                let name = if signature == "map(_)" {
+                    "MapSequence"
+                } else {
+                    "WhereSequence"
+                };
+                let Some(Value::Class(class)) = self.module_variable(CORE_MODULE, name).cloned()
+                else {
+                    return Ok(None);
+                };
+                let fields = vec![receiver, function.clone()];
+                Value::Instance(self.alloc(Object::Instance(Instance { class, fields })))
+            }
+            ("toList", []) => {
+                let list = self.alloc(Object::List(Vec::new()));
+                self.stack.push(Value::List(list));
+                let mut iterator = Value::Null;
+                while let Some(value) = self.next_value(&receiver, &mut iterator)? {
+                    if let Object::List(items) = self.heap.get_mut(list) {
+                        items.push(value);
+                    }
+                }
+                self.pop()?
+            }
+            ("count", []) | ("count(_)", [_]) => {
+                let mut count = 0;
+                let mut iterator = Value::Null;
+                while let Some(value) = self.next_value(&receiver, &mut iterator)? {
+                    let counted = match args.first() {
+                        Some(function) => {
+                            is_truthy(&self.call_method(function.clone(), "call(_)", &[value])?)
+                        }
+                        None => true,
+                    };
+                    count += counted as usize;
+                }
+                Value::Num(count as f64)
+            }
+            ("isEmpty", []) => {
+                let iterator = self.call_method(receiver, "iterate(_)", &[Value::Null])?;
+                Value::Bool(!is_truthy(&iterator))
+            }
+            ("contains(_)", [element]) => {
+                let mut iterator = Value::Null;
+                let mut found = false;
+                while let Some(value) = self.next_value(&receiver, &mut iterator)? {
+                    if equals(&value, element) {
+                        found = true;
+                        break;
+                    }
+                }
+                Value::Bool(found)
+            }
+            ("each(_)", [function]) => {
+                let mut iterator = Value::Null;
+                while let Some(value) = self.next_value(&receiver, &mut iterator)? {
+                    self.call_method(function.clone(), "call(_)", &[value])?;
+                }
+                Value::Null
+            }
+            ("all(_)" | "any(_)", [function]) => {
+                // `all` stops at the first element failing the
+                // test, and `any` at the first passing it.
+                let any = signature == "any(_)";
+                let mut iterator = Value::Null;
+                let mut result = !any;
+                while let Some(value) = self.next_value(&receiver, &mut iterator)? {
+                    if is_truthy(&self.call_method(function.clone(), "call(_)", &[value])?) == any {
+                        result = any;
+                        break;
+                    }
+                }
+                Value::Bool(result)
+            }
+            ("reduce(_)" | "reduce(_,_)", _) => {
+                let mut iterator = Value::Null;
+                let (accumulator, function) = match args.as_slice() {
+                    [function] => {
+                        let first = self.next_value(&receiver, &mut iterator)?.ok_or(
+                            RuntimeError::InvalidArgument("cannot reduce an empty sequence"),
+                        )?;
+                        (first, function.clone())
+                    }
+                    [accumulator, function] => (accumulator.clone(), function.clone()),
+                    _ => return Ok(None),
+                };
+                // The accumulator is kept on the stack between calls.
+                let slot = self.stack.len();
+                self.stack.push(accumulator);
+                while let Some(value) = self.next_value(&receiver, &mut iterator)? {
+                    let accumulator = self.stack[slot].clone();
+                    self.stack[slot] =
+                        self.call_method(function.clone(), "call(_,_)", &[accumulator, value])?;
+                }
+                self.pop()?
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(result))
+    }
+
+    // `iterate(_)` and `iteratorValue(_)` of the sequences `map(_)`
+    // and `where(_)` return, whose fields are the sequence they
+    // wrap and the function.
+    fn lazy_sequence_method(
+        &mut self,
+        receiver: &Value,
+        signature: &str,
+        args: &[Value],
+    ) -> RunResult<Option<Value>> {
+        let Value::Instance(handle) = receiver else {
+            return Ok(None);
+        };
+        let Object::Instance(instance) = self.heap.get(*handle) else {
+            return Err(RuntimeError::InvalidBytecode);
+        };
+        let kind = instance.class.name.clone();
+        let is_core = matches!(
+            self.module_variable(CORE_MODULE, &kind),
+            Some(Value::Class(core)) if Rc::ptr_eq(core, &instance.class)
+        );
+        let [sequence, function] = instance.fields.as_slice() else {
+            return Ok(None);
+        };
+        if !is_core {
+            return Ok(None);
+        }
+        let (sequence, function) = (sequence.clone(), function.clone());
+
+        let result = match (&*kind, signature, args) {
+            ("MapSequence", "iterate(_)", [_]) | ("WhereSequence", "iteratorValue(_)", [_]) => {
+                self.call_method(sequence, signature, args)?
+            }
+            ("MapSequence", "iteratorValue(_)", [_]) => {
+                let value = self.call_method(sequence, signature, args)?;
+                self.call_method(function, "call(_)", &[value])?
+            }
+            ("WhereSequence", "iterate(_)", [iterator]) => {
+                let mut iterator = iterator.clone();
+                loop {
+                    let Some(value) = self.next_value(&sequence, &mut iterator)? else {
+                        break iterator;
+                    };
+                    if is_truthy(&self.call_method(function.clone(), "call(_)", &[value])?) {
+                        break iterator;
+                    }
+                }
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(result))
+    }
+
+    // `Fiber.new(_)`, `Fiber.yield(_)` and the methods of
+    // fibers, which switch stacks and so are not foreign.
+    fn fiber_method(&mut self, signature: &str, base: usize) -> RunResult<()> {
+        let value = self.stack.get(base + 1).cloned().unwrap_or(Value::Null);
+        let current = self.fiber;
+        match (&self.stack[base], signature) {
+            (Value::Class(_), "new(_)") => {
+                let Value::Fn(function) = value else {
+                    return Err(RuntimeError::InvalidArgument(
+                        "fiber body must be a function",
+                    ));
+                };
+                if function.arity > 1 {
+                    return Err(RuntimeError::InvalidArgument(
+                        "fiber function must take at most one argument",
+                    ));
+                }
+                let fiber = self.alloc(Object::Fiber(Fiber::new(function)));
+                self.stack.truncate(base);
+                self.stack.push(Value::Fiber(fiber));
+                Ok(())
+            }
+            (Value::Class(_), "yield()" | "yield(_)") => {
+                let FiberRef::Fiber(handle) = current else {
+                    return Err(RuntimeError::InvalidArgument(
+                        "cannot yield from the root fiber",
+                    ));
+                };
+                let fiber = self.fiber_mut(handle);
+                let caller = fiber.caller.take().ok_or(RuntimeError::InvalidArgument(
+                    "cannot yield from a fiber that was not called",
+                ))?;
+                fiber.state = FiberState::Suspended;
+                self.stack.truncate(base);
+                self.switch(caller, value)
+            }
+            (Value::Fiber(handle), "call()" | "call(_)" | "transfer()" | "transfer(_)") => {
+                let handle = *handle;
+                let fiber = self.fiber_mut(handle);
+                match fiber.state {
+                    FiberState::Done => {
+                        return Err(RuntimeError::InvalidArgument(
+                            "cannot resume a finished fiber",
+                        ))
+                    }
+                    FiberState::Active => {
+                        return Err(RuntimeError::InvalidArgument("fiber is already running"))
+                    }
+                    FiberState::New | FiberState::Suspended => {}
+                }
+                if signature.starts_with("call") {
+                    fiber.caller = Some(current);
+                } else if let FiberRef::Fiber(current) = current {
+                    self.fiber_mut(current).state = FiberState::Suspended;
+                }
+                self.stack.truncate(base);
+                self.switch(FiberRef::Fiber(handle), value)
+            }
+            (Value::Fiber(handle), "isDone") => {
+                let done = self.fiber_mut(*handle).state == FiberState::Done;
+                self.stack.truncate(base);
+                self.stack.push(Value::Bool(done));
+                Ok(())
+            }
+            _ => {
+                let result = self.object_method(signature, base)?;
+                self.stack.truncate(base);
+                self.stack.push(result);
+                Ok(())
+            }
+        }
+    }
+
+    // The methods every object has, for a receiver whose class
+    // does not define `signature`.
+    fn object_method(&mut self, signature: &str, base: usize) -> RunResult<Value> {
+        let receiver = self.stack[base].clone();
+        let args = self.stack[base + 1..].to_vec();
+        builtins::object(self, &receiver, signature, &args)
+            .unwrap_or_else(|| Err(builtins::not_found(&self.heap, &receiver, signature)))
+    }
+
+    // Saves the running fiber and resumes `target`, handing it
+    // `value`: a new fiber takes it as its argument, and one that
+    // is waiting in `call` or `Fiber.yield` gets it as the result.
+    fn switch(&mut self, target: FiberRef, value: Value) -> RunResult<()> {
+        let running = Context {
+            stack: std::mem::take(&mut self.stack),
+            frames: std::mem::take(&mut self.frames),
+        };
+        *self.context(self.fiber) = running;
+        let resumed = std::mem::take(self.context(target));
+        self.stack = resumed.stack;
+        self.frames = resumed.frames;
+        self.fiber = target;
+
+        let FiberRef::Fiber(handle) = target else {
+            self.stack.push(value);
+            return Ok(());
+        };
+        let fiber = self.fiber_mut(handle);
+        if std::mem::replace(&mut fiber.state, FiberState::Active) != FiberState::New {
+            self.stack.push(value);
+            return Ok(());
+        }
+        let function = fiber.function.clone();
+        self.stack.push(Value::Fn(function.clone()));
+        if function.arity == 1 {
+            self.stack.push(value);
+        }
+        self.push_frame(function.clone(), 0, function.arity)
+    }
+
+    // Returns to the root fiber for good, finishing the running
+    // fiber and every fiber waiting on it.
+    fn leave_fibers(&mut self) {
+        let mut next = Some(self.fiber);
+        while let Some(FiberRef::Fiber(handle)) = next {
+            self.close_upvalues(FiberRef::Fiber(handle), 0);
+            let fiber = self.fiber_mut(handle);
+            fiber.state = FiberState::Done;
+            fiber.context = Context::default();
+            next = fiber.caller.take();
+        }
+        if self.fiber != FiberRef::Root {
+            let root = std::mem::take(&mut self.root);
+            self.stack = root.stack;
+            self.frames = root.frames;
+            self.fiber = FiberRef::Root;
+        }
+    }
+
+    // The stack of `fiber`, whether it is running or not.
+    fn stack_of(&mut self, fiber: FiberRef) -> &mut Vec<Value> {
+        if fiber == self.fiber {
+            &mut self.stack
+        } else {
+            &mut self.context(fiber).stack
+        }
+    }
+
+    // The upvalues for a new closure of a block, from the
+    // running frame's slots and upvalues.
+    fn capture(&mut self, captures: &[Capture]) -> RunResult<Vec<GcRef>> {
+        let frame = self.frames.last().ok_or(RuntimeError::InvalidBytecode)?;
+        let (base_slot, func) = (frame.base_slot, frame.func.clone());
+        let mut upvalues = Vec::with_capacity(captures.len());
+        for capture in captures {
+            let handle = match *capture {
+                Capture::Local(slot) => self.open_upvalue(base_slot + slot as usize),
+                Capture::Upvalue(index) => *func
+                    .upvalues
+                    .get(index as usize)
+                    .ok_or(RuntimeError::InvalidBytecode)?,
+            };
+            upvalues.push(handle);
+        }
+        Ok(upvalues)
+    }
+
+    // The open upvalue for `slot` of the running stack, so every
+    // block capturing a variable shares it.
+    fn open_upvalue(&mut self, slot: usize) -> GcRef {
+        let fiber = self.fiber;
+        let existing = self.open_upvalues.iter().copied().find(|handle| {
+            matches!(
+                self.heap.get(*handle),
+                Object::Upvalue(Upvalue::Open { fiber: f, slot: s }) if *f == fiber && *s == slot
+            )
+        });
+        existing.unwrap_or_else(|| {
+            let handle = self.alloc(Object::Upvalue(Upvalue::Open { fiber, slot }));
+            self.open_upvalues.push(handle);
+            handle
+        })
+    }
+
+    // Moves the variables in `from` and the slots above it of
+    // `fiber`'s stack into the upvalues referring to them.
+    fn close_upvalues(&mut self, fiber: FiberRef, from: usize) {
+        for handle in std::mem::take(&mut self.open_upvalues) {
+            let slot = match self.heap.get(handle) {
+                Object::Upvalue(Upvalue::Open { fiber: f, slot })
+                    if *f == fiber && *slot >= from =>
+                {
+                    *slot
+                }
+                _ => {
+                    self.open_upvalues.push(handle);
+                    continue;
+                }
+            };
+            let value = self
+                .stack_of(fiber)
+                .get(slot)
+                .cloned()
+                .unwrap_or(Value::Null);
+            *self.heap.get_mut(handle) = Object::Upvalue(Upvalue::Closed(value));
+        }
+    }
+
+    fn context(&mut self, fiber: FiberRef) -> &mut Context {
+        match fiber {
+            FiberRef::Root => &mut self.root,
+            FiberRef::Fiber(handle) => &mut self.fiber_mut(handle).context,
+        }
+    }
+
+    fn fiber_mut(&mut self, handle: GcRef) -> &mut Fiber {
+        match self.heap.get_mut(handle) {
+            Object::Fiber(fiber) => fiber,
+            _ => unreachable!("fiber handle refers to another object"),
+        }
+    }
+
+    fn find_method(
+        &mut self,
+        class: &Rc<Class>,
+        on_class: bool,
+        signature: &str,
+        site: Option<usize>,
+    ) -> RunResult<Option<Rc<Function>>> {
+        let lookup = || {
+            if on_class {
+                class.static_method(signature)
+            } else {
+                class.method(signature)
+            }
+        };
+        let Some(site) = site else {
+            return Ok(lookup());
+        };
+
+        let function = self.frame()?.func.clone();
+        if let Some(method) = function.cache.get(site, class, on_class) {
+            self.cache_stats.hits += 1;
+            return Ok(Some(method));
+        }
+        self.cache_stats.misses += 1;
+        let method = lookup();
+        if let Some(method) = &method {
+            function.cache.insert(site, class, on_class, method.clone());
+        }
+        Ok(method)
+    }
+
+    fn variable(&self, module: &str, name: Rc<str>) -> RunResult<Value> {
+        [module, CORE_MODULE]
+            .iter()
+            .find_map(|module| self.globals.get(*module)?.get(&name))
+            .cloned()
+            .ok_or(RuntimeError::UndefinedVariable(name))
+    }
+
+    fn import(&mut self, module: Rc<str>) -> RunResult<()> {
+        if self.globals.contains_key(&module) {
+            return Ok(());
+        }
+        let function = self
+            .pending_modules
+            .remove(&module)
+            .ok_or_else(|| RuntimeError::UnknownModule(module.clone()))?;
+
+        // Registered before running, so a cyclic import
+        // sees the partially initialized module.
+        self.globals.entry(module).or_default();
+        self.run(function)?;
+        Ok(())
+    }
+
+    /*
+
+        Frame Helpers
+
+    */
+    fn frame(&mut self) -> RunResult<&mut CallFrame> {
+        self.frames.last_mut().ok_or(RuntimeError::InvalidBytecode)
+    }
+
+    // Writes the instruction about to run and the stack it sees.
+    fn trace_instruction(&mut self) -> RunResult<()> {
+        let frame = self.frames.last().ok_or(RuntimeError::InvalidBytecode)?;
+        let ip = frame.ip;
+        let op = frame.func.chunk.read_u8(ip).and_then(Op::from_byte);
+        let stack: Vec<String> = self
+            .stack
+            .iter()
+            .map(|value| builtins::to_string(&self.heap, value))
+            .collect();
+        let Some(sink) = &mut self.trace else {
+            return Ok(());
+        };
+        let line = match op {
+            Some(op) => format!("{ip:04} {op:?} [{}]", stack.join(", ")),
+            None => format!("{ip:04} ? [{}]", stack.join(", ")),
+        };
+        writeln!(sink, "{line}").map_err(|error| RuntimeError::Output(error.kind()))
+    }
+
+    fn read_u8(&mut self) -> RunResult<u8> {
+        let frame = self.frame()?;
+        let byte = frame.func.chunk.read_u8(frame.ip);
+        frame.ip += 1;
+        byte.ok_or(RuntimeError::InvalidBytecode)
+    }
+
+    fn read_u16(&mut self) -> RunResult<u16> {
+        let frame = self.frame()?;
+        let value = frame.func.chunk.read_u16(frame.ip);
+        frame.ip += 2;
+        value.ok_or(RuntimeError::InvalidBytecode)
+    }
+
+    fn read_i16(&mut self) -> RunResult<i16> {
+        self.read_u16().map(|value| value as i16)
+    }
+
+    fn jump(&mut self, offset: i16) -> RunResult<()> {
+        let frame = self.frame()?;
+        frame.ip = frame
+            .ip
+            .checked_add_signed(offset as isize)
+            .ok_or(RuntimeError::InvalidBytecode)?;
+        Ok(())
+    }
+
+    fn constant(&mut self, index: u16) -> RunResult<Value> {
+        let frame = self.frame()?;
+        frame
+            .func
+            .chunk
+            .constants
+            .get(index as usize)
+            .cloned()
+            .ok_or(RuntimeError::InvalidBytecode)
+    }
+
+    fn name(&mut self) -> RunResult<Rc<str>> {
+        let index = self.read_u16()?;
+        match self.constant(index)? {
+            Value::Str(name) => Ok(name),
+            _ => Err(RuntimeError::InvalidBytecode),
+        }
+    }
+
+    // The fields of `this`, the instance in slot 0.
+    fn this_fields(&mut self) -> RunResult<&mut Vec<Value>> {
+        let base_slot = self.frame()?.base_slot;
+        let this = self.stack[base_slot].clone();
+        self.fields(&this)
+    }
+
+    fn fields(&mut self, instance: &Value) -> RunResult<&mut Vec<Value>> {
+        let Value::Instance(handle) = *instance else {
+            return Err(RuntimeError::InvalidBytecode);
+        };
+        match self.heap.get_mut(handle) {
+            Object::Instance(instance) => Ok(&mut instance.fields),
+            _ => Err(RuntimeError::InvalidBytecode),
+        }
+    }
+
+    fn upvalue(&mut self) -> RunResult<GcRef> {
+        let index = self.read_u8()? as usize;
+        let upvalue = self.frame()?.func.upvalues.get(index).copied();
+        upvalue.ok_or(RuntimeError::InvalidBytecode)
+    }
+
+    fn slot(&mut self) -> RunResult<usize> {
+        let slot = self.read_u8()? as usize;
+        let index = self.frame()?.base_slot + slot;
+        if index >= self.stack.len() {
+            return Err(RuntimeError::InvalidBytecode);
+        }
+        Ok(index)
+    }
+
+    /*
+
+        Stack Helpers
+
+    */
+    fn pop(&mut self) -> RunResult<Value> {
+        self.stack.pop().ok_or(RuntimeError::InvalidBytecode)
+    }
+
+    fn peek(&self) -> RunResult<&Value> {
+        self.stack.last().ok_or(RuntimeError::InvalidBytecode)
+    }
+
+    // A binary operator. Numbers take the fast path; an instance
+    // on the left calls its method for the operator's `signature`.
+    fn numeric(&mut self, signature: &str, op: impl Fn(f64, f64) -> Value) -> RunResult<()> {
+        if let [.., Value::Instance(_), _] = self.stack.as_slice() {
+            return self.operator(signature, 1);
+        }
+        let b = self.pop()?;
+        let a = self.pop()?;
+        match (a, b) {
+            (Value::Num(a), Value::Num(b)) => {
+                self.stack.push(op(a, b));
+                Ok(())
+            }
+            _ => Err(RuntimeError::OperandMustBeNumber),
+        }
+    }
+
+    // Bitwise operators work on the operands truncated to u32.
+    fn bitwise(&mut self, signature: &str, op: impl Fn(u32, u32) -> u32) -> RunResult<()> {
+        self.numeric(signature, |a, b| Value::Num(op(a as u32, b as u32) as f64))
+    }
+
+    fn range(&mut self, inclusive: bool) -> RunResult<()> {
+        let signature = if inclusive { "..(_)" } else { "...(_)" };
+        self.numeric(signature, |from, to| Value::Range {
+            from,
+            to,
+            inclusive,
+        })
+    }
+
+    // Calls the operator method on the receiver beneath its
+    // `argc` operands, caching the lookup at the operator's site.
+    fn operator(&mut self, signature: &str, argc: u8) -> RunResult<()> {
+        let site = self.call_site()?;
+        self.invoke(signature, argc, site)
+    }
+
+    // The offset of the instruction just read, under which its
+    // method lookup is cached, or `None` without inline caching.
+    fn call_site(&mut self) -> RunResult<Option<usize>> {
+        let frame = self.frame()?;
+        Ok((!frame.func.chunk.uncached).then(|| frame.ip - 1))
+    }
+
+    // Whether the receiver beneath `argc` operands is an instance
+    // whose class defines `signature`, for operators that every
+    // value otherwise supports.
+    fn overloads(&self, argc: usize, signature: &str) -> bool {
+        let Some(Value::Instance(handle)) = self.stack.iter().rev().nth(argc) else {
+            return false;
+        };
+        match self.heap.get(*handle) {
+            Object::Instance(instance) => instance.class.method(signature).is_some(),
+            _ => false,
+        }
+    }
+}
+
+// Only `false` and `null` are falsy in Wren.
+fn is_truthy(value: &Value) -> bool {
+    !matches!(value, Value::Null | Value::Bool(false))
+}
+
+// The `==` operator, which follows IEEE rules for numbers and
+// the bounds of ranges: `0 == -0`, and NaN equals nothing. Map
+// keys use `Value`'s own equality, under which NaN finds itself.
+pub(super) fn equals(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Num(a), Value::Num(b)) => a == b,
+        (
+            Value::Range {
+                from: from_a,
+                to: to_a,
+                inclusive: inclusive_a,
+            },
+            Value::Range {
+                from: from_b,
+                to: to_b,
+                inclusive: inclusive_b,
+            },
+        ) => from_a == from_b && to_a == to_b && inclusive_a == inclusive_b,
+        _ => a == b,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vm::chunk::Chunk;
+
+    fn function(name: &str, arity: u8, chunk: Chunk) -> Rc<Function> {
+        Rc::new(Function::new(name, arity, chunk, MAIN_MODULE))
+    }
+
+    // fact(n) = n < 2 ? 1 : n * fact(n - 1)
+    fn factorial() -> Rc<Function> {
+        let mut chunk = Chunk::new();
+        let two = chunk.add_constant(Value::Num(2.0));
+        let one = chunk.add_constant(Value::Num(1.0));
+        let name = chunk.add_constant(Value::Str("fact".into()));
+
+        chunk.write_op(Op::GetLocal);
+        chunk.write_u8(1);
+        chunk.write_op(Op::Constant);
+        chunk.write_u16(two);
+        chunk.write_op(Op::Less);
+        chunk.write_op(Op::JumpIfFalse);
+        chunk.write_i16(4);
+        chunk.write_op(Op::Constant);
+        chunk.write_u16(one);
+        chunk.write_op(Op::Return);
+
+        chunk.write_op(Op::GetLocal);
+        chunk.write_u8(1);
+        chunk.write_op(Op::GetGlobal);
+        chunk.write_u16(name);
+        chunk.write_op(Op::GetLocal);
+        chunk.write_u8(1);
+        chunk.write_op(Op::Constant);
+        chunk.write_u16(one);
+        chunk.write_op(Op::Subtract);
+        chunk.write_op(Op::Call);
+        chunk.write_u8(1);
+        chunk.write_op(Op::Multiply);
+        chunk.write_op(Op::Return);
+
+        function("fact", 1, chunk)
+    }
+
+    // Calls the global `name` with `args`.
+    fn call_global(name: &str, args: &[f64]) -> Rc<Function> {
+        let mut chunk = Chunk::new();
+        let name = chunk.add_constant(Value::Str(name.into()));
+        chunk.write_op(Op::GetGlobal);
+        chunk.write_u16(name);
+        for arg in args {
+            let arg = chunk.add_constant(Value::Num(*arg));
+            chunk.write_op(Op::Constant);
+            chunk.write_u16(arg);
+        }
+        chunk.write_op(Op::Call);
+        chunk.write_u8(args.len() as u8);
+        chunk.write_op(Op::Return);
+        function("main", 0, chunk)
+    }
+
+    #[test]
+    fn recursive_call() {
+        let mut vm = Vm::new();
+        vm.define_global("fact", Value::Fn(factorial()));
+
+        let result = vm.run(call_global("fact", &[5.0]));
+
+        assert_eq!(result, Ok(Value::Num(120.0)));
+        assert!(vm.stack.is_empty());
+        assert!(vm.frames.is_empty());
+    }
+
+    #[test]
+    fn return_restores_caller_stack() {
+        // 10 + fact(3)
+        let mut chunk = Chunk::new();
+        let ten = chunk.add_constant(Value::Num(10.0));
+        let three = chunk.add_constant(Value::Num(3.0));
+        let name = chunk.add_constant(Value::Str("fact".into()));
+        chunk.write_op(Op::Constant);
+        chunk.write_u16(ten);
+        chunk.write_op(Op::GetGlobal);
+        chunk.write_u16(name);
+        chunk.write_op(Op::Constant);
+        chunk.write_u16(three);
+        chunk.write_op(Op::Call);
+        chunk.write_u8(1);
+        chunk.write_op(Op::Add);
+        chunk.write_op(Op::Return);
+
+        let mut vm = Vm::new();
+        vm.define_global("fact", Value::Fn(factorial()));
+
+        assert_eq!(vm.run(function("main", 0, chunk)), Ok(Value::Num(16.0)));
+    }
+
+    // A sink the test can read back after the VM wrote to it.
+    #[derive(Clone, Default)]
+    struct Captured(Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn trace_lists_instructions_and_stacks() {
+        // 1 + 2
+        let mut chunk = Chunk::new();
+        let one = chunk.add_constant(Value::Num(1.0));
+        let two = chunk.add_constant(Value::Num(2.0));
+        chunk.write_op(Op::Constant);
+        chunk.write_u16(one);
+        chunk.write_op(Op::Constant);
+        chunk.write_u16(two);
+        chunk.write_op(Op::Add);
+        chunk.write_op(Op::Return);
+
+        let captured = Captured::default();
+        let mut vm = Vm::new().trace(Box::new(captured.clone()));
+
+        assert_eq!(vm.run(function("main", 0, chunk)), Ok(Value::Num(3.0)));
+        let trace = String::from_utf8(captured.0.borrow().clone()).unwrap();
+        assert_eq!(
+            trace,
+            "0000 Constant [Fn]
+0003 Constant [Fn, 1]
+0006 Add [Fn, 1, 2]
+0007 Return [Fn, 3]
+"
+        );
+    }
+
+    #[test]
+    fn frame_limit_overflows() {
+        // forever() = forever()
+        let mut chunk = Chunk::new();
+        let name = chunk.add_constant(Value::Str("forever".into()));
+        chunk.write_op(Op::GetGlobal);
+        chunk.write_u16(name);
+        chunk.write_op(Op::Call);
+        chunk.write_u8(0);
+        chunk.write_op(Op::Return);
+
+        let mut vm = Vm::new().max_frames(64);
+        vm.define_global("forever", Value::Fn(function("forever", 0, chunk)));
+
+        assert_eq!(
+            vm.run(call_global("forever", &[])),
+            Err(RuntimeError::StackOverflow)
+        );
+        assert!(vm.frames.is_empty());
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn alloc_collects_at_threshold() {
+        let mut vm = Vm::new().heap(Heap::with_threshold(8));
+        let kept = vm.alloc(Object::List(Vec::new()));
+        vm.define_global("kept", Value::List(kept));
+
+        for _ in 0..100 {
+            vm.alloc(Object::List(Vec::new()));
+        }
+
+        assert!(vm.objects().live_objects() <= 8);
+        vm.collect();
+        assert_eq!(vm.objects().live_objects(), 1);
+        assert!(matches!(vm.objects().get(kept), Object::List(_)));
+    }
+
+    #[test]
+    fn wrong_arity() {
+        let mut vm = Vm::new();
+        vm.define_global("fact", Value::Fn(factorial()));
+
+        assert_eq!(
+            vm.run(call_global("fact", &[1.0, 2.0])),
+            Err(RuntimeError::WrongArity {
+                expected: 1,
+                found: 2
+            })
+        );
+    }
+}
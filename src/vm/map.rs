@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::ops::Index;
+
+use super::value::Value;
+
+/*
+ *
+ * Maps
+ *
+ * The entries of a map object, kept in the order
+ * their keys were first inserted. `keys`, `values`
+ * and `for` loops over a map see them in that order,
+ * the same on every run. Wren leaves the order
+ * unspecified, so this is a deliberate guarantee
+ * on top of it rather than a difference.
+ *
+ * Entries live in a vector, with an index from each
+ * key to its position. Setting a key that is
+ * already there keeps its place, and removing one
+ * moves the entries after it up.
+ *
+ */
+
+#[derive(Debug, Clone, Default)]
+pub struct OrderedMap {
+    entries: Vec<(Value, Value)>,
+    positions: HashMap<Value, usize>,
+}
+
+impl OrderedMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, key: &Value) -> Option<&Value> {
+        let position = *self.positions.get(key)?;
+        Some(&self.entries[position].1)
+    }
+
+    pub fn contains_key(&self, key: &Value) -> bool {
+        self.positions.contains_key(key)
+    }
+
+    /// The entry `index` places from the first inserted.
+    pub fn get_index(&self, index: usize) -> Option<(&Value, &Value)> {
+        self.entries.get(index).map(|(key, value)| (key, value))
+    }
+
+    /// Sets the value of `key`, returning the one it replaced. A
+    /// new key goes after every other.
+    pub fn insert(&mut self, key: Value, value: Value) -> Option<Value> {
+        if let Some(&position) = self.positions.get(&key) {
+            return Some(std::mem::replace(&mut self.entries[position].1, value));
+        }
+        self.positions.insert(key.clone(), self.entries.len());
+        self.entries.push((key, value));
+        None
+    }
+
+    pub fn remove(&mut self, key: &Value) -> Option<Value> {
+        let position = self.positions.remove(key)?;
+        let (_, value) = self.entries.remove(position);
+        for (key, _) in &self.entries[position..] {
+            if let Some(later) = self.positions.get_mut(key) {
+                *later -= 1;
+            }
+        }
+        Some(value)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.positions.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Value, &Value)> {
+        self.entries.iter().map(|(key, value)| (key, value))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &Value> {
+        self.entries.iter().map(|(key, _)| key)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Value> {
+        self.entries.iter().map(|(_, value)| value)
+    }
+}
+
+impl FromIterator<(Value, Value)> for OrderedMap {
+    fn from_iter<I: IntoIterator<Item = (Value, Value)>>(entries: I) -> Self {
+        let mut map = OrderedMap::new();
+        for (key, value) in entries {
+            map.insert(key, value);
+        }
+        map
+    }
+}
+
+impl Index<&Value> for OrderedMap {
+    type Output = Value;
+
+    fn index(&self, key: &Value) -> &Value {
+        self.get(key).expect("no entry for key")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn num(n: f64) -> Value {
+        Value::Num(n)
+    }
+
+    #[test]
+    fn keeps_insertion_order() {
+        let mut map: OrderedMap = [3.0, 1.0, 2.0]
+            .map(|n| (num(n), num(n * 10.0)))
+            .into_iter()
+            .collect();
+        map.insert(num(1.0), num(0.0));
+
+        assert_eq!(
+            map.keys().cloned().collect::<Vec<_>>(),
+            [num(3.0), num(1.0), num(2.0)]
+        );
+        assert_eq!(map[&num(1.0)], num(0.0));
+
+        assert_eq!(map.remove(&num(3.0)), Some(num(30.0)));
+        map.insert(num(3.0), num(3.0));
+        assert_eq!(
+            map.keys().cloned().collect::<Vec<_>>(),
+            [num(1.0), num(2.0), num(3.0)]
+        );
+        assert_eq!(map.get(&num(2.0)), Some(&num(20.0)));
+        assert_eq!(map.get_index(2), Some((&num(3.0), &num(3.0))));
+        assert_eq!(map.remove(&num(4.0)), None);
+    }
+}
@@ -0,0 +1,14 @@
+mod builtins;
+pub mod cfg;
+pub mod chunk;
+pub mod disasm;
+pub mod fiber;
+pub mod heap;
+mod interpreter;
+pub mod map;
+pub mod regalloc;
+pub mod value;
+pub mod verify;
+
+pub use builtins::CORE_VARIABLES;
+pub use interpreter::{CacheStats, CallFrame, ForeignMethod, RuntimeError, Vm};
@@ -0,0 +1,393 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::cfg::{instructions, jump_target};
+use super::chunk::{Chunk, Op};
+use super::verify::{stack_depths, stack_effect, VerifyError};
+
+/*
+ *
+ * Register Allocation
+ *
+ * Decides where a native translation of a chunk
+ * keeps each local: in one of a fixed number of
+ * registers, or spilled to a slot in the native
+ * frame. Translated naively, every stack slot is
+ * memory, and each `GetLocal` is a load and each
+ * assignment a store.
+ *
+ * Liveness is worked out per instruction over the
+ * control flow graph. A slot is written by the
+ * instruction that pushes a value into it, or by
+ * `SetLocal`, and read by `GetLocal`. Values the
+ * chunk pushes and pops itself never outlive the
+ * expression that made them, so they stay out of
+ * allocation. Each slot gets one interval, from the
+ * first instruction it is live at to the last, so
+ * a slot reused by a later block's local covers
+ * both and the gap between them.
+ *
+ * Intervals are then allocated by linear scan:
+ * in order of where they start, each takes a free
+ * register, and when none is free whichever of it
+ * and the intervals holding registers ends last is
+ * spilled.
+ *
+ */
+
+/// The instructions a stack slot is live across.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LiveInterval {
+    pub slot: usize,
+    /// Index of the first instruction the slot is live at.
+    pub start: usize,
+    /// Index of the last, inclusive.
+    pub end: usize,
+}
+
+impl LiveInterval {
+    pub fn overlaps(&self, other: &LiveInterval) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+    Register(u8),
+    /// A slot in the native frame.
+    Spill(usize),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Allocation {
+    /// Each interval with where it lives, by where it starts.
+    pub assignments: Vec<(LiveInterval, Location)>,
+    /// How many spill slots the frame needs.
+    pub spill_slots: usize,
+}
+
+impl Allocation {
+    pub fn location(&self, slot: usize) -> Option<Location> {
+        self.assignments
+            .iter()
+            .find(|(interval, _)| interval.slot == slot)
+            .map(|(_, location)| *location)
+    }
+}
+
+// The slots one instruction reads and writes.
+#[derive(Debug, Default)]
+struct Access {
+    read: Option<usize>,
+    writes: Vec<usize>,
+}
+
+struct Liveness {
+    accesses: Vec<Access>,
+    live_in: Vec<BTreeSet<usize>>,
+    live_out: Vec<BTreeSet<usize>>,
+}
+
+// Slot 0 holds the receiver and the parameters follow it, so the
+// values the chunk pushes start at slot `arity + 1`.
+fn liveness(chunk: &Chunk, arity: u8) -> Result<Liveness, VerifyError> {
+    let depths = stack_depths(chunk)?;
+    let instructions = instructions(chunk)?;
+    let base = arity as usize + 1;
+
+    let mut accesses = Vec::with_capacity(instructions.len());
+    let mut successors = Vec::with_capacity(instructions.len());
+    for (instruction, depth) in instructions.iter().zip(&depths) {
+        let mut access = Access::default();
+        let mut next = Vec::new();
+        if let Some(depth) = *depth {
+            let slot = || chunk.read_u8(instruction.offset + 1).map(usize::from);
+            match instruction.op {
+                Op::GetLocal => {
+                    access.read = slot();
+                    access.writes.push(base + depth);
+                }
+                Op::SetLocal => access.writes.extend(slot()),
+                _ => {
+                    let (pops, pushes) = stack_effect(chunk, instruction);
+                    let from = base + depth - pops;
+                    access.writes.extend(from..from + pushes);
+                }
+            }
+
+            if let Some(target) = jump_target(chunk, instruction, &instructions)? {
+                next.push(target);
+            }
+            if !matches!(instruction.op, Op::Jump | Op::Return) {
+                next.push(instruction.next);
+            }
+        }
+        accesses.push(access);
+        successors.push(
+            next.into_iter()
+                .map(|offset| {
+                    instructions
+                        .binary_search_by_key(&offset, |i| i.offset)
+                        .expect("verified jumps start instructions")
+                })
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    let mut live_in = vec![BTreeSet::new(); instructions.len()];
+    let mut live_out = vec![BTreeSet::new(); instructions.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for index in (0..instructions.len()).rev() {
+            let out: BTreeSet<usize> = successors[index]
+                .iter()
+                .flat_map(|&next| live_in[next].iter().copied())
+                .collect();
+            let access = &accesses[index];
+            let mut live: BTreeSet<usize> = out
+                .iter()
+                .copied()
+                .filter(|slot| !access.writes.contains(slot))
+                .collect();
+            live.extend(access.read);
+            if live != live_in[index] || out != live_out[index] {
+                live_in[index] = live;
+                live_out[index] = out;
+                changed = true;
+            }
+        }
+    }
+    Ok(Liveness {
+        accesses,
+        live_in,
+        live_out,
+    })
+}
+
+/// The live interval of every local of `chunk`, a function taking
+/// `arity` parameters, by where they start.
+pub fn live_intervals(chunk: &Chunk, arity: u8) -> Result<Vec<LiveInterval>, VerifyError> {
+    let liveness = liveness(chunk, arity)?;
+    let mut ranges: BTreeMap<usize, (usize, usize)> = BTreeMap::new();
+    for index in 0..liveness.accesses.len() {
+        let written = liveness.accesses[index]
+            .writes
+            .iter()
+            .filter(|slot| liveness.live_out[index].contains(slot));
+        for &slot in liveness.live_in[index].iter().chain(written) {
+            let range = ranges.entry(slot).or_insert((index, index));
+            range.0 = range.0.min(index);
+            range.1 = range.1.max(index);
+        }
+    }
+
+    let mut intervals: Vec<LiveInterval> = ranges
+        .into_iter()
+        .map(|(slot, (start, end))| LiveInterval { slot, start, end })
+        .collect();
+    intervals.sort_by_key(|interval| (interval.start, interval.slot));
+    Ok(intervals)
+}
+
+/// Allocates `intervals` to at most `registers` registers, spilling
+/// the rest. With no registers everything is spilled, as a naive
+/// translation would have it.
+pub fn allocate(intervals: &[LiveInterval], registers: usize) -> Allocation {
+    let mut intervals = intervals.to_vec();
+    intervals.sort_by_key(|interval| (interval.start, interval.slot));
+
+    let mut free: Vec<u8> = (0..registers.min(256)).rev().map(|r| r as u8).collect();
+    // Indices into `assignments` of the intervals holding registers,
+    // by where they end.
+    let mut active: Vec<usize> = Vec::new();
+    let mut assignments: Vec<(LiveInterval, Location)> = Vec::with_capacity(intervals.len());
+    let mut spill_slots = 0;
+
+    for interval in intervals {
+        active.retain(|&index| {
+            let (held, location) = assignments[index];
+            if held.end >= interval.start {
+                return true;
+            }
+            if let Location::Register(register) = location {
+                free.push(register);
+            }
+            false
+        });
+
+        let location = if let Some(register) = free.pop() {
+            Location::Register(register)
+        } else {
+            match active.last() {
+                Some(&last) if assignments[last].0.end > interval.end => {
+                    active.pop();
+                    let taken =
+                        std::mem::replace(&mut assignments[last].1, Location::Spill(spill_slots));
+                    spill_slots += 1;
+                    taken
+                }
+                _ => {
+                    spill_slots += 1;
+                    assignments.push((interval, Location::Spill(spill_slots - 1)));
+                    continue;
+                }
+            }
+        };
+        assignments.push((interval, location));
+        let index = assignments.len() - 1;
+        let at = active.partition_point(|&held| assignments[held].0.end <= interval.end);
+        active.insert(at, index);
+    }
+
+    assignments.sort_by_key(|(interval, _)| (interval.start, interval.slot));
+    Allocation {
+        assignments,
+        spill_slots,
+    }
+}
+
+/// How many loads and stores of locals running `chunk` with
+/// `allocation` does: every read of a spilled local, and every
+/// write to one that is read later.
+pub fn memory_accesses(
+    chunk: &Chunk,
+    arity: u8,
+    allocation: &Allocation,
+) -> Result<usize, VerifyError> {
+    let liveness = liveness(chunk, arity)?;
+    let spilled = |slot: &usize| matches!(allocation.location(*slot), Some(Location::Spill(_)));
+    let mut count = 0;
+    for (index, access) in liveness.accesses.iter().enumerate() {
+        count += access.read.iter().filter(|slot| spilled(slot)).count();
+        count += access
+            .writes
+            .iter()
+            .filter(|slot| liveness.live_out[index].contains(slot) && spilled(slot))
+            .count();
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::codegen::compile;
+    use crate::vm::chunk::Function;
+    use crate::vm::value::Value;
+    use crate::vm::Vm;
+    use std::rc::Rc;
+
+    fn method(src: &str, name: &str) -> Rc<Function> {
+        compile(src)
+            .unwrap()
+            .constants
+            .iter()
+            .find_map(|constant| match constant {
+                Value::Fn(function) if function.name.starts_with(name) => Some(function.clone()),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    // No two intervals that are live at once share a register.
+    fn assert_valid(allocation: &Allocation) {
+        for (i, (a, at)) in allocation.assignments.iter().enumerate() {
+            for (b, bt) in &allocation.assignments[i + 1..] {
+                if a.overlaps(b) {
+                    assert_ne!(at, bt, "{a:?} and {b:?} share a location");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn intervals_follow_uses() {
+        let function = method(
+            "var Result
+            class A {
+                static m(a) {
+                    var b = a + 1
+                    var c = b * 2
+                    Result = c + a
+                }
+            }",
+            "m",
+        );
+        let intervals = live_intervals(&function.chunk, function.arity).unwrap();
+        let slots: Vec<usize> = intervals.iter().map(|interval| interval.slot).collect();
+
+        // `a` is live from the start until its last use, after `c`,
+        // and `b` ends where `c` is computed from it.
+        assert_eq!(slots, [1, 2, 3]);
+        let [a, b, c] = [0, 1, 2].map(|i| intervals[i]);
+        assert_eq!(a.start, 0);
+        assert!(b.start < c.start && b.end <= c.start);
+        assert!(a.end > c.start);
+    }
+
+    #[test]
+    fn many_locals() {
+        let mut body = String::from("var v0 = n\n");
+        for i in 1..20 {
+            body += &format!("var v{i} = v{} + {i}\n", i - 1);
+        }
+        body += "Result = v19 + v0 + v10";
+        let src = format!("var Result\nclass A {{\n static m(n) {{\n{body}\n}}\n}}\nA.m(1)");
+        let function = method(&src, "m");
+        let intervals = live_intervals(&function.chunk, function.arity).unwrap();
+        assert_eq!(intervals.len(), 21);
+
+        let naive = allocate(&intervals, 0);
+        let allocated = allocate(&intervals, 2);
+        assert_valid(&naive);
+        assert_valid(&allocated);
+        assert_eq!(naive.spill_slots, 21);
+        // Past `v10`, it and `v0` are live beside each new local.
+        // `v10` lives longest, so it is the one spilled.
+        assert_eq!(allocated.spill_slots, 1);
+        assert_eq!(allocated.location(12), Some(Location::Spill(0)));
+
+        let accesses = |allocation| memory_accesses(&function.chunk, function.arity, allocation);
+        assert!(accesses(&allocated).unwrap() < accesses(&naive).unwrap() / 4);
+
+        let mut vm = Vm::new();
+        let script = Function::script(compile(&src).unwrap());
+        assert_eq!(vm.run(Rc::new(script)), Ok(Value::Null));
+        assert_eq!(
+            vm.global("Result"),
+            Some(&Value::Num(1.0 + 190.0 + 1.0 + 56.0))
+        );
+    }
+
+    #[test]
+    fn loops_keep_locals_live() {
+        let function = method(
+            "var Result
+            class A {
+                static m(n) {
+                    var total = 0
+                    var i = 0
+                    while (i < n) {
+                        var square = i * i
+                        total = total + square
+                        i = i + 1
+                    }
+                    Result = total
+                }
+            }",
+            "m",
+        );
+        let intervals = live_intervals(&function.chunk, function.arity).unwrap();
+        let by_slot = |slot| *intervals.iter().find(|i| i.slot == slot).unwrap();
+        let (n, total, i, square) = (by_slot(1), by_slot(2), by_slot(3), by_slot(4));
+
+        // The loop reads `n`, `total` and `i` again on its way round,
+        // so they stay live across all of it, overlapping `square`.
+        assert!(n.overlaps(&square) && total.overlaps(&square) && i.overlaps(&square));
+        assert!(n.end >= square.end && i.end >= square.end);
+
+        let allocation = allocate(&intervals, 2);
+        assert_valid(&allocation);
+        assert_eq!(allocation.spill_slots, 2);
+    }
+}
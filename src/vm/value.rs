@@ -0,0 +1,505 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+use super::chunk::Function;
+use super::heap::{GcRef, Heap, Object};
+
+/*
+ *
+ * Values
+ *
+ * Everything a Wren program can hold in a variable.
+ *
+ * `PartialEq`, `Eq` and `Hash` implement the equality
+ * Wren uses for map keys: numbers compare by value (so
+ * `-0.0` and `0.0` are the same key) and NaN is equal to
+ * itself, so it can be stored and found again as a key.
+ * The `==` operator follows IEEE rules instead, where
+ * NaN never equals anything.
+ *
+ * Functions, classes, instances, lists, maps and
+ * fibers compare by identity. `deep_equals` compares
+ * lists and maps by their contents instead, for tests
+ * and hosts.
+ *
+ * Hosts convert Rust values into values with `From`,
+ * and back with `TryFrom` or `Value::to_rust`, which
+ * fail with a `TypeMismatch` naming both classes.
+ *
+ */
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(Rc<str>),
+    Range { from: f64, to: f64, inclusive: bool },
+    Fn(Rc<Function>),
+    Class(Rc<Class>),
+    Instance(GcRef),
+    List(GcRef),
+    Map(GcRef),
+    Fiber(GcRef),
+}
+
+/// A class and its methods, keyed by signature. Static methods
+/// are called on the class itself; foreign methods bound on the
+/// VM under the class name are looked up after them.
+///
+/// Binding a method bumps the class's version, invalidating any
+/// lookups inline caches remember.
+#[derive(Debug)]
+pub struct Class {
+    pub name: Rc<str>,
+    /// Number of fields each instance holds, including
+    /// those of its superclasses.
+    pub field_count: usize,
+    pub superclass: Option<Rc<Class>>,
+    methods: RefCell<HashMap<Rc<str>, Rc<Function>>>,
+    static_methods: RefCell<HashMap<Rc<str>, Rc<Function>>>,
+    version: Cell<u32>,
+}
+
+impl Class {
+    pub fn new(name: &str, field_count: usize) -> Self {
+        Class {
+            name: name.into(),
+            field_count,
+            superclass: None,
+            methods: RefCell::default(),
+            static_methods: RefCell::default(),
+            version: Cell::new(0),
+        }
+    }
+
+    /// A class inheriting the instance methods `superclass` has
+    /// now. Constructors and other static methods are not inherited.
+    pub fn subclass(name: &str, field_count: usize, superclass: Rc<Class>) -> Self {
+        let methods = superclass.methods.borrow().clone();
+        Class {
+            field_count: superclass.field_count + field_count,
+            methods: RefCell::new(methods),
+            superclass: Some(superclass),
+            ..Class::new(name, 0)
+        }
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version.get()
+    }
+
+    pub fn method(&self, signature: &str) -> Option<Rc<Function>> {
+        self.methods.borrow().get(signature).cloned()
+    }
+
+    pub fn static_method(&self, signature: &str) -> Option<Rc<Function>> {
+        self.static_methods.borrow().get(signature).cloned()
+    }
+
+    pub fn bind_method(&self, signature: &str, method: Rc<Function>) {
+        self.methods.borrow_mut().insert(signature.into(), method);
+        self.version.set(self.version.get().wrapping_add(1));
+    }
+
+    pub fn bind_static_method(&self, signature: &str, method: Rc<Function>) {
+        self.static_methods
+            .borrow_mut()
+            .insert(signature.into(), method);
+        self.version.set(self.version.get().wrapping_add(1));
+    }
+}
+
+// Numbers equal as keys hash to the same bits.
+fn key_bits(n: f64) -> u64 {
+    if n.is_nan() {
+        f64::NAN.to_bits()
+    } else if n == 0.0 {
+        0.0f64.to_bits()
+    } else {
+        n.to_bits()
+    }
+}
+
+fn key_eq(a: f64, b: f64) -> bool {
+    a == b || (a.is_nan() && b.is_nan())
+}
+
+impl Value {
+    /// The name of the built-in class of this value. Instances
+    /// report `Object`, as their own class is only known through
+    /// the heap.
+    pub fn class_name(&self) -> &'static str {
+        match self {
+            Value::Null => "Null",
+            Value::Bool(_) => "Bool",
+            Value::Num(_) => "Num",
+            Value::Str(_) => "String",
+            Value::Range { .. } => "Range",
+            Value::Fn(_) => "Fn",
+            Value::Class(_) => "Class",
+            Value::Instance(_) => "Object",
+            Value::List(_) => "List",
+            Value::Map(_) => "Map",
+            Value::Fiber(_) => "Fiber",
+        }
+    }
+
+    /// Whether the lists and maps in `self` and `other` hold equal
+    /// elements, all the way down. Other values compare as map keys
+    /// do. A collection that contains itself equals another with the
+    /// same shape.
+    pub fn deep_equals(&self, other: &Value, heap: &Heap) -> bool {
+        deep_equals(self, other, heap, &mut Vec::new())
+    }
+}
+
+// `comparing` holds the pairs of collections being compared. Meeting
+// one again means a cycle, which is assumed equal so the comparison
+// ends; any difference is still found along another path.
+fn deep_equals(a: &Value, b: &Value, heap: &Heap, comparing: &mut Vec<(GcRef, GcRef)>) -> bool {
+    let (a_ref, b_ref) = match (a, b) {
+        (Value::List(a), Value::List(b)) | (Value::Map(a), Value::Map(b)) => (*a, *b),
+        _ => return a == b,
+    };
+    if a_ref == b_ref || comparing.contains(&(a_ref, b_ref)) {
+        return true;
+    }
+    comparing.push((a_ref, b_ref));
+    let equal = match (heap.get(a_ref), heap.get(b_ref)) {
+        (Object::List(a), Object::List(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .zip(b)
+                    .all(|(a, b)| deep_equals(a, b, heap, comparing))
+        }
+        (Object::Map(a), Object::Map(b)) => {
+            a.len() == b.len()
+                && a.iter().all(|(key, a)| {
+                    b.get(key)
+                        .is_some_and(|b| deep_equals(a, b, heap, comparing))
+                })
+        }
+        _ => false,
+    };
+    comparing.pop();
+    equal
+}
+
+/// A value was not of the class a Rust type converts from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TypeMismatch {
+    pub expected: &'static str,
+    pub found: &'static str,
+}
+
+impl std::fmt::Display for TypeMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "expected {}, found {}", self.expected, self.found)
+    }
+}
+
+impl std::error::Error for TypeMismatch {}
+
+/// A Rust type a value can be converted into.
+pub trait FromWren: Sized {
+    fn from_wren(value: &Value) -> Result<Self, TypeMismatch>;
+}
+
+impl Value {
+    /// Converts this value into `T`, if it is of the class `T`
+    /// converts from.
+    pub fn to_rust<T: FromWren>(&self) -> Result<T, TypeMismatch> {
+        T::from_wren(self)
+    }
+
+    fn mismatch(&self, expected: &'static str) -> TypeMismatch {
+        TypeMismatch {
+            expected,
+            found: self.class_name(),
+        }
+    }
+}
+
+impl FromWren for f64 {
+    fn from_wren(value: &Value) -> Result<Self, TypeMismatch> {
+        match value {
+            Value::Num(n) => Ok(*n),
+            value => Err(value.mismatch("Num")),
+        }
+    }
+}
+
+impl FromWren for bool {
+    fn from_wren(value: &Value) -> Result<Self, TypeMismatch> {
+        match value {
+            Value::Bool(b) => Ok(*b),
+            value => Err(value.mismatch("Bool")),
+        }
+    }
+}
+
+impl FromWren for Rc<str> {
+    fn from_wren(value: &Value) -> Result<Self, TypeMismatch> {
+        match value {
+            Value::Str(s) => Ok(s.clone()),
+            value => Err(value.mismatch("String")),
+        }
+    }
+}
+
+impl FromWren for String {
+    fn from_wren(value: &Value) -> Result<Self, TypeMismatch> {
+        Rc::<str>::from_wren(value).map(|s| s.to_string())
+    }
+}
+
+/// `null` converts to `None`, anything else as `T` does.
+impl<T: FromWren> FromWren for Option<T> {
+    fn from_wren(value: &Value) -> Result<Self, TypeMismatch> {
+        match value {
+            Value::Null => Ok(None),
+            value => T::from_wren(value).map(Some),
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::Num(n)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Str(s.into())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Str(s.into())
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+impl TryFrom<Value> for f64 {
+    type Error = TypeMismatch;
+
+    fn try_from(value: Value) -> Result<Self, TypeMismatch> {
+        value.to_rust()
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = TypeMismatch;
+
+    fn try_from(value: Value) -> Result<Self, TypeMismatch> {
+        value.to_rust()
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = TypeMismatch;
+
+    fn try_from(value: Value) -> Result<Self, TypeMismatch> {
+        value.to_rust()
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Null, Value::Null) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Num(a), Value::Num(b)) => key_eq(*a, *b),
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (
+                Value::Range {
+                    from: from_a,
+                    to: to_a,
+                    inclusive: inclusive_a,
+                },
+                Value::Range {
+                    from: from_b,
+                    to: to_b,
+                    inclusive: inclusive_b,
+                },
+            ) => key_eq(*from_a, *from_b) && key_eq(*to_a, *to_b) && inclusive_a == inclusive_b,
+            (Value::Fn(a), Value::Fn(b)) => Rc::ptr_eq(a, b),
+            (Value::Class(a), Value::Class(b)) => Rc::ptr_eq(a, b),
+            (Value::Instance(a), Value::Instance(b))
+            | (Value::List(a), Value::List(b))
+            | (Value::Map(a), Value::Map(b))
+            | (Value::Fiber(a), Value::Fiber(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Value::Null => {}
+            Value::Bool(b) => b.hash(state),
+            Value::Num(n) => key_bits(*n).hash(state),
+            Value::Str(s) => s.hash(state),
+            Value::Range {
+                from,
+                to,
+                inclusive,
+            } => {
+                key_bits(*from).hash(state);
+                key_bits(*to).hash(state);
+                inclusive.hash(state);
+            }
+            Value::Fn(function) => Rc::as_ptr(function).hash(state),
+            Value::Class(class) => Rc::as_ptr(class).hash(state),
+            Value::Instance(handle)
+            | Value::List(handle)
+            | Value::Map(handle)
+            | Value::Fiber(handle) => handle.hash(state),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn deep_equality() {
+        let mut heap = Heap::new();
+        let list =
+            |heap: &mut Heap, items: Vec<Value>| Value::List(heap.alloc(Object::List(items)));
+        let inner_a = list(&mut heap, vec![Value::Num(1.0), Value::Str("a".into())]);
+        let inner_b = list(&mut heap, vec![Value::Num(1.0), Value::Str("a".into())]);
+        let a = list(&mut heap, vec![inner_a.clone(), Value::Null]);
+        let b = list(&mut heap, vec![inner_b, Value::Null]);
+        let c = list(&mut heap, vec![inner_a, Value::Bool(false)]);
+
+        assert!(a.deep_equals(&b, &heap));
+        assert!(!a.deep_equals(&c, &heap));
+        assert_ne!(a, b);
+
+        let map = |heap: &mut Heap, value: Value| {
+            Value::Map(heap.alloc(Object::Map(
+                [(Value::Num(1.0), value)].into_iter().collect(),
+            )))
+        };
+        let map_a = map(&mut heap, a);
+        let map_b = map(&mut heap, b);
+        let map_c = map(&mut heap, c);
+        assert!(map_a.deep_equals(&map_b, &heap));
+        assert!(!map_a.deep_equals(&map_c, &heap));
+
+        // Lists that contain themselves.
+        let cycle_a = list(&mut heap, vec![Value::Num(1.0)]);
+        let cycle_b = list(&mut heap, vec![Value::Num(1.0)]);
+        for cycle in [&cycle_a, &cycle_b] {
+            let Value::List(handle) = cycle else {
+                unreachable!()
+            };
+            let Object::List(items) = heap.get_mut(*handle) else {
+                unreachable!()
+            };
+            items.push(cycle.clone());
+        }
+        assert!(cycle_a.deep_equals(&cycle_b, &heap));
+        assert!(!cycle_a.deep_equals(&map_a, &heap));
+    }
+
+    #[test]
+    fn map_keys() {
+        let mut map = HashMap::new();
+        map.insert(Value::Num(1.0), "one");
+        map.insert(Value::Str("a".into()), "a");
+        map.insert(Value::Bool(true), "true");
+        map.insert(
+            Value::Range {
+                from: 1.0,
+                to: 3.0,
+                inclusive: true,
+            },
+            "range",
+        );
+
+        assert_eq!(map.get(&Value::Num(1.0)), Some(&"one"));
+        assert_eq!(map.get(&Value::Str("a".into())), Some(&"a"));
+        assert_eq!(map.get(&Value::Bool(true)), Some(&"true"));
+        assert_eq!(map.get(&Value::Bool(false)), None);
+        assert_eq!(
+            map.get(&Value::Range {
+                from: 1.0,
+                to: 3.0,
+                inclusive: false,
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn rust_conversions() {
+        assert_eq!(Value::from(2.5), Value::Num(2.5));
+        assert_eq!(f64::try_from(Value::from(2.5)), Ok(2.5));
+        assert_eq!(
+            String::try_from(Value::from("wren")),
+            Ok("wren".to_string())
+        );
+        assert_eq!(Value::from(None::<bool>), Value::Null);
+        assert_eq!(Value::Null.to_rust::<Option<f64>>(), Ok(None));
+
+        let mismatch = String::try_from(Value::from(true));
+        assert_eq!(
+            mismatch,
+            Err(TypeMismatch {
+                expected: "String",
+                found: "Bool"
+            })
+        );
+        assert_eq!(
+            mismatch.unwrap_err().to_string(),
+            "expected String, found Bool"
+        );
+    }
+
+    #[test]
+    fn equal_numbers_collide() {
+        let mut map = HashMap::new();
+        map.insert(Value::Num(1.0), "first");
+        map.insert(Value::Num(1f64), "second");
+        map.insert(Value::Num(0.0), "zero");
+        map.insert(Value::Num(-0.0), "negative zero");
+
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get(&Value::Num(1.0)), Some(&"second"));
+        assert_eq!(map.get(&Value::Num(0.0)), Some(&"negative zero"));
+    }
+
+    #[test]
+    fn nan_is_a_unique_key() {
+        let mut map = HashMap::new();
+        map.insert(Value::Num(f64::NAN), "nan");
+        map.insert(Value::Num(-f64::NAN), "also nan");
+
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.get(&Value::Num(f64::NAN)), Some(&"also nan"));
+    }
+}
@@ -0,0 +1,335 @@
+use super::cfg::{instructions, jump_target, CfgError, Instruction};
+use super::chunk::{Chunk, Op};
+
+/*
+ *
+ * Verifier
+ *
+ * Checks that a chunk is safe to run before it
+ * runs: every instruction decodes, jumps land on
+ * instructions inside the chunk, constant indices
+ * are in the table, and the operand stack never
+ * underflows and has the same depth whichever way
+ * an instruction is reached.
+ *
+ * Depths count the values a chunk pushes itself,
+ * so the receiver and parameters, which are on the
+ * stack before the first instruction, are not
+ * counted and cannot be popped.
+ *
+ */
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    /// The code does not decode, or a jump goes astray.
+    Malformed(CfgError),
+    /// The instruction at `offset` names a constant the table lacks.
+    InvalidConstant { offset: usize, index: u16 },
+    /// The instruction at `offset` pops more than is on the stack.
+    StackUnderflow { offset: usize },
+    /// The instruction at `offset` is reached with different depths.
+    InconsistentStack {
+        offset: usize,
+        expected: usize,
+        found: usize,
+    },
+    /// The instruction at `offset` runs on past the end of the chunk.
+    FallsOffEnd { offset: usize },
+}
+
+impl From<CfgError> for VerifyError {
+    fn from(error: CfgError) -> Self {
+        VerifyError::Malformed(error)
+    }
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyError::Malformed(error) => write!(f, "{error}"),
+            VerifyError::InvalidConstant { offset, index } => {
+                write!(f, "instruction at {offset} uses missing constant {index}")
+            }
+            VerifyError::StackUnderflow { offset } => {
+                write!(f, "instruction at {offset} pops an empty stack")
+            }
+            VerifyError::InconsistentStack {
+                offset,
+                expected,
+                found,
+            } => write!(
+                f,
+                "instruction at {offset} is reached with {expected} and {found} values on the stack"
+            ),
+            VerifyError::FallsOffEnd { offset } => {
+                write!(f, "instruction at {offset} runs past the end of the chunk")
+            }
+        }
+    }
+}
+
+pub fn verify(chunk: &Chunk) -> Result<(), VerifyError> {
+    stack_depths(chunk).map(|_| ())
+}
+
+/// Verifies `chunk`, returning the depth of the stack before each
+/// of its instructions, or `None` where one cannot be reached.
+pub fn stack_depths(chunk: &Chunk) -> Result<Vec<Option<usize>>, VerifyError> {
+    let instructions = instructions(chunk)?;
+    for instruction in &instructions {
+        check_constants(chunk, instruction)?;
+    }
+
+    // The depth before each instruction, once it is known.
+    let mut depths: Vec<Option<usize>> = vec![None; instructions.len()];
+    let mut pending = Vec::new();
+    if !instructions.is_empty() {
+        depths[0] = Some(0);
+        pending.push(0);
+    }
+    while let Some(index) = pending.pop() {
+        let instruction = &instructions[index];
+        let depth = depths[index].expect("pending instructions have a depth");
+        let (pops, pushes) = stack_effect(chunk, instruction);
+        let after = depth.checked_sub(pops).ok_or(VerifyError::StackUnderflow {
+            offset: instruction.offset,
+        })? + pushes;
+
+        let mut successors = Vec::new();
+        if let Some(target) = jump_target(chunk, instruction, &instructions)? {
+            // `And` and `Or` keep their operand when they jump.
+            let kept = matches!(instruction.op, Op::And | Op::Or) as usize;
+            successors.push((target, after + kept));
+        }
+        if !matches!(instruction.op, Op::Jump | Op::Return) {
+            if instruction.next == chunk.code.len() {
+                return Err(VerifyError::FallsOffEnd {
+                    offset: instruction.offset,
+                });
+            }
+            successors.push((instruction.next, after));
+        }
+
+        for (offset, depth) in successors {
+            let next = instructions
+                .binary_search_by_key(&offset, |i| i.offset)
+                .expect("successors start instructions");
+            match depths[next] {
+                None => {
+                    depths[next] = Some(depth);
+                    pending.push(next);
+                }
+                Some(expected) if expected != depth => {
+                    return Err(VerifyError::InconsistentStack {
+                        offset,
+                        expected,
+                        found: depth,
+                    });
+                }
+                Some(_) => {}
+            }
+        }
+    }
+    Ok(depths)
+}
+
+fn check_constants(chunk: &Chunk, instruction: &Instruction) -> Result<(), VerifyError> {
+    let operands: &[usize] = match instruction.op {
+        Op::Constant
+        | Op::GetGlobal
+        | Op::SetGlobal
+        | Op::ImportModule
+        | Op::Class
+        | Op::Subclass
+        | Op::Method
+        | Op::StaticMethod
+        | Op::Closure
+        | Op::Invoke
+        | Op::SuperInvoke
+        | Op::SuperConstruct => &[1],
+        Op::ImportVariable => &[1, 3],
+        _ => &[],
+    };
+    for operand in operands {
+        let offset = instruction.offset;
+        let index = chunk
+            .read_u16(offset + operand)
+            .expect("decoded instructions have their operands");
+        if index as usize >= chunk.constants.len() {
+            return Err(VerifyError::InvalidConstant { offset, index });
+        }
+    }
+    Ok(())
+}
+
+/// How many values the instruction pops, then pushes.
+pub(super) fn stack_effect(chunk: &Chunk, instruction: &Instruction) -> (usize, usize) {
+    let argument_count = |at| {
+        chunk
+            .read_u8(instruction.offset + at)
+            .expect("decoded instructions have their operands") as usize
+    };
+    match instruction.op {
+        Op::Constant
+        | Op::Null
+        | Op::True
+        | Op::False
+        | Op::GetLocal
+        | Op::GetGlobal
+        | Op::GetUpvalue
+        | Op::ImportVariable
+        | Op::Map
+        | Op::List
+        | Op::Class
+        | Op::GetField
+        | Op::Closure => (0, 1),
+        Op::Pop | Op::CloseUpvalue | Op::JumpIfFalse | Op::And | Op::Or | Op::Return => (1, 0),
+        // These read the top of the stack and leave it there.
+        Op::SetLocal | Op::SetGlobal | Op::SetUpvalue | Op::SetField => (1, 1),
+        Op::Negate | Op::Not | Op::Subclass | Op::GetFieldOf => (1, 1),
+        // Pops the instance, leaving the value beneath.
+        Op::SetFieldOf => (2, 1),
+        Op::Add
+        | Op::Subtract
+        | Op::Multiply
+        | Op::Divide
+        | Op::Modulo
+        | Op::Less
+        | Op::LessEqual
+        | Op::Greater
+        | Op::GreaterEqual
+        | Op::Equal
+        | Op::NotEqual
+        | Op::Is
+        | Op::BitwiseAnd
+        | Op::BitwiseOr
+        | Op::BitwiseXor
+        | Op::LeftShift
+        | Op::RightShift
+        | Op::InclusiveRange
+        | Op::ExclusiveRange => (2, 1),
+        // Pops the key and value, leaving the map.
+        Op::MapInsert => (3, 1),
+        // Pops the value, leaving the list.
+        Op::ListAppend => (2, 1),
+        // Pops the function, leaving the class.
+        Op::Method | Op::StaticMethod => (2, 1),
+        // Pops the values down to the copied one and puts them back.
+        Op::Dup => (argument_count(1) + 1, argument_count(1) + 2),
+        Op::Call => (argument_count(1) + 1, 1),
+        Op::Invoke | Op::SuperInvoke | Op::SuperConstruct => (argument_count(3) + 1, 1),
+        Op::ImportModule | Op::Construct | Op::Jump => (0, 0),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::codegen::compile;
+    use crate::vm::chunk::ChunkBuilder;
+    use crate::vm::value::Value;
+
+    // Verifies `chunk` and every function among its constants.
+    fn verify_all(chunk: &Chunk) -> Result<(), VerifyError> {
+        verify(chunk)?;
+        for constant in &chunk.constants {
+            if let Value::Fn(function) = constant {
+                verify_all(&function.chunk)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn compiled_code_verifies() {
+        let chunk = compile(
+            "class Counter {
+                construct new() { _n = 0 }
+                add(n) { _n = _n + n }
+                n { _n }
+            }
+            var counter = Counter.new()
+            for (i in 1..3) counter.add(i && i || 0)
+            var check = {1: counter.n > 5}.count
+            check += 1
+            var map = {1: 2}
+            map[1] *= 3
+            while (check > 0) check = check - 1
+            if (check == 0) check = 1 else check = 2
+            var f = Fn.new { |a| a }",
+        )
+        .unwrap();
+
+        assert_eq!(verify_all(&chunk), Ok(()));
+    }
+
+    #[test]
+    fn bad_jump() {
+        let mut builder = ChunkBuilder::new();
+        builder.op(Op::Jump).u16(5).op(Op::Null).op(Op::Return);
+
+        assert_eq!(
+            verify(&builder.build()),
+            Err(VerifyError::Malformed(CfgError::JumpOutOfBounds {
+                offset: 0,
+                target: 8
+            }))
+        );
+    }
+
+    #[test]
+    fn bad_constant_index() {
+        let mut builder = ChunkBuilder::new();
+        builder.add_constant(Value::Null);
+        builder.op(Op::Constant).u16(1).op(Op::Return);
+
+        assert_eq!(
+            verify(&builder.build()),
+            Err(VerifyError::InvalidConstant {
+                offset: 0,
+                index: 1
+            })
+        );
+    }
+
+    #[test]
+    fn stack_underflow() {
+        let mut builder = ChunkBuilder::new();
+        builder.op(Op::Null).op(Op::Add).op(Op::Return);
+
+        assert_eq!(
+            verify(&builder.build()),
+            Err(VerifyError::StackUnderflow { offset: 1 })
+        );
+    }
+
+    #[test]
+    fn inconsistent_stack() {
+        // Only one branch pushes before they join.
+        let mut builder = ChunkBuilder::new();
+        let skip = builder.op(Op::True).emit_jump(Op::JumpIfFalse);
+        builder.op(Op::Null);
+        builder.patch_jump(skip).unwrap();
+        builder.op(Op::Null).op(Op::Return);
+
+        assert_eq!(
+            verify(&builder.build()),
+            Err(VerifyError::InconsistentStack {
+                offset: 5,
+                expected: 0,
+                found: 1
+            })
+        );
+    }
+
+    #[test]
+    fn falls_off_end() {
+        let mut builder = ChunkBuilder::new();
+        builder.op(Op::Null);
+
+        assert_eq!(
+            verify(&builder.build()),
+            Err(VerifyError::FallsOffEnd { offset: 0 })
+        );
+    }
+}
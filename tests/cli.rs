@@ -0,0 +1,95 @@
+use std::path::PathBuf;
+
+use assert_cmd::assert::Assert;
+use assert_cmd::Command;
+use predicates::str::contains;
+
+// Writes `src` to a script of its own and runs the CLI on it.
+fn wren_jit(command: &str, name: &str, src: &str) -> Assert {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let script = dir.join(format!("{name}.wren"));
+    std::fs::write(&script, src).unwrap();
+    Command::cargo_bin("wren-jit")
+        .unwrap()
+        .arg(command)
+        .arg(&script)
+        .assert()
+}
+
+#[test]
+fn run_prints_output() {
+    wren_jit(
+        "run",
+        "run_prints_output",
+        "var sum = 0\nfor (i in 1..3) sum = sum + i\nSystem.print(sum)\n",
+    )
+    .success()
+    .stdout("6\n");
+}
+
+#[test]
+fn run_reports_errors() {
+    wren_jit("run", "run_reports_compile_errors", "var a = b\n")
+        .code(65)
+        .stderr(contains("variable 'b' is not defined"));
+
+    wren_jit("run", "run_reports_runtime_errors", "null.foo\n")
+        .code(70)
+        .stderr(contains("Null does not implement 'foo'"))
+        .stderr(contains(" --> 1:1"));
+}
+
+#[test]
+fn run_imports_modules_next_to_the_script() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("imports");
+    std::fs::create_dir_all(dir.join("lib")).unwrap();
+    std::fs::write(dir.join("lib/shapes.wren"), "var sides = 4\n").unwrap();
+    std::fs::write(dir.join("broken.wren"), "var a = b\n").unwrap();
+    let script = |name: &str, src: &str| {
+        let script = dir.join(name);
+        std::fs::write(&script, src).unwrap();
+        Command::cargo_bin("wren-jit")
+            .unwrap()
+            .arg("run")
+            .arg(&script)
+            .assert()
+    };
+
+    script(
+        "main.wren",
+        "import \"lib/shapes\" for sides\nSystem.print(sides)\n",
+    )
+    .success()
+    .stdout("4\n");
+    script("missing.wren", "import \"nowhere\"\n")
+        .code(66)
+        .stderr(contains("could not find module 'nowhere'"));
+    script("uses_broken.wren", "import \"broken\"\n")
+        .code(65)
+        .stderr(contains("in module 'broken'"))
+        .stderr(contains("variable 'b' is not defined"));
+}
+
+#[test]
+fn lex_parse_and_disasm() {
+    wren_jit("lex", "lex", "var a")
+        .success()
+        .stdout("0..3 Var \"var\"\n4..5 Identifier \"a\"\n");
+
+    wren_jit("parse", "parse", "var a = 1")
+        .success()
+        .stdout(contains("Var {"));
+
+    wren_jit("disasm", "disasm", "System.print(1)")
+        .success()
+        .stdout(contains("Invoke 2 (\"print(_)\") args 1"));
+}
+
+#[test]
+fn bad_usage() {
+    Command::cargo_bin("wren-jit")
+        .unwrap()
+        .arg("run")
+        .assert()
+        .code(64);
+}
@@ -0,0 +1,70 @@
+use std::fmt::Write;
+use std::path::Path;
+
+use wren_lift::compiler::lexer::lex_collect_errors;
+
+/*
+ *
+ * Lexer Snapshots
+ *
+ * Each `tests/lexer/<name>.wren` is lexed and its
+ * tokens, one per line as `kind span slice`, are
+ * compared with the insta snapshot `<name>.snap`
+ * beside it. Lex errors are listed among the tokens
+ * as `error`.
+ *
+ * After a deliberate change to the lexer, review
+ * and accept the new snapshots with `cargo insta
+ * review`.
+ *
+ */
+
+fn snapshot(src: &str) -> String {
+    let (tokens, errors) = lex_collect_errors(src);
+    let mut lines: Vec<(usize, String)> = tokens
+        .iter()
+        .map(|token| {
+            let text = token.text(src);
+            let line = format!("{:?} {:?} {text:?}", token.token, token.span);
+            (token.span.start, line)
+        })
+        .chain(errors.iter().map(|error| {
+            let text = &src[error.span.clone()];
+            (error.span.start, format!("error {:?} {text:?}", error.span))
+        }))
+        .collect();
+    lines.sort_by_key(|(start, _)| *start);
+
+    let mut out = String::new();
+    for (_, line) in lines {
+        let _ = writeln!(out, "{line}");
+    }
+    out
+}
+
+fn check(name: &str) {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lexer");
+    let src = std::fs::read_to_string(dir.join(format!("{name}.wren"))).unwrap();
+    insta::with_settings!({
+        snapshot_path => dir,
+        prepend_module_to_snapshot => false,
+        omit_expression => true,
+    }, {
+        insta::assert_snapshot!(name, snapshot(&src));
+    });
+}
+
+#[test]
+fn comments() {
+    check("comments");
+}
+
+#[test]
+fn ranges() {
+    check("ranges");
+}
+
+#[test]
+fn attributes() {
+    check("attributes");
+}